@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use rstore::engine::KVEngine;
+
+use crate::Timer;
+
+// Case 3. 서버를 거치지 않고 엔진 레이아웃(단일 Mutex vs 샤딩)별 처리량을 읽기/쓰기 비율에 따라 비교합니다.
+
+const CASE_3_THREAD_COUNT: usize = 10;
+const CASE_3_OPERATION_COUNT_PER_THREAD: usize = 200_000;
+const CASE_3_KEY_COUNT: usize = 100_000;
+
+/// The layout `KVEngine` had before sharding: one `Mutex` around the whole map.
+#[derive(Clone, Default)]
+pub struct SingleMutexEngine {
+    kv: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+pub trait LocalStore: Clone + Send + 'static {
+    fn set_key_value(&self, key: String, value: Vec<u8>);
+    fn get_key_value(&self, key: &str) -> Option<Vec<u8>>;
+}
+
+impl LocalStore for SingleMutexEngine {
+    fn set_key_value(&self, key: String, value: Vec<u8>) {
+        self.kv.lock().unwrap().insert(key, value);
+    }
+
+    fn get_key_value(&self, key: &str) -> Option<Vec<u8>> {
+        self.kv.lock().unwrap().get(key).cloned()
+    }
+}
+
+impl LocalStore for KVEngine {
+    fn set_key_value(&self, key: String, value: Vec<u8>) {
+        KVEngine::set_key_value(self, key, value).unwrap();
+    }
+
+    fn get_key_value(&self, key: &str) -> Option<Vec<u8>> {
+        KVEngine::get_key_value(self, key).ok()
+    }
+}
+
+// xorshift, so every layout sees the same access pattern without pulling in a rng crate
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn run_mix(store: &impl LocalStore, read_percent: u64) -> std::time::Duration {
+    for i in 0..CASE_3_KEY_COUNT {
+        store.set_key_value(format!("key{}", i), format!("value{}", i).into_bytes());
+    }
+
+    let timer = Timer::new();
+
+    let handles: Vec<_> = (0..CASE_3_THREAD_COUNT)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut state = 0x9E37_79B9_7F4A_7C15 ^ (i as u64 + 1);
+
+                for _ in 0..CASE_3_OPERATION_COUNT_PER_THREAD {
+                    let key = format!("key{}", next_random(&mut state) % CASE_3_KEY_COUNT as u64);
+
+                    if next_random(&mut state) % 100 < read_percent {
+                        store.get_key_value(&key);
+                    } else {
+                        store.set_key_value(key, b"value".to_vec());
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    timer.elapsed()
+}
+
+pub fn case_3() {
+    let mixes = [("read-heavy (90% GET)", 90), ("write-heavy (10% GET)", 10)];
+    let total_operations = (CASE_3_THREAD_COUNT * CASE_3_OPERATION_COUNT_PER_THREAD) as f64;
+
+    for (mix_name, read_percent) in mixes {
+        println!("[{}]", mix_name);
+
+        let sharded = KVEngine::new();
+        let layouts: [(String, Box<dyn Fn() -> std::time::Duration>); 3] = [
+            (
+                "single Mutex (old)".to_string(),
+                Box::new(move || run_mix(&SingleMutexEngine::default(), read_percent)),
+            ),
+            (
+                "1 shard RwLock".to_string(),
+                Box::new(move || run_mix(&KVEngine::with_shard_count(1), read_percent)),
+            ),
+            (
+                format!("{} shards RwLock", sharded.shard_count()),
+                Box::new(move || run_mix(&sharded, read_percent)),
+            ),
+        ];
+
+        for (layout_name, run) in layouts {
+            let elapsed = run();
+            println!(
+                "{}: {} ms, {:.0} ops/sec",
+                layout_name,
+                elapsed.as_millis(),
+                total_operations / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
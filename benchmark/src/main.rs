@@ -2,6 +2,7 @@ use std::thread;
 
 use redis::RedisClient;
 
+pub mod engine_layout;
 pub mod redis;
 pub mod rstore_http;
 pub mod rstore_tcp;
@@ -188,6 +189,15 @@ async fn benchmark_rstore_tcp() {
 
 #[tokio::main]
 async fn main() {
+    println!("------------------------------");
+    println!("Benchmarking RStore engine layouts...");
+    engine_layout::case_3();
+    println!("Benchmarking RStore engine layouts completed.");
+    println!("------------------------------");
+
+    println!("");
+    println!("");
+
     println!("------------------------------");
     println!("Benchmarking Redis...");
     benchmark_redis().await;
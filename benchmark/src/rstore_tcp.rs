@@ -1,8 +1,5 @@
 use futures::executor::block_on;
-use rstore::{
-    client::ConnectionConfig,
-    protocol::{GetRequest, SetRequest},
-};
+use rstore::client::ConnectionConfig;
 
 use crate::KeyValueStore;
 
@@ -28,25 +25,15 @@ impl RStoreClient {
 #[async_trait::async_trait]
 impl KeyValueStore for RStoreClient {
     async fn set_key_value(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
-        self.client
-            .set(SetRequest {
-                key: key.to_string(),
-                value: value.to_string(),
-            })
-            .await?;
+        self.client.set_string(key, value).await?;
 
         Ok(())
     }
 
     async fn get_key_value(&mut self, key: &str) -> anyhow::Result<String> {
-        let response = self
-            .client
-            .get(GetRequest {
-                key: key.to_string(),
-            })
-            .await?;
-
-        Ok(response.value)
+        let value = self.client.get_string(key).await?;
+
+        Ok(value)
     }
 
     async fn clear_all(&mut self) -> anyhow::Result<()> {
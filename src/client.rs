@@ -1,12 +1,19 @@
 use std::{
-    sync::{Arc, Mutex, Weak},
-    time::Duration,
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
 };
 
 use chorba::{decode, encode};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use futures_util::{Stream, TryStreamExt, stream};
+use tokio::{io::AsyncWriteExt, net::TcpStream, task::JoinHandle};
 
-use crate::protocol::{self, GetRequest, GetResponse, generate_packet, read_all_from_stream};
+use crate::protocol::{
+    self, GetRequest, GetResponse, generate_packet, read_all_from_stream, read_packet,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -16,6 +23,46 @@ pub enum ClientError {
     SendRequestError(std::io::Error),
     #[error("Packet error: {0}")]
     PacketError(#[from] protocol::PacketError),
+    #[error("Value is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Server is out of memory")]
+    OutOfMemory,
+    #[error("Value is not a number or out of range")]
+    NotANumber,
+    #[error("Key was modified since it was read")]
+    VersionMismatch,
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("Stream ID is invalid or not above the last one")]
+    InvalidStreamId,
+    #[error("No such consumer group")]
+    NoSuchGroup,
+    #[error("Consumer group already exists")]
+    GroupExists,
+    #[error("Value is not valid JSON")]
+    InvalidJson,
+    #[error("JSON path is invalid")]
+    InvalidJsonPath,
+    #[error("Nothing of the expected kind at the JSON path")]
+    JsonPathNotFound,
+    #[error("Key already exists")]
+    KeyExists,
+    #[error("Error rate must be at least 0.000001 and below 1, and capacity 1 to 4194304")]
+    InvalidFilterOptions,
+    #[error("Coordinates or search area out of range")]
+    InvalidCoordinates,
+    #[error("Rate limit needs a known algorithm, a limit above 0 and a window of at least 1ms")]
+    InvalidRateLimit,
+    #[error("Lock lease must be at least 1ms")]
+    InvalidLockLease,
+    #[error("Lock wasn't acquired")]
+    LockNotAcquired,
+    #[error("Namespace names are 1 to 64 ASCII letters, digits, '_' or '-'")]
+    InvalidNamespace,
+    #[error("Namespace limit reached")]
+    TooManyNamespaces,
+    #[error("Namespace still holds keys")]
+    NamespaceNotEmpty,
 }
 
 pub type ClientResult<T> = std::result::Result<T, ClientError>;
@@ -28,6 +75,7 @@ pub struct ConnectionConfig {
     pub max_connections: u32,         // 최대 허용 연결 수
     pub connection_timeout: Duration, // 유휴 연결 타임아웃
     pub idle_timeout: Duration,       // 연결 최대 수명
+    pub namespace: Option<String>,    // 연결마다 SELECT할 네임스페이스 (None이면 기본 네임스페이스)
 }
 
 const MIN_CONNECTION_DEFAULT: u32 = 1;
@@ -44,6 +92,7 @@ impl Default for ConnectionConfig {
             idle_timeout: IDLE_TIMEOUT_DEFAULT,
             host: "".into(),
             port: 0,
+            namespace: None,
         }
     }
 }
@@ -57,6 +106,7 @@ impl ConnectionConfig {
             max_connections: MAX_CONNECTION_DEFAULT,
             connection_timeout: CONNECTION_TIMEOUT_DEFAULT,
             idle_timeout: IDLE_TIMEOUT_DEFAULT,
+            namespace: None,
         }
     }
 }
@@ -80,13 +130,33 @@ impl RStoreClient {
         }
     }
 
-    async fn create_connection(&self) -> ClientResult<PooledConnection> {
-        let tcp_stream = TcpStream::connect(format!(
+    /// Connects to the server and selects the configured namespace, if any.
+    async fn open_connection(&self) -> ClientResult<TcpStream> {
+        let mut tcp_stream = TcpStream::connect(format!(
             "{}:{}",
             self.connection_config.host, self.connection_config.port
         ))
         .await?;
 
+        if let Some(namespace) = &self.connection_config.namespace {
+            let request = protocol::SelectRequest {
+                namespace: namespace.clone(),
+            };
+            send_request(
+                &mut tcp_stream,
+                protocol::SELECT,
+                &encode(&request),
+                protocol::SELECT_OK,
+            )
+            .await?;
+        }
+
+        Ok(tcp_stream)
+    }
+
+    async fn create_connection(&self) -> ClientResult<PooledConnection> {
+        let tcp_stream = self.open_connection().await?;
+
         {
             let mut pool = self.connection_pool.lock().unwrap();
 
@@ -176,6 +246,26 @@ impl RStoreClient {
         Ok(())
     }
 
+    /// Stores a UTF-8 string value.
+    pub async fn set_string(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> ClientResult<()> {
+        self.set(protocol::SetRequest {
+            key: key.into(),
+            value: value.into().into_bytes(),
+        })
+        .await
+    }
+
+    /// Fetches a value and interprets it as a UTF-8 string.
+    pub async fn get_string(&self, key: impl Into<String>) -> ClientResult<String> {
+        let response = self.get(GetRequest { key: key.into() }).await?;
+
+        Ok(String::from_utf8(response.value)?)
+    }
+
     pub async fn delete(&self, request: protocol::DeleteRequest) -> ClientResult<()> {
         let mut connection = self.get_connection_or_wait().await?;
 
@@ -186,149 +276,2274 @@ impl RStoreClient {
         Ok(())
     }
 
-    pub async fn clear(&self) -> ClientResult<()> {
+    pub async fn set_expire(&self, request: protocol::SetExpireRequest) -> ClientResult<()> {
         let mut connection = self.get_connection_or_wait().await?;
 
-        request_clear(&mut connection.tcp_stream).await?;
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::SET_EX,
+            &encode(&request),
+            protocol::SET_OK,
+        )
+        .await?;
 
         connection.release_to_pool();
 
         Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct ConnectionPool {
-    connections: Vec<PooledConnection>,
-    connection_count: u32,
-}
+    pub async fn expire(&self, request: protocol::ExpireRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-#[derive(Debug)]
-pub struct PooledConnection {
-    tcp_stream: TcpStream,
-    pool: Weak<Mutex<ConnectionPool>>,
-}
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::EXPIRE,
+            &encode(&request),
+            protocol::EXPIRE_OK,
+        )
+        .await?;
 
-impl PooledConnection {
-    pub fn new(tcp_stream: TcpStream, pool: Weak<Mutex<ConnectionPool>>) -> Self {
-        PooledConnection { tcp_stream, pool }
+        connection.release_to_pool();
+
+        Ok(())
     }
 
-    pub fn release_to_pool(self) {
-        if let Some(pool) = self.pool.upgrade() {
-            let mut pool = pool.lock().unwrap();
-            pool.connections.push(self);
-        }
+    pub async fn expire_at(&self, request: protocol::ExpireAtRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::EXPIRE_AT,
+            &encode(&request),
+            protocol::EXPIRE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
     }
-}
 
-async fn request_ping(tcp_stream: &mut TcpStream) -> ClientResult<()> {
-    // empty bytes array
-    let request_bytes = vec![];
+    pub async fn persist(&self, request: protocol::PersistRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-    let request_packet = generate_packet(protocol::PING, &request_bytes);
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::PERSIST,
+            &encode(&request),
+            protocol::PERSIST_OK,
+        )
+        .await?;
 
-    tcp_stream.write_all(&request_packet).await?;
+        connection.release_to_pool();
 
-    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+        Ok(())
+    }
 
-    if response_tag != protocol::PONG {
-        return Err(ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Ping Failed",
-        )));
+    pub async fn ttl(&self, request: protocol::TtlRequest) -> ClientResult<protocol::TtlResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::TTL,
+            &encode(&request),
+            protocol::TTL_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
     }
 
-    Ok(())
-}
+    pub async fn incr_by(
+        &self,
+        request: protocol::IncrByRequest,
+    ) -> ClientResult<protocol::IncrByResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-async fn request_get(tcp_stream: &mut TcpStream, request: GetRequest) -> ClientResult<GetResponse> {
-    let request_bytes = encode(&request);
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::INCR_BY,
+            &encode(&request),
+            protocol::INCR_OK,
+        )
+        .await?;
 
-    let request_packet = generate_packet(protocol::GET, &request_bytes);
+        connection.release_to_pool();
 
-    tcp_stream.write_all(&request_packet).await?;
+        decode_response(&response_bytes)
+    }
 
-    let (response_tag, response_bytes) = fetch_all_packet(tcp_stream).await?;
+    pub async fn decr_by(
+        &self,
+        request: protocol::IncrByRequest,
+    ) -> ClientResult<protocol::IncrByResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-    if response_tag != protocol::GET_OK {
-        return Err(ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid response tag",
-        )));
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::DECR_BY,
+            &encode(&request),
+            protocol::INCR_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
     }
 
-    let decoded = decode::<GetResponse>(&response_bytes).map_err(|_| {
-        ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to decode response",
-        ))
-    })?;
+    pub async fn incr_by_float(
+        &self,
+        request: protocol::IncrByFloatRequest,
+    ) -> ClientResult<protocol::IncrByFloatResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-    Ok(decoded)
-}
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::INCR_BY_FLOAT,
+            &encode(&request),
+            protocol::INCR_FLOAT_OK,
+        )
+        .await?;
 
-async fn request_set(
-    tcp_stream: &mut TcpStream,
-    request: protocol::SetRequest,
-) -> ClientResult<()> {
-    let request_bytes = encode(&request);
+        connection.release_to_pool();
 
-    let request_packet = generate_packet(protocol::SET, &request_bytes);
+        decode_response(&response_bytes)
+    }
 
-    tcp_stream.write_all(&request_packet).await?;
+    /// Stores the value only if the key is still at `request.expected_version`.
+    /// Fails with `ClientError::VersionMismatch` if it was modified in between.
+    pub async fn compare_and_set(
+        &self,
+        request: protocol::CompareAndSetRequest,
+    ) -> ClientResult<protocol::CompareAndSetResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::COMPARE_AND_SET,
+            &encode(&request),
+            protocol::COMPARE_AND_SET_OK,
+        )
+        .await?;
 
-    if response_tag != protocol::SET_OK {
-        return Err(ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid response tag",
-        )));
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
     }
 
-    Ok(())
-}
+    /// Conditional set (NX / XX) that can also return the previous value (GET).
+    pub async fn set_with_options(
+        &self,
+        request: protocol::SetWithOptionsRequest,
+    ) -> ClientResult<protocol::SetWithOptionsResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-async fn request_delete(
-    tcp_stream: &mut TcpStream,
-    request: protocol::DeleteRequest,
-) -> ClientResult<()> {
-    let request_bytes = encode(&request);
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SET_WITH_OPTIONS,
+            &encode(&request),
+            protocol::SET_WITH_OPTIONS_OK,
+        )
+        .await?;
 
-    let request_packet = generate_packet(protocol::DELETE, &request_bytes);
+        connection.release_to_pool();
 
-    tcp_stream.write_all(&request_packet).await?;
+        decode_response(&response_bytes)
+    }
 
-    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+    /// Stores the value only if the key doesn't exist yet. Returns whether it was written.
+    pub async fn set_if_absent(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+    ) -> ClientResult<bool> {
+        let request = protocol::SetWithOptionsRequest {
+            key: key.into(),
+            value: value.into(),
+            if_absent: true,
+            ..Default::default()
+        };
+
+        Ok(self.set_with_options(request).await?.version.is_some())
+    }
 
-    if response_tag != protocol::DELETE_OK {
-        return Err(ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid response tag",
-        )));
+    /// Removes the key and returns the value it held.
+    pub async fn get_and_delete(&self, request: GetRequest) -> ClientResult<GetResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::GET_DELETE,
+            &encode(&request),
+            protocol::GET_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
     }
 
-    Ok(())
-}
+    /// Fetches several keys in one round trip.
+    pub async fn mget(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::MGetResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-async fn request_clear(tcp_stream: &mut TcpStream) -> ClientResult<()> {
-    let request_bytes = vec![];
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::MGET,
+            &encode(&request),
+            protocol::MGET_OK,
+        )
+        .await?;
 
-    let request_packet = generate_packet(protocol::CLEAR, &request_bytes);
+        connection.release_to_pool();
 
-    tcp_stream.write_all(&request_packet).await?;
+        decode_response(&response_bytes)
+    }
 
-    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+    /// Stores several values in one round trip.
+    pub async fn mset(
+        &self,
+        request: protocol::MSetRequest,
+    ) -> ClientResult<protocol::MSetResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
 
-    if response_tag != protocol::CLEAR_OK {
-        return Err(ClientError::ConnectionError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid response tag",
-        )));
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::MSET,
+            &encode(&request),
+            protocol::MSET_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
     }
 
-    Ok(())
+    /// Deletes several keys in one round trip.
+    pub async fn mdel(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::MDelResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::MDEL,
+            &encode(&request),
+            protocol::MDEL_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Fetches a single page of a scan.
+    pub async fn scan_page(
+        &self,
+        request: protocol::ScanRequest,
+    ) -> ClientResult<protocol::ScanResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SCAN,
+            &encode(&request),
+            protocol::SCAN_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Streams every key of the scan in lexicographic order, starting at `request.cursor`.
+    /// Pages of `request.limit` keys are fetched lazily as the stream is polled.
+    pub fn scan(
+        &self,
+        request: protocol::ScanRequest,
+    ) -> impl Stream<Item = ClientResult<protocol::ScanEntry>> + Send + 'static {
+        let client = self.clone();
+
+        stream::try_unfold(Some(request), move |request| {
+            let client = client.clone();
+            async move {
+                let Some(request) = request else {
+                    return Ok(None);
+                };
+
+                let page = client.scan_page(request.clone()).await?;
+                let next_request = page.cursor.map(|cursor| protocol::ScanRequest {
+                    cursor: Some(cursor),
+                    ..request
+                });
+
+                let entries = stream::iter(page.entries.into_iter().map(Ok));
+                ClientResult::Ok(Some((entries, next_request)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Returns the current version of each key, for use as `WATCH` guards of a transaction.
+    pub async fn watch(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::WatchResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::WATCH,
+            &encode(&request),
+            protocol::WATCH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Runs the commands atomically. Fails with `ClientError::VersionMismatch`
+    /// if a watched key changed, in which case nothing is applied.
+    pub async fn exec(
+        &self,
+        request: protocol::ExecRequest,
+    ) -> ClientResult<protocol::ExecResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::EXEC,
+            &encode(&request),
+            protocol::EXEC_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Starts a transaction; commands are queued locally and sent by `Transaction::exec`.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            client: self,
+            request: protocol::ExecRequest::default(),
+        }
+    }
+
+    /// Sends the message to the channel's subscribers. Returns how many received it.
+    pub async fn publish(
+        &self,
+        request: protocol::PublishRequest,
+    ) -> ClientResult<protocol::PublishResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::PUBLISH,
+            &encode(&request),
+            protocol::PUBLISH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Subscribes to the channels and streams their messages.
+    ///
+    /// The subscription uses its own connection outside of the pool, closed when
+    /// the stream is dropped. The stream ends if the server closes the connection,
+    /// e.g. because the subscriber fell too far behind.
+    pub async fn subscribe(
+        &self,
+        channels: Vec<String>,
+    ) -> ClientResult<impl Stream<Item = ClientResult<protocol::PushMessage>> + Send + 'static>
+    {
+        self.open_subscription(protocol::SUBSCRIBE, channels).await
+    }
+
+    /// Subscribes to every channel matching the glob patterns, see `subscribe`.
+    pub async fn psubscribe(
+        &self,
+        patterns: Vec<String>,
+    ) -> ClientResult<impl Stream<Item = ClientResult<protocol::PushMessage>> + Send + 'static>
+    {
+        self.open_subscription(protocol::PSUBSCRIBE, patterns).await
+    }
+
+    /// Streams the events of the watched keys and prefixes (every key if both are empty),
+    /// on its own connection outside of the pool. The stream ends if the server
+    /// closes the connection, e.g. because the watcher fell behind and missed events.
+    pub async fn watch_keys(
+        &self,
+        request: protocol::WatchKeysRequest,
+    ) -> ClientResult<impl Stream<Item = ClientResult<protocol::KeyEventMessage>> + Send + 'static>
+    {
+        self.open_push_stream(
+            protocol::WATCH_KEYS,
+            encode(&request),
+            protocol::WATCH_KEYS_OK,
+            protocol::KEY_EVENT,
+        )
+        .await
+    }
+
+    async fn open_subscription(
+        &self,
+        tag: u8,
+        channels: Vec<String>,
+    ) -> ClientResult<impl Stream<Item = ClientResult<protocol::PushMessage>> + Send + 'static>
+    {
+        self.open_push_stream(
+            tag,
+            encode(&protocol::SubscribeRequest { channels }),
+            protocol::SUBSCRIBE_OK,
+            protocol::MESSAGE,
+        )
+        .await
+    }
+
+    /// Sends the request on a new, non-pooled connection and turns the packets
+    /// the server pushes with `push_tag` into a stream.
+    async fn open_push_stream<T: chorba::Decoder<T> + Send + 'static>(
+        &self,
+        request_tag: u8,
+        request_bytes: Vec<u8>,
+        expected_tag: u8,
+        push_tag: u8,
+    ) -> ClientResult<impl Stream<Item = ClientResult<T>> + Send + 'static> {
+        let mut tcp_stream = self.open_connection().await?;
+
+        let request_packet = generate_packet(request_tag, &request_bytes);
+        tcp_stream.write_all(&request_packet).await?;
+
+        let (response_tag, _) = read_packet(&mut tcp_stream).await?;
+        if response_tag != expected_tag {
+            return Err(ClientError::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid response tag",
+            )));
+        }
+
+        Ok(stream::try_unfold(
+            tcp_stream,
+            move |mut tcp_stream| async move {
+                loop {
+                    let (tag, bytes) = match read_packet(&mut tcp_stream).await {
+                        Ok(packet) => packet,
+                        Err(protocol::PacketError::NoDataReceived) => return Ok(None),
+                        Err(error) => return Err(error.into()),
+                    };
+
+                    // Anything else is a reply to a command sent on the pushing connection
+                    if tag == push_tag {
+                        let item = decode_response(&bytes)?;
+                        return ClientResult::Ok(Some((item, tcp_stream)));
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Pushes the values to the head of the list, creating it if needed. Returns the new length.
+    pub async fn lpush(
+        &self,
+        request: protocol::ListPushRequest,
+    ) -> ClientResult<protocol::ListLengthResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LPUSH,
+            &encode(&request),
+            protocol::LIST_LENGTH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Pushes the values to the tail of the list, creating it if needed. Returns the new length.
+    pub async fn rpush(
+        &self,
+        request: protocol::ListPushRequest,
+    ) -> ClientResult<protocol::ListLengthResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::RPUSH,
+            &encode(&request),
+            protocol::LIST_LENGTH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes and returns up to `count` values from the head of the list.
+    pub async fn lpop(
+        &self,
+        request: protocol::ListPopRequest,
+    ) -> ClientResult<protocol::ListValuesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LPOP,
+            &encode(&request),
+            protocol::LIST_VALUES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes and returns up to `count` values from the tail of the list.
+    pub async fn rpop(
+        &self,
+        request: protocol::ListPopRequest,
+    ) -> ClientResult<protocol::ListValuesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::RPOP,
+            &encode(&request),
+            protocol::LIST_VALUES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Values of the list between two inclusive indexes.
+    pub async fn lrange(
+        &self,
+        request: protocol::ListRangeRequest,
+    ) -> ClientResult<protocol::ListValuesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LRANGE,
+            &encode(&request),
+            protocol::LIST_VALUES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Length of the list, 0 if the key doesn't exist.
+    pub async fn llen(
+        &self,
+        request: protocol::GetRequest,
+    ) -> ClientResult<protocol::ListLengthResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LLEN,
+            &encode(&request),
+            protocol::LIST_LENGTH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Keeps only the values of the list between two inclusive indexes.
+    pub async fn ltrim(&self, request: protocol::ListRangeRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::LTRIM,
+            &encode(&request),
+            protocol::LTRIM_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Pops from the head of the first non-empty list, waiting for a push until the timeout.
+    /// The connection is held for the whole wait.
+    pub async fn blpop(
+        &self,
+        request: protocol::BlockingPopRequest,
+    ) -> ClientResult<protocol::BlockingPopResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::BLPOP,
+            &encode(&request),
+            protocol::BLOCKING_POP_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Pops from the tail of the first non-empty list, waiting for a push until the timeout.
+    /// The connection is held for the whole wait.
+    pub async fn brpop(
+        &self,
+        request: protocol::BlockingPopRequest,
+    ) -> ClientResult<protocol::BlockingPopResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::BRPOP,
+            &encode(&request),
+            protocol::BLOCKING_POP_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Sets the fields of the hash, creating it if needed. Returns how many fields were added.
+    pub async fn hset(
+        &self,
+        request: protocol::HashSetRequest,
+    ) -> ClientResult<protocol::HashCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HSET,
+            &encode(&request),
+            protocol::HASH_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Value of a field of the hash.
+    pub async fn hget(
+        &self,
+        request: protocol::HashFieldRequest,
+    ) -> ClientResult<protocol::HashValueResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HGET,
+            &encode(&request),
+            protocol::HASH_VALUE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Values of several fields of the hash in one round trip.
+    pub async fn hmget(
+        &self,
+        request: protocol::HashFieldsRequest,
+    ) -> ClientResult<protocol::HashValuesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HMGET,
+            &encode(&request),
+            protocol::HASH_VALUES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes fields of the hash. Returns how many existed.
+    pub async fn hdel(
+        &self,
+        request: protocol::HashFieldsRequest,
+    ) -> ClientResult<protocol::HashCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HDEL,
+            &encode(&request),
+            protocol::HASH_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Every field and value of the hash.
+    pub async fn hgetall(
+        &self,
+        request: protocol::GetRequest,
+    ) -> ClientResult<protocol::HashEntriesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HGETALL,
+            &encode(&request),
+            protocol::HASH_ENTRIES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds `delta` to the integer in a field of the hash and returns the new value.
+    pub async fn hincrby(
+        &self,
+        request: protocol::HashIncrByRequest,
+    ) -> ClientResult<protocol::IncrByResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HINCRBY,
+            &encode(&request),
+            protocol::INCR_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Whether the hash has the field.
+    pub async fn hexists(
+        &self,
+        request: protocol::HashFieldRequest,
+    ) -> ClientResult<protocol::HashExistsResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HEXISTS,
+            &encode(&request),
+            protocol::HASH_EXISTS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Number of fields of the hash, 0 if the key doesn't exist.
+    pub async fn hlen(
+        &self,
+        request: protocol::GetRequest,
+    ) -> ClientResult<protocol::HashCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::HLEN,
+            &encode(&request),
+            protocol::HASH_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds members to the set, creating it if needed. Returns how many were added.
+    pub async fn sadd(
+        &self,
+        request: protocol::MembersRequest,
+    ) -> ClientResult<protocol::MemberCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SADD,
+            &encode(&request),
+            protocol::MEMBER_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes members from the set. Returns how many were in it.
+    pub async fn srem(
+        &self,
+        request: protocol::MembersRequest,
+    ) -> ClientResult<protocol::MemberCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SREM,
+            &encode(&request),
+            protocol::MEMBER_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Whether the member is in the set.
+    pub async fn sismember(
+        &self,
+        request: protocol::MemberRequest,
+    ) -> ClientResult<protocol::IsMemberResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SISMEMBER,
+            &encode(&request),
+            protocol::IS_MEMBER_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Every member of the set.
+    pub async fn smembers(
+        &self,
+        request: protocol::GetRequest,
+    ) -> ClientResult<protocol::MembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SMEMBERS,
+            &encode(&request),
+            protocol::MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members of every one of the sets.
+    pub async fn sinter(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::MembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SINTER,
+            &encode(&request),
+            protocol::MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members of any of the sets.
+    pub async fn sunion(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::MembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SUNION,
+            &encode(&request),
+            protocol::MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members of the first set that are in none of the others.
+    pub async fn sdiff(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::MembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::SDIFF,
+            &encode(&request),
+            protocol::MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Sets the scores of members of the sorted set, creating it if needed.
+    /// Returns how many members were added.
+    pub async fn zadd(
+        &self,
+        request: protocol::SortedSetAddRequest,
+    ) -> ClientResult<protocol::MemberCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZADD,
+            &encode(&request),
+            protocol::MEMBER_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds `delta` to the member's score and returns the new score.
+    pub async fn zincrby(
+        &self,
+        request: protocol::SortedSetIncrByRequest,
+    ) -> ClientResult<protocol::IncrByFloatResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZINCRBY,
+            &encode(&request),
+            protocol::INCR_FLOAT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members and scores of the sorted set between two inclusive indexes.
+    pub async fn zrange(
+        &self,
+        request: protocol::SortedSetRangeRequest,
+    ) -> ClientResult<protocol::ScoredMembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZRANGE,
+            &encode(&request),
+            protocol::SCORED_MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members and scores of the sorted set between two inclusive scores.
+    pub async fn zrangebyscore(
+        &self,
+        request: protocol::SortedSetRangeByScoreRequest,
+    ) -> ClientResult<protocol::ScoredMembersResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZRANGEBYSCORE,
+            &encode(&request),
+            protocol::SCORED_MEMBERS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Position of the member in the sorted set.
+    pub async fn zrank(
+        &self,
+        request: protocol::SortedSetRankRequest,
+    ) -> ClientResult<protocol::RankResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZRANK,
+            &encode(&request),
+            protocol::RANK_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes members from the sorted set. Returns how many were in it.
+    pub async fn zrem(
+        &self,
+        request: protocol::MembersRequest,
+    ) -> ClientResult<protocol::MemberCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::ZREM,
+            &encode(&request),
+            protocol::MEMBER_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Appends an entry to the stream, creating it if needed. Returns the ID of the entry.
+    pub async fn xadd(
+        &self,
+        request: protocol::StreamAddRequest,
+    ) -> ClientResult<protocol::StreamAddResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XADD,
+            &encode(&request),
+            protocol::STREAM_ID_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Entries of the stream with an ID in the range, oldest first.
+    pub async fn xrange(
+        &self,
+        request: protocol::StreamRangeRequest,
+    ) -> ClientResult<protocol::StreamEntriesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XRANGE,
+            &encode(&request),
+            protocol::STREAM_ENTRIES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Entries added to the streams after the given IDs, waiting for one if asked to block.
+    /// The connection is held for the whole wait.
+    pub async fn xread(
+        &self,
+        request: protocol::StreamReadRequest,
+    ) -> ClientResult<protocol::StreamReadResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XREAD,
+            &encode(&request),
+            protocol::STREAM_READ_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Creates a consumer group on the stream, creating the stream if needed.
+    pub async fn xgroup_create(
+        &self,
+        request: protocol::StreamGroupCreateRequest,
+    ) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::XGROUP_CREATE,
+            &encode(&request),
+            protocol::XGROUP_CREATE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Reads entries as a consumer of a group, waiting for one if asked to block.
+    /// Entries read with `>` stay pending for the consumer until acknowledged.
+    pub async fn xreadgroup(
+        &self,
+        request: protocol::StreamReadGroupRequest,
+    ) -> ClientResult<protocol::StreamReadResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XREADGROUP,
+            &encode(&request),
+            protocol::STREAM_READ_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Acknowledges entries delivered by the group. Returns how many were pending.
+    pub async fn xack(
+        &self,
+        request: protocol::StreamAckRequest,
+    ) -> ClientResult<protocol::StreamAckResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XACK,
+            &encode(&request),
+            protocol::XACK_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Entries delivered by the group and not acknowledged yet, oldest first.
+    pub async fn xpending(
+        &self,
+        request: protocol::StreamPendingRequest,
+    ) -> ClientResult<protocol::StreamPendingResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XPENDING,
+            &encode(&request),
+            protocol::PENDING_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Takes over pending entries of the group that have been idle for a while, e.g. because
+    /// their consumer crashed. Returns the claimed entries.
+    pub async fn xclaim(
+        &self,
+        request: protocol::StreamClaimRequest,
+    ) -> ClientResult<protocol::StreamEntriesResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::XCLAIM,
+            &encode(&request),
+            protocol::STREAM_ENTRIES_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Part of the JSON document at the path, as JSON text.
+    pub async fn json_get(
+        &self,
+        request: protocol::JsonPathRequest,
+    ) -> ClientResult<protocol::JsonValueResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JSON_GET,
+            &encode(&request),
+            protocol::JSON_VALUE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Stores JSON text at the path; `$` replaces the whole document, creating the key if needed.
+    pub async fn json_set(&self, request: protocol::JsonSetRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::JSON_SET,
+            &encode(&request),
+            protocol::JSON_SET_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Removes the part of the JSON document at the path; `$` deletes the key.
+    pub async fn json_del(
+        &self,
+        request: protocol::JsonPathRequest,
+    ) -> ClientResult<protocol::JsonDeleteResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JSON_DEL,
+            &encode(&request),
+            protocol::JSON_DEL_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Appends to the array at the path. Returns the new length of the array.
+    pub async fn json_arrappend(
+        &self,
+        request: protocol::JsonAppendRequest,
+    ) -> ClientResult<protocol::ListLengthResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JSON_ARRAPPEND,
+            &encode(&request),
+            protocol::LIST_LENGTH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds to the number at the path. Returns the new number as JSON text.
+    pub async fn json_incrby(
+        &self,
+        request: protocol::JsonIncrByRequest,
+    ) -> ClientResult<protocol::JsonValueResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JSON_INCRBY,
+            &encode(&request),
+            protocol::JSON_VALUE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds elements to the HyperLogLog, creating it if needed.
+    pub async fn pfadd(
+        &self,
+        request: protocol::MembersRequest,
+    ) -> ClientResult<protocol::PfAddResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::PFADD,
+            &encode(&request),
+            protocol::PFADD_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Estimated number of distinct elements added to any of the HyperLogLogs.
+    pub async fn pfcount(
+        &self,
+        request: protocol::MultiKeyRequest,
+    ) -> ClientResult<protocol::PfCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::PFCOUNT,
+            &encode(&request),
+            protocol::PFCOUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Merges HyperLogLogs into the destination, creating it if needed.
+    pub async fn pfmerge(&self, request: protocol::PfMergeRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::PFMERGE,
+            &encode(&request),
+            protocol::PFMERGE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Creates an empty Bloom filter with the given error rate, sized for `capacity` items.
+    pub async fn bf_reserve(&self, request: protocol::BloomReserveRequest) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::BF_RESERVE,
+            &encode(&request),
+            protocol::BF_RESERVE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Adds the member to the Bloom filter, creating one with a 1% error rate if needed.
+    pub async fn bf_add(
+        &self,
+        request: protocol::MemberRequest,
+    ) -> ClientResult<protocol::BloomAddResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::BF_ADD,
+            &encode(&request),
+            protocol::BF_ADD_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Whether the member may have been added to the Bloom filter.
+    pub async fn bf_exists(
+        &self,
+        request: protocol::MemberRequest,
+    ) -> ClientResult<protocol::IsMemberResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::BF_EXISTS,
+            &encode(&request),
+            protocol::IS_MEMBER_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Adds members at the given coordinates, or moves them. Returns how many were added.
+    pub async fn geoadd(
+        &self,
+        request: protocol::GeoAddRequest,
+    ) -> ClientResult<protocol::MemberCountResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::GEOADD,
+            &encode(&request),
+            protocol::MEMBER_COUNT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Distance in meters between two members.
+    pub async fn geodist(
+        &self,
+        request: protocol::GeoDistRequest,
+    ) -> ClientResult<protocol::GeoDistResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::GEODIST,
+            &encode(&request),
+            protocol::GEODIST_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Members within a radius or a box around the given coordinates, with their distance.
+    pub async fn geosearch(
+        &self,
+        request: protocol::GeoSearchRequest,
+    ) -> ClientResult<protocol::GeoSearchResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::GEOSEARCH,
+            &encode(&request),
+            protocol::GEOSEARCH_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Counts a request against the limit at `key` on the server, which decides atomically
+    /// whether it's allowed, so concurrent clients can't race past the limit.
+    pub async fn rate_limit(
+        &self,
+        request: protocol::RateLimitRequest,
+    ) -> ClientResult<protocol::RateLimitResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::RATE_LIMIT,
+            &encode(&request),
+            protocol::RATE_LIMIT_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Takes a lock, optionally waiting for it. The connection is held for the whole wait.
+    /// See `lock` for a guard that keeps the lease alive.
+    pub async fn lock_acquire(
+        &self,
+        request: protocol::LockAcquireRequest,
+    ) -> ClientResult<protocol::LockAcquireResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LOCK_ACQUIRE,
+            &encode(&request),
+            protocol::LOCK_ACQUIRE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Extends the lease of a held lock.
+    pub async fn lock_renew(
+        &self,
+        request: protocol::LockRenewRequest,
+    ) -> ClientResult<protocol::LockRenewResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LOCK_RENEW,
+            &encode(&request),
+            protocol::LOCK_RENEW_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Releases a held lock.
+    pub async fn lock_release(
+        &self,
+        request: protocol::LockReleaseRequest,
+    ) -> ClientResult<protocol::LockReleaseResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::LOCK_RELEASE,
+            &encode(&request),
+            protocol::LOCK_RELEASE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Waits for the lock, then keeps its lease alive in the background until the guard
+    /// is dropped, which releases it. Renewals happen every third of the lease, so a lease
+    /// only runs out if the server can't be reached for that long. Fails with
+    /// `LockNotAcquired` if the server stopped waiting without handing the lock over.
+    pub async fn lock(&self, name: impl Into<String>, lease: Duration) -> ClientResult<LockGuard> {
+        let name = name.into();
+        let owner = lock_owner();
+
+        let response = self
+            .lock_acquire(protocol::LockAcquireRequest {
+                name: name.clone(),
+                owner: owner.clone(),
+                lease_millis: lease.as_millis() as u64,
+                wait_millis: Some(0),
+            })
+            .await?;
+        let Some(token) = response.token else {
+            return Err(ClientError::LockNotAcquired);
+        };
+
+        let held = Arc::new(AtomicBool::new(true));
+        let renewal = tokio::spawn(renew_lock(
+            self.clone(),
+            protocol::LockRenewRequest {
+                name: name.clone(),
+                owner: owner.clone(),
+                lease_millis: lease.as_millis() as u64,
+            },
+            held.clone(),
+        ));
+
+        Ok(LockGuard {
+            client: self.clone(),
+            name,
+            owner,
+            token,
+            held,
+            renewal,
+            released: false,
+        })
+    }
+
+    /// Adds a job to the delayed queue at `key`, handed out once its delay passed.
+    pub async fn job_enqueue(
+        &self,
+        request: protocol::JobEnqueueRequest,
+    ) -> ClientResult<protocol::JobEnqueueResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JOB_ENQUEUE,
+            &encode(&request),
+            protocol::JOB_ENQUEUE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Takes ready jobs; each must be acked or nacked within the visibility timeout,
+    /// or it is handed out again.
+    pub async fn job_dequeue(
+        &self,
+        request: protocol::JobDequeueRequest,
+    ) -> ClientResult<protocol::JobsResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JOB_DEQUEUE,
+            &encode(&request),
+            protocol::JOBS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Marks a taken job done, unless it was handed out again since `request.attempts`.
+    pub async fn job_ack(
+        &self,
+        request: protocol::JobAckRequest,
+    ) -> ClientResult<protocol::JobAckResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JOB_ACK,
+            &encode(&request),
+            protocol::JOB_ACK_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Gives a taken job back, to be retried after a delay or dead-lettered.
+    pub async fn job_nack(
+        &self,
+        request: protocol::JobNackRequest,
+    ) -> ClientResult<protocol::JobNackResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JOB_NACK,
+            &encode(&request),
+            protocol::JOB_NACK_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes and returns jobs that ran out of attempts.
+    pub async fn job_dead_letters(
+        &self,
+        request: protocol::JobDeadLettersRequest,
+    ) -> ClientResult<protocol::JobsResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::JOB_DEAD_LETTERS,
+            &encode(&request),
+            protocol::JOBS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Increments the integer at `key` by one and returns the new value.
+    pub async fn incr(&self, key: impl Into<String>) -> ClientResult<i64> {
+        let request = protocol::IncrByRequest {
+            key: key.into(),
+            delta: 1,
+        };
+
+        Ok(self.incr_by(request).await?.value)
+    }
+
+    /// Decrements the integer at `key` by one and returns the new value.
+    pub async fn decr(&self, key: impl Into<String>) -> ClientResult<i64> {
+        let request = protocol::IncrByRequest {
+            key: key.into(),
+            delta: 1,
+        };
+
+        Ok(self.decr_by(request).await?.value)
+    }
+
+    /// Saves a snapshot on the server, returning once it is on disk.
+    pub async fn save(&self) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::SAVE,
+            &[],
+            protocol::SAVE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Asks the server to save a snapshot in the background.
+    pub async fn bgsave(&self) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        send_request(
+            &mut connection.tcp_stream,
+            protocol::BGSAVE,
+            &[],
+            protocol::BGSAVE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+
+    /// Key counts and memory of the namespace the client is configured for.
+    pub async fn stats(&self) -> ClientResult<protocol::StatsResponse> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::STATS,
+            &[],
+            protocol::STATS_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response(&response_bytes)
+    }
+
+    /// Removes the namespace so it no longer counts towards the server's limit. Fails with
+    /// `NamespaceNotEmpty` while it holds keys; returns whether it existed.
+    pub async fn drop_namespace(&self, namespace: &str) -> ClientResult<bool> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        let request = protocol::DropNamespaceRequest {
+            namespace: namespace.to_owned(),
+        };
+        let response_bytes = send_request(
+            &mut connection.tcp_stream,
+            protocol::DROP_NAMESPACE,
+            &encode(&request),
+            protocol::DROP_NAMESPACE_OK,
+        )
+        .await?;
+
+        connection.release_to_pool();
+
+        decode_response::<protocol::DropNamespaceResponse>(&response_bytes)
+            .map(|response| response.dropped)
+    }
+
+    /// Removes every key of the namespace the client is configured for.
+    pub async fn clear(&self) -> ClientResult<()> {
+        let mut connection = self.get_connection_or_wait().await?;
+
+        request_clear(&mut connection.tcp_stream).await?;
+
+        connection.release_to_pool();
+
+        Ok(())
+    }
+}
+
+/// Queued commands of a transaction, see `RStoreClient::transaction`.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    client: &'a RStoreClient,
+    request: protocol::ExecRequest,
+}
+
+impl Transaction<'_> {
+    /// Aborts the transaction if `key` is modified before `exec`.
+    pub async fn watch(&mut self, key: impl Into<String>) -> ClientResult<&mut Self> {
+        let key = key.into();
+        let response = self
+            .client
+            .watch(protocol::MultiKeyRequest {
+                keys: vec![key.clone()],
+            })
+            .await?;
+
+        let version = response.versions.first().copied().unwrap_or(0);
+        self.request
+            .watches
+            .push(protocol::WatchedKey { key, version });
+
+        Ok(self)
+    }
+
+    pub fn get(&mut self, key: impl Into<String>) -> &mut Self {
+        self.request
+            .commands
+            .push(protocol::TxCommand::Get { key: key.into() });
+        self
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.request.commands.push(protocol::TxCommand::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl_millis: None,
+        });
+        self
+    }
+
+    pub fn set_expire(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        self.request.commands.push(protocol::TxCommand::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl_millis: Some(ttl.as_millis() as u64),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: impl Into<String>) -> &mut Self {
+        self.request
+            .commands
+            .push(protocol::TxCommand::Delete { key: key.into() });
+        self
+    }
+
+    pub fn incr_by(&mut self, key: impl Into<String>, delta: i64) -> &mut Self {
+        self.request.commands.push(protocol::TxCommand::IncrBy {
+            key: key.into(),
+            delta,
+        });
+        self
+    }
+
+    /// Sends the queued commands and returns one reply per command, in order.
+    pub async fn exec(self) -> ClientResult<Vec<protocol::TxReply>> {
+        Ok(self.client.exec(self.request).await?.replies)
+    }
+}
+
+/// Owner of a lock taken by `RStoreClient::lock`, unique to the guard.
+fn lock_owner() -> String {
+    static NEXT_LOCK: AtomicU64 = AtomicU64::new(0);
+
+    let random = RandomState::new().hash_one((
+        std::process::id(),
+        SystemTime::now(),
+        NEXT_LOCK.fetch_add(1, Ordering::Relaxed),
+    ));
+    format!("{}-{:016x}", std::process::id(), random)
+}
+
+// Renews the lease every third of it until it's lost; failed requests are retried on the
+// next round while the lease may still be alive.
+async fn renew_lock(
+    client: RStoreClient,
+    request: protocol::LockRenewRequest,
+    held: Arc<AtomicBool>,
+) {
+    let interval = Duration::from_millis(request.lease_millis / 3).max(Duration::from_millis(1));
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match client.lock_renew(request.clone()).await {
+            Ok(response) if response.renewed => {}
+            Ok(_) => {
+                held.store(false, Ordering::Relaxed);
+                return;
+            }
+            Err(error) => log::error!("Failed to renew lock {}: {}", request.name, error),
+        }
+    }
+}
+
+/// A lock held through `RStoreClient::lock`. Dropping it releases the lock in the
+/// background; `release` waits for that.
+#[derive(Debug)]
+pub struct LockGuard {
+    client: RStoreClient,
+    name: String,
+    owner: String,
+    token: u64,
+    held: Arc<AtomicBool>,
+    renewal: JoinHandle<()>,
+    released: bool,
+}
+
+impl LockGuard {
+    /// Fencing token: pass it along with writes made under the lock, so the storage can
+    /// reject writes carrying a lower token than one it has seen.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// False once a renewal found the lease ended, e.g. after the process was paused for
+    /// longer than the lease. Someone else may hold the lock then.
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock. Returns whether it was still held.
+    pub async fn release(mut self) -> ClientResult<bool> {
+        self.renewal.abort();
+        self.released = true;
+
+        let response = self
+            .client
+            .lock_release(protocol::LockReleaseRequest {
+                name: self.name.clone(),
+                owner: self.owner.clone(),
+            })
+            .await?;
+
+        Ok(response.released)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        self.renewal.abort();
+        if self.released {
+            return;
+        }
+
+        // Without a runtime the lease just runs out
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let client = self.client.clone();
+        let request = protocol::LockReleaseRequest {
+            name: std::mem::take(&mut self.name),
+            owner: std::mem::take(&mut self.owner),
+        };
+        runtime.spawn(async move {
+            let name = request.name.clone();
+            if let Err(error) = client.lock_release(request).await {
+                log::error!("Failed to release lock {}: {}", name, error);
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionPool {
+    connections: Vec<PooledConnection>,
+    connection_count: u32,
+}
+
+#[derive(Debug)]
+pub struct PooledConnection {
+    tcp_stream: TcpStream,
+    pool: Weak<Mutex<ConnectionPool>>,
+}
+
+impl PooledConnection {
+    pub fn new(tcp_stream: TcpStream, pool: Weak<Mutex<ConnectionPool>>) -> Self {
+        PooledConnection { tcp_stream, pool }
+    }
+
+    pub fn release_to_pool(self) {
+        if let Some(pool) = self.pool.upgrade() {
+            let mut pool = pool.lock().unwrap();
+            pool.connections.push(self);
+        }
+    }
+}
+
+async fn request_ping(tcp_stream: &mut TcpStream) -> ClientResult<()> {
+    // empty bytes array
+    let request_bytes = vec![];
+
+    let request_packet = generate_packet(protocol::PING, &request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag != protocol::PONG {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Ping Failed",
+        )));
+    }
+
+    Ok(())
+}
+
+async fn request_get(tcp_stream: &mut TcpStream, request: GetRequest) -> ClientResult<GetResponse> {
+    let request_bytes = encode(&request);
+
+    let request_packet = generate_packet(protocol::GET, &request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, response_bytes) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag != protocol::GET_OK {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid response tag",
+        )));
+    }
+
+    let decoded = decode::<GetResponse>(&response_bytes).map_err(|_| {
+        ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Failed to decode response",
+        ))
+    })?;
+
+    Ok(decoded)
+}
+
+async fn request_set(
+    tcp_stream: &mut TcpStream,
+    request: protocol::SetRequest,
+) -> ClientResult<()> {
+    let request_bytes = encode(&request);
+
+    let request_packet = generate_packet(protocol::SET, &request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag == protocol::OUT_OF_MEMORY {
+        return Err(ClientError::OutOfMemory);
+    }
+
+    if response_tag != protocol::SET_OK {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid response tag",
+        )));
+    }
+
+    Ok(())
+}
+
+async fn request_delete(
+    tcp_stream: &mut TcpStream,
+    request: protocol::DeleteRequest,
+) -> ClientResult<()> {
+    let request_bytes = encode(&request);
+
+    let request_packet = generate_packet(protocol::DELETE, &request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag != protocol::DELETE_OK {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid response tag",
+        )));
+    }
+
+    Ok(())
+}
+
+async fn request_clear(tcp_stream: &mut TcpStream) -> ClientResult<()> {
+    let request_bytes = vec![];
+
+    let request_packet = generate_packet(protocol::CLEAR, &request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, _) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag != protocol::CLEAR_OK {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid response tag",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Sends a request packet and returns the response payload if the response carries `expected_tag`.
+async fn send_request(
+    tcp_stream: &mut TcpStream,
+    request_tag: u8,
+    request_bytes: &[u8],
+    expected_tag: u8,
+) -> ClientResult<Vec<u8>> {
+    let request_packet = generate_packet(request_tag, request_bytes);
+
+    tcp_stream.write_all(&request_packet).await?;
+
+    let (response_tag, response_bytes) = fetch_all_packet(tcp_stream).await?;
+
+    if response_tag == protocol::OUT_OF_MEMORY {
+        return Err(ClientError::OutOfMemory);
+    }
+
+    if response_tag == protocol::NOT_A_NUMBER {
+        return Err(ClientError::NotANumber);
+    }
+
+    if response_tag == protocol::VERSION_MISMATCH {
+        return Err(ClientError::VersionMismatch);
+    }
+
+    if response_tag == protocol::WRONG_TYPE {
+        return Err(ClientError::WrongType);
+    }
+
+    if response_tag == protocol::INVALID_STREAM_ID {
+        return Err(ClientError::InvalidStreamId);
+    }
+
+    if response_tag == protocol::NO_SUCH_GROUP {
+        return Err(ClientError::NoSuchGroup);
+    }
+
+    if response_tag == protocol::GROUP_EXISTS {
+        return Err(ClientError::GroupExists);
+    }
+
+    if response_tag == protocol::INVALID_JSON {
+        return Err(ClientError::InvalidJson);
+    }
+
+    if response_tag == protocol::INVALID_JSON_PATH {
+        return Err(ClientError::InvalidJsonPath);
+    }
+
+    if response_tag == protocol::JSON_PATH_NOT_FOUND {
+        return Err(ClientError::JsonPathNotFound);
+    }
+
+    if response_tag == protocol::KEY_EXISTS {
+        return Err(ClientError::KeyExists);
+    }
+
+    if response_tag == protocol::INVALID_FILTER_OPTIONS {
+        return Err(ClientError::InvalidFilterOptions);
+    }
+
+    if response_tag == protocol::INVALID_COORDINATES {
+        return Err(ClientError::InvalidCoordinates);
+    }
+
+    if response_tag == protocol::INVALID_RATE_LIMIT {
+        return Err(ClientError::InvalidRateLimit);
+    }
+
+    if response_tag == protocol::INVALID_LOCK_LEASE {
+        return Err(ClientError::InvalidLockLease);
+    }
+
+    if response_tag == protocol::INVALID_NAMESPACE {
+        return Err(ClientError::InvalidNamespace);
+    }
+
+    if response_tag == protocol::TOO_MANY_NAMESPACES {
+        return Err(ClientError::TooManyNamespaces);
+    }
+
+    if response_tag == protocol::NAMESPACE_NOT_EMPTY {
+        return Err(ClientError::NamespaceNotEmpty);
+    }
+
+    if response_tag != expected_tag {
+        return Err(ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid response tag",
+        )));
+    }
+
+    Ok(response_bytes)
+}
+
+fn decode_response<T: chorba::Decoder<T>>(response_bytes: &[u8]) -> ClientResult<T> {
+    decode::<T>(response_bytes).map_err(|_| {
+        ClientError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Failed to decode response",
+        ))
+    })
 }
 
 async fn fetch_all_packet(tcp_stream: &mut TcpStream) -> ClientResult<(u8, Vec<u8>)> {
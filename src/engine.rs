@@ -1,11 +1,89 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    hash::{BuildHasher, RandomState},
+    path::PathBuf,
+    sync::{
+        Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use keyspace::KeySpace;
+use namespace::{Namespace, Namespaces};
+use pubsub::Broker;
+use snapshot::SnapshotRecord;
+use value::Value;
+use wal::{WalRecord, WriteAheadLog};
+
+mod batch;
+mod blocking;
+mod config;
+mod counter;
+mod events;
+mod eviction;
+mod geo;
+mod hash;
+mod job_queue;
+mod json;
+mod keyspace;
+mod list;
+mod lock;
+mod namespace;
+mod probabilistic;
+mod pubsub;
+mod rate_limit;
+mod scan;
+mod set;
+mod snapshot;
+mod sorted_set;
+mod stream;
+mod transaction;
+mod value;
+mod wal;
+
+pub use config::EngineConfig;
+pub use events::{KeyEvent, KeyEventOp, KeyFilter, KeyWatcher};
+pub use eviction::EvictionPolicy;
+pub use geo::{GeoMatch, GeoShape, GeoSort};
+pub use job_queue::QueuedJob;
+pub use list::ListEnd;
+pub use namespace::{DEFAULT_NAMESPACE, NamespaceStats};
+pub use pubsub::{PubSubMessage, Subscriber};
+pub use rate_limit::{RateLimitAlgorithm, RateLimitStatus};
+pub use scan::{KeyRange, ScanPage};
+pub use snapshot::SnapshotError;
+pub use stream::{PendingEntry, StreamEntry, StreamId};
+pub use transaction::{TxOperation, TxResult};
+pub use wal::{FsyncPolicy, WalError};
+
 #[derive(Debug, Clone)]
 pub struct KVEngine {
-    kv: Arc<Mutex<HashMap<String, String>>>,
+    inner: Arc<EngineInner>,
+    // Keyspace this handle operates on, see `KVEngine::namespace`
+    namespace: Arc<Namespace>,
+}
+
+#[derive(Debug)]
+struct EngineInner {
+    namespaces: RwLock<Namespaces>,
+    hasher: RandomState,
+    snapshot_path: Option<PathBuf>,
+    snapshot_interval: Option<Duration>,
+    snapshot_in_progress: AtomicBool,
+    // Set once the log has been replayed by `KVEngine::open`
+    wal: OnceLock<WriteAheadLog>,
+    used_memory: Arc<AtomicUsize>,
+    // 0 means unlimited
+    max_memory: usize,
+    eviction_policy: EvictionPolicy,
+    // 0 means unlimited
+    max_namespaces: usize,
+    random_state: AtomicU64,
+    // Next version handed out to a write; shared by all keys, so a deleted and
+    // recreated key never reuses a version
+    next_version: AtomicU64,
+    // Channels are shared by every namespace
+    broker: Arc<Broker>,
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -14,15 +92,178 @@ pub enum KVError {
     KeyNotFound,
     #[error("lock failed")]
     LockFailed,
+    #[error("Snapshot persistence is disabled")]
+    SnapshotDisabled,
+    #[error("A snapshot is already being saved")]
+    SnapshotInProgress,
+    #[error("Snapshot failed: {0}")]
+    SnapshotFailed(String),
+    #[error("Write-ahead log is disabled")]
+    WalDisabled,
+    #[error("Write-ahead log failed: {0}")]
+    WalFailed(String),
+    #[error("Out of memory")]
+    OutOfMemory,
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("Value is not a valid float")]
+    NotAFloat,
+    #[error("Version mismatch")]
+    VersionMismatch,
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("Stream ID is invalid or not above the last one")]
+    InvalidStreamId,
+    #[error("No such consumer group")]
+    NoSuchGroup,
+    #[error("Consumer group already exists")]
+    GroupExists,
+    #[error("JSON path is invalid")]
+    InvalidJsonPath,
+    #[error("Nothing of the expected kind at the JSON path")]
+    JsonPathNotFound,
+    #[error("Key already exists")]
+    KeyExists,
+    #[error("Error rate must be at least 0.000001 and below 1, and capacity 1 to 4194304")]
+    InvalidFilterOptions,
+    #[error("Coordinates or search area out of range")]
+    InvalidCoordinates,
+    #[error("Rate limit needs a known algorithm, a limit above 0 and a window of at least 1ms")]
+    InvalidRateLimit,
+    #[error("Lock lease must be at least 1ms")]
+    InvalidLockLease,
+    #[error("Namespace names are 1 to 64 ASCII letters, digits, '_' or '-'")]
+    InvalidNamespace,
+    #[error("Namespace limit reached")]
+    TooManyNamespaces,
+    #[error("Namespace still holds keys")]
+    NamespaceNotEmpty,
+}
+
+impl From<SnapshotError> for KVError {
+    fn from(error: SnapshotError) -> Self {
+        KVError::SnapshotFailed(error.to_string())
+    }
+}
+
+impl From<WalError> for KVError {
+    fn from(error: WalError) -> Self {
+        KVError::WalFailed(error.to_string())
+    }
 }
 
 pub type KVResult<T> = std::result::Result<T, KVError>;
 
+/// When a key should stop being visible.
+#[derive(Debug, Clone, Copy)]
+pub enum Expiration {
+    /// Relative to the time of the call
+    After(Duration),
+    /// Absolute unix timestamp in milliseconds
+    AtUnixMillis(u64),
+}
+
+impl Expiration {
+    fn to_unix_millis(self) -> u64 {
+        match self {
+            Expiration::After(duration) => {
+                now_unix_millis().saturating_add(duration.as_millis() as u64)
+            }
+            Expiration::AtUnixMillis(unix_millis) => unix_millis,
+        }
+    }
+}
+
+/// Precondition of `KVEngine::set_with_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    /// Only if the key doesn't exist (NX)
+    IfAbsent,
+    /// Only if the key exists (XX)
+    IfPresent,
+    /// Only if the key is at this version, 0 meaning it doesn't exist
+    IfVersion(u64),
+}
+
+impl SetCondition {
+    fn holds(self, current_version: Option<u64>) -> bool {
+        match self {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => current_version.is_none(),
+            SetCondition::IfPresent => current_version.is_some(),
+            SetCondition::IfVersion(expected) => current_version.unwrap_or(0) == expected,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SetOptions {
+    pub condition: SetCondition,
+    /// `None` stores the value without expiration
+    pub expiration: Option<Expiration>,
+    /// Return the value the key held before the write (GET)
+    pub return_previous: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SetOutcome {
+    /// New version of the key, `None` if the condition didn't hold and nothing was written
+    pub version: Option<u64>,
+    /// Value before the write, if `return_previous` was set and the key existed
+    pub previous: Option<Vec<u8>>,
+}
+
+const WAL_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Maximum number of expired keys reclaimed per lock acquisition of the sweeper
+const SWEEP_BATCH_SIZE: usize = 1000;
+
+pub(crate) fn now_unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 impl Default for KVEngine {
     fn default() -> Self {
-        KVEngine {
-            kv: Arc::new(Mutex::new(HashMap::new())),
+        KVEngine::with_config(EngineConfig::default())
+    }
+}
+
+fn read_shard(shard: &RwLock<KeySpace>) -> KVResult<RwLockReadGuard<'_, KeySpace>> {
+    let Ok(kv) = shard.read() else {
+        if shard.is_poisoned() {
+            shard.clear_poison();
         }
+        return Err(KVError::LockFailed);
+    };
+    Ok(kv)
+}
+
+fn write_shard(shard: &RwLock<KeySpace>) -> KVResult<RwLockWriteGuard<'_, KeySpace>> {
+    let Ok(kv) = shard.write() else {
+        if shard.is_poisoned() {
+            shard.clear_poison();
+        }
+        return Err(KVError::LockFailed);
+    };
+    Ok(kv)
+}
+
+/// Marks a snapshot as running until dropped.
+struct SnapshotGuard {
+    engine: KVEngine,
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.engine
+            .inner
+            .snapshot_in_progress
+            .store(false, Ordering::Release);
     }
 }
 
@@ -31,51 +272,908 @@ impl KVEngine {
         Default::default()
     }
 
-    pub fn set_key_value(&self, key: String, value: String) -> KVResult<()> {
-        let Ok(mut kv) = self.kv.lock() else {
-            if self.kv.is_poisoned() {
-                self.kv.clear_poison();
+    /// Creates an engine whose keyspace is hash-partitioned into `shard_count` independently locked shards.
+    pub fn with_shard_count(shard_count: usize) -> Self {
+        KVEngine::with_config(EngineConfig {
+            shard_count,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        let namespace = Arc::new(Namespace::new(
+            DEFAULT_NAMESPACE.to_owned(),
+            config.shard_count.max(1),
+            &used_memory,
+        ));
+        let hasher = RandomState::new();
+        // xorshift must not start at zero
+        let random_seed = hasher.hash_one(now_unix_millis()) | 1;
+
+        KVEngine {
+            inner: Arc::new(EngineInner {
+                namespaces: RwLock::new(Namespaces::new(namespace.clone())),
+                hasher,
+                snapshot_path: config.snapshot_path,
+                snapshot_interval: config.snapshot_interval,
+                snapshot_in_progress: AtomicBool::new(false),
+                wal: OnceLock::new(),
+                used_memory,
+                max_memory: config.max_memory,
+                eviction_policy: config.eviction_policy,
+                max_namespaces: config.max_namespaces,
+                random_state: AtomicU64::new(random_seed),
+                next_version: AtomicU64::new(1),
+                broker: Arc::new(Broker::new(config.pubsub_output_buffer_limit)),
+            }),
+            namespace,
+        }
+    }
+
+    /// Creates the engine and restores persisted data: the snapshot first,
+    /// then every write-ahead log record on top of it. Mutations are logged from here on.
+    pub fn open(config: EngineConfig) -> KVResult<Self> {
+        let wal_path = config.wal_path.clone();
+        let wal_fsync = config.wal_fsync;
+        let wal_rewrite_min_size = config.wal_rewrite_min_size;
+
+        let engine = KVEngine::with_config(config);
+
+        match engine.load_snapshot() {
+            Ok(restored) => log::info!("Restored {} keys from snapshot", restored),
+            Err(KVError::SnapshotDisabled) => {}
+            Err(error) => return Err(error),
+        }
+
+        if let Some(wal_path) = wal_path {
+            let (wal, records) = WriteAheadLog::open(&wal_path, wal_fsync, wal_rewrite_min_size)?;
+
+            let replayed = records.len();
+            for (namespace, record) in records {
+                engine
+                    .namespace_unchecked(&namespace)?
+                    .apply_wal_record(record)?;
             }
-            return Err(KVError::LockFailed);
-        };
-        kv.insert(key, value);
-        Ok(())
+            match wal.dropped_bytes() {
+                0 => log::info!("Replayed {} write-ahead log records", replayed),
+                dropped => log::warn!(
+                    "Replayed {} write-ahead log records, dropped {} invalid trailing bytes",
+                    replayed,
+                    dropped
+                ),
+            }
+
+            let _ = engine.inner.wal.set(wal);
+        }
+
+        // Namespaces emptied before the restart were dropped, or could have been
+        for name in engine.namespace_names()? {
+            match engine.drop_namespace(&name) {
+                Ok(_) | Err(KVError::NamespaceNotEmpty) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(engine)
     }
 
-    pub fn get_key_value(&self, key: &str) -> KVResult<String> {
-        let Ok(kv) = self.kv.lock() else {
-            if self.kv.is_poisoned() {
-                self.kv.clear_poison();
+    fn apply_wal_record(&self, record: WalRecord) -> KVResult<()> {
+        let now = now_unix_millis();
+
+        match record {
+            WalRecord::Set {
+                key,
+                value,
+                expires_at,
+                version,
+                kind,
+            } => {
+                let key = key.into_owned();
+                let value = Value::decode(kind, value.into_owned()).ok_or_else(|| {
+                    KVError::WalFailed(format!("invalid value of kind {} for key {}", kind, key))
+                })?;
+                let version = self.restore_version(version);
+                write_shard(self.shard(&key))?.insert(key, value, expires_at, version, now);
+            }
+            WalRecord::Delete { key } => {
+                write_shard(self.shard(&key))?.remove(&key, KeyEventOp::Delete);
+            }
+            WalRecord::Expire { key, expires_at } => {
+                write_shard(self.shard(&key))?.set_expiration(&key, expires_at, now);
+            }
+            WalRecord::Clear => self.clear_shards()?,
+            WalRecord::Mutate {
+                key,
+                version,
+                kind,
+                op,
+            } => {
+                let mut kv = write_shard(self.shard(&key))?;
+                // Already part of the value, which was restored from a snapshot or
+                // copied by a log rewrite after the mutation
+                if kv
+                    .entries
+                    .get(&*key)
+                    .is_some_and(|entry| entry.version >= version)
+                {
+                    return Ok(());
+                }
+
+                let version = self.restore_version(version);
+                value::replay_mutation(&mut kv, &key, kind, &op, version, now).ok_or_else(
+                    || {
+                        KVError::WalFailed(format!(
+                            "invalid mutation of kind {} for key {}",
+                            kind, key
+                        ))
+                    },
+                )?;
             }
-            return Err(KVError::LockFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the namespace if needed and appends the record to the write-ahead log,
+    /// if enabled. Called with the affected shards locked and before the change is
+    /// applied, so a failed append leaves the keyspace untouched.
+    fn log(&self, record: WalRecord) -> KVResult<()> {
+        self.create_on_write()?;
+        if let Some(wal) = self.inner.wal.get() {
+            wal.append(&self.namespace.name, &record)?;
+        }
+        Ok(())
+    }
+
+    fn next_version(&self) -> u64 {
+        self.inner.next_version.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Version for a restored entry: the persisted one, so versions clients hold stay valid
+    /// across restarts. Versions handed out from here on are above it.
+    fn restore_version(&self, version: u64) -> u64 {
+        self.inner
+            .next_version
+            .fetch_max(version + 1, Ordering::Relaxed);
+        version
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.namespace.shards.len()
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        self.inner.hasher.hash_one(key) as usize % self.namespace.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<KeySpace> {
+        &self.namespace.shards[self.shard_index(key)]
+    }
+
+    /// Stores the value without expiration, discarding any TTL the key had.
+    /// Returns the new version of the key.
+    pub fn set_key_value(&self, key: String, value: Vec<u8>) -> KVResult<u64> {
+        self.set_unconditionally(key, value, None)
+    }
+
+    pub fn set_key_value_with_expiration(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expiration: Expiration,
+    ) -> KVResult<u64> {
+        self.set_unconditionally(key, value, Some(expiration))
+    }
+
+    fn set_unconditionally(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expiration: Option<Expiration>,
+    ) -> KVResult<u64> {
+        let options = SetOptions {
+            expiration,
+            ..Default::default()
         };
-        match kv.get(key) {
-            Some(value) => Ok(value.to_owned()),
-            None => Err(KVError::KeyNotFound),
+        let outcome = self.set_with_options(key, value, options)?;
+
+        Ok(outcome.version.unwrap_or_default())
+    }
+
+    /// Stores the value only if the key is still at `expected_version`, as returned by
+    /// `get_key_value_with_version`. An `expected_version` of 0 means the key must not exist.
+    /// Fails with `KVError::VersionMismatch` otherwise; returns the new version on success.
+    pub fn compare_and_set(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Vec<u8>,
+    ) -> KVResult<u64> {
+        self.compare_and_set_inner(key, expected_version, value, None)
+    }
+
+    pub fn compare_and_set_with_expiration(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Vec<u8>,
+        expiration: Expiration,
+    ) -> KVResult<u64> {
+        self.compare_and_set_inner(key, expected_version, value, Some(expiration))
+    }
+
+    fn compare_and_set_inner(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Vec<u8>,
+        expiration: Option<Expiration>,
+    ) -> KVResult<u64> {
+        let options = SetOptions {
+            condition: SetCondition::IfVersion(expected_version),
+            expiration,
+            ..Default::default()
+        };
+        let outcome = self.set_with_options(key, value, options)?;
+
+        outcome.version.ok_or(KVError::VersionMismatch)
+    }
+
+    /// Stores the value if `options.condition` holds. A condition that doesn't hold is not an
+    /// error: the outcome just carries no version. Any TTL the key had is replaced.
+    pub fn set_with_options(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> KVResult<SetOutcome> {
+        let expires_at = options.expiration.map(Expiration::to_unix_millis);
+
+        // A write that isn't going to happen must not evict anything to make room for itself.
+        if options.condition != SetCondition::Always {
+            let kv = read_shard(self.shard(&key))?;
+            let now = now_unix_millis();
+            let current = kv.entries.get(&key).filter(|entry| !entry.is_expired(now));
+            if let Some(entry) = current {
+                entry.value.check_replaceable()?;
+            }
+
+            if !options.condition.holds(current.map(|entry| entry.version)) {
+                let previous = current
+                    .filter(|_| options.return_previous)
+                    .map(|entry| entry.value.as_string().cloned())
+                    .transpose()?;
+                return Ok(SetOutcome {
+                    version: None,
+                    previous,
+                });
+            }
+        }
+
+        let _reservation =
+            self.reserve_memory(self.memory_delta(&key, value.len(), expires_at.is_some())?)?;
+
+        // The key may have changed while making room, so the condition is checked again.
+        let mut kv = write_shard(self.shard(&key))?;
+        let now = now_unix_millis();
+
+        let current = kv.get_live_mut(&key, now);
+        if let Some(entry) = &current {
+            entry.value.check_replaceable()?;
         }
+        let current_version = current.as_ref().map(|entry| entry.version);
+        let previous = current
+            .filter(|_| options.return_previous)
+            .map(|entry| entry.value.as_string().cloned())
+            .transpose()?;
+
+        if !options.condition.holds(current_version) {
+            return Ok(SetOutcome {
+                version: None,
+                previous,
+            });
+        }
+
+        let version = self.next_version();
+        self.log(WalRecord::Set {
+            key: key.as_str().into(),
+            value: value.as_slice().into(),
+            expires_at,
+            version,
+            kind: value::KIND_STRING,
+        })?;
+        kv.insert(key, Value::String(value), expires_at, version, now);
+
+        Ok(SetOutcome {
+            version: Some(version),
+            previous,
+        })
     }
 
-    pub fn delete_key_value(&self, key: &str) -> KVResult<()> {
-        let Ok(mut kv) = self.kv.lock() else {
-            if self.kv.is_poisoned() {
-                self.kv.clear_poison();
+    pub fn get_key_value(&self, key: &str) -> KVResult<Vec<u8>> {
+        self.get_key_value_with_version(key).map(|(value, _)| value)
+    }
+
+    /// Returns the value together with its version, for a later `compare_and_set`.
+    pub fn get_key_value_with_version(&self, key: &str) -> KVResult<(Vec<u8>, u64)> {
+        let shard = self.shard(key);
+        let now = now_unix_millis();
+
+        {
+            let kv = read_shard(shard)?;
+            match kv.entries.get(key) {
+                Some(entry) if !entry.is_expired(now) => {
+                    entry.touch(now);
+                    return Ok((entry.value.as_string()?.to_owned(), entry.version));
+                }
+                Some(_) => {}
+                None => return Err(KVError::KeyNotFound),
             }
-            return Err(KVError::LockFailed);
+        }
+
+        // The key has expired: reclaim it under the write lock.
+        write_shard(shard)?.get_live_mut(key, now);
+        Err(KVError::KeyNotFound)
+    }
+
+    /// Removes the key and returns the value and version it held.
+    pub fn get_and_delete(&self, key: &str) -> KVResult<(Vec<u8>, u64)> {
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+        match kv.get_live_mut(key, now) {
+            Some(entry) => entry.value.as_string()?,
+            None => return Err(KVError::KeyNotFound),
         };
-        if kv.remove(key).is_none() {
+
+        self.log(WalRecord::Delete { key: key.into() })?;
+        let entry = kv
+            .remove(key, KeyEventOp::Delete)
+            .ok_or(KVError::KeyNotFound)?;
+        Ok((
+            Arc::unwrap_or_clone(entry.value).into_string()?,
+            entry.version,
+        ))
+    }
+
+    pub fn delete_key_value(&self, key: &str) -> KVResult<()> {
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+        match kv.get_live_mut(key, now) {
+            Some(entry) => entry.value.check_replaceable()?,
+            None => return Err(KVError::KeyNotFound),
+        }
+
+        self.log(WalRecord::Delete { key: key.into() })?;
+        kv.remove(key, KeyEventOp::Delete);
+        Ok(())
+    }
+
+    /// Sets a deadline on an existing key.
+    pub fn expire(&self, key: &str, expiration: Expiration) -> KVResult<()> {
+        self.set_expiration(key, Some(expiration.to_unix_millis()))
+    }
+
+    /// Removes the deadline of an existing key so it lives until deleted.
+    pub fn persist(&self, key: &str) -> KVResult<()> {
+        self.set_expiration(key, None)
+    }
+
+    fn set_expiration(&self, key: &str, expires_at: Option<u64>) -> KVResult<()> {
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+        if kv.get_live_mut(key, now).is_none() {
             return Err(KVError::KeyNotFound);
         }
+
+        self.log(WalRecord::Expire {
+            key: key.into(),
+            expires_at,
+        })?;
+        kv.set_expiration(key, expires_at, now);
         Ok(())
     }
 
+    /// Remaining lifetime of the key. `None` if the key has no expiration.
+    pub fn ttl(&self, key: &str) -> KVResult<Option<Duration>> {
+        let kv = read_shard(self.shard(key))?;
+        let now = now_unix_millis();
+        match kv.entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => Ok(entry
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now)))),
+            _ => Err(KVError::KeyNotFound),
+        }
+    }
+
+    /// Removes every key of the namespace; other namespaces are left alone.
     pub fn clear_all(&self) -> KVResult<()> {
-        let Ok(mut kv) = self.kv.lock() else {
-            if self.kv.is_poisoned() {
-                self.kv.clear_poison();
+        // Hold every shard at once so no writer observes a half-cleared keyspace.
+        let mut shards = self
+            .namespace
+            .shards
+            .iter()
+            .map(write_shard)
+            .collect::<KVResult<Vec<_>>>()?;
+        // A namespace that was never written to has nothing to clear
+        if !self.namespace.created.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.log(WalRecord::Clear)?;
+
+        for kv in shards.iter_mut() {
+            kv.clear();
+        }
+        events::emit(&self.namespace.events, "", KeyEventOp::Clear, 0);
+        Ok(())
+    }
+
+    /// Drops every key of the namespace without logging it, for restoring persisted data.
+    fn clear_shards(&self) -> KVResult<()> {
+        for shard in self.namespace.shards.iter() {
+            write_shard(shard)?.clear();
+        }
+        Ok(())
+    }
+
+    /// Removes expired keys that were never read again, in every namespace.
+    /// Returns the number removed.
+    pub fn remove_expired_keys(&self) -> KVResult<usize> {
+        let now = now_unix_millis();
+        let mut total = 0;
+
+        let namespaces = self.all_namespaces()?;
+        for shard in namespaces
+            .iter()
+            .flat_map(|engine| engine.namespace.shards.iter())
+        {
+            // Release the lock between batches so a large backlog doesn't stall writers.
+            loop {
+                let removed = write_shard(shard)?.remove_expired(now, SWEEP_BATCH_SIZE);
+                total += removed;
+
+                if removed < SWEEP_BATCH_SIZE {
+                    break;
+                }
             }
-            return Err(KVError::LockFailed);
+        }
+
+        Ok(total)
+    }
+
+    /// Spawns a background task that reclaims expired keys every `interval`.
+    /// The task stops once every handle to the engine has been dropped.
+    pub fn start_expiration_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let inner: Weak<EngineInner> = Arc::downgrade(&self.inner);
+        let namespace = self.namespace.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+
+                let engine = KVEngine {
+                    inner,
+                    namespace: namespace.clone(),
+                };
+                match engine.remove_expired_keys() {
+                    Ok(0) => {}
+                    Ok(removed) => log::debug!("Removed {} expired keys", removed),
+                    Err(error) => log::error!("Failed to remove expired keys: {}", error),
+                }
+            }
+        })
+    }
+
+    /// Loads the snapshot file into every namespace, replacing their contents.
+    /// Meant to run once at startup, before the server starts accepting connections.
+    /// Returns the number of keys restored; a missing snapshot file restores nothing.
+    pub fn load_snapshot(&self) -> KVResult<usize> {
+        let Some(path) = &self.inner.snapshot_path else {
+            return Err(KVError::SnapshotDisabled);
+        };
+
+        let Some(records) = snapshot::read_snapshot(path)? else {
+            return Ok(0);
         };
-        kv.clear();
+
+        let now = now_unix_millis();
+        for engine in self.all_namespaces()? {
+            engine.clear_shards()?;
+        }
+
+        let mut restored = 0;
+        for record in records {
+            if record
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                continue;
+            }
+
+            let engine = self.namespace_unchecked(&record.namespace)?;
+            let Some(value) = Value::decode(record.kind, record.value) else {
+                return Err(KVError::SnapshotFailed(format!(
+                    "invalid value of kind {} for key {}",
+                    record.kind, record.key
+                )));
+            };
+            let version = self.restore_version(record.version);
+            write_shard(engine.shard(&record.key))?.insert(
+                record.key,
+                value,
+                record.expires_at,
+                version,
+                now,
+            );
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+
+    /// Writes a snapshot of the keyspace and returns the number of keys saved.
+    ///
+    /// Shards are read-locked one at a time while their entries are copied out, see
+    /// `live_records`; serializing and writing the file happens without holding any lock.
+    pub fn save_snapshot(&self) -> KVResult<usize> {
+        let guard = self.begin_snapshot()?;
+        guard.engine.write_snapshot()
+    }
+
+    /// Starts saving a snapshot on a separate thread and returns immediately.
+    pub fn background_save(&self) -> KVResult<()> {
+        let guard = self.begin_snapshot()?;
+
+        std::thread::spawn(move || match guard.engine.write_snapshot() {
+            Ok(saved) => log::info!("Background snapshot saved {} keys", saved),
+            Err(error) => log::error!("Background snapshot failed: {}", error),
+        });
+
         Ok(())
     }
+
+    fn begin_snapshot(&self) -> KVResult<SnapshotGuard> {
+        if self.inner.snapshot_path.is_none() {
+            return Err(KVError::SnapshotDisabled);
+        }
+
+        if self.inner.snapshot_in_progress.swap(true, Ordering::AcqRel) {
+            return Err(KVError::SnapshotInProgress);
+        }
+
+        Ok(SnapshotGuard {
+            engine: self.clone(),
+        })
+    }
+
+    fn write_snapshot(&self) -> KVResult<usize> {
+        let Some(path) = &self.inner.snapshot_path else {
+            return Err(KVError::SnapshotDisabled);
+        };
+
+        let records = self.live_records()?;
+
+        snapshot::write_snapshot(path, &records)?;
+
+        Ok(records.len())
+    }
+
+    /// Copies every live entry out of every namespace.
+    ///
+    /// Shards are read-locked one at a time and only while their values are shared out,
+    /// so writers to other shards never wait; values are encoded once every lock is released.
+    /// The copy is therefore consistent per shard rather than across shards: a write spanning
+    /// shards (MSET, a transaction) may be caught half applied, and is completed by replaying
+    /// the write-ahead log on top.
+    fn live_records(&self) -> KVResult<Vec<SnapshotRecord>> {
+        let now = now_unix_millis();
+
+        let mut entries = vec![];
+        for engine in self.all_namespaces()? {
+            for shard in engine.namespace.shards.iter() {
+                let kv = read_shard(shard)?;
+                entries.reserve(kv.entries.len());
+
+                for (key, entry) in kv.entries.iter() {
+                    if entry.is_expired(now) {
+                        continue;
+                    }
+
+                    entries.push((
+                        engine.namespace.clone(),
+                        key.clone(),
+                        entry.value.clone(),
+                        entry.expires_at,
+                        entry.version,
+                    ));
+                }
+            }
+        }
+
+        let records = entries
+            .into_iter()
+            .map(
+                |(namespace, key, value, expires_at, version)| SnapshotRecord {
+                    key,
+                    value: value.encode().into_owned(),
+                    expires_at,
+                    version,
+                    kind: value.kind(),
+                    namespace: namespace.name.clone(),
+                },
+            )
+            .collect();
+
+        Ok(records)
+    }
+
+    /// Spawns a background task that saves a snapshot every configured interval.
+    /// Returns `None` when persistence or periodic snapshots are disabled.
+    pub fn start_snapshot_scheduler(&self) -> Option<tokio::task::JoinHandle<()>> {
+        self.inner.snapshot_path.as_ref()?;
+        let interval = self.inner.snapshot_interval?;
+
+        let inner: Weak<EngineInner> = Arc::downgrade(&self.inner);
+        let namespace = self.namespace.clone();
+
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately; there is nothing new to save at startup.
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let engine = KVEngine {
+                    inner,
+                    namespace: namespace.clone(),
+                };
+
+                match tokio::task::spawn_blocking(move || engine.save_snapshot()).await {
+                    Ok(Ok(saved)) => log::debug!("Snapshot saved {} keys", saved),
+                    Ok(Err(KVError::SnapshotInProgress)) => {}
+                    Ok(Err(error)) => log::error!("Failed to save snapshot: {}", error),
+                    Err(error) => log::error!("Snapshot task panicked: {}", error),
+                }
+            }
+        }))
+    }
+
+    /// Compacts the write-ahead log down to one record per live key.
+    /// Writers keep appending to the old log while the new one is written.
+    pub fn rewrite_wal(&self) -> KVResult<u64> {
+        let Some(wal) = self.inner.wal.get() else {
+            return Err(KVError::WalDisabled);
+        };
+
+        wal.begin_rewrite()?;
+
+        let records = match self.live_records() {
+            Ok(records) => records,
+            Err(error) => {
+                wal.abort_rewrite();
+                return Err(error);
+            }
+        };
+
+        let namespaces = match self.namespace_names() {
+            Ok(namespaces) => namespaces,
+            Err(error) => {
+                wal.abort_rewrite();
+                return Err(error);
+            }
+        };
+
+        // The new log describes every namespace on its own, so it starts by clearing them:
+        // keys deleted since the last snapshot must not come back when it is replayed on top of it.
+        let clears = namespaces
+            .iter()
+            .map(|namespace| (namespace.as_str(), WalRecord::Clear));
+        let live_keys = records.iter().map(|record| {
+            let set = WalRecord::Set {
+                key: record.key.as_str().into(),
+                value: record.value.as_slice().into(),
+                expires_at: record.expires_at,
+                version: record.version,
+                kind: record.kind,
+            };
+            (record.namespace.as_str(), set)
+        });
+        let size = wal.finish_rewrite(clears.chain(live_keys))?;
+
+        Ok(size)
+    }
+
+    /// Spawns a background task that fsyncs the write-ahead log every second (`everysec`)
+    /// and rewrites it once it has grown enough. Returns `None` when the log is disabled.
+    pub fn start_wal_maintenance(&self) -> Option<tokio::task::JoinHandle<()>> {
+        self.inner.wal.get()?;
+
+        let inner: Weak<EngineInner> = Arc::downgrade(&self.inner);
+        let namespace = self.namespace.clone();
+
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(WAL_MAINTENANCE_INTERVAL);
+
+            loop {
+                ticker.tick().await;
+
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let engine = KVEngine {
+                    inner,
+                    namespace: namespace.clone(),
+                };
+
+                let result = tokio::task::spawn_blocking(move || {
+                    let wal = engine.inner.wal.get().unwrap();
+
+                    if wal.fsync_policy() == FsyncPolicy::EverySec {
+                        wal.sync()?;
+                    }
+
+                    if wal.needs_rewrite() {
+                        let size = engine.rewrite_wal()?;
+                        log::info!("Rewrote write-ahead log to {} bytes", size);
+                    }
+
+                    KVResult::Ok(())
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => log::error!("Write-ahead log maintenance failed: {}", error),
+                    Err(error) => log::error!("Write-ahead log task panicked: {}", error),
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config(name: &str) -> EngineConfig {
+        let dir = std::env::temp_dir().join(format!("rstore-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        EngineConfig {
+            snapshot_path: Some(dir.join("dump.rstore")),
+            wal_path: Some(dir.join("appendonly.rstore")),
+            wal_fsync: FsyncPolicy::Always,
+            ..EngineConfig::default()
+        }
+    }
+
+    #[test]
+    fn restores_the_snapshot_then_the_log_on_top() {
+        let config = temp_config("recovery");
+        let dir = config
+            .snapshot_path
+            .as_ref()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_owned();
+
+        // Written without the log, so only the snapshot has these
+        let without_log = EngineConfig {
+            wal_path: None,
+            ..config.clone()
+        };
+        let engine = KVEngine::open(without_log).unwrap();
+        engine.set_key_value("kept".into(), b"1".to_vec()).unwrap();
+        engine
+            .set_key_value("overwritten".into(), b"old".to_vec())
+            .unwrap();
+        engine
+            .set_key_value("deleted".into(), b"1".to_vec())
+            .unwrap();
+        engine
+            .sorted_set_add("scores", vec![("a".into(), 1.0), ("b".into(), 2.0)])
+            .unwrap();
+        engine
+            .namespace("billing")
+            .unwrap()
+            .set_key_value("invoice".into(), b"7".to_vec())
+            .unwrap();
+        assert_eq!(engine.save_snapshot().unwrap(), 5);
+        drop(engine);
+
+        // Only in the log
+        let engine = KVEngine::open(config.clone()).unwrap();
+        engine
+            .set_key_value("overwritten".into(), b"new".to_vec())
+            .unwrap();
+        engine.delete_key_value("deleted").unwrap();
+        engine.set_key_value("added".into(), b"2".to_vec()).unwrap();
+        engine
+            .sorted_set_add("scores", vec![("a".into(), 3.0), ("c".into(), 0.5)])
+            .unwrap();
+        engine
+            .namespace("billing")
+            .unwrap()
+            .delete_key_value("invoice")
+            .unwrap();
+        let (_, version) = engine.get_key_value_with_version("added").unwrap();
+        drop(engine);
+
+        let engine = KVEngine::open(config).unwrap();
+        assert_eq!(engine.get_key_value("kept").unwrap(), b"1");
+        assert_eq!(engine.get_key_value("overwritten").unwrap(), b"new");
+        assert_eq!(engine.get_key_value("added").unwrap(), b"2");
+        assert!(matches!(
+            engine.get_key_value("deleted"),
+            Err(KVError::KeyNotFound)
+        ));
+        assert_eq!(
+            engine.sorted_set_range("scores", 0, -1, false).unwrap(),
+            [("c".into(), 0.5), ("b".into(), 2.0), ("a".into(), 3.0)]
+        );
+        assert_eq!(engine.namespace("billing").unwrap().size().unwrap(), 0);
+
+        // Versions keep counting from where they were before the restart
+        let (_, restored_version) = engine.get_key_value_with_version("added").unwrap();
+        assert_eq!(restored_version, version);
+        let next_version = engine.set_key_value("added".into(), b"3".to_vec()).unwrap();
+        assert!(next_version > version);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn compare_and_set_only_writes_over_the_expected_version() {
+        let engine = KVEngine::new();
+        let first = engine.set_key_value("key".into(), b"1".to_vec()).unwrap();
+        let second = engine.set_key_value("key".into(), b"2".to_vec()).unwrap();
+
+        // Someone wrote in between
+        assert!(matches!(
+            engine.compare_and_set("key".into(), first, b"3".to_vec()),
+            Err(KVError::VersionMismatch)
+        ));
+        assert!(matches!(
+            engine.compare_and_set("key".into(), 0, b"3".to_vec()),
+            Err(KVError::VersionMismatch)
+        ));
+        assert_eq!(
+            engine.get_key_value_with_version("key").unwrap(),
+            (b"2".to_vec(), second)
+        );
+
+        let third = engine
+            .compare_and_set("key".into(), second, b"3".to_vec())
+            .unwrap();
+        assert!(third > second);
+        assert!(matches!(
+            engine.compare_and_set("key".into(), second, b"4".to_vec()),
+            Err(KVError::VersionMismatch)
+        ));
+
+        // A recreated key never reuses the version it had before
+        engine.delete_key_value("key").unwrap();
+        assert!(matches!(
+            engine.compare_and_set("key".into(), third, b"4".to_vec()),
+            Err(KVError::VersionMismatch)
+        ));
+        let recreated = engine
+            .compare_and_set("key".into(), 0, b"4".to_vec())
+            .unwrap();
+        assert!(recreated > third);
+        assert_eq!(engine.get_key_value("key").unwrap(), b"4");
+    }
 }
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use super::{
+    KVEngine, KVResult, KeyEventOp, now_unix_millis, read_shard,
+    value::{KIND_STRING, Value},
+    wal::WalRecord,
+    write_shard,
+};
+
+impl KVEngine {
+    /// Shard index of every key, plus the distinct indexes in lock order.
+    pub(super) fn batch_shards<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a str>,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let indexes: Vec<usize> = keys.map(|key| self.shard_index(key)).collect();
+
+        let mut lock_order = indexes.clone();
+        lock_order.sort_unstable();
+        lock_order.dedup();
+
+        (indexes, lock_order)
+    }
+
+    /// Fetches several keys at once; `None` for keys that don't exist.
+    /// Every shard involved is locked once, so the values are a consistent point in time.
+    pub fn mget(&self, keys: &[String]) -> KVResult<Vec<Option<Vec<u8>>>> {
+        let (indexes, lock_order) = self.batch_shards(keys.iter().map(String::as_str));
+        let now = now_unix_millis();
+
+        let shards = lock_order
+            .into_iter()
+            .map(|index| Ok((index, read_shard(&self.namespace.shards[index])?)))
+            .collect::<KVResult<BTreeMap<_, _>>>()?;
+
+        let values = keys
+            .iter()
+            .zip(indexes)
+            .map(|(key, index)| {
+                let entry = shards[&index]
+                    .entries
+                    .get(key)
+                    .filter(|entry| !entry.is_expired(now))?;
+                entry.touch(now);
+                // Keys of other types read as missing
+                entry.value.as_string().ok().cloned()
+            })
+            .collect();
+
+        Ok(values)
+    }
+
+    /// Stores several values at once, without expiration, and returns their new versions.
+    /// Other clients never observe only part of the batch.
+    pub fn mset(&self, entries: Vec<(String, Vec<u8>)>) -> KVResult<Vec<u64>> {
+        let additional = entries
+            .iter()
+            .map(|(key, value)| self.memory_delta(key, value.len(), false))
+            .sum::<KVResult<usize>>()?;
+        let _reservation = self.reserve_memory(additional)?;
+
+        let (indexes, lock_order) = self.batch_shards(entries.iter().map(|(key, _)| key.as_str()));
+        let now = now_unix_millis();
+
+        let mut shards = lock_order
+            .into_iter()
+            .map(|index| Ok((index, write_shard(&self.namespace.shards[index])?)))
+            .collect::<KVResult<BTreeMap<_, _>>>()?;
+
+        // Checked up front, so a rejected key leaves the whole batch unwritten
+        for ((key, _), index) in entries.iter().zip(&indexes) {
+            if let Some(entry) = shards
+                .get_mut(index)
+                .and_then(|kv| kv.get_live_mut(key, now))
+            {
+                entry.value.check_replaceable()?;
+            }
+        }
+
+        let mut versions = Vec::with_capacity(entries.len());
+        for ((key, value), index) in entries.into_iter().zip(indexes) {
+            let version = self.next_version();
+            self.log(WalRecord::Set {
+                key: key.as_str().into(),
+                value: value.as_slice().into(),
+                expires_at: None,
+                version,
+                kind: KIND_STRING,
+            })?;
+
+            if let Some(kv) = shards.get_mut(&index) {
+                kv.insert(key, Value::String(value), None, version, now);
+            }
+            versions.push(version);
+        }
+
+        Ok(versions)
+    }
+
+    /// Deletes several keys at once. Returns, per key, whether it existed.
+    pub fn mdel(&self, keys: &[String]) -> KVResult<Vec<bool>> {
+        let (indexes, lock_order) = self.batch_shards(keys.iter().map(String::as_str));
+        let now = now_unix_millis();
+
+        let mut shards = lock_order
+            .into_iter()
+            .map(|index| Ok((index, write_shard(&self.namespace.shards[index])?)))
+            .collect::<KVResult<BTreeMap<_, _>>>()?;
+
+        for (key, index) in keys.iter().zip(&indexes) {
+            if let Some(entry) = shards
+                .get_mut(index)
+                .and_then(|kv| kv.get_live_mut(key, now))
+            {
+                entry.value.check_replaceable()?;
+            }
+        }
+
+        let mut deleted = Vec::with_capacity(keys.len());
+        for (key, index) in keys.iter().zip(indexes) {
+            let Some(kv) = shards.get_mut(&index) else {
+                deleted.push(false);
+                continue;
+            };
+
+            if kv.get_live_mut(key, now).is_none() {
+                deleted.push(false);
+                continue;
+            }
+
+            self.log(WalRecord::Delete {
+                key: key.as_str().into(),
+            })?;
+            kv.remove(key, KeyEventOp::Delete);
+            deleted.push(true);
+        }
+
+        Ok(deleted)
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::Notify;
+
+// Waiter id and the handle to wake it
+type WaiterSlot = (u64, Arc<Notify>);
+
+/// Tasks parked until a key is written to, e.g. by a blocking pop.
+#[derive(Debug, Default)]
+pub(super) struct KeyWaiters {
+    waiting: Mutex<HashMap<String, Vec<WaiterSlot>>>,
+    next_id: AtomicU64,
+}
+
+impl KeyWaiters {
+    /// Registers interest in the keys. Register before checking them, so a write
+    /// in between still wakes the waiter.
+    pub fn register(&self, keys: &[String]) -> Waiter<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+
+        let mut waiting = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        for key in keys {
+            waiting
+                .entry(key.clone())
+                .or_default()
+                .push((id, notify.clone()));
+        }
+
+        Waiter {
+            waiters: self,
+            id,
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    /// Wakes everyone waiting on the key. They race for whatever was written;
+    /// the losers simply wait again.
+    pub fn wake(&self, key: &str) {
+        let waiting = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        for (_, notify) in waiting.get(key).into_iter().flatten() {
+            notify.notify_one();
+        }
+    }
+}
+
+/// A registration made by `KeyWaiters::register`; dropping it unregisters.
+#[derive(Debug)]
+pub(super) struct Waiter<'a> {
+    waiters: &'a KeyWaiters,
+    id: u64,
+    keys: Vec<String>,
+    notify: Arc<Notify>,
+}
+
+impl Waiter<'_> {
+    /// Resolves once one of the keys was written to since the last call.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self
+            .waiters
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        for key in &self.keys {
+            if let Some(entries) = waiting.get_mut(key) {
+                entries.retain(|(id, _)| *id != self.id);
+                if entries.is_empty() {
+                    waiting.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Tasks waiting for locks, served in the order they started waiting.
+#[derive(Debug, Default)]
+pub(super) struct LockQueue {
+    waiting: Mutex<HashMap<String, VecDeque<WaiterSlot>>>,
+    next_id: AtomicU64,
+}
+
+impl LockQueue {
+    /// Gets in line for the lock.
+    pub fn enqueue(&self, name: &str) -> Ticket<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+
+        self.waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .entry(name.to_owned())
+            .or_default()
+            .push_back((id, notify.clone()));
+
+        Ticket {
+            queue: self,
+            id,
+            name: name.to_owned(),
+            notify,
+        }
+    }
+
+    /// Whether the ticket (none for a caller not in line) may take the lock: no one is
+    /// in line, or the ticket is first.
+    pub fn is_turn(&self, name: &str, ticket: Option<u64>) -> bool {
+        let waiting = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        match waiting.get(name).and_then(VecDeque::front) {
+            Some((first, _)) => Some(*first) == ticket,
+            None => true,
+        }
+    }
+
+    /// Wakes whoever is first in line for the lock.
+    pub fn wake_first(&self, name: &str) {
+        let waiting = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        if let Some((_, notify)) = waiting.get(name).and_then(VecDeque::front) {
+            notify.notify_one();
+        }
+    }
+}
+
+/// A place in line made by `LockQueue::enqueue`; dropping it leaves the line, handing the
+/// turn to the next in line if it was first.
+#[derive(Debug)]
+pub(super) struct Ticket<'a> {
+    queue: &'a LockQueue,
+    id: u64,
+    name: String,
+    notify: Arc<Notify>,
+}
+
+impl Ticket<'_> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Resolves once the ticket became first in line or the lock was released since the
+    /// last call.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self
+            .queue
+            .waiting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        let Some(entries) = waiting.get_mut(&self.name) else {
+            return;
+        };
+        let was_first = entries.front().is_some_and(|(id, _)| *id == self.id);
+        entries.retain(|(id, _)| *id != self.id);
+
+        match entries.front() {
+            Some((_, notify)) if was_first => notify.notify_one(),
+            Some(_) => {}
+            None => {
+                waiting.remove(&self.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::{EvictionPolicy, FsyncPolicy};
+
+// Used when the number of CPUs can't be determined
+const DEFAULT_SHARD_COUNT: usize = 16;
+
+const DEFAULT_SNAPSHOT_PATH: &str = "dump.rstore";
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_WAL_PATH: &str = "appendonly.rstore";
+const DEFAULT_WAL_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+
+const DEFAULT_PUBSUB_OUTPUT_BUFFER_LIMIT: usize = 32 * 1024 * 1024; // 32MB
+
+const DEFAULT_MAX_NAMESPACES: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub shard_count: usize,                  // 키 공간을 나눌 샤드 수
+    pub snapshot_path: Option<PathBuf>,      // 스냅샷 파일 경로 (None이면 영속화 비활성화)
+    pub snapshot_interval: Option<Duration>, // 주기적 스냅샷 간격 (None이면 SAVE 요청 시에만 저장)
+    pub wal_path: Option<PathBuf>,           // WAL 파일 경로 (None이면 WAL 비활성화)
+    pub wal_fsync: FsyncPolicy,              // WAL fsync 정책
+    pub wal_rewrite_min_size: u64,           // WAL 재작성을 시작할 최소 크기 (bytes)
+    pub max_memory: usize,                   // 최대 메모리 사용량 (bytes, 0이면 무제한)
+    pub eviction_policy: EvictionPolicy,     // 메모리 초과 시 키 제거 정책
+    pub pubsub_output_buffer_limit: usize,   // 구독자별 미전송 메시지 한도 (bytes, 0이면 무제한)
+    pub max_namespaces: usize,               // 최대 네임스페이스 수 (0이면 무제한)
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        let shard_count = std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(DEFAULT_SHARD_COUNT);
+
+        Self {
+            shard_count,
+            snapshot_path: None,
+            snapshot_interval: None,
+            wal_path: None,
+            wal_fsync: FsyncPolicy::EverySec,
+            wal_rewrite_min_size: DEFAULT_WAL_REWRITE_MIN_SIZE,
+            max_memory: 0,
+            eviction_policy: EvictionPolicy::NoEviction,
+            pubsub_output_buffer_limit: DEFAULT_PUBSUB_OUTPUT_BUFFER_LIMIT,
+            max_namespaces: DEFAULT_MAX_NAMESPACES,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Reads the server configuration from the environment.
+    ///
+    /// - `RSTORE_SHARD_COUNT`: number of shards (default: CPU count)
+    /// - `RSTORE_SNAPSHOT_PATH`: snapshot file, empty to disable persistence (default: `dump.rstore`)
+    /// - `RSTORE_SNAPSHOT_INTERVAL_SECS`: seconds between background snapshots, 0 to disable (default: 60)
+    /// - `RSTORE_WAL_PATH`: write-ahead log file, empty to disable the log (default: `appendonly.rstore`)
+    /// - `RSTORE_WAL_FSYNC`: `always`, `everysec` or `no` (default: `everysec`)
+    /// - `RSTORE_WAL_REWRITE_MIN_SIZE`: log size in bytes before it is compacted (default: 64MB)
+    /// - `RSTORE_MAXMEMORY`: memory budget in bytes, 0 for unlimited (default: 0)
+    /// - `RSTORE_EVICTION_POLICY`: `noeviction`, `allkeys-lru`, `allkeys-lfu`, `volatile-ttl` or `random` (default: `noeviction`)
+    /// - `RSTORE_PUBSUB_OUTPUT_BUFFER_LIMIT`: bytes queued for a subscriber before it is disconnected, 0 for unlimited (default: 32MB)
+    /// - `RSTORE_MAX_NAMESPACES`: namespaces that can exist at once, 0 for unlimited (default: 1024)
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(shard_count) = env_var("RSTORE_SHARD_COUNT").and_then(|v| v.parse().ok()) {
+            config.shard_count = shard_count;
+        }
+
+        config.snapshot_path = match env_var("RSTORE_SNAPSHOT_PATH") {
+            Some(path) if path.is_empty() => None,
+            Some(path) => Some(path.into()),
+            None => Some(DEFAULT_SNAPSHOT_PATH.into()),
+        };
+
+        config.snapshot_interval =
+            match env_var("RSTORE_SNAPSHOT_INTERVAL_SECS").and_then(|v| v.parse::<u64>().ok()) {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => Some(DEFAULT_SNAPSHOT_INTERVAL),
+            };
+
+        config.wal_path = match env_var("RSTORE_WAL_PATH") {
+            Some(path) if path.is_empty() => None,
+            Some(path) => Some(path.into()),
+            None => Some(DEFAULT_WAL_PATH.into()),
+        };
+
+        if let Some(fsync) = env_var("RSTORE_WAL_FSYNC") {
+            match fsync.parse() {
+                Ok(fsync) => config.wal_fsync = fsync,
+                Err(error) => log::warn!("Ignoring RSTORE_WAL_FSYNC: {}", error),
+            }
+        }
+
+        if let Some(size) = env_var("RSTORE_WAL_REWRITE_MIN_SIZE").and_then(|v| v.parse().ok()) {
+            config.wal_rewrite_min_size = size;
+        }
+
+        if let Some(max_memory) = env_var("RSTORE_MAXMEMORY").and_then(|v| v.parse().ok()) {
+            config.max_memory = max_memory;
+        }
+
+        if let Some(policy) = env_var("RSTORE_EVICTION_POLICY") {
+            match policy.parse() {
+                Ok(policy) => config.eviction_policy = policy,
+                Err(error) => log::warn!("Ignoring RSTORE_EVICTION_POLICY: {}", error),
+            }
+        }
+
+        if let Some(limit) =
+            env_var("RSTORE_PUBSUB_OUTPUT_BUFFER_LIMIT").and_then(|v| v.parse().ok())
+        {
+            config.pubsub_output_buffer_limit = limit;
+        }
+
+        if let Some(max_namespaces) = env_var("RSTORE_MAX_NAMESPACES").and_then(|v| v.parse().ok())
+        {
+            config.max_namespaces = max_namespaces;
+        }
+
+        config
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
@@ -0,0 +1,96 @@
+use super::{
+    KVEngine, KVError, KVResult, now_unix_millis,
+    value::{KIND_STRING, Value},
+    wal::WalRecord,
+    write_shard,
+};
+
+// Longest decimal form of an i64 ("-9223372036854775808")
+pub(super) const MAX_INTEGER_LEN: usize = 20;
+// Longest shortest-roundtrip form of a finite f64 (e.g. "-2.2250738585072014e-308")
+const MAX_FLOAT_LEN: usize = 24;
+
+pub(super) fn parse_integer(value: &[u8]) -> KVResult<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(KVError::NotAnInteger)
+}
+
+fn parse_float(value: &[u8]) -> KVResult<f64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or(KVError::NotAFloat)
+}
+
+impl KVEngine {
+    /// Adds `delta` to the integer stored at `key` and returns the new value.
+    /// A missing key counts as 0; the key keeps its expiration.
+    pub fn incr_by(&self, key: &str, delta: i64) -> KVResult<i64> {
+        self.update_number(key, MAX_INTEGER_LEN, |current| {
+            let current = current.map(parse_integer).transpose()?.unwrap_or(0);
+            let value = current.checked_add(delta).ok_or(KVError::NotAnInteger)?;
+
+            Ok((value.to_string().into_bytes(), value))
+        })
+    }
+
+    /// Subtracts `delta` from the integer stored at `key` and returns the new value.
+    pub fn decr_by(&self, key: &str, delta: i64) -> KVResult<i64> {
+        self.incr_by(key, delta.checked_neg().ok_or(KVError::NotAnInteger)?)
+    }
+
+    /// Adds `delta` to the number stored at `key` and returns the new value.
+    /// Results that are not finite are rejected and leave the value untouched.
+    pub fn incr_by_float(&self, key: &str, delta: f64) -> KVResult<f64> {
+        self.update_number(key, MAX_FLOAT_LEN, |current| {
+            let current = current.map(parse_float).transpose()?.unwrap_or(0.0);
+            let value = current + delta;
+
+            if !value.is_finite() {
+                return Err(KVError::NotAFloat);
+            }
+
+            Ok((value.to_string().into_bytes(), value))
+        })
+    }
+
+    /// Replaces the value at `key` with one computed from the current value,
+    /// all under the shard lock so concurrent updates never interleave.
+    fn update_number<T>(
+        &self,
+        key: &str,
+        max_len: usize,
+        update: impl FnOnce(Option<&[u8]>) -> KVResult<(Vec<u8>, T)>,
+    ) -> KVResult<T> {
+        let _reservation = self.reserve_memory(self.memory_delta(key, max_len, false)?)?;
+
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+
+        let entry = kv.get_live_mut(key, now);
+        let expires_at = entry.as_ref().and_then(|entry| entry.expires_at);
+        let current = entry.map(|entry| entry.value.as_string()).transpose()?;
+        let (value, result) = update(current.map(Vec::as_slice))?;
+
+        let version = self.next_version();
+        self.log(WalRecord::Set {
+            key: key.into(),
+            value: value.as_slice().into(),
+            expires_at,
+            version,
+            kind: KIND_STRING,
+        })?;
+        kv.insert(
+            key.to_owned(),
+            Value::String(value),
+            expires_at,
+            version,
+            now,
+        );
+
+        Ok(result)
+    }
+}
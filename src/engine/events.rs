@@ -0,0 +1,110 @@
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::KVEngine;
+
+// Events a watcher may fall behind by before it starts missing some
+pub(super) const EVENT_BUS_CAPACITY: usize = 4096;
+
+/// What happened to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventOp {
+    /// Written; the event carries the new version
+    Set,
+    Delete,
+    /// Removed because its deadline passed
+    Expire,
+    /// Removed to stay within the memory budget
+    Evict,
+    /// Every key was removed; the event has an empty key
+    Clear,
+}
+
+impl KeyEventOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyEventOp::Set => "set",
+            KeyEventOp::Delete => "delete",
+            KeyEventOp::Expire => "expire",
+            KeyEventOp::Evict => "evict",
+            KeyEventOp::Clear => "clear",
+        }
+    }
+}
+
+/// A change to the keyspace. Removals carry the last version the key had.
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    pub key: String,
+    pub op: KeyEventOp,
+    pub version: u64,
+}
+
+/// Keys a `KeyWatcher` is interested in.
+#[derive(Debug, Clone)]
+pub enum KeyFilter {
+    Key(String),
+    Prefix(String),
+}
+
+impl KeyFilter {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyFilter::Key(expected) => key == expected,
+            KeyFilter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Receives the events of the keys matching its filters, see `KVEngine::watch_keys`.
+#[derive(Debug)]
+pub struct KeyWatcher {
+    receiver: broadcast::Receiver<KeyEvent>,
+    filters: Vec<KeyFilter>,
+}
+
+impl KeyWatcher {
+    /// Waits for the next matching event. Returns `None` once the watcher fell
+    /// so far behind that events were dropped; the caller has to resynchronize.
+    pub async fn recv(&mut self) -> Option<KeyEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.matches(&event) => return Some(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Key watcher fell behind and missed {} events", missed);
+                    return None;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn matches(&self, event: &KeyEvent) -> bool {
+        event.op == KeyEventOp::Clear
+            || self.filters.is_empty()
+            || self.filters.iter().any(|filter| filter.matches(&event.key))
+    }
+}
+
+impl KVEngine {
+    /// Starts watching the keys matching any of the filters; every key if there are none.
+    /// Only changes made after this call are received.
+    pub fn watch_keys(&self, filters: Vec<KeyFilter>) -> KeyWatcher {
+        KeyWatcher {
+            receiver: self.namespace.events.subscribe(),
+            filters,
+        }
+    }
+}
+
+/// Sends an event if anyone is watching. Building the event is skipped otherwise,
+/// so writes cost nothing extra while there are no watchers.
+pub(super) fn emit(events: &broadcast::Sender<KeyEvent>, key: &str, op: KeyEventOp, version: u64) {
+    if events.receiver_count() > 0 {
+        let _ = events.send(KeyEvent {
+            key: key.to_owned(),
+            op,
+            version,
+        });
+    }
+}
@@ -0,0 +1,438 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    KVEngine, KVError, KVResult, KeyEventOp,
+    keyspace::{Entry, KeySpace},
+    now_unix_millis, read_shard,
+    value::Value,
+    wal::WalRecord,
+    write_shard,
+};
+
+// Number of random keys compared when choosing an LRU/LFU victim
+const EVICTION_SAMPLE_SIZE: usize = 5;
+
+/// What to do when a write would push the engine over `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Reject the write with `KVError::OutOfMemory`
+    NoEviction,
+    /// Evict the least recently used key (sampled)
+    AllKeysLru,
+    /// Evict the least frequently used key (sampled)
+    AllKeysLfu,
+    /// Evict the key with the nearest expiration, among keys that have one
+    VolatileTtl,
+    /// Evict a random key
+    Random,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            "random" | "allkeys-random" => Ok(EvictionPolicy::Random),
+            _ => Err(format!("unknown eviction policy: {}", value)),
+        }
+    }
+}
+
+/// Bytes set aside in `used_memory` for a write in progress, released when dropped.
+///
+/// Held until the write has been applied and charged to its shard, so concurrent writers
+/// each see the others' reservations and can't all squeeze into the same free space.
+#[must_use]
+#[derive(Debug)]
+pub(super) struct MemoryReservation {
+    used_memory: Option<Arc<AtomicUsize>>,
+    bytes: usize,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        if let Some(used_memory) = &self.used_memory {
+            used_memory.fetch_sub(self.bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+// Locks are never evicted: they change hands only through the lock commands.
+fn evictable(entry: &Entry) -> bool {
+    !matches!(*entry.value, Value::Lock(_))
+}
+
+// First evictable key among `tries` slots from the given sampling slot on, wrapping around.
+fn evictable_key_at(kv: &KeySpace, slot: usize, tries: usize) -> Option<&String> {
+    (0..tries.min(kv.entries.len()))
+        .filter_map(|offset| kv.key_at(slot.wrapping_add(offset)))
+        .find(|key| evictable(&kv.entries[*key]))
+}
+
+impl KVEngine {
+    /// Bytes currently accounted to keys and values.
+    pub fn used_memory(&self) -> usize {
+        self.inner.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// Makes room for a write that grows memory usage by `additional` bytes,
+    /// evicting keys according to the policy, and holds it until the reservation is dropped.
+    /// Must be called without holding any shard lock.
+    pub(super) fn reserve_memory(&self, additional: usize) -> KVResult<MemoryReservation> {
+        let max_memory = self.inner.max_memory;
+        if max_memory == 0 || additional == 0 {
+            return Ok(MemoryReservation {
+                used_memory: None,
+                bytes: 0,
+            });
+        }
+
+        // Check and claim in one step, so a concurrent writer can't take the same room.
+        while self
+            .inner
+            .used_memory
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(additional)
+                    .filter(|&total| total <= max_memory)
+            })
+            .is_err()
+        {
+            if self.inner.eviction_policy == EvictionPolicy::NoEviction || !self.evict_one()? {
+                return Err(KVError::OutOfMemory);
+            }
+        }
+
+        Ok(MemoryReservation {
+            used_memory: Some(self.inner.used_memory.clone()),
+            bytes: additional,
+        })
+    }
+
+    /// Additional bytes needed to store `value_len` bytes at `key`, net of what the key uses now.
+    pub(super) fn memory_delta(
+        &self,
+        key: &str,
+        value_len: usize,
+        has_expiration: bool,
+    ) -> KVResult<usize> {
+        let size = super::keyspace::entry_size(key.len(), value_len, has_expiration);
+        let current = read_shard(self.shard(key))?.entry_size(key).unwrap_or(0);
+
+        Ok(size.saturating_sub(current))
+    }
+
+    /// Evicts a single key, from the namespace being written to if it has one eligible
+    /// under the policy, so a namespace filling memory makes room at its own expense first.
+    /// Returns false if no namespace has an eligible key.
+    fn evict_one(&self) -> KVResult<bool> {
+        for engine in self.all_namespaces()? {
+            if engine.evict_from_namespace()? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn evict_from_namespace(&self) -> KVResult<bool> {
+        let Some((shard_index, key)) = self.eviction_candidate()? else {
+            return Ok(false);
+        };
+
+        let mut kv = write_shard(&self.namespace.shards[shard_index])?;
+        if !kv.entries.get(&key).is_some_and(evictable) {
+            // Removed or replaced concurrently; the caller re-checks usage anyway.
+            return Ok(true);
+        }
+
+        self.log(WalRecord::Delete {
+            key: key.as_str().into(),
+        })?;
+        kv.remove(&key, KeyEventOp::Evict);
+        log::debug!("Evicted key {}", key);
+
+        Ok(true)
+    }
+
+    fn eviction_candidate(&self) -> KVResult<Option<(usize, String)>> {
+        let shards = &self.namespace.shards;
+        let now = now_unix_millis();
+
+        match self.inner.eviction_policy {
+            EvictionPolicy::NoEviction => Ok(None),
+            EvictionPolicy::VolatileTtl => {
+                let mut candidate: Option<(u64, usize, String)> = None;
+
+                for (index, shard) in shards.iter().enumerate() {
+                    let kv = read_shard(shard)?;
+                    let Some((expires_at, key)) = kv
+                        .expirations
+                        .iter()
+                        .find(|(_, key)| evictable(&kv.entries[key]))
+                    else {
+                        continue;
+                    };
+
+                    if candidate
+                        .as_ref()
+                        .is_none_or(|(best, _, _)| expires_at < best)
+                    {
+                        candidate = Some((*expires_at, index, key.clone()));
+                    }
+                }
+
+                Ok(candidate.map(|(_, index, key)| (index, key)))
+            }
+            EvictionPolicy::Random => {
+                let start = self.next_random() as usize;
+
+                for offset in 0..shards.len() {
+                    let index = (start + offset) % shards.len();
+                    let kv = read_shard(&shards[index])?;
+                    if let Some(key) =
+                        evictable_key_at(&kv, self.next_random() as usize, EVICTION_SAMPLE_SIZE)
+                    {
+                        return Ok(Some((index, key.clone())));
+                    }
+                }
+
+                // Every sample hit an empty shard or locks
+                self.any_evictable_key()
+            }
+            EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu => {
+                let lfu = self.inner.eviction_policy == EvictionPolicy::AllKeysLfu;
+                // Lower score is evicted first
+                let mut candidate: Option<(u64, usize, String)> = None;
+
+                for _ in 0..EVICTION_SAMPLE_SIZE {
+                    let index = self.next_random() as usize % shards.len();
+                    let kv = read_shard(&shards[index])?;
+
+                    let Some(key) =
+                        evictable_key_at(&kv, self.next_random() as usize, EVICTION_SAMPLE_SIZE)
+                    else {
+                        continue;
+                    };
+                    let entry = &kv.entries[key];
+                    let score = if lfu {
+                        entry.frequency(now) as u64
+                    } else {
+                        entry.last_access()
+                    };
+
+                    if candidate.as_ref().is_none_or(|(best, _, _)| score < *best) {
+                        candidate = Some((score, index, key.clone()));
+                    }
+                }
+
+                match candidate {
+                    Some((_, index, key)) => Ok(Some((index, key))),
+                    // Every sample hit an empty shard or locks
+                    None => self.any_evictable_key(),
+                }
+            }
+        }
+    }
+
+    fn any_evictable_key(&self) -> KVResult<Option<(usize, String)>> {
+        for (index, shard) in self.namespace.shards.iter().enumerate() {
+            let kv = read_shard(shard)?;
+            if let Some(key) = evictable_key_at(&kv, 0, kv.entries.len()) {
+                return Ok(Some((index, key.clone())));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn next_random(&self) -> u64 {
+        // xorshift64; races between threads only make it more random
+        let mut state = self.inner.random_state.load(Ordering::Relaxed);
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        self.inner.random_state.store(state, Ordering::Relaxed);
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::engine::{EngineConfig, Expiration};
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    // An engine filled by `fill` up to `max_memory`, so the next write of a key as large as
+    // one already there has to evict exactly one
+    fn full_engine(eviction_policy: EvictionPolicy, fill: impl Fn(&KVEngine)) -> KVEngine {
+        let probe = KVEngine::with_shard_count(1);
+        fill(&probe);
+
+        let engine = KVEngine::with_config(EngineConfig {
+            shard_count: 1,
+            max_memory: probe.used_memory(),
+            eviction_policy,
+            ..EngineConfig::default()
+        });
+        fill(&engine);
+        engine
+    }
+
+    fn set(engine: &KVEngine, key: &str) -> KVResult<u64> {
+        engine.set_key_value(key.into(), b"1".to_vec())
+    }
+
+    fn exists(engine: &KVEngine, key: &str) -> bool {
+        // Works for every kind of value, unlike reading it
+        engine.ttl(key).is_ok()
+    }
+
+    // Victims are picked from a sample, so the least used key goes almost always, not always
+    fn count_evicted(
+        eviction_policy: EvictionPolicy,
+        use_keys: impl Fn(&KVEngine),
+        rounds: usize,
+    ) -> usize {
+        (0..rounds)
+            .filter(|_| {
+                let engine = full_engine(eviction_policy, |engine| {
+                    engine.lock_acquire("lock", "owner", LEASE).unwrap();
+                    set(engine, "cold").unwrap();
+                    set(engine, "warm").unwrap();
+                });
+                use_keys(&engine);
+
+                set(&engine, "next").unwrap();
+                assert!(exists(&engine, "lock"));
+                assert!(exists(&engine, "next"));
+                !exists(&engine, "cold")
+            })
+            .count()
+    }
+
+    #[test]
+    fn no_eviction_rejects_the_write() {
+        let engine = full_engine(EvictionPolicy::NoEviction, |engine| {
+            set(engine, "a").unwrap();
+        });
+
+        assert!(matches!(set(&engine, "b"), Err(KVError::OutOfMemory)));
+        assert!(exists(&engine, "a"));
+        assert!(!exists(&engine, "b"));
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key() {
+        let evicted = count_evicted(
+            EvictionPolicy::AllKeysLru,
+            |engine| {
+                std::thread::sleep(Duration::from_millis(2));
+                engine.get_key_value("warm").unwrap();
+            },
+            64,
+        );
+        // Every sample misses the cold key in about 1 of 243 rounds
+        assert!(evicted >= 48, "cold evicted in {} of 64 rounds", evicted);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_key() {
+        let evicted = count_evicted(
+            EvictionPolicy::AllKeysLfu,
+            |engine| {
+                for _ in 0..8 {
+                    engine.get_key_value("warm").unwrap();
+                }
+            },
+            64,
+        );
+        assert!(evicted >= 48, "cold evicted in {} of 64 rounds", evicted);
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_the_nearest_deadline_among_expiring_keys() {
+        let engine = full_engine(EvictionPolicy::VolatileTtl, |engine| {
+            // The lock expires first but is never evicted
+            engine
+                .lock_acquire("lock", "owner", Duration::from_secs(1))
+                .unwrap();
+            for (key, seconds) in [("late", 120), ("soon", 60)] {
+                engine
+                    .set_key_value_with_expiration(
+                        key.into(),
+                        b"1".to_vec(),
+                        Expiration::After(Duration::from_secs(seconds)),
+                    )
+                    .unwrap();
+            }
+            set(engine, "kept").unwrap();
+        });
+
+        set(&engine, "new1").unwrap();
+        assert!(!exists(&engine, "soon"));
+        assert!(exists(&engine, "late"));
+
+        set(&engine, "new2").unwrap();
+        assert!(!exists(&engine, "late"));
+
+        // Only keys without a deadline and the lock are left
+        assert!(matches!(set(&engine, "new3"), Err(KVError::OutOfMemory)));
+        assert!(exists(&engine, "kept"));
+        assert!(exists(&engine, "lock"));
+    }
+
+    #[test]
+    fn locks_are_never_evicted() {
+        for policy in [
+            EvictionPolicy::AllKeysLru,
+            EvictionPolicy::AllKeysLfu,
+            EvictionPolicy::VolatileTtl,
+            EvictionPolicy::Random,
+        ] {
+            let engine = full_engine(policy, |engine| {
+                for name in ["lock1", "lock2", "lock3"] {
+                    engine.lock_acquire(name, "owner", LEASE).unwrap();
+                }
+            });
+
+            // Nothing but locks to make room from
+            assert!(
+                matches!(
+                    engine.lock_acquire("lock4", "owner", LEASE),
+                    Err(KVError::OutOfMemory)
+                ),
+                "{:?}",
+                policy
+            );
+            for name in ["lock1", "lock2", "lock3"] {
+                assert!(engine.lock_release(name, "owner").unwrap(), "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn random_eviction_spares_locks() {
+        for _ in 0..16 {
+            let engine = full_engine(EvictionPolicy::Random, |engine| {
+                engine.lock_acquire("lock", "owner", LEASE).unwrap();
+                set(engine, "a").unwrap();
+                set(engine, "b").unwrap();
+            });
+
+            set(&engine, "c").unwrap();
+            assert!(exists(&engine, "lock"));
+            assert!(exists(&engine, "c"));
+            assert_eq!(exists(&engine, "a") as u8 + exists(&engine, "b") as u8, 1);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::f64::consts::PI;
+
+use super::{KVEngine, KVError, KVResult};
+
+// Latitudes beyond these can't be projected by web maps, so neither are they stored
+const LATITUDE_MIN: f64 = -85.051_128_78;
+const LATITUDE_MAX: f64 = 85.051_128_78;
+const LONGITUDE_MIN: f64 = -180.0;
+const LONGITUDE_MAX: f64 = 180.0;
+// Bits per coordinate; interleaved they make a 52-bit geohash, which an f64 score holds exactly
+const GEOHASH_STEP: u32 = 26;
+// Mean Earth radius used by the haversine formula
+const EARTH_RADIUS_METERS: f64 = 6_372_797.560_856;
+
+/// Area searched by `KVEngine::geo_search`, around its center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    /// Within this many meters
+    Radius(f64),
+    /// Within a box this many meters wide (east to west) and high (north to south)
+    Box { width: f64, height: f64 },
+}
+
+/// Order of the results of `KVEngine::geo_search`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoSort {
+    /// Nearest first
+    Ascending,
+    /// Farthest first
+    Descending,
+}
+
+/// A member found by `KVEngine::geo_search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoMatch {
+    pub member: String,
+    /// Meters from the center of the search
+    pub distance: f64,
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+fn check_coordinates(longitude: f64, latitude: f64) -> KVResult<()> {
+    if (LONGITUDE_MIN..=LONGITUDE_MAX).contains(&longitude)
+        && (LATITUDE_MIN..=LATITUDE_MAX).contains(&latitude)
+    {
+        Ok(())
+    } else {
+        Err(KVError::InvalidCoordinates)
+    }
+}
+
+// Spreads the low 32 bits of `value` to the even bits of the result.
+fn spread(value: u64) -> u64 {
+    let mut value = value & 0xffff_ffff;
+    value = (value | (value << 16)) & 0x0000_ffff_0000_ffff;
+    value = (value | (value << 8)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    (value | (value << 1)) & 0x5555_5555_5555_5555
+}
+
+// Inverse of `spread`.
+fn squash(value: u64) -> u64 {
+    let mut value = value & 0x5555_5555_5555_5555;
+    value = (value | (value >> 1)) & 0x3333_3333_3333_3333;
+    value = (value | (value >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value >> 4)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value >> 8)) & 0x0000_ffff_0000_ffff;
+    (value | (value >> 16)) & 0xffff_ffff
+}
+
+// Cell of the coordinates among 2^step by 2^step, as (latitude, longitude) indexes.
+fn cell(longitude: f64, latitude: f64, step: u32) -> (u64, u64) {
+    let cells = (1u64 << step) as f64;
+    let index = |value: f64, min: f64, max: f64| {
+        (((value - min) / (max - min)) * cells).clamp(0.0, cells - 1.0) as u64
+    };
+    (
+        index(latitude, LATITUDE_MIN, LATITUDE_MAX),
+        index(longitude, LONGITUDE_MIN, LONGITUDE_MAX),
+    )
+}
+
+// Interleaves the cell indexes, latitude in the even bits.
+fn interleave((latitude, longitude): (u64, u64)) -> u64 {
+    spread(latitude) | (spread(longitude) << 1)
+}
+
+/// Geohash of the coordinates, stored as the score of the member.
+fn encode(longitude: f64, latitude: f64) -> f64 {
+    interleave(cell(longitude, latitude, GEOHASH_STEP)) as f64
+}
+
+/// Center of the cell a geohash stands for, as (longitude, latitude).
+fn decode(score: f64) -> (f64, f64) {
+    let hash = score as u64;
+    let cells = (1u64 << GEOHASH_STEP) as f64;
+    let center = |index: u64, min: f64, max: f64| min + (index as f64 + 0.5) / cells * (max - min);
+
+    (
+        center(squash(hash >> 1), LONGITUDE_MIN, LONGITUDE_MAX),
+        center(squash(hash), LATITUDE_MIN, LATITUDE_MAX),
+    )
+}
+
+/// Great-circle distance in meters.
+fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (longitude1, latitude1) = (from.0.to_radians(), from.1.to_radians());
+    let (longitude2, latitude2) = (to.0.to_radians(), to.1.to_radians());
+    let u = ((latitude2 - latitude1) / 2.0).sin();
+    let v = ((longitude2 - longitude1) / 2.0).sin();
+    2.0 * EARTH_RADIUS_METERS
+        * (u * u + latitude1.cos() * latitude2.cos() * v * v)
+            .sqrt()
+            .asin()
+}
+
+// Distance from the center if the point is within the shape.
+fn distance_within(center: (f64, f64), shape: GeoShape, point: (f64, f64)) -> Option<f64> {
+    let meters = distance(center, point);
+    match shape {
+        GeoShape::Radius(radius) => (meters <= radius).then_some(meters),
+        GeoShape::Box { width, height } => {
+            let north_south = distance(center, (center.0, point.1));
+            let east_west = distance((center.0, point.1), point);
+            (north_south <= height / 2.0 && east_west <= width / 2.0).then_some(meters)
+        }
+    }
+}
+
+/// Score ranges, both ends inclusive, of the geohash cells that cover the shape: the cell of
+/// the center and its neighbors, at the finest step whose cells are at least as large as
+/// the shape reaches.
+fn search_ranges(center: (f64, f64), shape: GeoShape) -> Vec<(f64, f64)> {
+    let reach = match shape {
+        GeoShape::Radius(radius) => radius,
+        GeoShape::Box { width, height } => (width / 2.0).hypot(height / 2.0),
+    };
+
+    // Longitude cells narrow towards the poles, so size them at the latitude nearest a pole
+    // the shape reaches
+    let reach_degrees = (reach / EARTH_RADIUS_METERS).to_degrees();
+    let extreme_latitude = (center.1.abs() + reach_degrees).min(90.0);
+    let meters_per_degree = PI / 180.0 * EARTH_RADIUS_METERS;
+
+    let mut step = GEOHASH_STEP;
+    while step > 0 {
+        let cells = (1u64 << step) as f64;
+        let height = (LATITUDE_MAX - LATITUDE_MIN) / cells * meters_per_degree;
+        let width = (LONGITUDE_MAX - LONGITUDE_MIN) / cells
+            * meters_per_degree
+            * extreme_latitude.to_radians().cos();
+        if height >= reach && width >= reach {
+            break;
+        }
+        step -= 1;
+    }
+
+    if step == 0 {
+        return vec![(0.0, ((1u64 << (GEOHASH_STEP * 2)) - 1) as f64)];
+    }
+
+    let cells = 1i64 << step;
+    let (latitude, longitude) = cell(center.0, center.1, step);
+    let shift = (GEOHASH_STEP - step) * 2;
+
+    let mut hashes = vec![];
+    for latitude_offset in -1..=1 {
+        let latitude = latitude as i64 + latitude_offset;
+        if !(0..cells).contains(&latitude) {
+            continue;
+        }
+        for longitude_offset in -1..=1 {
+            let longitude = (longitude as i64 + longitude_offset).rem_euclid(cells);
+            hashes.push(interleave((latitude as u64, longitude as u64)));
+        }
+    }
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    hashes
+        .into_iter()
+        .map(|hash| {
+            let start = hash << shift;
+            let end = ((hash + 1) << shift) - 1;
+            (start as f64, end as f64)
+        })
+        .collect()
+}
+
+impl KVEngine {
+    /// Adds members at the given longitude and latitude, or moves existing ones.
+    /// Returns how many were added rather than moved.
+    ///
+    /// Positions are stored in a sorted set, as geohash scores, so sorted set commands
+    /// work on the key too.
+    pub fn geo_add(&self, key: &str, members: Vec<(String, f64, f64)>) -> KVResult<usize> {
+        let members = members
+            .into_iter()
+            .map(|(member, longitude, latitude)| {
+                check_coordinates(longitude, latitude)?;
+                Ok((member, encode(longitude, latitude)))
+            })
+            .collect::<KVResult<Vec<_>>>()?;
+
+        self.sorted_set_add(key, members)
+    }
+
+    /// Distance in meters between two members; `None` if either doesn't exist.
+    pub fn geo_dist(&self, key: &str, from: &str, to: &str) -> KVResult<Option<f64>> {
+        let meters = self.read_value(key, |value| {
+            let set = value.as_sorted_set()?;
+            Ok(set
+                .score(from)
+                .zip(set.score(to))
+                .map(|(from, to)| distance(decode(from), decode(to))))
+        })?;
+
+        Ok(meters.flatten())
+    }
+
+    /// Members within the shape around the given longitude and latitude, with their distance
+    /// from it. `count` caps how many are returned, the nearest or farthest if sorted.
+    pub fn geo_search(
+        &self,
+        key: &str,
+        longitude: f64,
+        latitude: f64,
+        shape: GeoShape,
+        sort: Option<GeoSort>,
+        count: Option<usize>,
+    ) -> KVResult<Vec<GeoMatch>> {
+        check_coordinates(longitude, latitude)?;
+        let dimensions = match shape {
+            GeoShape::Radius(radius) => [radius, radius],
+            GeoShape::Box { width, height } => [width, height],
+        };
+        if !dimensions
+            .iter()
+            .all(|meters| meters.is_finite() && *meters >= 0.0)
+        {
+            return Err(KVError::InvalidCoordinates);
+        }
+        let center = (longitude, latitude);
+        let ranges = search_ranges(center, shape);
+
+        let matches = self.read_value(key, |value| {
+            let set = value.as_sorted_set()?;
+
+            let mut matches = vec![];
+            for (min, max) in &ranges {
+                for (member, score) in set.range_by_score(*min, *max) {
+                    let point = decode(score);
+                    if let Some(distance) = distance_within(center, shape, point) {
+                        matches.push(GeoMatch {
+                            member,
+                            distance,
+                            longitude: point.0,
+                            latitude: point.1,
+                        });
+                    }
+                }
+            }
+            Ok(matches)
+        })?;
+
+        let mut matches = matches.unwrap_or_default();
+        match sort {
+            Some(GeoSort::Ascending) => {
+                matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            }
+            Some(GeoSort::Descending) => {
+                matches.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+            }
+            None => {}
+        }
+        if let Some(count) = count {
+            matches.truncate(count);
+        }
+
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Half the size of a cell at `GEOHASH_STEP`, in degrees
+    const HALF_CELL_LONGITUDE: f64 = (LONGITUDE_MAX - LONGITUDE_MIN) / (1u64 << 27) as f64;
+    const HALF_CELL_LATITUDE: f64 = (LATITUDE_MAX - LATITUDE_MIN) / (1u64 << 27) as f64;
+
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn between(&mut self, min: f64, max: f64) -> f64 {
+            min + (self.next() >> 11) as f64 / (1u64 << 53) as f64 * (max - min)
+        }
+    }
+
+    #[test]
+    fn spread_and_squash_are_inverse() {
+        let mut random = XorShift(0x9e37_79b9_7f4a_7c15);
+        for value in [0, 1, 0xffff_ffff]
+            .into_iter()
+            .chain((0..1000).map(|_| random.next()))
+        {
+            let value = value & 0xffff_ffff;
+            assert_eq!(squash(spread(value)), value);
+            assert_eq!(spread(value) & !0x5555_5555_5555_5555, 0);
+        }
+    }
+
+    #[test]
+    fn geohashes_decode_to_within_half_a_cell() {
+        let mut random = XorShift(0x2545_f491_4f6c_dd1d);
+        let corners = [
+            (LONGITUDE_MIN, LATITUDE_MIN),
+            (LONGITUDE_MIN, LATITUDE_MAX),
+            (LONGITUDE_MAX, LATITUDE_MIN),
+            (LONGITUDE_MAX, LATITUDE_MAX),
+            (0.0, 0.0),
+            (-0.000_001, -0.000_001),
+        ];
+        let points = corners.into_iter().chain((0..10_000).map(|_| {
+            (
+                random.between(LONGITUDE_MIN, LONGITUDE_MAX),
+                random.between(LATITUDE_MIN, LATITUDE_MAX),
+            )
+        }));
+
+        for (longitude, latitude) in points {
+            let score = encode(longitude, latitude);
+            assert!(score < (1u64 << (GEOHASH_STEP * 2)) as f64);
+            assert_eq!(score, score.trunc());
+
+            let (decoded_longitude, decoded_latitude) = decode(score);
+            assert!(
+                (decoded_longitude - longitude).abs() <= HALF_CELL_LONGITUDE + 1e-9
+                    && (decoded_latitude - latitude).abs() <= HALF_CELL_LATITUDE + 1e-9,
+                "({}, {}) decoded to ({}, {})",
+                longitude,
+                latitude,
+                decoded_longitude,
+                decoded_latitude
+            );
+            assert_eq!(encode(decoded_longitude, decoded_latitude), score);
+        }
+    }
+
+    // Checks `geo_search` against every member, for random centers and sizes in the area
+    fn check_search(area_longitude: (f64, f64), area_latitude: (f64, f64), seed: u64) {
+        let engine = KVEngine::new();
+        let mut random = XorShift(seed);
+        let random_point = |random: &mut XorShift| {
+            let longitude = random.between(area_longitude.0, area_longitude.1);
+            // Wrap areas given across the antimeridian, like (175, 185)
+            let longitude = if longitude > LONGITUDE_MAX {
+                longitude - 360.0
+            } else {
+                longitude
+            };
+            (longitude, random.between(area_latitude.0, area_latitude.1))
+        };
+
+        let members: Vec<(String, f64, f64)> = (0..2000)
+            .map(|i| {
+                let (longitude, latitude) = random_point(&mut random);
+                (format!("m{}", i), longitude, latitude)
+            })
+            .collect();
+        engine.geo_add("places", members.clone()).unwrap();
+
+        for _ in 0..200 {
+            let center = random_point(&mut random);
+            let shape = if random.next() & 1 == 0 {
+                GeoShape::Radius(random.between(100.0, 300_000.0))
+            } else {
+                GeoShape::Box {
+                    width: random.between(100.0, 600_000.0),
+                    height: random.between(100.0, 600_000.0),
+                }
+            };
+
+            let mut expected: Vec<String> = members
+                .iter()
+                .filter(|(_, longitude, latitude)| {
+                    let point = decode(encode(*longitude, *latitude));
+                    distance_within(center, shape, point).is_some()
+                })
+                .map(|(member, _, _)| member.clone())
+                .collect();
+            expected.sort();
+
+            let mut found: Vec<String> = engine
+                .geo_search("places", center.0, center.1, shape, None, None)
+                .unwrap()
+                .into_iter()
+                .map(|found| found.member)
+                .collect();
+            found.sort();
+
+            assert_eq!(found, expected, "{:?} around {:?}", shape, center);
+        }
+    }
+
+    #[test]
+    fn searches_across_the_antimeridian() {
+        check_search((178.0, 182.0), (-2.0, 2.0), 1);
+    }
+
+    #[test]
+    fn searches_near_the_latitude_bounds() {
+        check_search((-20.0, 20.0), (82.0, LATITUDE_MAX), 2);
+        check_search((-20.0, 20.0), (LATITUDE_MIN, -82.0), 3);
+        check_search((170.0, 190.0), (83.0, LATITUDE_MAX), 4);
+    }
+
+    #[test]
+    fn searches_match_nearby_members_on_both_sides_of_the_antimeridian() {
+        let engine = KVEngine::new();
+        engine
+            .geo_add(
+                "places",
+                vec![
+                    ("east".into(), 179.9999, 0.0),
+                    ("west".into(), -179.9999, 0.0),
+                    ("far".into(), 179.0, 0.0),
+                ],
+            )
+            .unwrap();
+
+        let found = engine
+            .geo_search(
+                "places",
+                179.9995,
+                0.0,
+                GeoShape::Radius(200.0),
+                Some(GeoSort::Ascending),
+                None,
+            )
+            .unwrap();
+        let members: Vec<&str> = found.iter().map(|found| found.member.as_str()).collect();
+        assert_eq!(members, ["east", "west"]);
+        assert!(found[1].distance > 60.0 && found[1].distance < 70.0);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult,
+    counter::{MAX_INTEGER_LEN, parse_integer},
+    value::{KIND_HASH, Mutation, Value, hash_field_size},
+};
+
+// Mutation tags, as logged
+const OP_SET: u32 = 0;
+const OP_DELETE: u32 = 1;
+
+/// A write to a hash, see `Mutation`.
+pub(super) enum HashOp {
+    Set { fields: Vec<(String, Vec<u8>)> },
+    Delete { fields: Vec<String> },
+}
+
+impl Mutation for HashOp {
+    const KIND: u32 = KIND_HASH;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            HashOp::Set { fields } => {
+                OP_SET.write_field(buffer);
+                for (field, value) in fields {
+                    field.write_field(buffer);
+                    value.write_field(buffer);
+                }
+            }
+            HashOp::Delete { fields } => {
+                OP_DELETE.write_field(buffer);
+                for field in fields {
+                    field.write_field(buffer);
+                }
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, mut rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_SET => {
+                let mut fields = vec![];
+                while !rest.is_empty() {
+                    let (field, next) = String::read_field(rest).ok()?;
+                    let (value, next) = Vec::<u8>::read_field(next).ok()?;
+                    fields.push((field, value));
+                    rest = next;
+                }
+                Some(HashOp::Set { fields })
+            }
+            OP_DELETE => {
+                let mut fields = vec![];
+                while !rest.is_empty() {
+                    let (field, next) = String::read_field(rest).ok()?;
+                    fields.push(field);
+                    rest = next;
+                }
+                Some(HashOp::Delete { fields })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, HashOp::Set { .. }).then(|| Value::Hash(HashMap::new()))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::Hash(hash) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            HashOp::Set { fields } => {
+                for (field, value) in fields {
+                    *size += hash_field_size(&field, &value);
+                    if let Some(previous) = hash.get(&field) {
+                        *size -= hash_field_size(&field, previous);
+                    }
+                    hash.insert(field, value);
+                }
+            }
+            HashOp::Delete { fields } => {
+                for field in fields {
+                    if let Some(previous) = hash.remove(&field) {
+                        *size -= hash_field_size(&field, &previous);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl KVEngine {
+    /// Sets the fields, creating the hash if the key doesn't exist.
+    /// Returns how many fields were added rather than overwritten.
+    pub fn hash_set(&self, key: &str, fields: Vec<(String, Vec<u8>)>) -> KVResult<usize> {
+        let growth = fields
+            .iter()
+            .map(|(field, value)| hash_field_size(field, value))
+            .sum();
+
+        self.mutate_value(key, growth, |current| {
+            let hash = current.map(Value::as_hash).transpose()?;
+            if fields.is_empty() {
+                return Ok((None, 0));
+            }
+
+            let added = fields
+                .iter()
+                .map(|(field, _)| field)
+                .filter(|field| hash.is_none_or(|hash| !hash.contains_key(*field)))
+                .collect::<HashSet<_>>()
+                .len();
+
+            Ok((Some(HashOp::Set { fields }), added))
+        })
+    }
+
+    /// Value of the field; `None` if the field or the key doesn't exist.
+    pub fn hash_get(&self, key: &str, field: &str) -> KVResult<Option<Vec<u8>>> {
+        let value = self.read_value(key, |value| Ok(value.as_hash()?.get(field).cloned()))?;
+        Ok(value.flatten())
+    }
+
+    /// Values of several fields, in order; `None` for fields that don't exist.
+    pub fn hash_mget(&self, key: &str, fields: &[String]) -> KVResult<Vec<Option<Vec<u8>>>> {
+        let values = self.read_value(key, |value| {
+            let hash = value.as_hash()?;
+            Ok(fields
+                .iter()
+                .map(|field| hash.get(field).cloned())
+                .collect())
+        })?;
+
+        Ok(values.unwrap_or_else(|| vec![None; fields.len()]))
+    }
+
+    /// Every field and value, in no particular order.
+    pub fn hash_get_all(&self, key: &str) -> KVResult<Vec<(String, Vec<u8>)>> {
+        let entries = self.read_value(key, |value| {
+            Ok(value
+                .as_hash()?
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect())
+        })?;
+
+        Ok(entries.unwrap_or_default())
+    }
+
+    /// Removes the fields. Returns how many existed; the key is deleted once the hash is empty.
+    pub fn hash_delete(&self, key: &str, fields: &[String]) -> KVResult<usize> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Ok((None, 0));
+            };
+            let hash = value.as_hash()?;
+
+            let removed = fields
+                .iter()
+                .filter(|field| hash.contains_key(field.as_str()))
+                .count();
+            if removed == 0 {
+                return Ok((None, 0));
+            }
+
+            let fields = fields.to_vec();
+            Ok((Some(HashOp::Delete { fields }), removed))
+        })
+    }
+
+    /// Adds `delta` to the integer stored in the field and returns the new value.
+    /// A missing field counts as 0.
+    pub fn hash_incr_by(&self, key: &str, field: &str, delta: i64) -> KVResult<i64> {
+        let growth = hash_field_size(field, &[]) + MAX_INTEGER_LEN;
+
+        self.mutate_value(key, growth, |current| {
+            let hash = current.map(Value::as_hash).transpose()?;
+
+            let value = match hash.and_then(|hash| hash.get(field)) {
+                Some(value) => parse_integer(value)?,
+                None => 0,
+            };
+            let value = value.checked_add(delta).ok_or(KVError::NotAnInteger)?;
+
+            let fields = vec![(field.to_owned(), value.to_string().into_bytes())];
+            Ok((Some(HashOp::Set { fields }), value))
+        })
+    }
+
+    pub fn hash_exists(&self, key: &str, field: &str) -> KVResult<bool> {
+        let exists = self.read_value(key, |value| Ok(value.as_hash()?.contains_key(field)))?;
+        Ok(exists.unwrap_or(false))
+    }
+
+    /// Number of fields, 0 if the key doesn't exist.
+    pub fn hash_len(&self, key: &str) -> KVResult<usize> {
+        let length = self.read_value(key, |value| Ok(value.as_hash()?.len()))?;
+        Ok(length.unwrap_or(0))
+    }
+}
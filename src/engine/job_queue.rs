@@ -0,0 +1,805 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult, now_unix_millis,
+    value::{KIND_JOB_QUEUE, Mutation, Value},
+};
+
+// The timer wheel has this many slots of this many milliseconds each, so one turn
+// covers about five seconds; later timers stay in their slot for more turns
+const WHEEL_SLOTS: u64 = 512;
+const WHEEL_TICK_MILLIS: u64 = 10;
+// Approximate bookkeeping cost of a job besides its payload: the map node and timer entry
+const JOB_OVERHEAD: usize = 96;
+// Approximate bookkeeping cost of a queue besides its jobs: the slots of the wheel
+const JOB_QUEUE_OVERHEAD: usize = WHEEL_SLOTS as usize * 24;
+
+// State of a job as stored in snapshots and the write-ahead log
+const STATE_DELAYED: u32 = 0;
+const STATE_READY: u32 = 1;
+const STATE_IN_FLIGHT: u32 = 2;
+const STATE_DEAD: u32 = 3;
+
+// Mutation tags, as logged
+const OP_ENQUEUE: u32 = 0;
+const OP_DELIVER: u32 = 1;
+const OP_ACK: u32 = 2;
+const OP_NACK: u32 = 3;
+const OP_TAKE_DEAD: u32 = 4;
+
+/// A job handed out by `KVEngine::job_dequeue` or `KVEngine::job_dead_letters`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedJob {
+    pub id: u64,
+    pub payload: Vec<u8>,
+    /// Deliveries so far, including this one. Also the receipt of this delivery: acking or
+    /// nacking takes it, so a consumer whose delivery timed out can't settle a later one.
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    // Becomes ready at this unix millis time
+    Delayed(u64),
+    Ready,
+    // Handed out; delivered again once this unix millis time passes without an ack
+    InFlight(u64),
+    Dead,
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+    payload: Vec<u8>,
+    attempts: u32,
+    // Dead-lettered once a delivery fails after this many attempts; 0 never
+    max_attempts: u32,
+    state: JobState,
+}
+
+impl Job {
+    fn exhausted(&self) -> bool {
+        self.max_attempts > 0 && self.attempts >= self.max_attempts
+    }
+
+    /// Whether the job is handed out, in the delivery with this many attempts.
+    fn delivered_as(&self, attempts: u32) -> bool {
+        matches!(self.state, JobState::InFlight(_)) && self.attempts == attempts
+    }
+
+    fn memory_size(&self) -> usize {
+        self.payload.len() + JOB_OVERHEAD
+    }
+
+    fn to_queued(&self, id: u64) -> QueuedJob {
+        QueuedJob {
+            id,
+            payload: self.payload.clone(),
+            attempts: self.attempts,
+        }
+    }
+}
+
+/// A hashed timing wheel: a timer goes in the slot of its tick, modulo the number of slots,
+/// so advancing only looks at the slots of the ticks passed, however many timers are
+/// pending. Timers due on a later turn just stay in their slot.
+///
+/// Timers aren't removed when a job moves on; a timer that fires is checked against the
+/// job's state instead.
+#[derive(Debug, Clone)]
+struct TimerWheel {
+    // (due unix millis, job ID)
+    slots: Vec<Vec<(u64, u64)>>,
+    // Tick the wheel was last advanced to. Its slot is checked again on the next advance,
+    // as it may hold timers due later within the tick
+    cursor: u64,
+}
+
+impl TimerWheel {
+    fn new(now: u64) -> Self {
+        TimerWheel {
+            slots: vec![vec![]; WHEEL_SLOTS as usize],
+            cursor: now / WHEEL_TICK_MILLIS,
+        }
+    }
+
+    fn insert(&mut self, due: u64, id: u64) {
+        // Timers already due go where the next advance looks first
+        let tick = (due / WHEEL_TICK_MILLIS).max(self.cursor);
+        self.slots[(tick % WHEEL_SLOTS) as usize].push((due, id));
+    }
+
+    // Ticks whose slots may hold timers due by `now`. After a whole turn every slot has
+    // been looked at
+    fn ticks(&self, now: u64) -> std::ops::Range<u64> {
+        let ticks = ((now / WHEEL_TICK_MILLIS).saturating_sub(self.cursor) + 1).min(WHEEL_SLOTS);
+        self.cursor..self.cursor + ticks
+    }
+
+    /// The timers due by `now`, earliest first, without removing them.
+    fn due(&self, now: u64) -> Vec<(u64, u64)> {
+        let mut due: Vec<(u64, u64)> = self
+            .ticks(now)
+            .flat_map(|tick| &self.slots[(tick % WHEEL_SLOTS) as usize])
+            .filter(|(due, _)| *due <= now)
+            .copied()
+            .collect();
+        due.sort_unstable();
+        due
+    }
+
+    /// Removes the timers due by `now`, earliest first.
+    fn advance(&mut self, now: u64) -> Vec<(u64, u64)> {
+        let now_tick = now / WHEEL_TICK_MILLIS;
+
+        let mut fired = vec![];
+        for tick in self.ticks(now) {
+            let slot = &mut self.slots[(tick % WHEEL_SLOTS) as usize];
+            slot.retain(|&(due, id)| {
+                if due > now {
+                    return true;
+                }
+                fired.push((due, id));
+                false
+            });
+        }
+        self.cursor = self.cursor.max(now_tick);
+
+        fired.sort_unstable();
+        fired
+    }
+}
+
+/// Jobs that become ready at a given time, are handed out for a while to be acked, and
+/// are dead-lettered after failing too often.
+#[derive(Debug, Clone)]
+pub(super) struct JobQueue {
+    jobs: BTreeMap<u64, Job>,
+    // Ready jobs in the order they are handed out
+    ready: VecDeque<u64>,
+    // Dead-lettered jobs, oldest first
+    dead: VecDeque<u64>,
+    // Delayed and in-flight jobs by when they are due
+    timers: TimerWheel,
+}
+
+impl JobQueue {
+    fn new(now: u64) -> Self {
+        JobQueue {
+            jobs: BTreeMap::new(),
+            ready: VecDeque::new(),
+            dead: VecDeque::new(),
+            timers: TimerWheel::new(now),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    // Puts the job in `state` and wherever that state is tracked.
+    fn place(&mut self, id: u64, state: JobState) {
+        let Some(job) = self.jobs.get_mut(&id) else {
+            return;
+        };
+        job.state = state;
+
+        match state {
+            JobState::Delayed(due) | JobState::InFlight(due) => self.timers.insert(due, id),
+            JobState::Ready => self.ready.push_back(id),
+            JobState::Dead => self.dead.push_back(id),
+        }
+    }
+
+    // A delivery failed: try again after `delay`, unless the job is out of attempts.
+    fn retry(&mut self, id: u64, delay: u64, now: u64) {
+        let Some(job) = self.jobs.get(&id) else {
+            return;
+        };
+
+        let state = if job.exhausted() {
+            JobState::Dead
+        } else if delay > 0 {
+            JobState::Delayed(now + delay)
+        } else {
+            JobState::Ready
+        };
+        self.place(id, state);
+    }
+
+    /// Makes delayed jobs due by `now` ready, and redelivers jobs whose visibility
+    /// timeout passed.
+    fn advance(&mut self, now: u64) {
+        for (due, id) in self.timers.advance(now) {
+            match self.jobs.get(&id).map(|job| job.state) {
+                Some(JobState::Delayed(ready_at)) if ready_at == due => {
+                    self.place(id, JobState::Ready)
+                }
+                Some(JobState::InFlight(deadline)) if deadline == due => self.retry(id, 0, now),
+                // Acked, or moved on since the timer was set
+                _ => {}
+            }
+        }
+    }
+
+    /// Jobs that `advance(now)` would make ready and dead-letter, in the order it would,
+    /// without advancing.
+    fn due(&self, now: u64) -> (Vec<u64>, Vec<u64>) {
+        let mut due = self.timers.due(now);
+        // A job may have several timers due at once; only one matches its state
+        due.dedup();
+
+        let (mut ready, mut dead) = (vec![], vec![]);
+        for (due, id) in due {
+            let Some(job) = self.jobs.get(&id) else {
+                continue;
+            };
+            match job.state {
+                JobState::Delayed(ready_at) if ready_at == due => ready.push(id),
+                JobState::InFlight(deadline) if deadline == due && job.exhausted() => dead.push(id),
+                JobState::InFlight(deadline) if deadline == due => ready.push(id),
+                _ => {}
+            }
+        }
+        (ready, dead)
+    }
+
+    /// First `count` jobs `deliver(now, ...)` would hand out.
+    fn next_ready(&self, now: u64, count: usize) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.ready.iter().copied().take(count).collect();
+        if ids.len() < count {
+            ids.extend(self.due(now).0.into_iter().take(count - ids.len()));
+        }
+        ids
+    }
+
+    /// First `count` jobs `take_dead(now, ...)` would remove.
+    fn next_dead(&self, now: u64, count: usize) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.dead.iter().copied().take(count).collect();
+        if ids.len() < count {
+            ids.extend(self.due(now).1.into_iter().take(count - ids.len()));
+        }
+        ids
+    }
+
+    /// Advances to `now`, then hands out the first `count` ready jobs until `deadline`.
+    fn deliver(&mut self, now: u64, count: usize, deadline: u64) {
+        self.advance(now);
+
+        for _ in 0..count {
+            let Some(id) = self.ready.pop_front() else {
+                break;
+            };
+            if let Some(job) = self.jobs.get_mut(&id) {
+                job.attempts = job.attempts.saturating_add(1);
+                self.place(id, JobState::InFlight(deadline));
+            }
+        }
+    }
+
+    /// Advances to `now`, then removes the first `count` dead-lettered jobs.
+    /// Returns the bytes they took.
+    fn take_dead(&mut self, now: u64, count: usize) -> usize {
+        self.advance(now);
+
+        let mut freed = 0;
+        for _ in 0..count {
+            let Some(id) = self.dead.pop_front() else {
+                break;
+            };
+            if let Some(job) = self.jobs.remove(&id) {
+                freed += job.memory_size();
+            }
+        }
+        freed
+    }
+
+    pub fn memory_size(&self) -> usize {
+        JOB_QUEUE_OVERHEAD + self.jobs.values().map(Job::memory_size).sum::<usize>()
+    }
+
+    /// Ready and dead jobs first, in order, so decoding restores their order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        let ordered = self.ready.iter().chain(&self.dead);
+        let timed = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| matches!(job.state, JobState::Delayed(_) | JobState::InFlight(_)))
+            .map(|(id, _)| id);
+
+        for id in ordered.chain(timed) {
+            let job = &self.jobs[id];
+            let (state, due) = match job.state {
+                JobState::Delayed(due) => (STATE_DELAYED, due),
+                JobState::Ready => (STATE_READY, 0),
+                JobState::InFlight(due) => (STATE_IN_FLIGHT, due),
+                JobState::Dead => (STATE_DEAD, 0),
+            };
+
+            id.write_field(&mut buffer);
+            job.payload.write_field(&mut buffer);
+            job.attempts.write_field(&mut buffer);
+            job.max_attempts.write_field(&mut buffer);
+            state.write_field(&mut buffer);
+            due.write_field(&mut buffer);
+        }
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<JobQueue> {
+        let mut queue = JobQueue::new(now_unix_millis());
+        let mut buffer = bytes;
+
+        while !buffer.is_empty() {
+            let (id, rest) = u64::read_field(buffer).ok()?;
+            let (payload, rest) = Vec::<u8>::read_field(rest).ok()?;
+            let (attempts, rest) = u32::read_field(rest).ok()?;
+            let (max_attempts, rest) = u32::read_field(rest).ok()?;
+            let (state, rest) = u32::read_field(rest).ok()?;
+            let (due, rest) = u64::read_field(rest).ok()?;
+            let state = match state {
+                STATE_DELAYED => JobState::Delayed(due),
+                STATE_READY => JobState::Ready,
+                STATE_IN_FLIGHT => JobState::InFlight(due),
+                STATE_DEAD => JobState::Dead,
+                _ => return None,
+            };
+
+            let job = Job {
+                payload,
+                attempts,
+                max_attempts,
+                state,
+            };
+            if queue.jobs.insert(id, job).is_some() {
+                return None;
+            }
+            queue.place(id, state);
+            buffer = rest;
+        }
+
+        Some(queue)
+    }
+}
+
+/// A write to a job queue, see `Mutation`. Writes that advance the queue carry the time
+/// they did so at, so replaying them makes the same jobs ready and dead-letters the same.
+pub(super) enum JobOp {
+    /// Adds a job that becomes ready at `ready_at`, or right away if it's 0
+    Enqueue {
+        id: u64,
+        payload: Vec<u8>,
+        max_attempts: u32,
+        ready_at: u64,
+    },
+    /// See `JobQueue::deliver`
+    Deliver {
+        now: u64,
+        count: usize,
+        deadline: u64,
+    },
+    /// Removes the job, handed out with this many attempts
+    Ack { id: u64, attempts: u32 },
+    /// Gives the job, handed out with this many attempts, back at `now`, to be retried
+    /// after `delay`
+    Nack {
+        id: u64,
+        attempts: u32,
+        now: u64,
+        delay: u64,
+    },
+    /// See `JobQueue::take_dead`
+    TakeDead { now: u64, count: usize },
+}
+
+impl Mutation for JobOp {
+    const KIND: u32 = KIND_JOB_QUEUE;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            JobOp::Enqueue {
+                id,
+                payload,
+                max_attempts,
+                ready_at,
+            } => {
+                OP_ENQUEUE.write_field(buffer);
+                id.write_field(buffer);
+                payload.write_field(buffer);
+                max_attempts.write_field(buffer);
+                ready_at.write_field(buffer);
+            }
+            JobOp::Deliver {
+                now,
+                count,
+                deadline,
+            } => {
+                OP_DELIVER.write_field(buffer);
+                now.write_field(buffer);
+                (*count as u64).write_field(buffer);
+                deadline.write_field(buffer);
+            }
+            JobOp::Ack { id, attempts } => {
+                OP_ACK.write_field(buffer);
+                id.write_field(buffer);
+                attempts.write_field(buffer);
+            }
+            JobOp::Nack {
+                id,
+                attempts,
+                now,
+                delay,
+            } => {
+                OP_NACK.write_field(buffer);
+                id.write_field(buffer);
+                attempts.write_field(buffer);
+                now.write_field(buffer);
+                delay.write_field(buffer);
+            }
+            JobOp::TakeDead { now, count } => {
+                OP_TAKE_DEAD.write_field(buffer);
+                now.write_field(buffer);
+                (*count as u64).write_field(buffer);
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_ENQUEUE => {
+                let (id, rest) = u64::read_field(rest).ok()?;
+                let (payload, rest) = Vec::<u8>::read_field(rest).ok()?;
+                let (max_attempts, rest) = u32::read_field(rest).ok()?;
+                let (ready_at, _) = u64::read_field(rest).ok()?;
+                Some(JobOp::Enqueue {
+                    id,
+                    payload,
+                    max_attempts,
+                    ready_at,
+                })
+            }
+            OP_DELIVER => {
+                let (now, rest) = u64::read_field(rest).ok()?;
+                let (count, rest) = u64::read_field(rest).ok()?;
+                let (deadline, _) = u64::read_field(rest).ok()?;
+                Some(JobOp::Deliver {
+                    now,
+                    count: count as usize,
+                    deadline,
+                })
+            }
+            OP_ACK => {
+                let (id, rest) = u64::read_field(rest).ok()?;
+                let (attempts, _) = u32::read_field(rest).ok()?;
+                Some(JobOp::Ack { id, attempts })
+            }
+            OP_NACK => {
+                let (id, rest) = u64::read_field(rest).ok()?;
+                let (attempts, rest) = u32::read_field(rest).ok()?;
+                let (now, rest) = u64::read_field(rest).ok()?;
+                let (delay, _) = u64::read_field(rest).ok()?;
+                Some(JobOp::Nack {
+                    id,
+                    attempts,
+                    now,
+                    delay,
+                })
+            }
+            OP_TAKE_DEAD => {
+                let (now, rest) = u64::read_field(rest).ok()?;
+                let (count, _) = u64::read_field(rest).ok()?;
+                Some(JobOp::TakeDead {
+                    now,
+                    count: count as usize,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, JobOp::Enqueue { .. })
+            .then(|| Value::JobQueue(JobQueue::new(now_unix_millis())))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::JobQueue(queue) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            JobOp::Enqueue {
+                id,
+                payload,
+                max_attempts,
+                ready_at,
+            } => {
+                let state = if ready_at > 0 {
+                    JobState::Delayed(ready_at)
+                } else {
+                    JobState::Ready
+                };
+                let job = Job {
+                    payload,
+                    attempts: 0,
+                    max_attempts,
+                    state,
+                };
+                *size += job.memory_size();
+                if let Some(previous) = queue.jobs.insert(id, job) {
+                    *size -= previous.memory_size();
+                }
+                queue.place(id, state);
+            }
+            JobOp::Deliver {
+                now,
+                count,
+                deadline,
+            } => queue.deliver(now, count, deadline),
+            JobOp::Ack { id, attempts } => {
+                if queue
+                    .jobs
+                    .get(&id)
+                    .is_some_and(|job| job.delivered_as(attempts))
+                {
+                    let job = queue.jobs.remove(&id).expect("job was just found");
+                    *size -= job.memory_size();
+                }
+            }
+            JobOp::Nack {
+                id,
+                attempts,
+                now,
+                delay,
+            } => {
+                if queue
+                    .jobs
+                    .get(&id)
+                    .is_some_and(|job| job.delivered_as(attempts))
+                {
+                    queue.retry(id, delay, now);
+                }
+            }
+            JobOp::TakeDead { now, count } => *size -= queue.take_dead(now, count),
+        }
+
+        Ok(())
+    }
+}
+
+impl KVEngine {
+    /// Adds a job that becomes ready after `delay`. Once a delivery of it fails
+    /// `max_attempts` times, 0 meaning never, it is dead-lettered. Returns its ID.
+    pub fn job_enqueue(
+        &self,
+        key: &str,
+        payload: Vec<u8>,
+        delay: Duration,
+        max_attempts: u32,
+    ) -> KVResult<u64> {
+        let growth = payload.len() + JOB_OVERHEAD + JOB_QUEUE_OVERHEAD;
+        let delay = delay.as_millis() as u64;
+
+        self.mutate_value(key, growth, |current| {
+            current.map(Value::as_job_queue).transpose()?;
+
+            // IDs come from the version counter, so they aren't reused once the queue
+            // is emptied and deleted
+            let id = self.next_version();
+            let ready_at = if delay > 0 {
+                now_unix_millis() + delay
+            } else {
+                0
+            };
+            let enqueue = JobOp::Enqueue {
+                id,
+                payload,
+                max_attempts,
+                ready_at,
+            };
+            Ok((Some(enqueue), id))
+        })
+    }
+
+    /// Hands out up to `count` ready jobs, oldest first. Each is hidden for `visibility`,
+    /// then delivered again unless acked or nacked by then.
+    pub fn job_dequeue(
+        &self,
+        key: &str,
+        visibility: Duration,
+        count: usize,
+    ) -> KVResult<Vec<QueuedJob>> {
+        let visibility = visibility.as_millis() as u64;
+
+        self.mutate_value(key, 0, |current| {
+            let Some(current) = current else {
+                return Ok((None, vec![]));
+            };
+            let queue = current.as_job_queue()?;
+            let now = now_unix_millis();
+
+            let ids = queue.next_ready(now, count);
+            let jobs: Vec<QueuedJob> = ids
+                .iter()
+                .filter_map(|&id| {
+                    let job = queue.jobs.get(&id)?;
+                    let mut queued = job.to_queued(id);
+                    queued.attempts = job.attempts.saturating_add(1);
+                    Some(queued)
+                })
+                .collect();
+
+            // Advancing alone changes nothing the next call wouldn't redo
+            if jobs.is_empty() {
+                return Ok((None, jobs));
+            }
+            let deliver = JobOp::Deliver {
+                now,
+                count: ids.len(),
+                deadline: now + visibility,
+            };
+            Ok((Some(deliver), jobs))
+        })
+    }
+
+    /// Marks a handed out job done, removing it. `attempts` is the receipt of the delivery
+    /// being acked, see `QueuedJob::attempts`. Returns whether the job was handed out in
+    /// that delivery; false if it was acked already, or handed out again since.
+    pub fn job_ack(&self, key: &str, id: u64, attempts: u32) -> KVResult<bool> {
+        self.mutate_value(key, 0, |current| {
+            let Some(current) = current else {
+                return Ok((None, false));
+            };
+            let queue = current.as_job_queue()?;
+            if !queue
+                .jobs
+                .get(&id)
+                .is_some_and(|job| job.delivered_as(attempts))
+            {
+                return Ok((None, false));
+            }
+
+            Ok((Some(JobOp::Ack { id, attempts }), true))
+        })
+    }
+
+    /// Gives a handed out job back, to be delivered again after `delay`, or dead-lettered
+    /// if it is out of attempts. `attempts` is the receipt of the delivery, as for
+    /// `job_ack`. Returns whether the job was handed out in that delivery.
+    pub fn job_nack(&self, key: &str, id: u64, attempts: u32, delay: Duration) -> KVResult<bool> {
+        self.mutate_value(key, 0, |current| {
+            let Some(current) = current else {
+                return Ok((None, false));
+            };
+            let queue = current.as_job_queue()?;
+            if !queue
+                .jobs
+                .get(&id)
+                .is_some_and(|job| job.delivered_as(attempts))
+            {
+                return Ok((None, false));
+            }
+
+            let nack = JobOp::Nack {
+                id,
+                attempts,
+                now: now_unix_millis(),
+                delay: delay.as_millis() as u64,
+            };
+            Ok((Some(nack), true))
+        })
+    }
+
+    /// Removes and returns up to `count` dead-lettered jobs, oldest first, including those
+    /// whose last visibility timeout just passed.
+    pub fn job_dead_letters(&self, key: &str, count: usize) -> KVResult<Vec<QueuedJob>> {
+        self.mutate_value(key, 0, |current| {
+            let Some(current) = current else {
+                return Ok((None, vec![]));
+            };
+            let queue = current.as_job_queue()?;
+            let now = now_unix_millis();
+
+            let ids = queue.next_dead(now, count);
+            let jobs: Vec<QueuedJob> = ids
+                .iter()
+                .filter_map(|&id| Some(queue.jobs.get(&id)?.to_queued(id)))
+                .collect();
+
+            if jobs.is_empty() {
+                return Ok((None, jobs));
+            }
+            let take = JobOp::TakeDead {
+                now,
+                count: ids.len(),
+            };
+            Ok((Some(take), jobs))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TURN: u64 = WHEEL_SLOTS * WHEEL_TICK_MILLIS;
+
+    fn advance(wheel: &mut TimerWheel, now: u64) -> Vec<(u64, u64)> {
+        let due = wheel.due(now);
+        let fired = wheel.advance(now);
+        assert_eq!(due, fired);
+        fired
+    }
+
+    #[test]
+    fn timers_sharing_a_slot_fire_on_their_own_turn() {
+        let start = 1_000_000 * TURN + 3;
+        let mut wheel = TimerWheel::new(start);
+
+        // All three land in the slot of the starting tick
+        wheel.insert(start + 2, 1);
+        wheel.insert(start + TURN + 2, 2);
+        wheel.insert(start + 2 * TURN + 2, 3);
+
+        assert_eq!(advance(&mut wheel, start + 1), []);
+        assert_eq!(advance(&mut wheel, start + 2), [(start + 2, 1)]);
+        assert_eq!(advance(&mut wheel, start + TURN + 1), []);
+        assert_eq!(
+            advance(&mut wheel, start + TURN + 2),
+            [(start + TURN + 2, 2)]
+        );
+        assert_eq!(
+            advance(&mut wheel, start + 2 * TURN + 2),
+            [(start + 2 * TURN + 2, 3)]
+        );
+    }
+
+    #[test]
+    fn advancing_past_whole_turns_fires_everything_due() {
+        let start = 7 * TURN - 25;
+        let mut wheel = TimerWheel::new(start);
+
+        // Spread over the wrap of the slot indexes and several turns ahead
+        let timers: Vec<(u64, u64)> = (0..40).map(|id| (start + id * 397 + 1, id)).collect();
+        for &(due, id) in timers.iter().rev() {
+            wheel.insert(due, id);
+        }
+
+        let now = start + 3 * TURN + 10;
+        let expected: Vec<_> = timers
+            .iter()
+            .copied()
+            .filter(|(due, _)| *due <= now)
+            .collect();
+        assert_eq!(advance(&mut wheel, now), expected);
+
+        let rest: Vec<_> = timers
+            .iter()
+            .copied()
+            .filter(|(due, _)| *due > now)
+            .collect();
+        assert_eq!(advance(&mut wheel, u64::MAX / 2), rest);
+        assert!(wheel.slots.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn timers_already_due_fire_on_the_next_advance() {
+        let start = 5 * TURN;
+        let mut wheel = TimerWheel::new(start);
+        assert_eq!(advance(&mut wheel, start + 2 * TURN), []);
+
+        wheel.insert(start, 1);
+        wheel.insert(start + TURN, 2);
+        assert_eq!(
+            advance(&mut wheel, start + 2 * TURN),
+            [(start, 1), (start + TURN, 2)]
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use serde_json::{Number, Value as Json};
+
+use crate::protocol::{WireField, read_chunk, write_chunk};
+
+use super::{
+    KVEngine, KVError, KVResult,
+    value::{KIND_JSON, Mutation, Update, Value},
+};
+
+// Approximate bookkeeping cost of one node of a JSON document besides its strings
+const JSON_NODE_OVERHEAD: usize = 32;
+
+// Mutation tags, as logged
+const OP_SET: u32 = 0;
+const OP_DELETE: u32 = 1;
+const OP_APPEND: u32 = 2;
+
+// Path segment tags, as logged
+const SEGMENT_FIELD: u32 = 0;
+const SEGMENT_INDEX: u32 = 1;
+
+/// One step of a path into a JSON document.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Segment {
+    /// Member of an object
+    Field(String),
+    /// Element of an array; negative indexes count from the end
+    Index(i64),
+}
+
+/// Parses a path like `$.users[0].name` or `$["odd key"][-1]`. The leading `$` is optional
+/// and an empty path, like `$`, is the whole document.
+fn parse_path(path: &str) -> KVResult<Vec<Segment>> {
+    let path = path.trim();
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = vec![];
+
+    // Allow `a.b` as shorthand for `$.a.b`
+    if !rest.is_empty() && !rest.starts_with(['.', '[']) {
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        segments.push(Segment::Field(rest[..end].to_owned()));
+        rest = &rest[end..];
+    }
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return Err(KVError::InvalidJsonPath);
+            }
+            segments.push(Segment::Field(after[..end].to_owned()));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let after = after.trim_start();
+            // A quoted key ends at its closing quote, whatever brackets it holds
+            let (segment, after) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let key = &after[1..];
+                    let end = key.find(quote).ok_or(KVError::InvalidJsonPath)?;
+                    (Segment::Field(key[..end].to_owned()), &key[end + 1..])
+                }
+                _ => {
+                    let end = after.find(']').ok_or(KVError::InvalidJsonPath)?;
+                    let index = after[..end].trim_end();
+                    let index = index.parse().map_err(|_| KVError::InvalidJsonPath)?;
+                    (Segment::Index(index), &after[end..])
+                }
+            };
+            segments.push(segment);
+            rest = after
+                .trim_start()
+                .strip_prefix(']')
+                .ok_or(KVError::InvalidJsonPath)?;
+        } else {
+            return Err(KVError::InvalidJsonPath);
+        }
+    }
+
+    Ok(segments)
+}
+
+fn resolve_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+fn resolve<'a>(mut node: &'a Json, path: &[Segment]) -> Option<&'a Json> {
+    for segment in path {
+        node = match (segment, node) {
+            (Segment::Field(field), Json::Object(object)) => object.get(field)?,
+            (Segment::Index(index), Json::Array(array)) => {
+                &array[resolve_index(array.len(), *index)?]
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+fn resolve_mut<'a>(mut node: &'a mut Json, path: &[Segment]) -> Option<&'a mut Json> {
+    for segment in path {
+        node = match (segment, node) {
+            (Segment::Field(field), Json::Object(object)) => object.get_mut(field)?,
+            (Segment::Index(index), Json::Array(array)) => {
+                let index = resolve_index(array.len(), *index)?;
+                &mut array[index]
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// Bytes accounted to a JSON document.
+pub(super) fn json_memory_size(node: &Json) -> usize {
+    JSON_NODE_OVERHEAD
+        + match node {
+            Json::String(string) => string.len(),
+            Json::Array(array) => array.iter().map(json_memory_size).sum(),
+            Json::Object(object) => object
+                .iter()
+                .map(|(field, value)| field.len() + json_memory_size(value))
+                .sum(),
+            Json::Null | Json::Bool(_) | Json::Number(_) => 0,
+        }
+}
+
+fn write_path(path: &[Segment], buffer: &mut Vec<u8>) {
+    let mut chunk = vec![];
+    for segment in path {
+        match segment {
+            Segment::Field(field) => {
+                SEGMENT_FIELD.write_field(&mut chunk);
+                field.write_field(&mut chunk);
+            }
+            Segment::Index(index) => {
+                SEGMENT_INDEX.write_field(&mut chunk);
+                index.write_field(&mut chunk);
+            }
+        }
+    }
+    write_chunk(buffer, &chunk);
+}
+
+fn read_path(buffer: &[u8]) -> Option<(Vec<Segment>, &[u8])> {
+    let (mut chunk, rest) = read_chunk(buffer).ok()?;
+    let mut path = vec![];
+    while !chunk.is_empty() {
+        let (tag, next) = u32::read_field(chunk).ok()?;
+        let (segment, next) = match tag {
+            SEGMENT_FIELD => {
+                let (field, next) = String::read_field(next).ok()?;
+                (Segment::Field(field), next)
+            }
+            SEGMENT_INDEX => {
+                let (index, next) = i64::read_field(next).ok()?;
+                (Segment::Index(index), next)
+            }
+            _ => return None,
+        };
+        path.push(segment);
+        chunk = next;
+    }
+    Some((path, rest))
+}
+
+fn write_json(value: &Json, buffer: &mut Vec<u8>) {
+    write_chunk(buffer, value.to_string().as_bytes());
+}
+
+fn read_json(buffer: &[u8]) -> Option<(Json, &[u8])> {
+    let (chunk, rest) = read_chunk(buffer).ok()?;
+    Some((serde_json::from_slice(chunk).ok()?, rest))
+}
+
+/// A write to a JSON document, see `Mutation`. Paths are kept as given: applied to the
+/// same document they resolve to the same place.
+pub(super) enum JsonOp {
+    /// Stores the value at the path, see `KVEngine::json_set`
+    Set {
+        path: Vec<Segment>,
+        value: Json,
+    },
+    Delete {
+        path: Vec<Segment>,
+    },
+    /// Appends the values to the array at the path
+    Append {
+        path: Vec<Segment>,
+        values: Vec<Json>,
+    },
+}
+
+impl Mutation for JsonOp {
+    const KIND: u32 = KIND_JSON;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            JsonOp::Set { path, value } => {
+                OP_SET.write_field(buffer);
+                write_path(path, buffer);
+                write_json(value, buffer);
+            }
+            JsonOp::Delete { path } => {
+                OP_DELETE.write_field(buffer);
+                write_path(path, buffer);
+            }
+            JsonOp::Append { path, values } => {
+                OP_APPEND.write_field(buffer);
+                write_path(path, buffer);
+                for value in values {
+                    write_json(value, buffer);
+                }
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+        let (path, mut rest) = read_path(rest)?;
+
+        match tag {
+            OP_SET => {
+                let (value, _) = read_json(rest)?;
+                Some(JsonOp::Set { path, value })
+            }
+            OP_DELETE => Some(JsonOp::Delete { path }),
+            OP_APPEND => {
+                let mut values = vec![];
+                while !rest.is_empty() {
+                    let (value, next) = read_json(rest)?;
+                    values.push(value);
+                    rest = next;
+                }
+                Some(JsonOp::Append { path, values })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        match self {
+            JsonOp::Set { path, .. } if path.is_empty() => Some(Value::Json(Json::Null)),
+            _ => None,
+        }
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::Json(document) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            JsonOp::Set { path, value } => {
+                let Some((last, parent_path)) = path.split_last() else {
+                    *size = json_memory_size(&value);
+                    *document = value;
+                    return Ok(());
+                };
+
+                let parent = resolve_mut(document, parent_path).ok_or(KVError::JsonPathNotFound)?;
+                let added = json_memory_size(&value);
+                let removed = match (last, parent) {
+                    (Segment::Field(field), Json::Object(object)) => {
+                        *size += field.len() + added;
+                        object
+                            .insert(field.clone(), value)
+                            .map_or(0, |previous| field.len() + json_memory_size(&previous))
+                    }
+                    (Segment::Index(index), Json::Array(array)) => {
+                        let index =
+                            resolve_index(array.len(), *index).ok_or(KVError::JsonPathNotFound)?;
+                        *size += added;
+                        json_memory_size(&std::mem::replace(&mut array[index], value))
+                    }
+                    _ => return Err(KVError::JsonPathNotFound),
+                };
+                *size -= removed;
+            }
+            JsonOp::Delete { path } => {
+                let Some((last, parent_path)) = path.split_last() else {
+                    return Err(KVError::JsonPathNotFound);
+                };
+
+                match (last, resolve_mut(document, parent_path)) {
+                    (Segment::Field(field), Some(Json::Object(object))) => {
+                        if let Some(previous) = object.remove(field) {
+                            *size -= field.len() + json_memory_size(&previous);
+                        }
+                    }
+                    (Segment::Index(index), Some(Json::Array(array))) => {
+                        if let Some(index) = resolve_index(array.len(), *index) {
+                            *size -= json_memory_size(&array.remove(index));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            JsonOp::Append { path, values } => {
+                let Some(Json::Array(array)) = resolve_mut(document, &path) else {
+                    return Err(KVError::JsonPathNotFound);
+                };
+
+                *size += values.iter().map(json_memory_size).sum::<usize>();
+                array.extend(values);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Adds `delta`, keeping integers integers as long as the delta is whole.
+fn add_number(number: &Number, delta: f64) -> KVResult<Number> {
+    let whole = delta.fract() == 0.0 && delta.abs() < i64::MAX as f64;
+    let sum = number
+        .as_i64()
+        .filter(|_| whole)
+        .and_then(|integer| integer.checked_add(delta as i64));
+    if let Some(sum) = sum {
+        return Ok(sum.into());
+    }
+
+    let sum = number.as_f64().ok_or(KVError::NotAFloat)? + delta;
+    Number::from_f64(sum).ok_or(KVError::NotAFloat)
+}
+
+impl KVEngine {
+    /// The part of the document at `path`; `None` if nothing is there or the key
+    /// doesn't exist.
+    pub fn json_get(&self, key: &str, path: &str) -> KVResult<Option<Json>> {
+        let path = parse_path(path)?;
+
+        let value = self.read_value(key, |value| Ok(resolve(value.as_json()?, &path).cloned()))?;
+        Ok(value.flatten())
+    }
+
+    /// Stores `value` at `path`. The root path replaces the whole document and creates the key
+    /// if it doesn't exist; otherwise the parent must exist, and the value either becomes a
+    /// member of an object or replaces an element of an array.
+    pub fn json_set(&self, key: &str, path: &str, value: Json) -> KVResult<()> {
+        let path = parse_path(path)?;
+        let growth = json_memory_size(&value);
+
+        self.mutate_value(key, growth, |current| {
+            let document = current.map(Value::as_json).transpose()?;
+            let Some((last, parent_path)) = path.split_last() else {
+                return Ok((Some(JsonOp::Set { path, value }), ()));
+            };
+
+            let document = document.ok_or(KVError::JsonPathNotFound)?;
+            let parent = resolve(document, parent_path).ok_or(KVError::JsonPathNotFound)?;
+            let found = match (last, parent) {
+                (Segment::Field(_), Json::Object(_)) => true,
+                (Segment::Index(index), Json::Array(array)) => {
+                    resolve_index(array.len(), *index).is_some()
+                }
+                _ => false,
+            };
+            if !found {
+                return Err(KVError::JsonPathNotFound);
+            }
+
+            Ok((Some(JsonOp::Set { path, value }), ()))
+        })
+    }
+
+    /// Removes the part of the document at `path`; the root path deletes the key.
+    /// Returns whether anything was there.
+    pub fn json_del(&self, key: &str, path: &str) -> KVResult<bool> {
+        let path = parse_path(path)?;
+
+        if path.is_empty() {
+            return self.update_value(key, 0, |current| match current {
+                Some(current) => {
+                    current.as_json()?;
+                    Ok((Update::Remove, true))
+                }
+                None => Ok((Update::Keep, false)),
+            });
+        }
+
+        self.mutate_value(key, 0, |current| {
+            let Some(current) = current else {
+                return Ok((None, false));
+            };
+            if resolve(current.as_json()?, &path).is_none() {
+                return Ok((None, false));
+            }
+
+            Ok((Some(JsonOp::Delete { path }), true))
+        })
+    }
+
+    /// Appends the values to the array at `path`. Returns the new length of the array.
+    pub fn json_array_append(&self, key: &str, path: &str, values: Vec<Json>) -> KVResult<usize> {
+        let path = parse_path(path)?;
+        let growth = values.iter().map(json_memory_size).sum();
+
+        self.mutate_value(key, growth, |current| {
+            let document = match current {
+                Some(current) => current.as_json()?,
+                None => return Err(KVError::JsonPathNotFound),
+            };
+            let Some(Json::Array(array)) = resolve(document, &path) else {
+                return Err(KVError::JsonPathNotFound);
+            };
+
+            let length = array.len() + values.len();
+            Ok((Some(JsonOp::Append { path, values }), length))
+        })
+    }
+
+    /// Adds `delta` to the number at `path` and returns the new number. Integers stay
+    /// integers as long as `delta` is whole and the sum fits.
+    pub fn json_incr_by(&self, key: &str, path: &str, delta: f64) -> KVResult<Number> {
+        let path = parse_path(path)?;
+        if !delta.is_finite() {
+            return Err(KVError::NotAFloat);
+        }
+
+        // The resulting number is logged, so replaying gives the same number whatever the
+        // floating-point rounding
+        self.mutate_value(key, 0, |current| {
+            let document = match current {
+                Some(current) => current.as_json()?,
+                None => return Err(KVError::JsonPathNotFound),
+            };
+            let node = resolve(document, &path).ok_or(KVError::JsonPathNotFound)?;
+            let Json::Number(number) = node else {
+                return Err(KVError::NotAFloat);
+            };
+
+            let sum = add_number(number, delta)?;
+            let set = JsonOp::Set {
+                path,
+                value: Json::Number(sum.clone()),
+            };
+            Ok((Some(set), sum))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str) -> Segment {
+        Segment::Field(name.to_owned())
+    }
+
+    #[test]
+    fn parses_paths() {
+        let cases = [
+            ("$", vec![]),
+            ("", vec![]),
+            (" $ ", vec![]),
+            ("$.a.b", vec![field("a"), field("b")]),
+            ("a.b", vec![field("a"), field("b")]),
+            ("a[0]", vec![field("a"), Segment::Index(0)]),
+            (
+                "$.users[0].name",
+                vec![field("users"), Segment::Index(0), field("name")],
+            ),
+            ("$[-1]", vec![Segment::Index(-1)]),
+            ("$.a[ -2 ]", vec![field("a"), Segment::Index(-2)]),
+            (
+                "$[\"odd key\"][-1]",
+                vec![field("odd key"), Segment::Index(-1)],
+            ),
+            ("$['single']", vec![field("single")]),
+            ("$[\"a]b\"]", vec![field("a]b")]),
+            ("$['a[0]'].c", vec![field("a[0]"), field("c")]),
+            ("$[\"it's\"]", vec![field("it's")]),
+            ("$[ \"spaced\" ]", vec![field("spaced")]),
+            ("$[\"\"]", vec![field("")]),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(parse_path(path).unwrap(), expected, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        for path in [
+            "$.",
+            "$[",
+            "$..a",
+            "$.a.",
+            "$[]",
+            "$[a]",
+            "$[1.5]",
+            "$[0",
+            "$[\"a]",
+            "$[\"a\"",
+            "$[\"a\"x]",
+        ] {
+            assert!(
+                matches!(parse_path(path), Err(KVError::InvalidJsonPath)),
+                "{:?}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::broadcast;
+
+use super::{
+    KVResult,
+    events::{KeyEvent, KeyEventOp, emit},
+    value::{Mutation, Value},
+};
+
+// Approximate bookkeeping cost of one key: hash map slot, sampling slot, ordered index node
+// and Entry fields
+const ENTRY_OVERHEAD: usize = 128;
+// Approximate cost of one node in the expiration index, besides the copy of the key
+const EXPIRATION_OVERHEAD: usize = 48;
+
+/// Bytes accounted to a key holding a value of `value_size` bytes, see `Value::memory_size`.
+pub(super) fn entry_size(key_len: usize, value_size: usize, has_expiration: bool) -> usize {
+    // The key is stored three times: in the map, the sampling slots and the ordered index.
+    let mut size = ENTRY_OVERHEAD + key_len * 3 + value_size;
+
+    if has_expiration {
+        size += EXPIRATION_OVERHEAD + key_len;
+    }
+
+    size
+}
+
+#[derive(Debug)]
+pub(super) struct Entry {
+    // Shared with snapshots in progress, so they can copy entries without copying values;
+    // a write to a value still held by one copies it first
+    pub value: Arc<Value>,
+    // `Value::memory_size` of the value, kept up to date by in-place mutations so it
+    // never has to be recomputed from the whole value
+    value_size: usize,
+    pub expires_at: Option<u64>,
+    // Bumped on every write to the value, see `KVEngine::compare_and_set`
+    pub version: u64,
+    // Position of the key in `KeySpace::slots`
+    slot: usize,
+    // Unix millis of the last read or write, for LRU/LFU eviction
+    last_access: AtomicU64,
+    // Access counter for LFU eviction, halved for every idle minute when compared
+    frequency: AtomicU32,
+}
+
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Records an access. Only needs a shared reference, so reads under a read lock count too.
+    pub fn touch(&self, now: u64) {
+        self.last_access.store(now, Ordering::Relaxed);
+        let _ = self
+            .frequency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |frequency| {
+                frequency.checked_add(1)
+            });
+    }
+
+    pub fn last_access(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+
+    pub fn frequency(&self, now: u64) -> u32 {
+        let idle_minutes = now.saturating_sub(self.last_access()) / 60_000;
+        self.frequency.load(Ordering::Relaxed) >> idle_minutes.min(31)
+    }
+
+    fn size(&self, key: &str) -> usize {
+        entry_size(key.len(), self.value_size, self.expires_at.is_some())
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct KeySpace {
+    pub entries: HashMap<String, Entry>,
+    // (expires_at, key) for every key with a TTL, ordered by deadline
+    pub expirations: BTreeSet<(u64, String)>,
+    // Every key, so the evictor can pick random keys in O(1)
+    slots: Vec<String>,
+    // Every key in lexicographic order, for scans
+    pub ordered: BTreeSet<String>,
+    used_memory: usize,
+    // Shared by all shards of every namespace of an engine
+    total_memory: Arc<AtomicUsize>,
+    events: broadcast::Sender<KeyEvent>,
+}
+
+impl KeySpace {
+    pub fn new(total_memory: Arc<AtomicUsize>, events: broadcast::Sender<KeyEvent>) -> Self {
+        KeySpace {
+            entries: HashMap::new(),
+            expirations: BTreeSet::new(),
+            slots: vec![],
+            ordered: BTreeSet::new(),
+            used_memory: 0,
+            total_memory,
+            events,
+        }
+    }
+
+    fn charge(&mut self, size: usize) {
+        self.used_memory += size;
+        self.total_memory.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn release(&mut self, size: usize) {
+        self.used_memory -= size;
+        self.total_memory.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: u64,
+        now: u64,
+    ) {
+        emit(&self.events, &key, KeyEventOp::Set, version);
+        self.store(key, value, expires_at, version, now);
+    }
+
+    // `insert` without telling watchers
+    fn store(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: u64,
+        now: u64,
+    ) {
+        let value_size = value.memory_size();
+        let size = entry_size(key.len(), value_size, expires_at.is_some());
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            let previous_size = entry.size(&key);
+            let previous_expires_at = std::mem::replace(&mut entry.expires_at, expires_at);
+            entry.value = Arc::new(value);
+            entry.value_size = value_size;
+            entry.version = version;
+            entry.touch(now);
+
+            if previous_expires_at != expires_at {
+                if let Some(previous_expires_at) = previous_expires_at {
+                    self.expirations.remove(&(previous_expires_at, key.clone()));
+                }
+                if let Some(expires_at) = expires_at {
+                    self.expirations.insert((expires_at, key));
+                }
+            }
+
+            self.release(previous_size);
+            self.charge(size);
+            return;
+        }
+
+        if let Some(expires_at) = expires_at {
+            self.expirations.insert((expires_at, key.clone()));
+        }
+
+        let entry = Entry {
+            value: Arc::new(value),
+            value_size,
+            expires_at,
+            version,
+            slot: self.slots.len(),
+            last_access: AtomicU64::new(now),
+            frequency: AtomicU32::new(1),
+        };
+        self.slots.push(key.clone());
+        self.ordered.insert(key.clone());
+        self.entries.insert(key, entry);
+        self.charge(size);
+    }
+
+    /// Applies the mutation to the value at `key` in place, starting from `Mutation::create`
+    /// if the key doesn't exist, and deletes the key if that leaves an empty collection.
+    /// Only the bytes the mutation adds or frees are accounted, so the cost doesn't grow
+    /// with the size of the value.
+    ///
+    /// A mutation that needs an existing value does nothing if the key doesn't exist.
+    pub fn mutate<M: Mutation>(
+        &mut self,
+        key: &str,
+        mutation: M,
+        version: u64,
+        now: u64,
+    ) -> KVResult<()> {
+        if !self.entries.contains_key(key) {
+            let Some(value) = mutation.create() else {
+                return Ok(());
+            };
+            self.store(key.to_owned(), value, None, version, now);
+        }
+
+        let entry = self.entries.get_mut(key).expect("entry was just stored");
+        let previous_size = entry.size(key);
+        // Copies the value first if a snapshot still holds it
+        mutation.apply(Arc::make_mut(&mut entry.value), &mut entry.value_size)?;
+        entry.version = version;
+        entry.touch(now);
+
+        let size = entry.size(key);
+        let empty = entry.value.is_empty();
+        self.release(previous_size);
+        self.charge(size);
+
+        if empty {
+            self.remove(key, KeyEventOp::Delete);
+        } else {
+            emit(&self.events, key, KeyEventOp::Set, version);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the value at `key` in place with `update`, keeping its version and without
+    /// telling watchers, for bookkeeping nobody watches or compares against. The value's
+    /// size is measured again afterwards, so this suits values that are cheap to measure.
+    /// `None` if the key doesn't exist.
+    pub fn update_in_place<T>(
+        &mut self,
+        key: &str,
+        now: u64,
+        update: impl FnOnce(&mut Value) -> T,
+    ) -> Option<T> {
+        let entry = self.entries.get_mut(key)?;
+        let previous_size = entry.size(key);
+        let value = Arc::make_mut(&mut entry.value);
+        let result = update(value);
+        entry.value_size = value.memory_size();
+        entry.touch(now);
+
+        let size = entry.size(key);
+        self.release(previous_size);
+        self.charge(size);
+
+        Some(result)
+    }
+
+    /// Removes the key; `op` tells watchers why.
+    pub fn remove(&mut self, key: &str, op: KeyEventOp) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        emit(&self.events, key, op, entry.version);
+
+        if let Some(expires_at) = entry.expires_at {
+            self.expirations.remove(&(expires_at, key.to_owned()));
+        }
+
+        self.ordered.remove(key);
+        self.slots.swap_remove(entry.slot);
+        let moved = self
+            .slots
+            .get(entry.slot)
+            .and_then(|moved_key| self.entries.get_mut(moved_key));
+        if let Some(moved) = moved {
+            moved.slot = entry.slot;
+        }
+
+        self.release(entry.size(key));
+
+        Some(entry)
+    }
+
+    /// Returns the live entry for the key, dropping it first if it has expired.
+    pub fn get_live_mut(&mut self, key: &str, now: u64) -> Option<&mut Entry> {
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove(key, KeyEventOp::Expire);
+            return None;
+        }
+
+        self.entries.get_mut(key)
+    }
+
+    pub fn set_expiration(&mut self, key: &str, expires_at: Option<u64>, now: u64) -> bool {
+        let Some(entry) = self.get_live_mut(key, now) else {
+            return false;
+        };
+
+        let previous_size = entry.size(key);
+        let previous = std::mem::replace(&mut entry.expires_at, expires_at);
+        let size = entry.size(key);
+
+        if let Some(previous) = previous {
+            self.expirations.remove(&(previous, key.to_owned()));
+        }
+        if let Some(expires_at) = expires_at {
+            self.expirations.insert((expires_at, key.to_owned()));
+        }
+
+        self.release(previous_size);
+        self.charge(size);
+
+        true
+    }
+
+    /// Removes up to `limit` keys whose deadline has passed. Returns the number removed.
+    pub fn remove_expired(&mut self, now: u64, limit: usize) -> usize {
+        let mut removed = 0;
+
+        while removed < limit {
+            let Some((expires_at, key)) = self.expirations.first() else {
+                break;
+            };
+            if *expires_at > now {
+                break;
+            }
+
+            let key = key.clone();
+            self.remove(&key, KeyEventOp::Expire);
+            removed += 1;
+        }
+
+        removed
+    }
+
+    /// Drops every key without emitting events; the engine reports a clear once for all shards.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expirations.clear();
+        self.slots.clear();
+        self.ordered.clear();
+        self.release(self.used_memory);
+    }
+
+    /// Bytes accounted to the keys of this shard.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// Bytes accounted to `key` if it exists, regardless of expiration.
+    pub fn entry_size(&self, key: &str) -> Option<usize> {
+        self.entries.get(key).map(|entry| entry.size(key))
+    }
+
+    /// Key stored at the given sampling slot, wrapping around.
+    pub fn key_at(&self, slot: usize) -> Option<&String> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        self.slots.get(slot % self.slots.len())
+    }
+}
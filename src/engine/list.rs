@@ -0,0 +1,287 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult,
+    value::{KIND_LIST, Mutation, Value, list_memory_size},
+};
+
+// Mutation tags, as logged
+const OP_PUSH: u32 = 0;
+const OP_POP: u32 = 1;
+const OP_TRIM: u32 = 2;
+
+/// End of a list that is pushed to or popped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    /// The head, index 0
+    Left,
+    /// The tail, index -1
+    Right,
+}
+
+impl ListEnd {
+    fn to_field(self) -> u32 {
+        match self {
+            ListEnd::Left => 0,
+            ListEnd::Right => 1,
+        }
+    }
+
+    fn from_field(field: u32) -> Option<Self> {
+        match field {
+            0 => Some(ListEnd::Left),
+            1 => Some(ListEnd::Right),
+            _ => None,
+        }
+    }
+}
+
+/// A write to a list, see `Mutation`.
+pub(super) enum ListOp {
+    Push {
+        end: ListEnd,
+        values: Vec<Vec<u8>>,
+    },
+    Pop {
+        end: ListEnd,
+        count: usize,
+    },
+    /// Keeps the values at positions `start..end`
+    Trim {
+        start: usize,
+        end: usize,
+    },
+}
+
+impl Mutation for ListOp {
+    const KIND: u32 = KIND_LIST;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            ListOp::Push { end, values } => {
+                OP_PUSH.write_field(buffer);
+                end.to_field().write_field(buffer);
+                values.write_field(buffer);
+            }
+            ListOp::Pop { end, count } => {
+                OP_POP.write_field(buffer);
+                end.to_field().write_field(buffer);
+                (*count as u64).write_field(buffer);
+            }
+            ListOp::Trim { start, end } => {
+                OP_TRIM.write_field(buffer);
+                (*start as u64).write_field(buffer);
+                (*end as u64).write_field(buffer);
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_PUSH => {
+                let (end, rest) = u32::read_field(rest).ok()?;
+                let (values, _) = Vec::<Vec<u8>>::read_field(rest).ok()?;
+                Some(ListOp::Push {
+                    end: ListEnd::from_field(end)?,
+                    values,
+                })
+            }
+            OP_POP => {
+                let (end, rest) = u32::read_field(rest).ok()?;
+                let (count, _) = u64::read_field(rest).ok()?;
+                Some(ListOp::Pop {
+                    end: ListEnd::from_field(end)?,
+                    count: count as usize,
+                })
+            }
+            OP_TRIM => {
+                let (start, rest) = u64::read_field(rest).ok()?;
+                let (end, _) = u64::read_field(rest).ok()?;
+                Some(ListOp::Trim {
+                    start: start as usize,
+                    end: end as usize,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, ListOp::Push { .. }).then(|| Value::List(VecDeque::new()))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::List(list) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            ListOp::Push { end, values } => {
+                *size += list_memory_size(values.iter());
+                for value in values {
+                    match end {
+                        ListEnd::Left => list.push_front(value),
+                        ListEnd::Right => list.push_back(value),
+                    }
+                }
+            }
+            ListOp::Pop { end, count } => {
+                let count = count.min(list.len());
+                let range = match end {
+                    ListEnd::Left => 0..count,
+                    ListEnd::Right => list.len() - count..list.len(),
+                };
+                *size -= list_memory_size(list.range(range.clone()));
+                list.drain(range);
+            }
+            ListOp::Trim { start, end } => {
+                let end = end.min(list.len());
+                *size -= list_memory_size(list.range(end..));
+                list.truncate(end);
+
+                let start = start.min(list.len());
+                *size -= list_memory_size(list.range(..start));
+                list.drain(..start);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolves Redis-style inclusive indexes, negative ones counting from the end,
+/// to a range of `0..len`. `None` if the range is empty.
+fn resolve_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+
+    (start <= stop).then_some((start as usize, stop as usize))
+}
+
+impl KVEngine {
+    /// Pushes the values one after the other, creating the list if the key doesn't exist,
+    /// so pushing `a`, `b` to the left leaves `b` first. Returns the new length.
+    pub fn list_push(&self, key: &str, end: ListEnd, values: Vec<Vec<u8>>) -> KVResult<usize> {
+        let growth = list_memory_size(values.iter());
+
+        let length = self.mutate_value(key, growth, |current| {
+            let length = match current {
+                Some(value) => value.as_list()?.len(),
+                None => 0,
+            };
+            if values.is_empty() {
+                return Ok((None, length));
+            }
+
+            let length = length + values.len();
+            Ok((Some(ListOp::Push { end, values }), length))
+        })?;
+
+        self.namespace.waiters.wake(key);
+        Ok(length)
+    }
+
+    /// Removes and returns up to `count` values from the end. Empty if the key doesn't exist;
+    /// the key is deleted once the list is empty.
+    pub fn list_pop(&self, key: &str, end: ListEnd, count: usize) -> KVResult<Vec<Vec<u8>>> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Ok((None, vec![]));
+            };
+            let list = value.as_list()?;
+            if count == 0 {
+                return Ok((None, vec![]));
+            }
+
+            let count = count.min(list.len());
+            let popped: Vec<Vec<u8>> = match end {
+                ListEnd::Left => list.iter().take(count).cloned().collect(),
+                ListEnd::Right => list.iter().rev().take(count).cloned().collect(),
+            };
+
+            Ok((Some(ListOp::Pop { end, count }), popped))
+        })
+    }
+
+    /// Values from `start` to `stop`, both inclusive; negative indexes count from the end.
+    pub fn list_range(&self, key: &str, start: i64, stop: i64) -> KVResult<Vec<Vec<u8>>> {
+        let values = self.read_value(key, |value| {
+            let list = value.as_list()?;
+            Ok(match resolve_range(list.len(), start, stop) {
+                Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+                None => vec![],
+            })
+        })?;
+
+        Ok(values.unwrap_or_default())
+    }
+
+    /// Length of the list, 0 if the key doesn't exist.
+    pub fn list_len(&self, key: &str) -> KVResult<usize> {
+        let length = self.read_value(key, |value| Ok(value.as_list()?.len()))?;
+        Ok(length.unwrap_or(0))
+    }
+
+    /// Keeps only the values from `start` to `stop` (see `list_range`), deleting the key
+    /// if none are left.
+    pub fn list_trim(&self, key: &str, start: i64, stop: i64) -> KVResult<()> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Ok((None, ()));
+            };
+            let list = value.as_list()?;
+
+            let trim = match resolve_range(list.len(), start, stop) {
+                Some((start, stop)) if start == 0 && stop + 1 == list.len() => None,
+                Some((start, stop)) => Some(ListOp::Trim {
+                    start,
+                    end: stop + 1,
+                }),
+                None => Some(ListOp::Trim { start: 0, end: 0 }),
+            };
+            Ok((trim, ()))
+        })
+    }
+
+    /// Pops a value from the first non-empty list among `keys`, waiting for a push if they
+    /// are all empty. Returns the key and the value, or `None` once `timeout` elapsed
+    /// (`None` waits forever).
+    ///
+    /// Waiting parks the calling task, not a thread; dropping the future stops waiting.
+    pub async fn list_blocking_pop(
+        &self,
+        keys: &[String],
+        end: ListEnd,
+        timeout: Option<Duration>,
+    ) -> KVResult<Option<(String, Vec<u8>)>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let waiter = self.namespace.waiters.register(keys);
+
+        loop {
+            for key in keys {
+                if let Some(value) = self.list_pop(key, end, 1)?.pop() {
+                    return Ok(Some((key.clone(), value)));
+                }
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, waiter.notified())
+                        .await
+                        .is_err()
+                    {
+                        return Ok(None);
+                    }
+                }
+                None => waiter.notified().await,
+            }
+        }
+    }
+}
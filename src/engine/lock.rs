@@ -0,0 +1,314 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult, now_unix_millis,
+    value::{Update, Value},
+};
+
+// Approximate bookkeeping cost of a lock besides the name of its holder
+const LOCK_OVERHEAD: usize = 48;
+
+/// A lock and the fencing token of its latest acquisition. Tokens are drawn from the
+/// engine's version counter, which only moves forward, across restarts too, so recreating
+/// the key doesn't start them over. The key outlives releases and expired leases.
+#[derive(Debug, Clone)]
+pub(super) struct Lock {
+    token: u64,
+    // Owner and the unix millis its lease ends at; `None` once released
+    holder: Option<(String, u64)>,
+}
+
+impl Lock {
+    /// Owner whose lease hasn't ended by `now`.
+    fn live_holder(&self, now: u64) -> Option<&(String, u64)> {
+        self.holder
+            .as_ref()
+            .filter(|(_, expires_at)| *expires_at > now)
+    }
+
+    fn held_by(&self, owner: &str, now: u64) -> bool {
+        self.live_holder(now)
+            .is_some_and(|(holder, _)| holder == owner)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        LOCK_OVERHEAD + self.holder.as_ref().map_or(0, |(owner, _)| owner.len())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        self.token.write_field(&mut buffer);
+        let (owner, expires_at) = match &self.holder {
+            Some((owner, expires_at)) => (Some(owner.clone()), *expires_at),
+            None => (None, 0),
+        };
+        owner.write_field(&mut buffer);
+        expires_at.write_field(&mut buffer);
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Lock> {
+        let (token, rest) = u64::read_field(bytes).ok()?;
+        let (owner, rest) = Option::<String>::read_field(rest).ok()?;
+        let (expires_at, rest) = u64::read_field(rest).ok()?;
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some(Lock {
+            token,
+            holder: owner.map(|owner| (owner, expires_at)),
+        })
+    }
+}
+
+// Outcome of one attempt to take a lock.
+enum Attempt {
+    Acquired(u64),
+    // Held by someone else until this unix millis time, unless released earlier
+    Held(u64),
+    // Free, but someone who waited longer goes first
+    Queued,
+}
+
+fn lease_millis(lease: Duration) -> KVResult<u64> {
+    match lease.as_millis() as u64 {
+        0 => Err(KVError::InvalidLockLease),
+        millis => Ok(millis),
+    }
+}
+
+impl KVEngine {
+    /// Takes the lock for `owner` unless someone else holds it or is waiting for it.
+    /// Returns the fencing token, higher than that of every earlier acquisition of the lock;
+    /// `None` if the lock wasn't taken.
+    ///
+    /// The lock is released once `lease` passes without a renewal. An owner already
+    /// holding the lock gets its lease extended and keeps its token.
+    pub fn lock_acquire(&self, name: &str, owner: &str, lease: Duration) -> KVResult<Option<u64>> {
+        match self.try_lock_acquire(name, owner, lease_millis(lease)?, None)? {
+            Attempt::Acquired(token) => Ok(Some(token)),
+            Attempt::Held(_) | Attempt::Queued => Ok(None),
+        }
+    }
+
+    /// Like `lock_acquire`, but waits for the lock to be released or its lease to end,
+    /// behind those who started waiting earlier. Returns `None` on timeout; no timeout
+    /// waits forever.
+    pub async fn lock_acquire_blocking(
+        &self,
+        name: &str,
+        owner: &str,
+        lease: Duration,
+        timeout: Option<Duration>,
+    ) -> KVResult<Option<u64>> {
+        let lease = lease_millis(lease)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let ticket = self.namespace.lock_queue.enqueue(name);
+
+        loop {
+            let expires_at = match self.try_lock_acquire(name, owner, lease, Some(ticket.id()))? {
+                Attempt::Acquired(token) => return Ok(Some(token)),
+                Attempt::Held(expires_at) => Some(expires_at),
+                Attempt::Queued => None,
+            };
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+
+            // Nothing wakes the first in line when a lease ends, so it tries again then
+            let lease_end = expires_at.map(|expires_at| {
+                Instant::now() + Duration::from_millis(expires_at.saturating_sub(now_unix_millis()))
+            });
+            match lease_end.into_iter().chain(deadline).min() {
+                Some(wake_at) => {
+                    let _ = tokio::time::timeout_at(wake_at, ticket.notified()).await;
+                }
+                None => ticket.notified().await,
+            }
+        }
+    }
+
+    /// Extends the lease of `owner` to `lease` from now. Returns whether `owner` still held
+    /// the lock; once its lease ended, someone else may have taken it.
+    pub fn lock_renew(&self, name: &str, owner: &str, lease: Duration) -> KVResult<bool> {
+        let lease = lease_millis(lease)?;
+
+        self.update_value(name, 0, |current| {
+            let Some(current) = current else {
+                return Ok((Update::Keep, false));
+            };
+            let lock = current.as_lock()?;
+            let now = now_unix_millis();
+            if !lock.held_by(owner, now) {
+                return Ok((Update::Keep, false));
+            }
+
+            let lock = Lock {
+                token: lock.token,
+                holder: Some((owner.to_owned(), now + lease)),
+            };
+            Ok((Update::Store(Value::Lock(lock)), true))
+        })
+    }
+
+    /// Releases the lock if `owner` holds it, handing it to whoever waited longest.
+    /// Returns whether `owner` held it.
+    pub fn lock_release(&self, name: &str, owner: &str) -> KVResult<bool> {
+        let released = self.update_value(name, 0, |current| {
+            let Some(current) = current else {
+                return Ok((Update::Keep, false));
+            };
+            let lock = current.as_lock()?;
+            if !lock.held_by(owner, now_unix_millis()) {
+                return Ok((Update::Keep, false));
+            }
+
+            let lock = Lock {
+                token: lock.token,
+                holder: None,
+            };
+            Ok((Update::Store(Value::Lock(lock)), true))
+        })?;
+
+        if released {
+            self.namespace.lock_queue.wake_first(name);
+        }
+        Ok(released)
+    }
+
+    fn try_lock_acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        lease: u64,
+        ticket: Option<u64>,
+    ) -> KVResult<Attempt> {
+        let growth = LOCK_OVERHEAD + owner.len();
+
+        self.update_value(name, growth, |current| {
+            let lock = current.map(Value::as_lock).transpose()?;
+            let now = now_unix_millis();
+
+            let token = match lock.map(|lock| (lock.token, lock.live_holder(now))) {
+                Some((token, Some((holder, _)))) if holder == owner => token,
+                Some((_, Some((_, expires_at)))) => {
+                    return Ok((Update::Keep, Attempt::Held(*expires_at)));
+                }
+                _ if !self.namespace.lock_queue.is_turn(name, ticket) => {
+                    return Ok((Update::Keep, Attempt::Queued));
+                }
+                _ => self.next_version(),
+            };
+
+            let lock = Lock {
+                token,
+                holder: Some((owner.to_owned(), now + lease)),
+            };
+            Ok((Update::Store(Value::Lock(lock)), Attempt::Acquired(token)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn tokens_increase_across_release_and_reacquire() {
+        let engine = KVEngine::new();
+
+        let first = engine.lock_acquire("report", "a", LEASE).unwrap().unwrap();
+        // Renewing by acquiring again keeps the token
+        assert_eq!(
+            engine.lock_acquire("report", "a", LEASE).unwrap(),
+            Some(first)
+        );
+        assert_eq!(engine.lock_acquire("report", "b", LEASE).unwrap(), None);
+        assert!(engine.lock_release("report", "a").unwrap());
+        assert!(!engine.lock_release("report", "a").unwrap());
+
+        let second = engine.lock_acquire("report", "b", LEASE).unwrap().unwrap();
+        assert!(second > first);
+        assert!(engine.lock_release("report", "b").unwrap());
+
+        // Other writes move the version counter, never the token backwards
+        engine.set_key_value("other".into(), b"1".to_vec()).unwrap();
+        let third = engine.lock_acquire("report", "a", LEASE).unwrap().unwrap();
+        assert!(third > second);
+    }
+
+    #[test]
+    fn an_expired_lease_hands_the_lock_over_with_a_higher_token() {
+        let engine = KVEngine::new();
+
+        let first = engine
+            .lock_acquire("report", "a", Duration::from_millis(1))
+            .unwrap()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(!engine.lock_renew("report", "a", LEASE).unwrap());
+        let second = engine.lock_acquire("report", "b", LEASE).unwrap().unwrap();
+        assert!(second > first);
+        assert!(!engine.lock_release("report", "a").unwrap());
+    }
+
+    #[tokio::test]
+    async fn waiters_get_the_lock_in_arrival_order() {
+        let engine = KVEngine::new();
+        let first = engine
+            .lock_acquire("report", "holder", LEASE)
+            .unwrap()
+            .unwrap();
+
+        let acquired = Arc::new(Mutex::new(vec![]));
+        let mut waiters = vec![];
+        for owner in ["w1", "w2", "w3"] {
+            let engine = engine.clone();
+            let acquired = acquired.clone();
+            waiters.push(tokio::spawn(async move {
+                let token = engine
+                    .lock_acquire_blocking("report", owner, LEASE, Some(Duration::from_secs(5)))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                acquired.lock().unwrap().push((owner, token));
+                engine.lock_release("report", owner).unwrap();
+            }));
+            // Let the waiter get in line before the next one
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Not waiting doesn't jump the line, even once the lock is free
+        assert!(engine.lock_release("report", "holder").unwrap());
+        assert_eq!(engine.lock_acquire("report", "late", LEASE).unwrap(), None);
+
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+
+        let acquired = acquired.lock().unwrap();
+        let owners: Vec<&str> = acquired.iter().map(|(owner, _)| *owner).collect();
+        assert_eq!(owners, ["w1", "w2", "w3"]);
+        let tokens: Vec<u64> = acquired.iter().map(|(_, token)| *token).collect();
+        assert!(first < tokens[0] && tokens[0] < tokens[1] && tokens[1] < tokens[2]);
+
+        // Once the line is empty anyone can take it
+        assert!(
+            engine
+                .lock_acquire("report", "late", LEASE)
+                .unwrap()
+                .unwrap()
+                > tokens[2]
+        );
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::broadcast;
+
+use super::{
+    KVEngine, KVError, KVResult,
+    blocking::{KeyWaiters, LockQueue},
+    events::{EVENT_BUS_CAPACITY, KeyEvent},
+    keyspace::KeySpace,
+    now_unix_millis, read_shard, write_shard,
+};
+
+/// Namespace of connections that never selected one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+const MAX_NAMESPACE_LENGTH: usize = 64;
+
+/// A keyspace of its own, sharing the memory budget, version counter and
+/// persistence of the engine with every other namespace.
+#[derive(Debug)]
+pub(super) struct Namespace {
+    pub name: String,
+    pub shards: Box<[RwLock<KeySpace>]>,
+    // Keyspace changes, see `KVEngine::watch_keys`
+    pub events: broadcast::Sender<KeyEvent>,
+    // Blocking pops and stream reads parked until their keys are written to
+    pub waiters: KeyWaiters,
+    // Blocking lock acquisitions, in line per lock
+    pub lock_queue: LockQueue,
+    // Whether it's among `Namespaces::created`: set by the first write,
+    // cleared by `KVEngine::drop_namespace`
+    pub created: AtomicBool,
+}
+
+impl Namespace {
+    pub fn new(name: String, shard_count: usize, used_memory: &Arc<AtomicUsize>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        let shards = (0..shard_count)
+            .map(|_| RwLock::new(KeySpace::new(used_memory.clone(), events.clone())))
+            .collect();
+
+        Namespace {
+            name,
+            shards,
+            events,
+            waiters: KeyWaiters::default(),
+            lock_queue: LockQueue::default(),
+            created: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Namespaces by name. Only the ones written to count towards the limit; the others
+/// live as long as a handle to them does.
+#[derive(Debug)]
+pub(super) struct Namespaces {
+    created: HashMap<String, Arc<Namespace>>,
+    // Selected but never written to, shared so their handles see each other's
+    // blocking operations and events
+    selected: HashMap<String, Weak<Namespace>>,
+}
+
+impl Namespaces {
+    pub fn new(default: Arc<Namespace>) -> Self {
+        default.created.store(true, Ordering::Relaxed);
+        Namespaces {
+            created: HashMap::from([(default.name.clone(), default)]),
+            selected: HashMap::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<Namespace>> {
+        match self.created.get(name) {
+            Some(namespace) => Some(namespace.clone()),
+            None => self.selected.get(name).and_then(Weak::upgrade),
+        }
+    }
+
+    fn select(&mut self, namespace: &Arc<Namespace>) {
+        // Forget the namespaces whose last handle is gone
+        self.selected
+            .retain(|_, namespace| namespace.strong_count() > 0);
+        self.selected
+            .insert(namespace.name.clone(), Arc::downgrade(namespace));
+    }
+}
+
+/// Point-in-time figures of one namespace, see `KVEngine::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceStats {
+    /// Live keys
+    pub keys: u64,
+    /// Live keys with a deadline
+    pub expiring_keys: u64,
+    /// Bytes accounted to the keys, see `KVEngine::used_memory`
+    pub used_memory: u64,
+}
+
+fn validate_name(name: &str) -> KVResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAMESPACE_LENGTH
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(KVError::InvalidNamespace)
+    }
+}
+
+impl KVEngine {
+    /// Returns a handle to the namespace called `name`. Every operation through the handle,
+    /// clears included, only sees the keys of that namespace.
+    ///
+    /// Names are up to 64 ASCII letters, digits, `_` or `-`, so numbered databases
+    /// (`"0"`, `"1"`, ...) work as well as named ones. The namespace is only created by the
+    /// first write through a handle, so reading one that doesn't exist leaves nothing
+    /// behind; that write fails with `TooManyNamespaces` once
+    /// `EngineConfig::max_namespaces` of them exist.
+    pub fn namespace(&self, name: &str) -> KVResult<KVEngine> {
+        validate_name(name)?;
+        self.namespace_handle(name)
+    }
+
+    /// Like `namespace`, but creates the namespace right away, so the limit is checked
+    /// before anything is written.
+    pub fn create_namespace(&self, name: &str) -> KVResult<KVEngine> {
+        let engine = self.namespace(name)?;
+        engine.create(true)?;
+        Ok(engine)
+    }
+
+    /// Removes the namespace called `name` so it no longer counts towards
+    /// `EngineConfig::max_namespaces`. Fails with `NamespaceNotEmpty` while it holds keys.
+    /// Returns whether the namespace existed; the default one is never dropped.
+    ///
+    /// Handles to the namespace stay usable, and writing through one creates it again.
+    pub fn drop_namespace(&self, name: &str) -> KVResult<bool> {
+        if name == DEFAULT_NAMESPACE {
+            return Ok(false);
+        }
+        let Some(namespace) = self.namespaces()?.created.get(name).cloned() else {
+            return Ok(false);
+        };
+
+        // Writers create the namespace with a shard locked, so none can while these are held
+        let mut shards = namespace
+            .shards
+            .iter()
+            .map(write_shard)
+            .collect::<KVResult<Vec<_>>>()?;
+        if !namespace.created.load(Ordering::Relaxed) {
+            // Dropped by someone else in the meantime
+            return Ok(false);
+        }
+
+        let now = now_unix_millis();
+        for kv in shards.iter_mut() {
+            kv.remove_expired(now, usize::MAX);
+            if !kv.entries.is_empty() {
+                return Err(KVError::NamespaceNotEmpty);
+            }
+        }
+
+        let mut namespaces = self.namespaces_mut()?;
+        namespaces.created.remove(name);
+        namespaces.select(&namespace);
+        namespace.created.store(false, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Name of the namespace this handle operates on.
+    pub fn namespace_name(&self) -> &str {
+        &self.namespace.name
+    }
+
+    /// Names of every namespace that exists, in no particular order.
+    pub fn namespace_names(&self) -> KVResult<Vec<String>> {
+        Ok(self.namespaces()?.created.keys().cloned().collect())
+    }
+
+    /// Number of live keys in the namespace.
+    pub fn size(&self) -> KVResult<usize> {
+        self.stats().map(|stats| stats.keys as usize)
+    }
+
+    /// Key counts and memory of the namespace. Keys whose deadline passed but haven't been
+    /// reclaimed yet are left out.
+    pub fn stats(&self) -> KVResult<NamespaceStats> {
+        let now = now_unix_millis();
+        let mut stats = NamespaceStats {
+            keys: 0,
+            expiring_keys: 0,
+            used_memory: 0,
+        };
+
+        for shard in self.namespace.shards.iter() {
+            let kv = read_shard(shard)?;
+            let expired = kv
+                .expirations
+                .iter()
+                .take_while(|(expires_at, _)| *expires_at <= now)
+                .count();
+
+            stats.keys += (kv.entries.len() - expired) as u64;
+            stats.expiring_keys += (kv.expirations.len() - expired) as u64;
+            stats.used_memory += kv.used_memory() as u64;
+        }
+
+        Ok(stats)
+    }
+
+    /// Handles to every namespace that exists, the one of this handle first.
+    pub(super) fn all_namespaces(&self) -> KVResult<Vec<KVEngine>> {
+        let namespaces = self.namespaces()?;
+
+        let others = namespaces
+            .created
+            .values()
+            .filter(|namespace| !Arc::ptr_eq(namespace, &self.namespace));
+        Ok(std::iter::once(&self.namespace)
+            .chain(others)
+            .map(|namespace| KVEngine {
+                inner: self.inner.clone(),
+                namespace: namespace.clone(),
+            })
+            .collect())
+    }
+
+    /// Like `create_namespace`, but without checking the characters of the name or the
+    /// number of namespaces, so persisted records are restored whatever rules applied when
+    /// they were written. The length of the name is still capped.
+    pub(super) fn namespace_unchecked(&self, name: &str) -> KVResult<KVEngine> {
+        if name.len() > MAX_NAMESPACE_LENGTH {
+            return Err(KVError::InvalidNamespace);
+        }
+
+        let engine = self.namespace_handle(name)?;
+        engine.create(false)?;
+        Ok(engine)
+    }
+
+    /// Creates the namespace of this handle if it doesn't exist yet. Called with a shard of
+    /// the namespace locked before each write, so it can't be dropped until the write is done.
+    pub(super) fn create_on_write(&self) -> KVResult<()> {
+        self.create(true)
+    }
+
+    fn create(&self, capped: bool) -> KVResult<()> {
+        if self.namespace.created.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let mut namespaces = self.namespaces_mut()?;
+        // Another handle may have created it while the lock was released
+        if self.namespace.created.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let max_namespaces = self.inner.max_namespaces;
+        if capped && max_namespaces != 0 && namespaces.created.len() >= max_namespaces {
+            return Err(KVError::TooManyNamespaces);
+        }
+
+        namespaces.selected.remove(&self.namespace.name);
+        namespaces
+            .created
+            .insert(self.namespace.name.clone(), self.namespace.clone());
+        self.namespace.created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // The namespace called `name`, whether it exists or not
+    fn namespace_handle(&self, name: &str) -> KVResult<KVEngine> {
+        let existing = self.namespaces()?.get(name);
+        let namespace = match existing {
+            Some(namespace) => namespace,
+            None => {
+                let mut namespaces = self.namespaces_mut()?;
+                // Another thread may have selected it while the lock was released
+                match namespaces.get(name) {
+                    Some(namespace) => namespace,
+                    None => {
+                        let namespace = Arc::new(Namespace::new(
+                            name.to_owned(),
+                            self.namespace.shards.len(),
+                            &self.inner.used_memory,
+                        ));
+                        namespaces.select(&namespace);
+                        namespace
+                    }
+                }
+            }
+        };
+
+        Ok(KVEngine {
+            inner: self.inner.clone(),
+            namespace,
+        })
+    }
+
+    fn namespaces(&self) -> KVResult<RwLockReadGuard<'_, Namespaces>> {
+        self.inner
+            .namespaces
+            .read()
+            .map_err(|_| KVError::LockFailed)
+    }
+
+    fn namespaces_mut(&self) -> KVResult<RwLockWriteGuard<'_, Namespaces>> {
+        self.inner
+            .namespaces
+            .write()
+            .map_err(|_| KVError::LockFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{EngineConfig, FsyncPolicy, ListEnd};
+
+    fn with_max_namespaces(max_namespaces: usize) -> KVEngine {
+        KVEngine::with_config(EngineConfig {
+            max_namespaces,
+            ..EngineConfig::default()
+        })
+    }
+
+    fn sorted_names(engine: &KVEngine) -> Vec<String> {
+        let mut names = engine.namespace_names().unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn namespaces_keep_their_keys_apart() {
+        let engine = KVEngine::new();
+        let billing = engine.namespace("billing").unwrap();
+        let orders = engine.namespace("orders").unwrap();
+
+        engine
+            .set_key_value("key".into(), b"default".to_vec())
+            .unwrap();
+        billing
+            .set_key_value("key".into(), b"billing".to_vec())
+            .unwrap();
+        billing
+            .list_push("queue", ListEnd::Right, vec![b"job".to_vec()])
+            .unwrap();
+
+        assert_eq!(engine.get_key_value("key").unwrap(), b"default");
+        assert_eq!(billing.get_key_value("key").unwrap(), b"billing");
+        assert!(matches!(
+            orders.get_key_value("key"),
+            Err(KVError::KeyNotFound)
+        ));
+        assert_eq!(engine.list_len("queue").unwrap(), 0);
+        assert_eq!(billing.size().unwrap(), 2);
+
+        billing.delete_key_value("key").unwrap();
+        assert_eq!(engine.get_key_value("key").unwrap(), b"default");
+    }
+
+    #[test]
+    fn clearing_only_empties_its_own_namespace() {
+        let engine = KVEngine::new();
+        let billing = engine.namespace("billing").unwrap();
+        let orders = engine.namespace("orders").unwrap();
+        for namespace in [&engine, &billing, &orders] {
+            namespace
+                .set_key_value("key".into(), b"1".to_vec())
+                .unwrap();
+        }
+
+        billing.clear_all().unwrap();
+
+        assert_eq!(billing.size().unwrap(), 0);
+        assert_eq!(engine.get_key_value("key").unwrap(), b"1");
+        assert_eq!(orders.get_key_value("key").unwrap(), b"1");
+    }
+
+    #[test]
+    fn writes_replay_into_their_namespace() {
+        let dir = std::env::temp_dir().join(format!("rstore-isolation-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = EngineConfig {
+            wal_path: Some(dir.join("appendonly.rstore")),
+            wal_fsync: FsyncPolicy::Always,
+            ..EngineConfig::default()
+        };
+
+        let engine = KVEngine::open(config.clone()).unwrap();
+        let billing = engine.namespace("billing").unwrap();
+        let orders = engine.namespace("orders").unwrap();
+        engine
+            .set_key_value("key".into(), b"default".to_vec())
+            .unwrap();
+        billing
+            .set_key_value("key".into(), b"billing".to_vec())
+            .unwrap();
+        billing
+            .list_push("queue", ListEnd::Right, vec![b"a".to_vec(), b"b".to_vec()])
+            .unwrap();
+        orders
+            .set_key_value("key".into(), b"orders".to_vec())
+            .unwrap();
+        orders.set_key_value("other".into(), b"1".to_vec()).unwrap();
+        // Only the clear of orders may reach orders on replay
+        orders.clear_all().unwrap();
+        orders
+            .set_key_value("key".into(), b"again".to_vec())
+            .unwrap();
+        drop((engine, billing, orders));
+
+        let engine = KVEngine::open(config).unwrap();
+        let billing = engine.namespace("billing").unwrap();
+        let orders = engine.namespace("orders").unwrap();
+        assert_eq!(engine.get_key_value("key").unwrap(), b"default");
+        assert_eq!(engine.size().unwrap(), 1);
+        assert_eq!(billing.get_key_value("key").unwrap(), b"billing");
+        assert_eq!(
+            billing.list_range("queue", 0, -1).unwrap(),
+            [b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(orders.get_key_value("key").unwrap(), b"again");
+        assert_eq!(orders.size().unwrap(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_leave_no_namespace_behind() {
+        let engine = with_max_namespaces(2);
+
+        let billing = engine.namespace("billing").unwrap();
+        assert!(matches!(
+            billing.get_key_value("invoice"),
+            Err(KVError::KeyNotFound)
+        ));
+        assert_eq!(billing.size().unwrap(), 0);
+        billing.clear_all().unwrap();
+        assert_eq!(sorted_names(&engine), [DEFAULT_NAMESPACE]);
+
+        billing
+            .set_key_value("invoice".into(), b"7".to_vec())
+            .unwrap();
+        assert_eq!(sorted_names(&engine), ["billing", DEFAULT_NAMESPACE]);
+
+        // Past the limit reads still work, only the first write fails
+        let orders = engine.namespace("orders").unwrap();
+        assert!(matches!(
+            orders.get_key_value("order"),
+            Err(KVError::KeyNotFound)
+        ));
+        assert!(matches!(
+            orders.set_key_value("order".into(), b"1".to_vec()),
+            Err(KVError::TooManyNamespaces)
+        ));
+        assert!(matches!(
+            engine.create_namespace("orders"),
+            Err(KVError::TooManyNamespaces)
+        ));
+        assert_eq!(orders.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn handles_to_a_namespace_share_it_before_it_exists() {
+        let engine = KVEngine::new();
+        let first = engine.namespace("billing").unwrap();
+        let second = engine.namespace("billing").unwrap();
+        assert!(Arc::ptr_eq(&first.namespace, &second.namespace));
+
+        first
+            .set_key_value("invoice".into(), b"7".to_vec())
+            .unwrap();
+        assert_eq!(second.get_key_value("invoice").unwrap(), b"7");
+    }
+
+    #[test]
+    fn only_empty_namespaces_are_dropped() {
+        let engine = with_max_namespaces(2);
+        let billing = engine.create_namespace("billing").unwrap();
+        billing
+            .set_key_value("invoice".into(), b"7".to_vec())
+            .unwrap();
+
+        assert!(matches!(
+            engine.drop_namespace("billing"),
+            Err(KVError::NamespaceNotEmpty)
+        ));
+        billing.delete_key_value("invoice").unwrap();
+        assert!(engine.drop_namespace("billing").unwrap());
+        assert!(!engine.drop_namespace("billing").unwrap());
+        assert!(!engine.drop_namespace(DEFAULT_NAMESPACE).unwrap());
+        assert_eq!(sorted_names(&engine), [DEFAULT_NAMESPACE]);
+
+        // The dropped namespace freed its place under the limit
+        engine
+            .namespace("orders")
+            .unwrap()
+            .set_key_value("order".into(), b"1".to_vec())
+            .unwrap();
+        assert!(matches!(
+            billing.set_key_value("invoice".into(), b"8".to_vec()),
+            Err(KVError::TooManyNamespaces)
+        ));
+    }
+
+    #[test]
+    fn restoring_ignores_the_namespace_limit() {
+        let dir = std::env::temp_dir().join(format!("rstore-namespaces-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = EngineConfig {
+            wal_path: Some(dir.join("appendonly.rstore")),
+            wal_fsync: FsyncPolicy::Always,
+            ..EngineConfig::default()
+        };
+
+        let engine = KVEngine::open(config.clone()).unwrap();
+        for name in ["billing", "orders", "emptied"] {
+            engine
+                .namespace(name)
+                .unwrap()
+                .set_key_value("key".into(), name.as_bytes().to_vec())
+                .unwrap();
+        }
+        engine
+            .namespace("emptied")
+            .unwrap()
+            .delete_key_value("key")
+            .unwrap();
+        drop(engine);
+
+        let engine = KVEngine::open(EngineConfig {
+            max_namespaces: 2,
+            ..config
+        })
+        .unwrap();
+        assert_eq!(
+            sorted_names(&engine),
+            ["billing", DEFAULT_NAMESPACE, "orders"]
+        );
+        assert_eq!(
+            engine
+                .namespace("orders")
+                .unwrap()
+                .get_key_value("key")
+                .unwrap(),
+            b"orders"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,647 @@
+use crate::protocol::{WireField, read_chunk, write_chunk};
+
+use super::{
+    KVEngine, KVError, KVResult,
+    value::{KIND_BLOOM_FILTER, KIND_HYPERLOGLOG, Mutation, Update, Value},
+};
+
+// Registers of a HyperLogLog are addressed by this many bits of the hash, which gives
+// 16384 registers and a standard error of 0.81%
+const HLL_INDEX_BITS: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_INDEX_BITS;
+// Approximate bookkeeping cost of a structure besides its registers or bits
+const PROBABILISTIC_OVERHEAD: usize = 64;
+
+// Filters created by `bloom_add` on a missing key
+const BLOOM_DEFAULT_ERROR_RATE: f64 = 0.01;
+const BLOOM_DEFAULT_CAPACITY: u64 = 100;
+// Each layer added to a full filter holds this many times the items of the previous one...
+const BLOOM_EXPANSION: u64 = 2;
+// ...with this fraction of its error rate, so the rates of all layers add up to at most
+// the rate the filter was created with
+const BLOOM_TIGHTENING: f64 = 0.5;
+// Bounds on the options of a filter; together they keep the first layer under 16MB
+const BLOOM_MIN_ERROR_RATE: f64 = 1e-6;
+const BLOOM_MAX_CAPACITY: u64 = 1 << 22;
+// Upper bound on the bits of one layer (16MB), so the layers added as a filter fills up
+// don't grow without limit
+const BLOOM_MAX_LAYER_BITS: u64 = 1 << 27;
+
+// Mutation tags, as logged
+const OP_ADD: u32 = 0;
+
+/// A 64-bit hash that stays the same across processes and Rust releases, so structures
+/// restored from disk keep addressing the same registers and bits.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    // FNV-1a, then the MurmurHash3 finalizer to spread the bits
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    mix(hash)
+}
+
+// MurmurHash3 finalizer
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A write to a HyperLogLog, see `Mutation`.
+pub(super) enum HllOp {
+    /// Adds the elements, creating the HyperLogLog if the key doesn't exist
+    Add { elements: Vec<String> },
+}
+
+impl Mutation for HllOp {
+    const KIND: u32 = KIND_HYPERLOGLOG;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let HllOp::Add { elements } = self;
+        OP_ADD.write_field(buffer);
+        elements.write_field(buffer);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_ADD => {
+                let (elements, _) = Vec::<String>::read_field(rest).ok()?;
+                Some(HllOp::Add { elements })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        Some(Value::HyperLogLog(HyperLogLog::default()))
+    }
+
+    fn apply(self, value: &mut Value, _size: &mut usize) -> KVResult<()> {
+        let Value::HyperLogLog(hll) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        let HllOp::Add { elements } = self;
+        for element in elements {
+            hll.add(element.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// A write to a Bloom filter, see `Mutation`.
+pub(super) enum BloomOp {
+    /// Adds the item, creating a filter with the default options if the key doesn't exist
+    Add { item: String },
+}
+
+impl Mutation for BloomOp {
+    const KIND: u32 = KIND_BLOOM_FILTER;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let BloomOp::Add { item } = self;
+        OP_ADD.write_field(buffer);
+        item.write_field(buffer);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_ADD => {
+                let (item, _) = String::read_field(rest).ok()?;
+                Some(BloomOp::Add { item })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        let filter = BloomFilter::new(BLOOM_DEFAULT_ERROR_RATE, BLOOM_DEFAULT_CAPACITY).ok()?;
+        Some(Value::BloomFilter(filter))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::BloomFilter(filter) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        let BloomOp::Add { item } = self;
+        let before = filter.memory_size();
+        filter.insert(item.as_bytes());
+        *size += filter.memory_size() - before;
+        Ok(())
+    }
+}
+
+/// Estimates the number of distinct elements added, in a fixed 16KB.
+#[derive(Debug, Clone)]
+pub(super) struct HyperLogLog {
+    // Per register, the longest run of trailing zeros (plus one) seen in the hashes
+    // addressed to it
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+}
+
+// Register an element is addressed to, and the run it puts there.
+fn hll_register(element: &[u8]) -> (usize, u8) {
+    let hash = stable_hash(element);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    // The marker bit caps the run for hashes whose remaining bits are all zero
+    let rank = ((hash >> HLL_INDEX_BITS) | (1 << (64 - HLL_INDEX_BITS))).trailing_zeros() + 1;
+    (index, rank as u8)
+}
+
+impl HyperLogLog {
+    /// Whether adding the element would change a register.
+    fn would_change(&self, element: &[u8]) -> bool {
+        let (index, rank) = hll_register(element);
+        rank > self.registers[index]
+    }
+
+    fn add(&mut self, element: &[u8]) {
+        let (index, rank) = hll_register(element);
+        let register = &mut self.registers[index];
+        *register = (*register).max(rank);
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities leave registers empty, where linear counting is more accurate
+        let zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.registers.len() + PROBABILISTIC_OVERHEAD
+    }
+
+    pub fn encode(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn decode(bytes: Vec<u8>) -> Option<HyperLogLog> {
+        let valid = bytes.len() == HLL_REGISTERS
+            && bytes
+                .iter()
+                .all(|register| u32::from(*register) <= 64 - HLL_INDEX_BITS + 1);
+        valid.then_some(HyperLogLog { registers: bytes })
+    }
+}
+
+/// A Bloom filter that adds layers as it fills up, so it keeps its error rate however many
+/// items are added. Each layer's size is fixed when it is created.
+#[derive(Debug, Clone)]
+pub(super) struct BloomFilter {
+    error_rate: f64,
+    layers: Vec<BloomLayer>,
+}
+
+#[derive(Debug, Clone)]
+struct BloomLayer {
+    // Items the layer takes before the next one is added
+    capacity: u64,
+    // Items added to the layer
+    count: u64,
+    hashes: u32,
+    bits: Vec<u64>,
+    bit_count: u64,
+}
+
+// Bits and hash functions for a layer of `capacity` items at `error_rate`.
+fn layer_size(capacity: u64, error_rate: f64) -> (u64, u32) {
+    let ln2 = std::f64::consts::LN_2;
+    let bit_count = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2))
+        .ceil()
+        .clamp(64.0, BLOOM_MAX_LAYER_BITS as f64) as u64;
+    let hashes = (-error_rate.log2()).ceil().max(1.0) as u32;
+    (bit_count, hashes)
+}
+
+// Bytes taken by a layer of `capacity` items at `error_rate`, see `BloomFilter::memory_size`.
+fn layer_memory_size(capacity: u64, error_rate: f64) -> usize {
+    let (bit_count, _) = layer_size(capacity, error_rate);
+    bit_count.div_ceil(64) as usize * 8 + PROBABILISTIC_OVERHEAD
+}
+
+impl BloomLayer {
+    fn new(capacity: u64, error_rate: f64) -> Self {
+        let (bit_count, hashes) = layer_size(capacity, error_rate);
+
+        Self {
+            capacity,
+            count: 0,
+            hashes,
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+        }
+    }
+
+    // Bit positions of the item, by double hashing
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = u64> + '_ {
+        let first = stable_hash(item);
+        // Mixed from the first rather than hashed again with another seed: FNV runs from
+        // different starting points stay correlated, which shows in small layers
+        let second = mix(first ^ 0x9e37_79b9_7f4a_7c15) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % self.bit_count)
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+
+    fn insert(&mut self, item: &[u8]) {
+        let positions: Vec<u64> = self.positions(item).collect();
+        for position in positions {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+        self.count += 1;
+    }
+}
+
+impl BloomFilter {
+    /// `error_rate` is the chance that an item that was never added is reported as added;
+    /// `capacity` is how many items the first layer takes.
+    fn new(error_rate: f64, capacity: u64) -> KVResult<Self> {
+        Self::check_options(error_rate, capacity)?;
+
+        Ok(Self {
+            error_rate,
+            layers: vec![BloomLayer::new(capacity, error_rate * BLOOM_TIGHTENING)],
+        })
+    }
+
+    fn check_options(error_rate: f64, capacity: u64) -> KVResult<()> {
+        let valid = (BLOOM_MIN_ERROR_RATE..1.0).contains(&error_rate)
+            && (1..=BLOOM_MAX_CAPACITY).contains(&capacity);
+        if !valid {
+            return Err(KVError::InvalidFilterOptions);
+        }
+        Ok(())
+    }
+
+    /// Bytes a new filter takes, without allocating it.
+    fn new_size(error_rate: f64, capacity: u64) -> KVResult<usize> {
+        Self::check_options(error_rate, capacity)?;
+        Ok(PROBABILISTIC_OVERHEAD + layer_memory_size(capacity, error_rate * BLOOM_TIGHTENING))
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.layers.iter().any(|layer| layer.contains(item))
+    }
+
+    /// Returns false if the item may have been added before.
+    fn insert(&mut self, item: &[u8]) -> bool {
+        if self.contains(item) {
+            return false;
+        }
+
+        if let Some((capacity, error_rate)) = self.next_layer() {
+            self.layers.push(BloomLayer::new(capacity, error_rate));
+        }
+
+        self.layers
+            .last_mut()
+            .expect("a filter has at least one layer")
+            .insert(item);
+        true
+    }
+
+    // Capacity and error rate of the layer the next insert adds, if the last one is full
+    fn next_layer(&self) -> Option<(u64, f64)> {
+        let last = self.layers.last().expect("a filter has at least one layer");
+        if last.count < last.capacity {
+            return None;
+        }
+
+        let error_rate = self.error_rate * BLOOM_TIGHTENING.powi(self.layers.len() as i32 + 1);
+        Some((last.capacity.saturating_mul(BLOOM_EXPANSION), error_rate))
+    }
+
+    /// Bytes the next insert may allocate.
+    fn insert_growth(&self) -> usize {
+        self.next_layer().map_or(0, |(capacity, error_rate)| {
+            layer_memory_size(capacity, error_rate)
+        })
+    }
+
+    pub fn memory_size(&self) -> usize {
+        PROBABILISTIC_OVERHEAD
+            + self
+                .layers
+                .iter()
+                .map(|layer| layer.bits.len() * 8 + PROBABILISTIC_OVERHEAD)
+                .sum::<usize>()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        self.error_rate.write_field(&mut buffer);
+        for layer in &self.layers {
+            layer.capacity.write_field(&mut buffer);
+            layer.count.write_field(&mut buffer);
+            layer.hashes.write_field(&mut buffer);
+            layer.bit_count.write_field(&mut buffer);
+            let mut bits = Vec::with_capacity(layer.bits.len() * 8);
+            for word in &layer.bits {
+                bits.extend_from_slice(&word.to_be_bytes());
+            }
+            write_chunk(&mut buffer, &bits);
+        }
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<BloomFilter> {
+        let (error_rate, mut buffer) = f64::read_field(bytes).ok()?;
+        let mut layers = vec![];
+        while !buffer.is_empty() {
+            let (capacity, rest) = u64::read_field(buffer).ok()?;
+            let (count, rest) = u64::read_field(rest).ok()?;
+            let (hashes, rest) = u32::read_field(rest).ok()?;
+            let (bit_count, rest) = u64::read_field(rest).ok()?;
+            let (bits, rest) = read_chunk(rest).ok()?;
+            if bit_count == 0 || bits.len() as u64 != bit_count.div_ceil(64) * 8 {
+                return None;
+            }
+
+            layers.push(BloomLayer {
+                capacity,
+                count,
+                hashes,
+                bits: bits
+                    .chunks_exact(8)
+                    .map(|word| u64::from_be_bytes(word.try_into().expect("8 bytes")))
+                    .collect(),
+                bit_count,
+            });
+            buffer = rest;
+        }
+
+        (!layers.is_empty()).then_some(BloomFilter { error_rate, layers })
+    }
+}
+
+impl KVEngine {
+    /// Adds the elements to the HyperLogLog, creating it if the key doesn't exist.
+    /// Returns whether the estimated count may have changed.
+    pub fn hll_add(&self, key: &str, elements: &[String]) -> KVResult<bool> {
+        let growth = HyperLogLog::default().memory_size();
+
+        self.mutate_value(key, growth, |current| {
+            let Some(value) = current else {
+                let elements = elements.to_vec();
+                return Ok((Some(HllOp::Add { elements }), true));
+            };
+
+            // Most adds to a busy counter change no register, and only those that do are logged
+            let hll = value.as_hyperloglog()?;
+            let changing: Vec<String> = elements
+                .iter()
+                .filter(|element| hll.would_change(element.as_bytes()))
+                .cloned()
+                .collect();
+            if changing.is_empty() {
+                return Ok((None, false));
+            }
+
+            Ok((Some(HllOp::Add { elements: changing }), true))
+        })
+    }
+
+    /// Estimated number of distinct elements added to any of the HyperLogLogs;
+    /// keys that don't exist count as empty.
+    pub fn hll_count(&self, keys: &[String]) -> KVResult<u64> {
+        let mut union = HyperLogLog::default();
+        for key in keys {
+            self.read_value(key, |value| {
+                union.merge(value.as_hyperloglog()?);
+                Ok(())
+            })?;
+        }
+        Ok(union.count())
+    }
+
+    /// Merges the HyperLogLogs at `sources` into the one at `destination`, creating it if
+    /// needed, so it counts every element added to any of them.
+    ///
+    /// The sources are read one after the other, not at a single point in time.
+    pub fn hll_merge(&self, destination: &str, sources: &[String]) -> KVResult<()> {
+        let mut union = HyperLogLog::default();
+        for source in sources {
+            self.read_value(source, |value| {
+                union.merge(value.as_hyperloglog()?);
+                Ok(())
+            })?;
+        }
+
+        self.update_value(destination, union.memory_size(), |current| {
+            if let Some(value) = current {
+                union.merge(value.as_hyperloglog()?);
+            }
+            Ok((Update::Store(Value::HyperLogLog(union)), ()))
+        })
+    }
+
+    /// Creates an empty Bloom filter that reports items that were never added as added
+    /// with at most `error_rate` probability. Its first layer is sized for `capacity` items;
+    /// adding more allocates layers twice the size of the last one.
+    ///
+    /// Fails with `KVError::KeyExists` if the key exists.
+    pub fn bloom_reserve(&self, key: &str, error_rate: f64, capacity: u64) -> KVResult<()> {
+        // The filter is only allocated once its memory is reserved
+        let size = BloomFilter::new_size(error_rate, capacity)?;
+
+        self.update_value(key, size, |current| {
+            if current.is_some() {
+                return Err(KVError::KeyExists);
+            }
+            let filter = BloomFilter::new(error_rate, capacity)?;
+            Ok((Update::Store(Value::BloomFilter(filter)), ()))
+        })
+    }
+
+    /// Adds the item to the Bloom filter, creating one with a 1% error rate if the key doesn't
+    /// exist. Returns false if the item may have been added before.
+    pub fn bloom_add(&self, key: &str, item: &str) -> KVResult<bool> {
+        // Reserve enough for a new filter or a new layer, whichever an insert may allocate
+        let growth = self.read_value(key, |value| Ok(value.as_bloom_filter()?.insert_growth()))?;
+        let growth = match growth {
+            Some(growth) => growth,
+            None => BloomFilter::new_size(BLOOM_DEFAULT_ERROR_RATE, BLOOM_DEFAULT_CAPACITY)?,
+        };
+
+        self.mutate_value(key, growth, |current| {
+            if let Some(value) = current
+                && value.as_bloom_filter()?.contains(item.as_bytes())
+            {
+                return Ok((None, false));
+            }
+
+            let item = item.to_owned();
+            Ok((Some(BloomOp::Add { item }), true))
+        })
+    }
+
+    /// Whether the item may have been added to the Bloom filter. False if the key
+    /// doesn't exist.
+    pub fn bloom_exists(&self, key: &str, item: &str) -> KVResult<bool> {
+        let exists = self.read_value(key, |value| {
+            Ok(value.as_bloom_filter()?.contains(item.as_bytes()))
+        })?;
+        Ok(exists.unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Standard error of the estimate with 2^14 registers is 1.04 / 128, about 0.8%
+    const HLL_TOLERANCE: f64 = 3.0 * 1.04 / 128.0;
+
+    fn hll_of(range: std::ops::Range<u64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::default();
+        for i in range {
+            hll.add(format!("element-{}", i).as_bytes());
+        }
+        hll
+    }
+
+    fn assert_estimate(hll: &HyperLogLog, expected: u64) {
+        let estimate = hll.count();
+        let error = (estimate as f64 - expected as f64).abs() / expected as f64;
+        assert!(
+            error <= HLL_TOLERANCE,
+            "estimated {} for {} elements",
+            estimate,
+            expected
+        );
+    }
+
+    #[test]
+    fn hyperloglog_estimates_within_its_error_bound() {
+        assert_eq!(HyperLogLog::default().count(), 0);
+        assert_eq!(hll_of(0..1).count(), 1);
+
+        // Linear counting below 2.5 * 2^14, the raw estimate above it
+        for count in [100, 1_000, 10_000, 50_000, 200_000] {
+            assert_estimate(&hll_of(0..count), count);
+        }
+
+        // Adding the same elements again changes nothing
+        let mut hll = hll_of(0..10_000);
+        assert!((0..10_000).all(|i| !hll.would_change(format!("element-{}", i).as_bytes())));
+        let before = hll.count();
+        hll.add(b"element-0");
+        assert_eq!(hll.count(), before);
+    }
+
+    #[test]
+    fn hyperloglog_merge_counts_the_union() {
+        let mut hll = hll_of(0..30_000);
+        hll.merge(&hll_of(20_000..60_000));
+        assert_estimate(&hll, 60_000);
+
+        let decoded = HyperLogLog::decode(hll.encode().to_vec()).unwrap();
+        assert_eq!(decoded.count(), hll.count());
+    }
+
+    #[test]
+    fn bloom_filter_grows_past_its_first_layer_without_false_negatives() {
+        let error_rate = 0.01;
+        let mut filter = BloomFilter::new(error_rate, 100).unwrap();
+        let item = |i: u32| format!("item-{}", i);
+
+        let mut size = filter.memory_size();
+        for i in 0..3000 {
+            let growth = filter.insert_growth();
+            filter.insert(item(i).as_bytes());
+            assert!(filter.memory_size() <= size + growth);
+            size = filter.memory_size();
+        }
+
+        // 100 + 200 + 400 + 800 + 1600 items fill five layers
+        assert_eq!(filter.layers.len(), 5);
+        let capacities: Vec<u64> = filter.layers.iter().map(|layer| layer.capacity).collect();
+        assert_eq!(capacities, [100, 200, 400, 800, 1600]);
+        assert!(
+            filter
+                .layers
+                .iter()
+                .all(|layer| layer.count <= layer.capacity)
+        );
+
+        assert!((0..3000).all(|i| filter.contains(item(i).as_bytes())));
+        assert!((0..3000).all(|i| !filter.insert(item(i).as_bytes())));
+
+        // The layers' error rates add up to just under the filter's; the margin covers
+        // rounding the hash count up and sampling, while a slip in the bit math is far off
+        let false_positives = (3000..403_000)
+            .filter(|i| filter.contains(item(*i).as_bytes()))
+            .count();
+        let observed = false_positives as f64 / 400_000.0;
+        assert!(
+            observed < error_rate * 1.1,
+            "false positive rate {}",
+            observed
+        );
+
+        let decoded = BloomFilter::decode(&filter.encode()).unwrap();
+        assert_eq!(decoded.layers.len(), 5);
+        assert!((0..3000).all(|i| decoded.contains(item(i).as_bytes())));
+    }
+
+    #[test]
+    fn bloom_filter_options_are_bounded() {
+        assert!(BloomFilter::new_size(BLOOM_MIN_ERROR_RATE, BLOOM_MAX_CAPACITY).is_ok());
+        for (error_rate, capacity) in [
+            (0.0, 100),
+            (1.0, 100),
+            (BLOOM_MIN_ERROR_RATE / 2.0, 100),
+            (f64::NAN, 100),
+            (0.01, 0),
+            (0.01, BLOOM_MAX_CAPACITY + 1),
+        ] {
+            assert!(matches!(
+                BloomFilter::new_size(error_rate, capacity),
+                Err(KVError::InvalidFilterOptions)
+            ));
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::{Notify, mpsc};
+
+use super::KVEngine;
+
+/// A message delivered to a subscriber.
+#[derive(Debug, Clone)]
+pub struct PubSubMessage {
+    pub channel: String,
+    /// The pattern that matched, for `psubscribe` subscriptions
+    pub pattern: Option<String>,
+    pub payload: Arc<[u8]>,
+}
+
+impl PubSubMessage {
+    // Bytes counted against the subscriber's output buffer
+    fn size(&self) -> usize {
+        self.channel.len() + self.pattern.as_ref().map_or(0, String::len) + self.payload.len()
+    }
+}
+
+/// Fans published messages out to subscribers by channel name or glob pattern.
+#[derive(Debug)]
+pub(crate) struct Broker {
+    state: Mutex<BrokerState>,
+    next_id: AtomicU64,
+    // 0 means unlimited
+    output_buffer_limit: usize,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+    subscribers: HashMap<u64, SubscriberEntry>,
+}
+
+#[derive(Debug)]
+struct SubscriberEntry {
+    sender: mpsc::UnboundedSender<PubSubMessage>,
+    shared: Arc<SubscriberShared>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+#[derive(Debug, Default)]
+struct SubscriberShared {
+    // Bytes of messages queued but not received yet
+    pending: AtomicUsize,
+    // Set when the output buffer limit was exceeded
+    disconnected: AtomicBool,
+    disconnect: Notify,
+}
+
+impl BrokerState {
+    fn remove_subscriber(&mut self, id: u64) -> Option<SubscriberEntry> {
+        let entry = self.subscribers.remove(&id)?;
+
+        for channel in &entry.channels {
+            remove_from(&mut self.channels, channel, id);
+        }
+        for pattern in &entry.patterns {
+            remove_from(&mut self.patterns, pattern, id);
+        }
+
+        Some(entry)
+    }
+}
+
+fn remove_from(index: &mut HashMap<String, HashSet<u64>>, name: &str, id: u64) {
+    if let Some(ids) = index.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(name);
+        }
+    }
+}
+
+impl Broker {
+    pub(crate) fn new(output_buffer_limit: usize) -> Self {
+        Broker {
+            state: Mutex::new(BrokerState::default()),
+            next_id: AtomicU64::new(1),
+            output_buffer_limit,
+        }
+    }
+
+    fn publish(&self, channel: &str, payload: &[u8]) -> usize {
+        let payload: Arc<[u8]> = payload.into();
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+
+        let mut deliveries: Vec<(u64, Option<String>)> = state
+            .channels
+            .get(channel)
+            .into_iter()
+            .flatten()
+            .map(|id| (*id, None))
+            .collect();
+        for (pattern, ids) in &state.patterns {
+            if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                deliveries.extend(ids.iter().map(|id| (*id, Some(pattern.clone()))));
+            }
+        }
+
+        let mut receivers = 0;
+        let mut overflowed = vec![];
+
+        for (id, pattern) in deliveries {
+            let Some(entry) = state.subscribers.get(&id) else {
+                continue;
+            };
+
+            let message = PubSubMessage {
+                channel: channel.to_owned(),
+                pattern,
+                payload: payload.clone(),
+            };
+            let size = message.size();
+
+            let pending = entry.shared.pending.fetch_add(size, Ordering::Relaxed) + size;
+            if self.output_buffer_limit > 0 && pending > self.output_buffer_limit {
+                // Dropping a slow subscriber keeps publishers from stalling or buffering without bound
+                overflowed.push(id);
+                continue;
+            }
+
+            if entry.sender.send(message).is_ok() {
+                receivers += 1;
+            }
+        }
+
+        for id in overflowed {
+            if let Some(entry) = state.remove_subscriber(id) {
+                log::warn!("Subscriber {} exceeded the output buffer limit", id);
+                entry.shared.disconnected.store(true, Ordering::Relaxed);
+                entry.shared.disconnect.notify_one();
+            }
+        }
+
+        receivers
+    }
+}
+
+/// A connection's set of subscriptions. Messages are received with `recv`;
+/// dropping it unsubscribes from everything.
+#[derive(Debug)]
+pub struct Subscriber {
+    id: u64,
+    broker: Arc<Broker>,
+    receiver: mpsc::UnboundedReceiver<PubSubMessage>,
+    shared: Arc<SubscriberShared>,
+}
+
+impl Subscriber {
+    /// Subscribes to the channels. Returns the number of active subscriptions.
+    pub fn subscribe(&self, channels: &[String]) -> usize {
+        self.update(|state, id| {
+            for channel in channels {
+                if let Some(entry) = state.subscribers.get_mut(&id) {
+                    entry.channels.insert(channel.clone());
+                }
+                state
+                    .channels
+                    .entry(channel.clone())
+                    .or_default()
+                    .insert(id);
+            }
+        })
+    }
+
+    /// Subscribes to every channel matching the glob patterns (`*`, `?`, `[a-z]`, `\` escapes).
+    /// Returns the number of active subscriptions.
+    pub fn psubscribe(&self, patterns: &[String]) -> usize {
+        self.update(|state, id| {
+            for pattern in patterns {
+                if let Some(entry) = state.subscribers.get_mut(&id) {
+                    entry.patterns.insert(pattern.clone());
+                }
+                state
+                    .patterns
+                    .entry(pattern.clone())
+                    .or_default()
+                    .insert(id);
+            }
+        })
+    }
+
+    /// Unsubscribes from the channels and patterns; from everything if both are empty.
+    /// Returns the number of remaining subscriptions.
+    pub fn unsubscribe(&self, channels: &[String], patterns: &[String]) -> usize {
+        self.update(|state, id| {
+            let Some(entry) = state.subscribers.get_mut(&id) else {
+                return;
+            };
+
+            let (channels, patterns): (Vec<String>, Vec<String>) =
+                if channels.is_empty() && patterns.is_empty() {
+                    (
+                        entry.channels.drain().collect(),
+                        entry.patterns.drain().collect(),
+                    )
+                } else {
+                    for channel in channels {
+                        entry.channels.remove(channel);
+                    }
+                    for pattern in patterns {
+                        entry.patterns.remove(pattern);
+                    }
+                    (channels.to_vec(), patterns.to_vec())
+                };
+
+            for channel in &channels {
+                remove_from(&mut state.channels, channel, id);
+            }
+            for pattern in &patterns {
+                remove_from(&mut state.patterns, pattern, id);
+            }
+        })
+    }
+
+    /// Number of active channel and pattern subscriptions.
+    pub fn subscription_count(&self) -> usize {
+        self.update(|_, _| {})
+    }
+
+    /// Whether the subscriber was dropped for exceeding the output buffer limit.
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the subscriber was dropped for exceeding the output buffer limit,
+    /// so a write stuck on a client that stopped reading can be abandoned.
+    pub async fn disconnected(&self) {
+        if !self.is_disconnected() {
+            self.shared.disconnect.notified().await;
+        }
+    }
+
+    /// Waits for the next message. Returns `None` once the subscriber was
+    /// disconnected for exceeding the output buffer limit.
+    pub async fn recv(&mut self) -> Option<PubSubMessage> {
+        if self.shared.disconnected.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let message = self.receiver.recv().await?;
+        if self.shared.disconnected.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        self.shared
+            .pending
+            .fetch_sub(message.size(), Ordering::Relaxed);
+
+        Some(message)
+    }
+
+    fn update(&self, update: impl FnOnce(&mut BrokerState, u64)) -> usize {
+        let mut state = self
+            .broker
+            .state
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        update(&mut state, self.id);
+
+        state
+            .subscribers
+            .get(&self.id)
+            .map_or(0, |entry| entry.channels.len() + entry.patterns.len())
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut state = self
+            .broker
+            .state
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        state.remove_subscriber(self.id);
+    }
+}
+
+impl KVEngine {
+    /// Sends the message to every subscriber of the channel or of a matching pattern.
+    /// Returns the number of subscribers it was delivered to.
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        self.inner.broker.publish(channel, message)
+    }
+
+    /// Creates a subscriber with no subscriptions yet.
+    pub fn subscriber(&self) -> Subscriber {
+        let broker = self.inner.broker.clone();
+        let id = broker.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let shared = Arc::new(SubscriberShared::default());
+
+        broker
+            .state
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .subscribers
+            .insert(
+                id,
+                SubscriberEntry {
+                    sender,
+                    shared: shared.clone(),
+                    channels: HashSet::new(),
+                    patterns: HashSet::new(),
+                },
+            );
+
+        Subscriber {
+            id,
+            broker,
+            receiver,
+            shared,
+        }
+    }
+}
+
+/// Redis-style glob matching: `*` any run, `?` any byte, `[...]` a class
+/// (with `^` negation and `a-z` ranges), `\` escapes the next byte.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Position to resume from after the last `*`: (pattern index, text index)
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == b'[' {
+                        // Unterminated class: a literal `[`
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                byte => {
+                    if byte == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Mismatch: let the last `*` swallow one more byte
+        match backtrack {
+            Some((star, start)) => {
+                backtrack = Some((star, start + 1));
+                p = star + 1;
+                t = start + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+// Matches `byte` against the class starting at `pattern[start] == b'['`.
+// Returns whether it matched and the index after the class, or `None` if the class is unterminated.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match *pattern.get(i)? {
+            b']' => break,
+            b'\\' => {
+                matched |= *pattern.get(i + 1)? == byte;
+                i += 2;
+            }
+            low if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2) != Some(&b']') => {
+                let high = *pattern.get(i + 2)?;
+                let (low, high) = if low <= high {
+                    (low, high)
+                } else {
+                    (high, low)
+                };
+                matched |= (low..=high).contains(&byte);
+                i += 3;
+            }
+            literal => {
+                matched |= literal == byte;
+                i += 1;
+            }
+        }
+    }
+
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns_match_like_redis() {
+        let cases: &[(&str, &str, bool)] = &[
+            // Literals and wildcards
+            ("news", "news", true),
+            ("news", "newsletter", false),
+            ("news.*", "news.sports", true),
+            ("news.*", "news.", true),
+            ("news.*", "news", false),
+            ("*", "", true),
+            ("*.*.*", "a.b.c", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            // Empty pattern
+            ("", "", true),
+            ("", "a", false),
+            // Classes, ranges and negation
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("[a-c]x", "bx", true),
+            ("[c-a]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            ("[^a]x", "bx", true),
+            ("[^a]x", "ax", false),
+            ("[^a-c]", "d", true),
+            ("[a-]", "-", true),
+            ("[\\]]", "]", true),
+            // Escapes
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("a\\?", "a?", true),
+            ("a\\?", "ab", false),
+            ("a\\", "a\\", true),
+            // A `[` without a closing `]` is literal
+            ("a[", "a[", true),
+            ("a[", "ab", false),
+            ("a[b", "a[b", true),
+            // Multibyte names are matched byte by byte
+            ("café.*", "café.menu", true),
+            ("caf?", "café", false),
+            ("caf??", "café", true),
+            ("*é", "café", true),
+            ("频道.*", "频道.新闻", true),
+        ];
+
+        for &(pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::{collections::VecDeque, fmt, str::FromStr, time::Duration};
+
+use crate::protocol::WireField;
+
+use super::{KVEngine, KVError, KVResult, now_unix_millis, value::Value, write_shard};
+
+// Approximate bookkeeping cost of a limiter besides the timestamps of a sliding log
+const RATE_LIMITER_OVERHEAD: usize = 64;
+const TIMESTAMP_SIZE: usize = 8;
+
+// Algorithm of a limiter as stored in snapshots and the write-ahead log
+const ALGORITHM_FIXED_WINDOW: u32 = 0;
+const ALGORITHM_SLIDING_LOG: u32 = 1;
+const ALGORITHM_TOKEN_BUCKET: u32 = 2;
+
+/// How `KVEngine::rate_limit` counts requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAlgorithm {
+    /// `limit` requests per window, with windows aligned to multiples of its length.
+    /// Cheapest, but allows up to twice the limit around the edge of a window.
+    FixedWindow,
+    /// `limit` requests in any window ending now. Exact, but keeps a timestamp per
+    /// request allowed.
+    SlidingLog,
+    /// A bucket of `limit` tokens refilling at `limit` per window, one taken per request.
+    /// Allows bursts of up to `limit` after a quiet period.
+    TokenBucket,
+}
+
+impl fmt::Display for RateLimitAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RateLimitAlgorithm::FixedWindow => "fixed-window",
+            RateLimitAlgorithm::SlidingLog => "sliding-log",
+            RateLimitAlgorithm::TokenBucket => "token-bucket",
+        })
+    }
+}
+
+impl FromStr for RateLimitAlgorithm {
+    type Err = KVError;
+
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        match algorithm {
+            "fixed-window" => Ok(RateLimitAlgorithm::FixedWindow),
+            "sliding-log" => Ok(RateLimitAlgorithm::SlidingLog),
+            "token-bucket" => Ok(RateLimitAlgorithm::TokenBucket),
+            _ => Err(KVError::InvalidRateLimit),
+        }
+    }
+}
+
+/// Outcome of `KVEngine::rate_limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    /// Whether the request is within the limit; denied requests aren't counted
+    pub allowed: bool,
+    /// Requests still allowed right now
+    pub remaining: u64,
+    /// Time until the whole limit is available again
+    pub reset_after: Duration,
+}
+
+/// Requests counted against a key, in the shape of the algorithm last used on it.
+#[derive(Debug, Clone)]
+pub(super) enum RateLimiter {
+    FixedWindow {
+        // Unix millis at which the current window started
+        window_start: u64,
+        count: u64,
+    },
+    SlidingLog {
+        // Unix millis of the requests allowed, oldest first
+        timestamps: VecDeque<u64>,
+    },
+    TokenBucket {
+        tokens: f64,
+        // Unix millis the tokens were last refilled at
+        refilled_at: u64,
+    },
+}
+
+impl RateLimiter {
+    fn new(algorithm: RateLimitAlgorithm, limit: u64, now: u64) -> Self {
+        match algorithm {
+            RateLimitAlgorithm::FixedWindow => RateLimiter::FixedWindow {
+                window_start: 0,
+                count: 0,
+            },
+            RateLimitAlgorithm::SlidingLog => RateLimiter::SlidingLog {
+                timestamps: VecDeque::new(),
+            },
+            RateLimitAlgorithm::TokenBucket => RateLimiter::TokenBucket {
+                tokens: limit as f64,
+                refilled_at: now,
+            },
+        }
+    }
+
+    fn algorithm(&self) -> RateLimitAlgorithm {
+        match self {
+            RateLimiter::FixedWindow { .. } => RateLimitAlgorithm::FixedWindow,
+            RateLimiter::SlidingLog { .. } => RateLimitAlgorithm::SlidingLog,
+            RateLimiter::TokenBucket { .. } => RateLimitAlgorithm::TokenBucket,
+        }
+    }
+
+    /// Counts a request at `now` if it's within the limit.
+    fn acquire(&mut self, limit: u64, window: u64, now: u64) -> RateLimitStatus {
+        match self {
+            RateLimiter::FixedWindow {
+                window_start,
+                count,
+            } => {
+                let start = now - now % window;
+                if *window_start != start {
+                    *window_start = start;
+                    *count = 0;
+                }
+
+                let allowed = *count < limit;
+                if allowed {
+                    *count += 1;
+                }
+                RateLimitStatus {
+                    allowed,
+                    remaining: limit.saturating_sub(*count),
+                    reset_after: Duration::from_millis(start + window - now),
+                }
+            }
+            RateLimiter::SlidingLog { timestamps } => {
+                while timestamps
+                    .front()
+                    .is_some_and(|timestamp| timestamp + window <= now)
+                {
+                    timestamps.pop_front();
+                }
+
+                let allowed = (timestamps.len() as u64) < limit;
+                if allowed {
+                    timestamps.push_back(now);
+                }
+                let newest = timestamps.back().copied().unwrap_or(now);
+                RateLimitStatus {
+                    allowed,
+                    remaining: limit.saturating_sub(timestamps.len() as u64),
+                    reset_after: Duration::from_millis((newest + window).saturating_sub(now)),
+                }
+            }
+            RateLimiter::TokenBucket {
+                tokens,
+                refilled_at,
+            } => {
+                let per_milli = limit as f64 / window as f64;
+                let elapsed = now.saturating_sub(*refilled_at) as f64;
+                *tokens = (*tokens + elapsed * per_milli).min(limit as f64);
+                *refilled_at = now;
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                let refill = ((limit as f64 - *tokens) / per_milli).ceil() as u64;
+                RateLimitStatus {
+                    allowed,
+                    remaining: tokens.floor() as u64,
+                    reset_after: Duration::from_millis(refill),
+                }
+            }
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        RATE_LIMITER_OVERHEAD
+            + match self {
+                RateLimiter::SlidingLog { timestamps } => timestamps.len() * TIMESTAMP_SIZE,
+                RateLimiter::FixedWindow { .. } | RateLimiter::TokenBucket { .. } => 0,
+            }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        match self {
+            RateLimiter::FixedWindow {
+                window_start,
+                count,
+            } => {
+                ALGORITHM_FIXED_WINDOW.write_field(&mut buffer);
+                window_start.write_field(&mut buffer);
+                count.write_field(&mut buffer);
+            }
+            RateLimiter::SlidingLog { timestamps } => {
+                ALGORITHM_SLIDING_LOG.write_field(&mut buffer);
+                for timestamp in timestamps {
+                    timestamp.write_field(&mut buffer);
+                }
+            }
+            RateLimiter::TokenBucket {
+                tokens,
+                refilled_at,
+            } => {
+                ALGORITHM_TOKEN_BUCKET.write_field(&mut buffer);
+                tokens.write_field(&mut buffer);
+                refilled_at.write_field(&mut buffer);
+            }
+        }
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<RateLimiter> {
+        let (algorithm, buffer) = u32::read_field(bytes).ok()?;
+        let (limiter, rest) = match algorithm {
+            ALGORITHM_FIXED_WINDOW => {
+                let (window_start, rest) = u64::read_field(buffer).ok()?;
+                let (count, rest) = u64::read_field(rest).ok()?;
+                (
+                    RateLimiter::FixedWindow {
+                        window_start,
+                        count,
+                    },
+                    rest,
+                )
+            }
+            ALGORITHM_SLIDING_LOG => {
+                let mut timestamps = VecDeque::new();
+                let mut buffer = buffer;
+                while !buffer.is_empty() {
+                    let (timestamp, rest) = u64::read_field(buffer).ok()?;
+                    timestamps.push_back(timestamp);
+                    buffer = rest;
+                }
+                (RateLimiter::SlidingLog { timestamps }, buffer)
+            }
+            ALGORITHM_TOKEN_BUCKET => {
+                let (tokens, rest) = f64::read_field(buffer).ok()?;
+                let (refilled_at, rest) = u64::read_field(rest).ok()?;
+                if tokens.is_nan() {
+                    return None;
+                }
+                (
+                    RateLimiter::TokenBucket {
+                        tokens,
+                        refilled_at,
+                    },
+                    rest,
+                )
+            }
+            _ => return None,
+        };
+
+        rest.is_empty().then_some(limiter)
+    }
+}
+
+impl KVEngine {
+    /// Counts a request against the limit at `key`, atomically, and tells whether it's
+    /// allowed. Denied requests aren't counted, so a client retrying in a loop doesn't push
+    /// its own reset further away.
+    ///
+    /// The limit and window are given on every call rather than stored, so they can be
+    /// changed at any time; switching a key to another algorithm starts it over. The key
+    /// expires once the whole limit is available again.
+    ///
+    /// The count is updated in place and not written to the write-ahead log: it's short-lived
+    /// state, so only snapshots keep it, and a restart may forget requests counted since.
+    /// Counting doesn't change the key's version or notify watchers either; only creating
+    /// the limiter, or switching its algorithm, does.
+    pub fn rate_limit(
+        &self,
+        key: &str,
+        limit: u64,
+        window: Duration,
+        algorithm: RateLimitAlgorithm,
+    ) -> KVResult<RateLimitStatus> {
+        let window = window.as_millis() as u64;
+        if limit == 0 || window == 0 {
+            return Err(KVError::InvalidRateLimit);
+        }
+
+        let growth = RATE_LIMITER_OVERHEAD + TIMESTAMP_SIZE;
+        let _reservation = self.reserve_growth(key, growth)?;
+
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+
+        let current = kv.get_live_mut(key, now);
+        let current = current
+            .map(|entry| entry.value.as_rate_limiter())
+            .transpose()?;
+        if current.is_some_and(|limiter| limiter.algorithm() == algorithm) {
+            let status = kv.update_in_place(key, now, |value| match value {
+                Value::RateLimiter(limiter) => limiter.acquire(limit, window, now),
+                _ => unreachable!("checked to be a rate limiter above"),
+            });
+            let status = status.expect("checked to exist above");
+            if status.allowed {
+                let expires_at = now + status.reset_after.as_millis() as u64;
+                kv.set_expiration(key, Some(expires_at), now);
+            }
+            return Ok(status);
+        }
+
+        let mut limiter = RateLimiter::new(algorithm, limit, now);
+        let status = limiter.acquire(limit, window, now);
+        if status.allowed {
+            // Not logged, so the namespace isn't created by `log`
+            self.create_on_write()?;
+            let expires_at = now + status.reset_after.as_millis() as u64;
+            kv.insert(
+                key.to_owned(),
+                Value::RateLimiter(limiter),
+                Some(expires_at),
+                self.next_version(),
+                now,
+            );
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{events::KeyEventOp, read_shard};
+
+    fn acquire(limiter: &mut RateLimiter, limit: u64, window: u64, now: u64) -> (bool, u64, u64) {
+        let status = limiter.acquire(limit, window, now);
+        (
+            status.allowed,
+            status.remaining,
+            status.reset_after.as_millis() as u64,
+        )
+    }
+
+    #[test]
+    fn fixed_window_starts_over_at_the_next_window() {
+        let mut limiter = RateLimiter::new(RateLimitAlgorithm::FixedWindow, 2, 5000);
+
+        assert_eq!(acquire(&mut limiter, 2, 1000, 5000), (true, 1, 1000));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 5500), (true, 0, 500));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 5999), (false, 0, 1));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 6000), (true, 1, 1000));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 6001), (true, 0, 999));
+    }
+
+    #[test]
+    fn sliding_log_allows_again_once_the_oldest_request_leaves_the_window() {
+        let mut limiter = RateLimiter::new(RateLimitAlgorithm::SlidingLog, 2, 10_000);
+
+        assert_eq!(acquire(&mut limiter, 2, 1000, 10_000), (true, 1, 1000));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 10_400), (true, 0, 1000));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 10_999), (false, 0, 401));
+        assert_eq!(acquire(&mut limiter, 2, 1000, 11_000), (true, 0, 1000));
+        // Denied requests aren't logged, so they don't hold the limit back
+        assert_eq!(acquire(&mut limiter, 2, 1000, 11_100), (false, 0, 900));
+        assert_eq!(
+            limiter.memory_size(),
+            RATE_LIMITER_OVERHEAD + 2 * TIMESTAMP_SIZE
+        );
+        assert_eq!(acquire(&mut limiter, 2, 1000, 11_400), (true, 0, 1000));
+    }
+
+    #[test]
+    fn token_bucket_refills_over_the_window_up_to_the_limit() {
+        // 4 tokens per 1024ms is one every 256ms, exactly
+        let mut limiter = RateLimiter::new(RateLimitAlgorithm::TokenBucket, 4, 0);
+
+        assert_eq!(acquire(&mut limiter, 4, 1024, 0), (true, 3, 256));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 0), (true, 2, 512));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 0), (true, 1, 768));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 0), (true, 0, 1024));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 0), (false, 0, 1024));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 255), (false, 0, 769));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 256), (true, 0, 1024));
+        assert_eq!(acquire(&mut limiter, 4, 1024, 256), (false, 0, 1024));
+
+        // A long pause doesn't fill the bucket past the limit
+        assert_eq!(acquire(&mut limiter, 4, 1024, 100_000), (true, 3, 256));
+    }
+
+    #[test]
+    fn counting_keeps_the_version_and_tells_no_watchers() {
+        let engine = KVEngine::new();
+        let mut events = engine.namespace.events.subscribe();
+        let version =
+            |engine: &KVEngine| read_shard(engine.shard("api")).unwrap().entries["api"].version;
+
+        let window = Duration::from_secs(60);
+        let status = engine
+            .rate_limit("api", 2, window, RateLimitAlgorithm::FixedWindow)
+            .unwrap();
+        assert!(status.allowed);
+        let created = version(&engine);
+        assert_eq!(events.try_recv().unwrap().op, KeyEventOp::Set);
+
+        for allowed in [true, false, false] {
+            let status = engine
+                .rate_limit("api", 2, window, RateLimitAlgorithm::FixedWindow)
+                .unwrap();
+            assert_eq!(status.allowed, allowed);
+        }
+        assert_eq!(version(&engine), created);
+        assert!(events.try_recv().is_err());
+
+        // Switching the algorithm replaces the limiter
+        engine
+            .rate_limit("api", 2, window, RateLimitAlgorithm::TokenBucket)
+            .unwrap();
+        assert!(version(&engine) > created);
+        assert_eq!(events.try_recv().unwrap().op, KeyEventOp::Set);
+    }
+}
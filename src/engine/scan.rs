@@ -0,0 +1,106 @@
+use std::ops::Bound;
+
+use super::{KVEngine, KVResult, now_unix_millis, read_shard};
+
+// Upper bound for the page size of a single scan call
+const MAX_SCAN_LIMIT: usize = 10_000;
+
+/// Keys visited by a scan.
+#[derive(Debug, Clone)]
+pub enum KeyRange {
+    /// Every key starting with the prefix; an empty prefix matches every key
+    Prefix(String),
+    /// Keys from `start` (inclusive) up to `end` (exclusive); `None` leaves that side open
+    Range {
+        start: Option<String>,
+        end: Option<String>,
+    },
+}
+
+impl KeyRange {
+    fn lower_bound(&self) -> Option<&str> {
+        match self {
+            KeyRange::Prefix(prefix) => Some(prefix),
+            KeyRange::Range { start, .. } => start.as_deref(),
+        }
+    }
+
+    /// Whether a key at or past the lower bound is still inside the range.
+    /// Keys are visited in order, so the first key outside ends the scan.
+    fn contains_upper(&self, key: &str) -> bool {
+        match self {
+            KeyRange::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeyRange::Range { end, .. } => end.as_deref().is_none_or(|end| key < end),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanPage {
+    /// Keys in lexicographic order, with their values if requested
+    pub entries: Vec<(String, Option<Vec<u8>>)>,
+    /// Pass back to `scan` to get the next page; `None` once the range is exhausted
+    pub next_cursor: Option<String>,
+}
+
+impl KVEngine {
+    /// Returns up to `limit` keys of the range in lexicographic order, starting after `cursor`.
+    ///
+    /// The cursor is the last key of the previous page, so it stays valid whatever is written
+    /// in between: keys that exist for the whole scan are returned exactly once, keys added or
+    /// removed meanwhile may or may not be.
+    pub fn scan(
+        &self,
+        range: &KeyRange,
+        cursor: Option<&str>,
+        limit: usize,
+        with_values: bool,
+    ) -> KVResult<ScanPage> {
+        let limit = limit.clamp(1, MAX_SCAN_LIMIT);
+        let now = now_unix_millis();
+
+        let lower = match (range.lower_bound(), cursor) {
+            (Some(start), Some(cursor)) if cursor < start => Bound::Included(start),
+            (_, Some(cursor)) => Bound::Excluded(cursor),
+            (Some(start), None) => Bound::Included(start),
+            (None, None) => Bound::Unbounded,
+        };
+
+        // Each shard contributes its first `limit + 1` keys; the extra one tells
+        // whether anything is left after this page.
+        let mut entries = vec![];
+        for shard in self.namespace.shards.iter() {
+            let kv = read_shard(shard)?;
+
+            let keys = kv
+                .ordered
+                .range::<str, _>((lower, Bound::Unbounded))
+                .take_while(|key| range.contains_upper(key))
+                .filter_map(|key| {
+                    let entry = kv.entries.get(key).filter(|entry| !entry.is_expired(now))?;
+                    // Values of other types are left out, like missing ones
+                    let value = with_values
+                        .then(|| entry.value.as_string().ok().cloned())
+                        .flatten();
+                    Some((key.clone(), value))
+                })
+                .take(limit + 1);
+
+            entries.extend(keys);
+        }
+
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+
+        Ok(ScanPage {
+            entries,
+            next_cursor,
+        })
+    }
+}
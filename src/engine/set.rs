@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult, now_unix_millis, read_shard,
+    value::{KIND_SET, Mutation, Value, set_member_size},
+};
+
+// Mutation tags, as logged
+const OP_ADD: u32 = 0;
+const OP_REMOVE: u32 = 1;
+
+/// A write to a set, see `Mutation`.
+pub(super) enum SetOp {
+    Add { members: Vec<String> },
+    Remove { members: Vec<String> },
+}
+
+impl Mutation for SetOp {
+    const KIND: u32 = KIND_SET;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let (tag, members) = match self {
+            SetOp::Add { members } => (OP_ADD, members),
+            SetOp::Remove { members } => (OP_REMOVE, members),
+        };
+        tag.write_field(buffer);
+        members.write_field(buffer);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+        let (members, _) = Vec::<String>::read_field(rest).ok()?;
+
+        match tag {
+            OP_ADD => Some(SetOp::Add { members }),
+            OP_REMOVE => Some(SetOp::Remove { members }),
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, SetOp::Add { .. }).then(|| Value::Set(HashSet::new()))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::Set(set) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            SetOp::Add { members } => {
+                for member in members {
+                    if !set.contains(&member) {
+                        *size += set_member_size(&member);
+                        set.insert(member);
+                    }
+                }
+            }
+            SetOp::Remove { members } => {
+                for member in members {
+                    if set.remove(&member) {
+                        *size -= set_member_size(&member);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl KVEngine {
+    /// Adds the members, creating the set if the key doesn't exist.
+    /// Returns how many weren't in the set yet.
+    pub fn set_add(&self, key: &str, members: Vec<String>) -> KVResult<usize> {
+        let growth = members.iter().map(|member| set_member_size(member)).sum();
+
+        self.mutate_value(key, growth, |current| {
+            let set = current.map(Value::as_set).transpose()?;
+            let added = members
+                .iter()
+                .filter(|member| set.is_none_or(|set| !set.contains(*member)))
+                .collect::<HashSet<_>>()
+                .len();
+            if added == 0 {
+                return Ok((None, 0));
+            }
+
+            Ok((Some(SetOp::Add { members }), added))
+        })
+    }
+
+    /// Removes the members. Returns how many were in the set; the key is deleted
+    /// once the set is empty.
+    pub fn set_remove(&self, key: &str, members: &[String]) -> KVResult<usize> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Ok((None, 0));
+            };
+            let set = value.as_set()?;
+            let removed = members
+                .iter()
+                .filter(|member| set.contains(*member))
+                .collect::<HashSet<_>>()
+                .len();
+            if removed == 0 {
+                return Ok((None, 0));
+            }
+
+            let members = members.to_vec();
+            Ok((Some(SetOp::Remove { members }), removed))
+        })
+    }
+
+    pub fn set_is_member(&self, key: &str, member: &str) -> KVResult<bool> {
+        let is_member = self.read_value(key, |value| Ok(value.as_set()?.contains(member)))?;
+        Ok(is_member.unwrap_or(false))
+    }
+
+    /// Every member, in no particular order.
+    pub fn set_members(&self, key: &str) -> KVResult<Vec<String>> {
+        let members =
+            self.read_value(key, |value| Ok(value.as_set()?.iter().cloned().collect()))?;
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Members of every one of the sets. A missing key counts as an empty set.
+    pub fn set_intersection(&self, keys: &[String]) -> KVResult<Vec<String>> {
+        self.combine_sets(keys, |sets| {
+            let Some((first, rest)) = sets.split_first() else {
+                return vec![];
+            };
+            first
+                .iter()
+                .filter(|member| rest.iter().all(|set| set.contains(*member)))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Members of any of the sets.
+    pub fn set_union(&self, keys: &[String]) -> KVResult<Vec<String>> {
+        self.combine_sets(keys, |sets| {
+            let union: HashSet<&String> = sets.iter().flat_map(|set| set.iter()).collect();
+            union.into_iter().cloned().collect()
+        })
+    }
+
+    /// Members of the first set that are in none of the others.
+    pub fn set_difference(&self, keys: &[String]) -> KVResult<Vec<String>> {
+        self.combine_sets(keys, |sets| {
+            let Some((first, rest)) = sets.split_first() else {
+                return vec![];
+            };
+            first
+                .iter()
+                .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Runs `combine` on the sets at `keys`, in order. Every shard involved is locked once,
+    /// so the sets are a consistent point in time.
+    fn combine_sets(
+        &self,
+        keys: &[String],
+        combine: impl FnOnce(&[&HashSet<String>]) -> Vec<String>,
+    ) -> KVResult<Vec<String>> {
+        let (indexes, lock_order) = self.batch_shards(keys.iter().map(String::as_str));
+        let now = now_unix_millis();
+
+        let shards = lock_order
+            .into_iter()
+            .map(|index| Ok((index, read_shard(&self.namespace.shards[index])?)))
+            .collect::<KVResult<BTreeMap<_, _>>>()?;
+
+        let empty = HashSet::new();
+        let sets = keys
+            .iter()
+            .zip(indexes)
+            .map(|(key, index)| {
+                let entry = shards[&index]
+                    .entries
+                    .get(key)
+                    .filter(|entry| !entry.is_expired(now));
+                match entry {
+                    Some(entry) => {
+                        entry.touch(now);
+                        entry.value.as_set()
+                    }
+                    None => Ok(&empty),
+                }
+            })
+            .collect::<KVResult<Vec<_>>>()?;
+
+        Ok(combine(&sets))
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::protocol::{WireField, wire_struct};
+
+// File layout:
+//   MAGIC (8 bytes) | VERSION (u32) | RECORD COUNT (u64) | RECORDS... | CRC32 of everything before (u32)
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RSTORE\0\0";
+const SNAPSHOT_VERSION: u32 = 1;
+const SNAPSHOT_HEADER_SIZE: usize = 8 + 4 + 8;
+const SNAPSHOT_CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not a snapshot file")]
+    InvalidMagic,
+    #[error("Unsupported snapshot version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Snapshot checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Snapshot is corrupted: {0}")]
+    Corrupted(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotRecord {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub version: u64,
+    // See `Value::kind`
+    pub kind: u32,
+    pub namespace: String,
+}
+
+wire_struct!(SnapshotRecord {
+    key,
+    value,
+    expires_at,
+    version,
+    kind,
+    namespace
+});
+
+/// Writes the records next to `path` and atomically renames the file into place,
+/// so a crash mid-write never leaves a truncated snapshot behind.
+pub(crate) fn write_snapshot(path: &Path, records: &[SnapshotRecord]) -> Result<(), SnapshotError> {
+    let temp_path = path.with_extension("tmp");
+
+    {
+        let file = File::create(&temp_path)?;
+        let mut writer = ChecksumWriter::new(BufWriter::new(file));
+
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        writer.write_all(&(records.len() as u64).to_be_bytes())?;
+
+        let mut buffer = Vec::new();
+        for record in records {
+            buffer.clear();
+            record.write_field(&mut buffer);
+            writer.write_all(&buffer)?;
+        }
+
+        let (mut writer, checksum) = writer.finish();
+        writer.write_all(&checksum.to_be_bytes())?;
+
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+    }
+
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+/// Reads every record of the snapshot. Returns `None` if the file doesn't exist.
+pub(crate) fn read_snapshot(path: &Path) -> Result<Option<Vec<SnapshotRecord>>, SnapshotError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    if bytes.len() < SNAPSHOT_HEADER_SIZE + SNAPSHOT_CHECKSUM_SIZE
+        || !bytes.starts_with(SNAPSHOT_MAGIC)
+    {
+        return Err(SnapshotError::InvalidMagic);
+    }
+
+    let version = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - SNAPSHOT_CHECKSUM_SIZE);
+    if crc32fast::hash(content) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let record_count = u64::from_be_bytes(content[12..20].try_into().unwrap());
+
+    let mut records = Vec::with_capacity(record_count as usize);
+    let mut buffer = &content[SNAPSHOT_HEADER_SIZE..];
+    while !buffer.is_empty() {
+        let (record, rest) = SnapshotRecord::read_field(buffer)
+            .map_err(|error| SnapshotError::Corrupted(error.to_string()))?;
+        records.push(record);
+        buffer = rest;
+    }
+
+    if records.len() as u64 != record_count {
+        return Err(SnapshotError::Corrupted(format!(
+            "expected {} records, found {}",
+            record_count,
+            records.len()
+        )));
+    }
+
+    Ok(Some(records))
+}
+
+/// Computes the CRC32 of everything written through it.
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn finish(self) -> (W, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, RandomState},
+};
+
+use crate::protocol::WireField;
+
+use super::{
+    KVEngine, KVError, KVResult,
+    value::{KIND_SORTED_SET, Mutation, Value, sorted_set_member_size},
+};
+
+// Mutation tags, as logged
+const OP_ADD: u32 = 0;
+const OP_REMOVE: u32 = 1;
+
+/// Members ordered by score, then by member, with O(log n) rank and index lookups.
+///
+/// A treap whose nodes also count their subtree, so the position of a member
+/// is found on the way down instead of by walking the members before it.
+#[derive(Debug, Clone, Default)]
+pub(super) struct SortedSet {
+    scores: HashMap<String, f64>,
+    root: Link,
+    // Priorities are hashes of the members, keyed per process so they can't be aimed at
+    priorities: RandomState,
+}
+
+type Link = Option<Box<Node>>;
+
+#[derive(Debug, Clone)]
+struct Node {
+    score: f64,
+    member: String,
+    priority: u64,
+    // Nodes in this subtree, including this one
+    size: usize,
+    left: Link,
+    right: Link,
+}
+
+fn size(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+fn compare(score: f64, member: &str, node: &Node) -> Ordering {
+    score
+        .total_cmp(&node.score)
+        .then_with(|| member.cmp(&node.member))
+}
+
+impl Node {
+    fn update_size(&mut self) {
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+// Splits into the nodes ordered before (score, member) and the rest.
+fn split(link: Link, score: f64, member: &str) -> (Link, Link) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+
+    if compare(score, member, &node) == Ordering::Greater {
+        let (left, right) = split(node.right.take(), score, member);
+        node.right = left;
+        node.update_size();
+        (Some(node), right)
+    } else {
+        let (left, right) = split(node.left.take(), score, member);
+        node.left = right;
+        node.update_size();
+        (left, Some(node))
+    }
+}
+
+// Joins two treaps where every node of `left` is ordered before every node of `right`.
+fn merge(left: Link, right: Link) -> Link {
+    match (left, right) {
+        (None, right) => right,
+        (left, None) => left,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority > right.priority {
+                left.right = merge(left.right.take(), Some(right));
+                left.update_size();
+                Some(left)
+            } else {
+                right.left = merge(Some(left), right.left.take());
+                right.update_size();
+                Some(right)
+            }
+        }
+    }
+}
+
+fn remove(link: &mut Link, score: f64, member: &str) {
+    let Some(node) = link else {
+        return;
+    };
+
+    match compare(score, member, node) {
+        Ordering::Less => remove(&mut node.left, score, member),
+        Ordering::Greater => remove(&mut node.right, score, member),
+        Ordering::Equal => {
+            let left = node.left.take();
+            let right = node.right.take();
+            *link = merge(left, right);
+            return;
+        }
+    }
+
+    node.update_size();
+}
+
+// Appends the nodes at positions `start..end` of the subtree, in order.
+fn collect(link: &Link, start: usize, end: usize, out: &mut Vec<(String, f64)>) {
+    let Some(node) = link else {
+        return;
+    };
+    if start >= end {
+        return;
+    }
+
+    let left_size = size(&node.left);
+    if start < left_size {
+        collect(&node.left, start, end.min(left_size), out);
+    }
+    if start <= left_size && left_size < end {
+        out.push((node.member.clone(), node.score));
+    }
+    if end > left_size + 1 {
+        collect(
+            &node.right,
+            start.saturating_sub(left_size + 1),
+            end - left_size - 1,
+            out,
+        );
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &f64)> {
+        self.scores.iter()
+    }
+
+    /// Sets the member's score. Returns whether the member was added rather than updated.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let added = match self.scores.insert(member.clone(), score) {
+            Some(previous) => {
+                remove(&mut self.root, previous, &member);
+                false
+            }
+            None => true,
+        };
+
+        let (left, right) = split(self.root.take(), score, &member);
+        let node = Box::new(Node {
+            score,
+            priority: self.priorities.hash_one(&member),
+            member,
+            size: 1,
+            left: None,
+            right: None,
+        });
+        self.root = merge(merge(left, Some(node)), right);
+
+        added
+    }
+
+    /// Returns whether the member existed.
+    pub fn remove(&mut self, member: &str) -> bool {
+        let Some(score) = self.scores.remove(member) else {
+            return false;
+        };
+
+        remove(&mut self.root, score, member);
+        true
+    }
+
+    /// Position of the member, counting from the lowest score.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+
+        let mut rank = 0;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            match compare(score, member, node) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => {
+                    rank += size(&node.left) + 1;
+                    link = &node.right;
+                }
+                Ordering::Equal => return Some(rank + size(&node.left)),
+            }
+        }
+
+        None
+    }
+
+    /// Number of members with a score below `score`, or not above it if `inclusive`.
+    fn count_below(&self, score: f64, inclusive: bool) -> usize {
+        let mut count = 0;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let below = match score.total_cmp(&node.score) {
+                Ordering::Greater => true,
+                Ordering::Equal => inclusive,
+                Ordering::Less => false,
+            };
+
+            if below {
+                count += size(&node.left) + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+
+        count
+    }
+
+    /// Members at positions `start..end`, in order.
+    pub fn range(&self, start: usize, end: usize) -> Vec<(String, f64)> {
+        let end = end.min(self.len());
+        let mut members = Vec::with_capacity(end.saturating_sub(start));
+        collect(&self.root, start, end, &mut members);
+        members
+    }
+
+    /// Members with a score from `min` to `max`, both inclusive, in order.
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<(String, f64)> {
+        let start = self.count_below(min, false);
+        let end = self.count_below(max, true);
+        self.range(start, end)
+    }
+}
+
+/// A write to a sorted set, see `Mutation`.
+pub(super) enum SortedSetOp {
+    /// Sets the scores, adding the members that aren't in the set
+    Add {
+        members: Vec<(String, f64)>,
+    },
+    Remove {
+        members: Vec<String>,
+    },
+}
+
+impl Mutation for SortedSetOp {
+    const KIND: u32 = KIND_SORTED_SET;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            SortedSetOp::Add { members } => {
+                OP_ADD.write_field(buffer);
+                for (member, score) in members {
+                    member.write_field(buffer);
+                    score.write_field(buffer);
+                }
+            }
+            SortedSetOp::Remove { members } => {
+                OP_REMOVE.write_field(buffer);
+                for member in members {
+                    member.write_field(buffer);
+                }
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, mut rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_ADD => {
+                let mut members = vec![];
+                while !rest.is_empty() {
+                    let (member, next) = String::read_field(rest).ok()?;
+                    let (score, next) = f64::read_field(next).ok()?;
+                    members.push((member, check_score(score).ok()?));
+                    rest = next;
+                }
+                Some(SortedSetOp::Add { members })
+            }
+            OP_REMOVE => {
+                let mut members = vec![];
+                while !rest.is_empty() {
+                    let (member, next) = String::read_field(rest).ok()?;
+                    members.push(member);
+                    rest = next;
+                }
+                Some(SortedSetOp::Remove { members })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, SortedSetOp::Add { .. }).then(|| Value::SortedSet(SortedSet::default()))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::SortedSet(set) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            SortedSetOp::Add { members } => {
+                for (member, score) in members {
+                    let member_size = sorted_set_member_size(&member);
+                    if set.insert(member, score) {
+                        *size += member_size;
+                    }
+                }
+            }
+            SortedSetOp::Remove { members } => {
+                for member in members {
+                    if set.remove(&member) {
+                        *size -= sorted_set_member_size(&member);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolves Redis-style inclusive indexes, negative ones counting from the end,
+/// to positions `start..end`.
+fn resolve_range(len: usize, start: i64, stop: i64) -> (usize, usize) {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.clamp(0, len);
+    let stop = if stop < 0 { len + stop } else { stop }.clamp(-1, len - 1);
+
+    (start as usize, (stop + 1).max(start) as usize)
+}
+
+// Rejects NaN, which has no place in the order, and folds -0.0 into 0.0 so that
+// equal scores compare equal
+fn check_score(score: f64) -> KVResult<f64> {
+    if score.is_nan() {
+        return Err(KVError::NotAFloat);
+    }
+    Ok(score + 0.0)
+}
+
+impl KVEngine {
+    /// Sets the scores of the members, creating the sorted set if the key doesn't exist.
+    /// Returns how many members were added rather than updated.
+    pub fn sorted_set_add(&self, key: &str, members: Vec<(String, f64)>) -> KVResult<usize> {
+        let members = members
+            .into_iter()
+            .map(|(member, score)| Ok((member, check_score(score)?)))
+            .collect::<KVResult<Vec<_>>>()?;
+        let growth = members
+            .iter()
+            .map(|(member, _)| sorted_set_member_size(member))
+            .sum();
+
+        self.mutate_value(key, growth, |current| {
+            let set = current.map(Value::as_sorted_set).transpose()?;
+            if members.is_empty() {
+                return Ok((None, 0));
+            }
+
+            let added = members
+                .iter()
+                .map(|(member, _)| member)
+                .filter(|member| set.is_none_or(|set| set.score(member).is_none()))
+                .collect::<HashSet<_>>()
+                .len();
+
+            Ok((Some(SortedSetOp::Add { members }), added))
+        })
+    }
+
+    /// Adds `delta` to the member's score, a missing member counting as 0.
+    /// Returns the new score.
+    pub fn sorted_set_incr_by(&self, key: &str, member: &str, delta: f64) -> KVResult<f64> {
+        check_score(delta)?;
+
+        self.mutate_value(key, sorted_set_member_size(member), |current| {
+            let set = current.map(Value::as_sorted_set).transpose()?;
+            let score = set.and_then(|set| set.score(member)).unwrap_or(0.0);
+            let score = check_score(score + delta)?;
+
+            let members = vec![(member.to_owned(), score)];
+            Ok((Some(SortedSetOp::Add { members }), score))
+        })
+    }
+
+    /// Members and scores from `start` to `stop`, both inclusive; negative indexes count
+    /// from the end. `reverse` orders from the highest score.
+    pub fn sorted_set_range(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+        reverse: bool,
+    ) -> KVResult<Vec<(String, f64)>> {
+        let members = self.read_value(key, |value| {
+            let set = value.as_sorted_set()?;
+            let (start, end) = resolve_range(set.len(), start, stop);
+
+            if !reverse {
+                return Ok(set.range(start, end));
+            }
+
+            let mut members = set.range(set.len() - end, set.len() - start);
+            members.reverse();
+            Ok(members)
+        })?;
+
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Members with a score from `min` to `max`, both inclusive, lowest first.
+    pub fn sorted_set_range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+    ) -> KVResult<Vec<(String, f64)>> {
+        let min = check_score(min)?;
+        let max = check_score(max)?;
+
+        let members = self.read_value(key, |value| {
+            Ok(value.as_sorted_set()?.range_by_score(min, max))
+        })?;
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Position of the member from the lowest score, or from the highest if `reverse`.
+    /// `None` if the member or the key doesn't exist.
+    pub fn sorted_set_rank(
+        &self,
+        key: &str,
+        member: &str,
+        reverse: bool,
+    ) -> KVResult<Option<usize>> {
+        let rank = self.read_value(key, |value| {
+            let set = value.as_sorted_set()?;
+            Ok(set
+                .rank(member)
+                .map(|rank| if reverse { set.len() - 1 - rank } else { rank }))
+        })?;
+
+        Ok(rank.flatten())
+    }
+
+    /// Removes the members. Returns how many existed; the key is deleted once the set is empty.
+    pub fn sorted_set_remove(&self, key: &str, members: &[String]) -> KVResult<usize> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Ok((None, 0));
+            };
+            let set = value.as_sorted_set()?;
+            let removed = members
+                .iter()
+                .filter(|member| set.score(member).is_some())
+                .collect::<HashSet<_>>()
+                .len();
+            if removed == 0 {
+                return Ok((None, 0));
+            }
+
+            let members = members.to_vec();
+            Ok((Some(SortedSetOp::Remove { members }), removed))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Members in order, the way the treap should hold them
+    fn sorted(members: &HashMap<String, f64>) -> Vec<(String, f64)> {
+        let mut members: Vec<_> = members
+            .iter()
+            .map(|(member, score)| (member.clone(), *score))
+            .collect();
+        members.sort_by(|(a, a_score), (b, b_score)| a_score.total_cmp(b_score).then(a.cmp(b)));
+        members
+    }
+
+    fn check(set: &SortedSet, expected: &HashMap<String, f64>) {
+        let expected = sorted(expected);
+        assert_eq!(set.len(), expected.len());
+        assert_eq!(size(&set.root), expected.len());
+        assert_eq!(set.range(0, usize::MAX), expected);
+
+        for (rank, (member, _)) in expected.iter().enumerate() {
+            assert_eq!(set.rank(member), Some(rank));
+            assert_eq!(set.range(rank, rank + 1), [expected[rank].clone()]);
+        }
+    }
+
+    #[test]
+    fn keeps_ranks_and_ranges_through_inserts_updates_and_removes() {
+        let mut set = SortedSet::default();
+        let mut expected = HashMap::new();
+
+        // xorshift, so the run is the same every time
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..2000 {
+            let member = format!("m{}", next() % 300);
+            // Few distinct scores, so members often tie on score
+            let score = (next() % 20) as f64 - 10.0;
+            match next() % 4 {
+                0 => assert_eq!(set.remove(&member), expected.remove(&member).is_some()),
+                _ => assert_eq!(
+                    set.insert(member.clone(), score),
+                    expected.insert(member, score).is_none()
+                ),
+            }
+        }
+
+        check(&set, &expected);
+        assert_eq!(set.rank("missing"), None);
+    }
+
+    #[test]
+    fn ranges_by_score_include_both_bounds() {
+        let mut set = SortedSet::default();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0), ("e", 4.0)] {
+            set.insert(member.to_owned(), score);
+        }
+
+        let members = |min, max| -> Vec<String> {
+            set.range_by_score(min, max)
+                .into_iter()
+                .map(|(member, _)| member)
+                .collect()
+        };
+        assert_eq!(members(2.0, 3.0), ["b", "c", "d"]);
+        assert_eq!(members(1.5, 2.5), ["b", "c"]);
+        assert_eq!(members(f64::NEG_INFINITY, 1.0), ["a"]);
+        assert_eq!(members(4.0, f64::INFINITY), ["e"]);
+        assert!(members(2.5, 2.9).is_empty());
+        assert!(members(3.0, 2.0).is_empty());
+
+        assert_eq!(set.range(3, 10), [("d".into(), 3.0), ("e".into(), 4.0)]);
+        assert!(set.range(5, 10).is_empty());
+    }
+}
@@ -0,0 +1,889 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Bound,
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::protocol::{WireField, read_chunk, write_chunk};
+
+use super::{
+    KVEngine, KVError, KVResult, now_unix_millis,
+    value::{KIND_STREAM, Mutation, Value},
+};
+
+// Approximate bookkeeping cost of one stream entry besides its fields: the ID and the tree slot
+const STREAM_ENTRY_OVERHEAD: usize = 64;
+// Approximate bookkeeping cost of one stream entry field besides its name and value
+const STREAM_FIELD_OVERHEAD: usize = 48;
+// Approximate bookkeeping cost of one pending entry besides the consumer name
+const PENDING_ENTRY_OVERHEAD: usize = 64;
+
+// Mutation tags, as logged
+const OP_ADD: u32 = 0;
+const OP_CREATE_GROUP: u32 = 1;
+const OP_DELIVER: u32 = 2;
+const OP_ACK: u32 = 3;
+const OP_CLAIM: u32 = 4;
+
+/// ID of a stream entry: the unix time in milliseconds it was added at, and a sequence
+/// number telling apart entries added in the same millisecond. Written as `millis-seq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub millis: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { millis: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        millis: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(millis: u64, seq: u64) -> Self {
+        Self { millis, seq }
+    }
+
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        self.millis.write_field(buffer);
+        self.seq.write_field(buffer);
+    }
+
+    fn read_field(buffer: &[u8]) -> Option<(Self, &[u8])> {
+        let (millis, buffer) = u64::read_field(buffer).ok()?;
+        let (seq, buffer) = u64::read_field(buffer).ok()?;
+        Some((Self { millis, seq }, buffer))
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.millis, self.seq)
+    }
+}
+
+/// Parses `millis-seq`, or just `millis` for sequence 0.
+impl FromStr for StreamId {
+    type Err = KVError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (millis, seq) = value.split_once('-').unwrap_or((value, "0"));
+        let millis = millis.parse().map_err(|_| KVError::InvalidStreamId)?;
+        let seq = seq.parse().map_err(|_| KVError::InvalidStreamId)?;
+        Ok(Self { millis, seq })
+    }
+}
+
+/// An entry of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(String, Vec<u8>)>,
+}
+
+/// An entry delivered to a consumer of a group and not acknowledged yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub consumer: String,
+    /// Time since the entry was last delivered
+    pub idle: Duration,
+    /// How many times the entry was delivered, claims included
+    pub deliveries: u64,
+}
+
+/// An append-only log of entries, each a list of fields, with consumer groups that
+/// share out the entries among their consumers.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Stream {
+    entries: BTreeMap<StreamId, Vec<(String, Vec<u8>)>>,
+    // Highest ID ever added, so IDs keep increasing
+    last_id: StreamId,
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ConsumerGroup {
+    // Highest ID delivered to any consumer of the group
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, Delivery>,
+}
+
+#[derive(Debug, Clone)]
+struct Delivery {
+    consumer: String,
+    // Unix timestamp in milliseconds
+    delivered_at: u64,
+    deliveries: u64,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Bytes accounted to the stream.
+    pub fn memory_size(&self) -> usize {
+        let entries: usize = self.entries.values().map(|fields| entry_size(fields)).sum();
+        let pending: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                name.len()
+                    + group
+                        .pending
+                        .values()
+                        .map(|delivery| delivery.consumer.len() + PENDING_ENTRY_OVERHEAD)
+                        .sum::<usize>()
+            })
+            .sum();
+        entries + pending
+    }
+
+    /// Entries from `start` to `end`, both inclusive, at most `count` of them.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>) -> Vec<StreamEntry> {
+        if start > end {
+            return vec![];
+        }
+
+        self.entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| StreamEntry {
+                id: *id,
+                fields: fields.clone(),
+            })
+            .collect()
+    }
+
+    /// Entries with an ID above `after`, at most `count` of them.
+    fn after(&self, after: StreamId, count: Option<usize>) -> Vec<StreamEntry> {
+        self.entries
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| StreamEntry {
+                id: *id,
+                fields: fields.clone(),
+            })
+            .collect()
+    }
+
+    fn group(&self, group: &str) -> KVResult<&ConsumerGroup> {
+        self.groups.get(group).ok_or(KVError::NoSuchGroup)
+    }
+
+    fn group_mut(&mut self, group: &str) -> KVResult<&mut ConsumerGroup> {
+        self.groups.get_mut(group).ok_or(KVError::NoSuchGroup)
+    }
+
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        self.last_id.write_field(buffer);
+
+        let mut entries = vec![];
+        for (id, fields) in &self.entries {
+            id.write_field(&mut entries);
+            let mut chunk = vec![];
+            for (field, value) in fields {
+                write_chunk(&mut chunk, field.as_bytes());
+                write_chunk(&mut chunk, value);
+            }
+            write_chunk(&mut entries, &chunk);
+        }
+        write_chunk(buffer, &entries);
+
+        for (name, group) in &self.groups {
+            write_chunk(buffer, name.as_bytes());
+            group.last_delivered.write_field(buffer);
+            let mut pending = vec![];
+            for (id, delivery) in &group.pending {
+                id.write_field(&mut pending);
+                write_chunk(&mut pending, delivery.consumer.as_bytes());
+                delivery.delivered_at.write_field(&mut pending);
+                delivery.deliveries.write_field(&mut pending);
+            }
+            write_chunk(buffer, &pending);
+        }
+    }
+
+    pub fn decode(buffer: &[u8]) -> Option<Stream> {
+        let mut stream = Stream::default();
+        let (last_id, buffer) = StreamId::read_field(buffer)?;
+        stream.last_id = last_id;
+
+        let (mut entries, mut buffer) = read_chunk(buffer).ok()?;
+        while !entries.is_empty() {
+            let (id, rest) = StreamId::read_field(entries)?;
+            let (mut chunk, rest) = read_chunk(rest).ok()?;
+            let mut fields = vec![];
+            while !chunk.is_empty() {
+                let (field, next) = read_chunk(chunk).ok()?;
+                let (value, next) = read_chunk(next).ok()?;
+                fields.push((String::from_utf8(field.to_vec()).ok()?, value.to_vec()));
+                chunk = next;
+            }
+            stream.entries.insert(id, fields);
+            entries = rest;
+        }
+
+        while !buffer.is_empty() {
+            let (name, rest) = read_chunk(buffer).ok()?;
+            let (last_delivered, rest) = StreamId::read_field(rest)?;
+            let (mut pending, rest) = read_chunk(rest).ok()?;
+            let mut group = ConsumerGroup {
+                last_delivered,
+                pending: BTreeMap::new(),
+            };
+            while !pending.is_empty() {
+                let (id, next) = StreamId::read_field(pending)?;
+                let (consumer, next) = read_chunk(next).ok()?;
+                let (delivered_at, next) = u64::read_field(next).ok()?;
+                let (deliveries, next) = u64::read_field(next).ok()?;
+                group.pending.insert(
+                    id,
+                    Delivery {
+                        consumer: String::from_utf8(consumer.to_vec()).ok()?,
+                        delivered_at,
+                        deliveries,
+                    },
+                );
+                pending = next;
+            }
+            stream
+                .groups
+                .insert(String::from_utf8(name.to_vec()).ok()?, group);
+            buffer = rest;
+        }
+
+        Some(stream)
+    }
+}
+
+fn write_ids(ids: &[StreamId], buffer: &mut Vec<u8>) {
+    let mut chunk = vec![];
+    for id in ids {
+        id.write_field(&mut chunk);
+    }
+    write_chunk(buffer, &chunk);
+}
+
+fn read_ids(buffer: &[u8]) -> Option<(Vec<StreamId>, &[u8])> {
+    let (mut chunk, rest) = read_chunk(buffer).ok()?;
+    let mut ids = vec![];
+    while !chunk.is_empty() {
+        let (id, next) = StreamId::read_field(chunk)?;
+        ids.push(id);
+        chunk = next;
+    }
+    Some((ids, rest))
+}
+
+/// A write to a stream, see `Mutation`. Generated IDs and delivery times are resolved
+/// before the mutation is logged.
+pub(super) enum StreamOp {
+    Add {
+        id: StreamId,
+        fields: Vec<(String, Vec<u8>)>,
+    },
+    CreateGroup {
+        group: String,
+        last_delivered: StreamId,
+    },
+    /// Makes the entries pending for the consumer, the last one becoming the group's
+    /// last delivered entry
+    Deliver {
+        group: String,
+        consumer: String,
+        delivered_at: u64,
+        ids: Vec<StreamId>,
+    },
+    Ack {
+        group: String,
+        ids: Vec<StreamId>,
+    },
+    /// Hands pending entries over to the consumer
+    Claim {
+        group: String,
+        consumer: String,
+        delivered_at: u64,
+        ids: Vec<StreamId>,
+    },
+}
+
+impl Mutation for StreamOp {
+    const KIND: u32 = KIND_STREAM;
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            StreamOp::Add { id, fields } => {
+                OP_ADD.write_field(buffer);
+                id.write_field(buffer);
+                for (field, value) in fields {
+                    field.write_field(buffer);
+                    value.write_field(buffer);
+                }
+            }
+            StreamOp::CreateGroup {
+                group,
+                last_delivered,
+            } => {
+                OP_CREATE_GROUP.write_field(buffer);
+                group.write_field(buffer);
+                last_delivered.write_field(buffer);
+            }
+            StreamOp::Deliver {
+                group,
+                consumer,
+                delivered_at,
+                ids,
+            } => {
+                OP_DELIVER.write_field(buffer);
+                group.write_field(buffer);
+                consumer.write_field(buffer);
+                delivered_at.write_field(buffer);
+                write_ids(ids, buffer);
+            }
+            StreamOp::Ack { group, ids } => {
+                OP_ACK.write_field(buffer);
+                group.write_field(buffer);
+                write_ids(ids, buffer);
+            }
+            StreamOp::Claim {
+                group,
+                consumer,
+                delivered_at,
+                ids,
+            } => {
+                OP_CLAIM.write_field(buffer);
+                group.write_field(buffer);
+                consumer.write_field(buffer);
+                delivered_at.write_field(buffer);
+                write_ids(ids, buffer);
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, rest) = u32::read_field(bytes).ok()?;
+
+        match tag {
+            OP_ADD => {
+                let (id, mut rest) = StreamId::read_field(rest)?;
+                let mut fields = vec![];
+                while !rest.is_empty() {
+                    let (field, next) = String::read_field(rest).ok()?;
+                    let (value, next) = Vec::<u8>::read_field(next).ok()?;
+                    fields.push((field, value));
+                    rest = next;
+                }
+                Some(StreamOp::Add { id, fields })
+            }
+            OP_CREATE_GROUP => {
+                let (group, rest) = String::read_field(rest).ok()?;
+                let (last_delivered, _) = StreamId::read_field(rest)?;
+                Some(StreamOp::CreateGroup {
+                    group,
+                    last_delivered,
+                })
+            }
+            OP_DELIVER | OP_CLAIM => {
+                let (group, rest) = String::read_field(rest).ok()?;
+                let (consumer, rest) = String::read_field(rest).ok()?;
+                let (delivered_at, rest) = u64::read_field(rest).ok()?;
+                let (ids, _) = read_ids(rest)?;
+                if tag == OP_DELIVER {
+                    Some(StreamOp::Deliver {
+                        group,
+                        consumer,
+                        delivered_at,
+                        ids,
+                    })
+                } else {
+                    Some(StreamOp::Claim {
+                        group,
+                        consumer,
+                        delivered_at,
+                        ids,
+                    })
+                }
+            }
+            OP_ACK => {
+                let (group, rest) = String::read_field(rest).ok()?;
+                let (ids, _) = read_ids(rest)?;
+                Some(StreamOp::Ack { group, ids })
+            }
+            _ => None,
+        }
+    }
+
+    fn create(&self) -> Option<Value> {
+        matches!(self, StreamOp::Add { .. } | StreamOp::CreateGroup { .. })
+            .then(|| Value::Stream(Stream::default()))
+    }
+
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()> {
+        let Value::Stream(stream) = value else {
+            return Err(KVError::WrongType);
+        };
+
+        match self {
+            StreamOp::Add { id, fields } => {
+                *size += entry_size(&fields);
+                if let Some(previous) = stream.entries.insert(id, fields) {
+                    *size -= entry_size(&previous);
+                }
+                stream.last_id = stream.last_id.max(id);
+            }
+            StreamOp::CreateGroup {
+                group,
+                last_delivered,
+            } => {
+                if stream.groups.contains_key(&group) {
+                    return Err(KVError::GroupExists);
+                }
+                *size += group.len();
+                stream.groups.insert(
+                    group,
+                    ConsumerGroup {
+                        last_delivered,
+                        pending: BTreeMap::new(),
+                    },
+                );
+            }
+            StreamOp::Deliver {
+                group,
+                consumer,
+                delivered_at,
+                ids,
+            } => {
+                let group = stream.group_mut(&group)?;
+                for id in ids {
+                    group.last_delivered = group.last_delivered.max(id);
+                    let delivery = Delivery {
+                        consumer: consumer.clone(),
+                        delivered_at,
+                        deliveries: 1,
+                    };
+                    *size += consumer.len() + PENDING_ENTRY_OVERHEAD;
+                    if let Some(previous) = group.pending.insert(id, delivery) {
+                        *size -= previous.consumer.len() + PENDING_ENTRY_OVERHEAD;
+                    }
+                }
+            }
+            StreamOp::Ack { group, ids } => {
+                let pending = &mut stream.group_mut(&group)?.pending;
+                for id in ids {
+                    if let Some(delivery) = pending.remove(&id) {
+                        *size -= delivery.consumer.len() + PENDING_ENTRY_OVERHEAD;
+                    }
+                }
+            }
+            StreamOp::Claim {
+                group,
+                consumer,
+                delivered_at,
+                ids,
+            } => {
+                let pending = &mut stream.group_mut(&group)?.pending;
+                for id in ids {
+                    let Some(delivery) = pending.get_mut(&id) else {
+                        continue;
+                    };
+                    *size += consumer.len();
+                    *size -= delivery.consumer.len();
+                    delivery.consumer = consumer.clone();
+                    delivery.delivered_at = delivered_at;
+                    delivery.deliveries += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn entry_size(fields: &[(String, Vec<u8>)]) -> usize {
+    STREAM_ENTRY_OVERHEAD
+        + fields
+            .iter()
+            .map(|(field, value)| field.len() + value.len() + STREAM_FIELD_OVERHEAD)
+            .sum::<usize>()
+}
+
+// Entries read per key, keyed like the request
+type StreamsRead = Vec<(String, Vec<StreamEntry>)>;
+
+impl KVEngine {
+    /// Appends an entry, creating the stream if the key doesn't exist, and returns its ID.
+    /// `None` generates an ID from the current time that is above every ID in the stream;
+    /// an explicit ID must be too, or the call fails with `KVError::InvalidStreamId`.
+    pub fn stream_add(
+        &self,
+        key: &str,
+        id: Option<StreamId>,
+        fields: Vec<(String, Vec<u8>)>,
+    ) -> KVResult<StreamId> {
+        let growth = entry_size(&fields);
+
+        let id = self.mutate_value(key, growth, |current| {
+            let last = match current {
+                Some(value) => value.as_stream()?.last_id,
+                None => StreamId::MIN,
+            };
+
+            let id = match id {
+                Some(id) if id > last => id,
+                Some(_) => return Err(KVError::InvalidStreamId),
+                None => {
+                    let millis = now_unix_millis().max(last.millis);
+                    if millis > last.millis {
+                        StreamId::new(millis, 0)
+                    } else {
+                        let seq = last.seq.checked_add(1).ok_or(KVError::InvalidStreamId)?;
+                        StreamId::new(millis, seq)
+                    }
+                }
+            };
+
+            Ok((Some(StreamOp::Add { id, fields }), id))
+        })?;
+
+        self.namespace.waiters.wake(key);
+        Ok(id)
+    }
+
+    /// Entries with an ID from `start` to `end`, both inclusive, oldest first.
+    /// `count` caps how many are returned.
+    pub fn stream_range(
+        &self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> KVResult<Vec<StreamEntry>> {
+        let entries =
+            self.read_value(key, |value| Ok(value.as_stream()?.range(start, end, count)))?;
+        Ok(entries.unwrap_or_default())
+    }
+
+    /// Number of entries, 0 if the key doesn't exist.
+    pub fn stream_len(&self, key: &str) -> KVResult<usize> {
+        let length = self.read_value(key, |value| Ok(value.as_stream()?.len()))?;
+        Ok(length.unwrap_or(0))
+    }
+
+    /// Highest ID ever added to the stream; `StreamId::MIN` if the key doesn't exist.
+    pub fn stream_last_id(&self, key: &str) -> KVResult<StreamId> {
+        let id = self.read_value(key, |value| Ok(value.as_stream()?.last_id()))?;
+        Ok(id.unwrap_or(StreamId::MIN))
+    }
+
+    /// Entries above the given ID of each stream, at most `count` per stream.
+    /// Streams without such entries are left out.
+    pub fn stream_read(
+        &self,
+        streams: &[(String, StreamId)],
+        count: Option<usize>,
+    ) -> KVResult<StreamsRead> {
+        let mut read = vec![];
+        for (key, after) in streams {
+            let entries =
+                self.read_value(key, |value| Ok(value.as_stream()?.after(*after, count)))?;
+            if let Some(entries) = entries.filter(|entries| !entries.is_empty()) {
+                read.push((key.clone(), entries));
+            }
+        }
+        Ok(read)
+    }
+
+    /// Like `stream_read`, but waits for an entry to be added if there is none yet.
+    /// `None` as the ID stands for the last ID at the time of the call, so only entries
+    /// added from now on are read. Empty once `timeout` elapsed (`None` waits forever).
+    pub async fn stream_read_blocking(
+        &self,
+        streams: &[(String, Option<StreamId>)],
+        count: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> KVResult<StreamsRead> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+        let waiter = self.namespace.waiters.register(&keys);
+
+        let streams = streams
+            .iter()
+            .map(|(key, after)| {
+                let after = match after {
+                    Some(after) => *after,
+                    None => self.stream_last_id(key)?,
+                };
+                Ok((key.clone(), after))
+            })
+            .collect::<KVResult<Vec<_>>>()?;
+
+        loop {
+            let read = self.stream_read(&streams, count)?;
+            if !read.is_empty() || !wait(&waiter, deadline).await {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Creates a consumer group that delivers the entries above `start`; `None` starts
+    /// after the last entry, so only entries added from now on are delivered.
+    /// Creates an empty stream if the key doesn't exist.
+    pub fn stream_group_create(
+        &self,
+        key: &str,
+        group: &str,
+        start: Option<StreamId>,
+    ) -> KVResult<()> {
+        self.mutate_value(key, group.len(), |current| {
+            let stream = current.map(Value::as_stream).transpose()?;
+            if stream.is_some_and(|stream| stream.groups.contains_key(group)) {
+                return Err(KVError::GroupExists);
+            }
+
+            let last_delivered = start.unwrap_or(stream.map_or(StreamId::MIN, Stream::last_id));
+            let create = StreamOp::CreateGroup {
+                group: group.to_owned(),
+                last_delivered,
+            };
+            Ok((Some(create), ()))
+        })
+    }
+
+    /// Reads entries of each stream on behalf of a consumer of the group.
+    ///
+    /// `None` as the ID delivers entries the group hasn't delivered yet, at most `count`
+    /// per stream; they stay pending for the consumer until acknowledged. An ID instead
+    /// re-reads the consumer's own pending entries above it, without delivering anything.
+    /// Fails with `KVError::NoSuchGroup` if a stream doesn't have the group.
+    pub fn stream_read_group(
+        &self,
+        group: &str,
+        consumer: &str,
+        streams: &[(String, Option<StreamId>)],
+        count: Option<usize>,
+    ) -> KVResult<StreamsRead> {
+        let mut read = vec![];
+        for (key, after) in streams {
+            let entries = match after {
+                Some(after) => self.stream_consumer_pending(key, group, consumer, *after, count)?,
+                None => self.stream_deliver(key, group, consumer, count)?,
+            };
+            if !entries.is_empty() {
+                read.push((key.clone(), entries));
+            }
+        }
+        Ok(read)
+    }
+
+    /// Like `stream_read_group`, but if only new entries are asked for and there are none,
+    /// waits for one to be added. Empty once `timeout` elapsed (`None` waits forever).
+    pub async fn stream_read_group_blocking(
+        &self,
+        group: &str,
+        consumer: &str,
+        streams: &[(String, Option<StreamId>)],
+        count: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> KVResult<StreamsRead> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+        let waiter = self.namespace.waiters.register(&keys);
+        let history = streams.iter().any(|(_, after)| after.is_some());
+
+        loop {
+            let read = self.stream_read_group(group, consumer, streams, count)?;
+            if !read.is_empty() || history || !wait(&waiter, deadline).await {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Acknowledges entries delivered by the group, removing them from its pending entries.
+    /// Returns how many were pending.
+    pub fn stream_ack(&self, key: &str, group: &str, ids: &[StreamId]) -> KVResult<usize> {
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Err(KVError::NoSuchGroup);
+            };
+            let pending = &value.as_stream()?.group(group)?.pending;
+            let acked = ids
+                .iter()
+                .filter(|id| pending.contains_key(id))
+                .collect::<BTreeSet<_>>()
+                .len();
+            if acked == 0 {
+                return Ok((None, 0));
+            }
+
+            let ack = StreamOp::Ack {
+                group: group.to_owned(),
+                ids: ids.to_vec(),
+            };
+            Ok((Some(ack), acked))
+        })
+    }
+
+    /// Entries delivered by the group and not acknowledged yet, oldest first.
+    /// `count` caps how many are returned.
+    pub fn stream_pending(
+        &self,
+        key: &str,
+        group: &str,
+        count: Option<usize>,
+    ) -> KVResult<Vec<PendingEntry>> {
+        let now = now_unix_millis();
+
+        let pending = self.read_value(key, |value| {
+            Ok(value
+                .as_stream()?
+                .group(group)?
+                .pending
+                .iter()
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, delivery)| PendingEntry {
+                    id: *id,
+                    consumer: delivery.consumer.clone(),
+                    idle: Duration::from_millis(now.saturating_sub(delivery.delivered_at)),
+                    deliveries: delivery.deliveries,
+                })
+                .collect())
+        })?;
+
+        pending.ok_or(KVError::NoSuchGroup)
+    }
+
+    /// Hands pending entries that no consumer acknowledged for at least `min_idle` to
+    /// `consumer`, oldest first and at most `count` of them, e.g. after the consumer they
+    /// were delivered to crashed. Returns the claimed entries.
+    pub fn stream_claim(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        min_idle: Duration,
+        count: Option<usize>,
+    ) -> KVResult<Vec<StreamEntry>> {
+        let now = now_unix_millis();
+        let min_idle = min_idle.as_millis() as u64;
+
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Err(KVError::NoSuchGroup);
+            };
+            let stream = value.as_stream()?;
+            let stale: Vec<StreamId> = stream
+                .group(group)?
+                .pending
+                .iter()
+                .filter(|(_, delivery)| now.saturating_sub(delivery.delivered_at) >= min_idle)
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, _)| *id)
+                .collect();
+            if stale.is_empty() {
+                return Ok((None, vec![]));
+            }
+
+            let claimed = stale
+                .iter()
+                .filter_map(|id| {
+                    let fields = stream.entries.get(id)?;
+                    Some(StreamEntry {
+                        id: *id,
+                        fields: fields.clone(),
+                    })
+                })
+                .collect();
+
+            let claim = StreamOp::Claim {
+                group: group.to_owned(),
+                consumer: consumer.to_owned(),
+                delivered_at: now,
+                ids: stale,
+            };
+            Ok((Some(claim), claimed))
+        })
+    }
+
+    // Delivers entries the group hasn't delivered yet to the consumer.
+    fn stream_deliver(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+    ) -> KVResult<Vec<StreamEntry>> {
+        let now = now_unix_millis();
+
+        self.mutate_value(key, 0, |current| {
+            let Some(value) = current else {
+                return Err(KVError::NoSuchGroup);
+            };
+            let stream = value.as_stream()?;
+            let entries = stream.after(stream.group(group)?.last_delivered, count);
+            if entries.is_empty() {
+                return Ok((None, entries));
+            }
+
+            let deliver = StreamOp::Deliver {
+                group: group.to_owned(),
+                consumer: consumer.to_owned(),
+                delivered_at: now,
+                ids: entries.iter().map(|entry| entry.id).collect(),
+            };
+            Ok((Some(deliver), entries))
+        })
+    }
+
+    // Entries pending for the consumer with an ID above `after`.
+    fn stream_consumer_pending(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: Option<usize>,
+    ) -> KVResult<Vec<StreamEntry>> {
+        let entries = self.read_value(key, |value| {
+            let stream = value.as_stream()?;
+            Ok(stream
+                .group(group)?
+                .pending
+                .range((Bound::Excluded(after), Bound::Unbounded))
+                .filter(|(_, delivery)| delivery.consumer == consumer)
+                .filter_map(|(id, _)| {
+                    let fields = stream.entries.get(id)?;
+                    Some(StreamEntry {
+                        id: *id,
+                        fields: fields.clone(),
+                    })
+                })
+                .take(count.unwrap_or(usize::MAX))
+                .collect())
+        })?;
+
+        entries.ok_or(KVError::NoSuchGroup)
+    }
+}
+
+// Waits for one of the waiter's keys to be written to. Returns false once the deadline passed.
+async fn wait(waiter: &super::blocking::Waiter<'_>, deadline: Option<Instant>) -> bool {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, waiter.notified())
+            .await
+            .is_ok(),
+        None => {
+            waiter.notified().await;
+            true
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    Expiration, KVEngine, KVError, KVResult, KeyEventOp,
+    counter::{MAX_INTEGER_LEN, parse_integer},
+    now_unix_millis, read_shard,
+    value::Value,
+    wal::WalRecord,
+    write_shard,
+};
+
+/// One operation of a transaction.
+#[derive(Debug, Clone)]
+pub enum TxOperation {
+    Get {
+        key: String,
+    },
+    /// Replaces the value; `None` stores it without expiration
+    Set {
+        key: String,
+        value: Vec<u8>,
+        expiration: Option<Expiration>,
+    },
+    Delete {
+        key: String,
+    },
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+}
+
+impl TxOperation {
+    fn key(&self) -> &str {
+        match self {
+            TxOperation::Get { key }
+            | TxOperation::Set { key, .. }
+            | TxOperation::Delete { key }
+            | TxOperation::IncrBy { key, .. } => key,
+        }
+    }
+}
+
+/// Result of a `TxOperation`, in the same order as the operations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxResult {
+    /// `Get`: the value, as seen by the transaction so far
+    Value(Option<Vec<u8>>),
+    /// `Set`
+    Stored,
+    /// `Delete`: whether the key existed
+    Deleted(bool),
+    /// `IncrBy`: the new value
+    Integer(i64),
+}
+
+// Value and deadline of a key as the transaction sees it; `None` once deleted
+type Staged = Option<(Value, Option<u64>)>;
+
+impl KVEngine {
+    /// Current version of every key, 0 for keys that don't exist. Used to `WATCH` keys.
+    pub fn versions(&self, keys: &[String]) -> KVResult<Vec<u64>> {
+        let now = now_unix_millis();
+
+        keys.iter()
+            .map(|key| {
+                let kv = read_shard(self.shard(key))?;
+                let version = kv
+                    .entries
+                    .get(key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .map_or(0, |entry| entry.version);
+                Ok(version)
+            })
+            .collect()
+    }
+
+    /// Runs the operations as one atomic unit.
+    ///
+    /// Every shard involved is locked for the whole transaction. If any `watches` key is no longer
+    /// at the given version (0 meaning absent), nothing runs and `KVError::VersionMismatch`
+    /// is returned. Effects are staged and only applied once every operation succeeded,
+    /// so an error such as `KVError::NotAnInteger` leaves the keyspace untouched.
+    pub fn execute_transaction(
+        &self,
+        watches: &[(String, u64)],
+        operations: Vec<TxOperation>,
+    ) -> KVResult<Vec<TxResult>> {
+        let additional = operations
+            .iter()
+            .map(|operation| match operation {
+                TxOperation::Set {
+                    key,
+                    value,
+                    expiration,
+                } => self.memory_delta(key, value.len(), expiration.is_some()),
+                TxOperation::IncrBy { key, .. } => self.memory_delta(key, MAX_INTEGER_LEN, false),
+                TxOperation::Get { .. } | TxOperation::Delete { .. } => Ok(0),
+            })
+            .sum::<KVResult<usize>>()?;
+        let _reservation = self.reserve_memory(additional)?;
+
+        let mut lock_order: Vec<usize> = watches
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .chain(operations.iter().map(TxOperation::key))
+            .map(|key| self.shard_index(key))
+            .collect();
+        lock_order.sort_unstable();
+        lock_order.dedup();
+
+        let mut shards = lock_order
+            .into_iter()
+            .map(|index| Ok((index, write_shard(&self.namespace.shards[index])?)))
+            .collect::<KVResult<BTreeMap<_, _>>>()?;
+        let now = now_unix_millis();
+
+        for (key, expected_version) in watches {
+            let version = shards
+                .get_mut(&self.shard_index(key))
+                .and_then(|kv| kv.get_live_mut(key, now))
+                .map_or(0, |entry| entry.version);
+
+            if version != *expected_version {
+                return Err(KVError::VersionMismatch);
+            }
+        }
+
+        let mut staged: HashMap<String, Staged> = HashMap::new();
+        let current = |staged: &HashMap<String, Staged>, key: &str| -> Staged {
+            if let Some(value) = staged.get(key) {
+                return value.clone();
+            }
+            let entry = shards[&self.shard_index(key)]
+                .entries
+                .get(key)
+                .filter(|entry| !entry.is_expired(now))?;
+            Some((Value::clone(&entry.value), entry.expires_at))
+        };
+        // Staged values are strings, so only a value from before the transaction can be a lock
+        let check_replaceable = |staged: &HashMap<String, Staged>, key: &str| -> KVResult<()> {
+            if staged.contains_key(key) {
+                return Ok(());
+            }
+            let entry = shards[&self.shard_index(key)]
+                .entries
+                .get(key)
+                .filter(|entry| !entry.is_expired(now));
+            entry.map_or(Ok(()), |entry| entry.value.check_replaceable())
+        };
+
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = match operation {
+                TxOperation::Get { key } => TxResult::Value(
+                    current(&staged, &key)
+                        .map(|(value, _)| value.into_string())
+                        .transpose()?,
+                ),
+                TxOperation::Set {
+                    key,
+                    value,
+                    expiration,
+                } => {
+                    check_replaceable(&staged, &key)?;
+                    let expires_at = expiration.map(Expiration::to_unix_millis);
+                    staged.insert(key, Some((Value::String(value), expires_at)));
+                    TxResult::Stored
+                }
+                TxOperation::Delete { key } => {
+                    check_replaceable(&staged, &key)?;
+                    let existed = current(&staged, &key).is_some();
+                    staged.insert(key, None);
+                    TxResult::Deleted(existed)
+                }
+                TxOperation::IncrBy { key, delta } => {
+                    let (value, expires_at) = match current(&staged, &key) {
+                        Some((value, expires_at)) => {
+                            (parse_integer(value.as_string()?)?, expires_at)
+                        }
+                        None => (0, None),
+                    };
+                    let value = value.checked_add(delta).ok_or(KVError::NotAnInteger)?;
+
+                    let stored = Value::String(value.to_string().into_bytes());
+                    staged.insert(key, Some((stored, expires_at)));
+                    TxResult::Integer(value)
+                }
+            };
+            results.push(result);
+        }
+
+        for (key, state) in staged {
+            let Some(kv) = shards.get_mut(&self.shard_index(&key)) else {
+                continue;
+            };
+
+            match state {
+                Some((value, expires_at)) => {
+                    let version = self.next_version();
+                    self.log(WalRecord::Set {
+                        key: key.as_str().into(),
+                        value: value.encode(),
+                        expires_at,
+                        version,
+                        kind: value.kind(),
+                    })?;
+                    kv.insert(key, value, expires_at, version, now);
+                }
+                None => {
+                    if kv.get_live_mut(&key, now).is_none() {
+                        continue;
+                    }
+                    self.log(WalRecord::Delete {
+                        key: key.as_str().into(),
+                    })?;
+                    kv.remove(&key, KeyEventOp::Delete);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(key: &str, value: &[u8]) -> TxOperation {
+        TxOperation::Set {
+            key: key.into(),
+            value: value.to_vec(),
+            expiration: None,
+        }
+    }
+
+    #[test]
+    fn exec_aborts_once_a_watched_key_changed() {
+        let engine = KVEngine::new();
+        engine
+            .set_key_value("balance".into(), b"10".to_vec())
+            .unwrap();
+        let keys = ["balance".to_owned(), "last".to_owned()];
+        let versions = engine.versions(&keys).unwrap();
+        assert_eq!(versions[1], 0);
+        let watches: Vec<_> = keys.iter().cloned().zip(versions).collect();
+
+        let operations = vec![
+            TxOperation::IncrBy {
+                key: "balance".into(),
+                delta: -10,
+            },
+            set("last", b"withdraw"),
+        ];
+
+        // Another client writes the watched key after WATCH
+        engine
+            .set_key_value("balance".into(), b"5".to_vec())
+            .unwrap();
+        assert!(matches!(
+            engine.execute_transaction(&watches, operations.clone()),
+            Err(KVError::VersionMismatch)
+        ));
+        assert_eq!(engine.get_key_value("balance").unwrap(), b"5");
+        assert!(matches!(
+            engine.get_key_value("last"),
+            Err(KVError::KeyNotFound)
+        ));
+
+        // Watching again picks up the new version
+        let versions = engine.versions(&keys).unwrap();
+        let watches: Vec<_> = keys.iter().cloned().zip(versions).collect();
+        assert_eq!(
+            engine.execute_transaction(&watches, operations).unwrap(),
+            [TxResult::Integer(-5), TxResult::Stored]
+        );
+        assert_eq!(engine.get_key_value("last").unwrap(), b"withdraw");
+    }
+
+    #[test]
+    fn exec_aborts_once_a_watched_absent_key_is_created() {
+        let engine = KVEngine::new();
+        let watches = [("lock".to_owned(), 0)];
+
+        engine
+            .set_key_value("lock".into(), b"taken".to_vec())
+            .unwrap();
+        assert!(matches!(
+            engine.execute_transaction(&watches, vec![set("lock", b"mine")]),
+            Err(KVError::VersionMismatch)
+        ));
+        assert_eq!(engine.get_key_value("lock").unwrap(), b"taken");
+
+        // Deleted and recreated after WATCH, the key has a new version and counts as changed
+        let version = engine.versions(&["lock".to_owned()]).unwrap()[0];
+        engine.delete_key_value("lock").unwrap();
+        engine
+            .set_key_value("lock".into(), b"taken".to_vec())
+            .unwrap();
+        assert!(matches!(
+            engine.execute_transaction(&[("lock".to_owned(), version)], vec![set("lock", b"mine")]),
+            Err(KVError::VersionMismatch)
+        ));
+        assert_eq!(engine.get_key_value("lock").unwrap(), b"taken");
+    }
+}
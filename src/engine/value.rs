@@ -0,0 +1,546 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+};
+
+use crate::protocol::{read_chunk, write_chunk};
+
+use super::{
+    KVEngine, KVError, KVResult, KeyEventOp,
+    eviction::MemoryReservation,
+    hash::HashOp,
+    job_queue::{JobOp, JobQueue},
+    json::{JsonOp, json_memory_size},
+    keyspace::{KeySpace, entry_size},
+    list::ListOp,
+    lock::Lock,
+    now_unix_millis,
+    probabilistic::{BloomFilter, BloomOp, HllOp, HyperLogLog},
+    rate_limit::RateLimiter,
+    read_shard,
+    set::SetOp,
+    sorted_set::{SortedSet, SortedSetOp},
+    stream::{Stream, StreamOp},
+    wal::WalRecord,
+    write_shard,
+};
+
+// Approximate bookkeeping cost of one list element besides its bytes: the Vec header
+// and its slot in the deque
+const LIST_ELEMENT_OVERHEAD: usize = 32;
+// Approximate bookkeeping cost of one hash field besides its name and value: the map slot
+// and both headers
+const HASH_FIELD_OVERHEAD: usize = 64;
+// Approximate bookkeeping cost of one set member besides its bytes
+const SET_MEMBER_OVERHEAD: usize = 48;
+// Approximate bookkeeping cost of one sorted set member besides its two copies:
+// the score map slot and the tree node
+const SORTED_SET_MEMBER_OVERHEAD: usize = 112;
+
+// Type of a value as stored in snapshots and the write-ahead log
+pub(super) const KIND_STRING: u32 = 0;
+pub(super) const KIND_LIST: u32 = 1;
+pub(super) const KIND_HASH: u32 = 2;
+pub(super) const KIND_SET: u32 = 3;
+pub(super) const KIND_SORTED_SET: u32 = 4;
+pub(super) const KIND_STREAM: u32 = 5;
+pub(super) const KIND_JSON: u32 = 6;
+pub(super) const KIND_HYPERLOGLOG: u32 = 7;
+pub(super) const KIND_BLOOM_FILTER: u32 = 8;
+pub(super) const KIND_RATE_LIMITER: u32 = 9;
+pub(super) const KIND_LOCK: u32 = 10;
+pub(super) const KIND_JOB_QUEUE: u32 = 11;
+
+/// A value stored at a key.
+#[derive(Debug, Clone)]
+pub(super) enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<String, Vec<u8>>),
+    Set(HashSet<String>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+    Json(serde_json::Value),
+    HyperLogLog(HyperLogLog),
+    BloomFilter(BloomFilter),
+    RateLimiter(RateLimiter),
+    Lock(Lock),
+    JobQueue(JobQueue),
+}
+
+impl Value {
+    pub fn kind(&self) -> u32 {
+        match self {
+            Value::String(_) => KIND_STRING,
+            Value::List(_) => KIND_LIST,
+            Value::Hash(_) => KIND_HASH,
+            Value::Set(_) => KIND_SET,
+            Value::SortedSet(_) => KIND_SORTED_SET,
+            Value::Stream(_) => KIND_STREAM,
+            Value::Json(_) => KIND_JSON,
+            Value::HyperLogLog(_) => KIND_HYPERLOGLOG,
+            Value::BloomFilter(_) => KIND_BLOOM_FILTER,
+            Value::RateLimiter(_) => KIND_RATE_LIMITER,
+            Value::Lock(_) => KIND_LOCK,
+            Value::JobQueue(_) => KIND_JOB_QUEUE,
+        }
+    }
+
+    /// Bytes accounted to the value.
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Value::String(value) => value.len(),
+            Value::List(list) => list_memory_size(list.iter()),
+            Value::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| hash_field_size(field, value))
+                .sum(),
+            Value::Set(set) => set.iter().map(|member| set_member_size(member)).sum(),
+            Value::SortedSet(set) => set
+                .iter()
+                .map(|(member, _)| sorted_set_member_size(member))
+                .sum(),
+            Value::Stream(stream) => stream.memory_size(),
+            Value::Json(document) => json_memory_size(document),
+            Value::HyperLogLog(hll) => hll.memory_size(),
+            Value::BloomFilter(filter) => filter.memory_size(),
+            Value::RateLimiter(limiter) => limiter.memory_size(),
+            Value::Lock(lock) => lock.memory_size(),
+            Value::JobQueue(queue) => queue.memory_size(),
+        }
+    }
+
+    /// The value as stored in snapshots and the log. Strings are stored as-is.
+    pub fn encode(&self) -> Cow<'_, [u8]> {
+        match self {
+            Value::String(value) => Cow::Borrowed(value),
+            Value::List(list) => {
+                let mut buffer = vec![];
+                for element in list {
+                    write_chunk(&mut buffer, element);
+                }
+                Cow::Owned(buffer)
+            }
+            Value::Hash(hash) => {
+                let mut buffer = vec![];
+                for (field, value) in hash {
+                    write_chunk(&mut buffer, field.as_bytes());
+                    write_chunk(&mut buffer, value);
+                }
+                Cow::Owned(buffer)
+            }
+            Value::Set(set) => {
+                let mut buffer = vec![];
+                for member in set {
+                    write_chunk(&mut buffer, member.as_bytes());
+                }
+                Cow::Owned(buffer)
+            }
+            Value::SortedSet(set) => {
+                let mut buffer = vec![];
+                for (member, score) in set.iter() {
+                    write_chunk(&mut buffer, member.as_bytes());
+                    write_chunk(&mut buffer, &score.to_be_bytes());
+                }
+                Cow::Owned(buffer)
+            }
+            Value::Stream(stream) => {
+                let mut buffer = vec![];
+                stream.encode(&mut buffer);
+                Cow::Owned(buffer)
+            }
+            Value::Json(document) => Cow::Owned(document.to_string().into_bytes()),
+            Value::HyperLogLog(hll) => Cow::Borrowed(hll.encode()),
+            Value::BloomFilter(filter) => Cow::Owned(filter.encode()),
+            Value::RateLimiter(limiter) => Cow::Owned(limiter.encode()),
+            Value::Lock(lock) => Cow::Owned(lock.encode()),
+            Value::JobQueue(queue) => Cow::Owned(queue.encode()),
+        }
+    }
+
+    pub fn decode(kind: u32, bytes: Vec<u8>) -> Option<Value> {
+        match kind {
+            KIND_STRING => Some(Value::String(bytes)),
+            KIND_LIST => {
+                let mut list = VecDeque::new();
+                let mut buffer = bytes.as_slice();
+                while !buffer.is_empty() {
+                    let (element, rest) = read_chunk(buffer).ok()?;
+                    list.push_back(element.to_vec());
+                    buffer = rest;
+                }
+                Some(Value::List(list))
+            }
+            KIND_HASH => {
+                let mut hash = HashMap::new();
+                let mut buffer = bytes.as_slice();
+                while !buffer.is_empty() {
+                    let (field, rest) = read_chunk(buffer).ok()?;
+                    let (value, rest) = read_chunk(rest).ok()?;
+                    hash.insert(String::from_utf8(field.to_vec()).ok()?, value.to_vec());
+                    buffer = rest;
+                }
+                Some(Value::Hash(hash))
+            }
+            KIND_SET => {
+                let mut set = HashSet::new();
+                let mut buffer = bytes.as_slice();
+                while !buffer.is_empty() {
+                    let (member, rest) = read_chunk(buffer).ok()?;
+                    set.insert(String::from_utf8(member.to_vec()).ok()?);
+                    buffer = rest;
+                }
+                Some(Value::Set(set))
+            }
+            KIND_SORTED_SET => {
+                let mut set = SortedSet::default();
+                let mut buffer = bytes.as_slice();
+                while !buffer.is_empty() {
+                    let (member, rest) = read_chunk(buffer).ok()?;
+                    let (score, rest) = read_chunk(rest).ok()?;
+                    let score = f64::from_be_bytes(score.try_into().ok()?);
+                    if score.is_nan() {
+                        return None;
+                    }
+                    set.insert(String::from_utf8(member.to_vec()).ok()?, score);
+                    buffer = rest;
+                }
+                Some(Value::SortedSet(set))
+            }
+            KIND_STREAM => Stream::decode(&bytes).map(Value::Stream),
+            KIND_JSON => serde_json::from_slice(&bytes).ok().map(Value::Json),
+            KIND_HYPERLOGLOG => HyperLogLog::decode(bytes).map(Value::HyperLogLog),
+            KIND_BLOOM_FILTER => BloomFilter::decode(&bytes).map(Value::BloomFilter),
+            KIND_RATE_LIMITER => RateLimiter::decode(&bytes).map(Value::RateLimiter),
+            KIND_LOCK => Lock::decode(&bytes).map(Value::Lock),
+            KIND_JOB_QUEUE => JobQueue::decode(&bytes).map(Value::JobQueue),
+            _ => None,
+        }
+    }
+
+    /// Whether the value is a collection left without elements, whose key is then deleted.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(set) => set.is_empty(),
+            Value::JobQueue(queue) => queue.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Fails with `KVError::WrongType` for a lock, which generic writes must leave alone:
+    /// it only changes hands through the lock commands, which keep its fencing tokens
+    /// counting up.
+    pub fn check_replaceable(&self) -> KVResult<()> {
+        match self {
+            Value::Lock(_) => Err(KVError::WrongType),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn as_string(&self) -> KVResult<&Vec<u8>> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn into_string(self) -> KVResult<Vec<u8>> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> KVResult<&VecDeque<Vec<u8>>> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> KVResult<&HashMap<String, Vec<u8>>> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> KVResult<&HashSet<String>> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> KVResult<&SortedSet> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> KVResult<&Stream> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_json(&self) -> KVResult<&serde_json::Value> {
+        match self {
+            Value::Json(document) => Ok(document),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_hyperloglog(&self) -> KVResult<&HyperLogLog> {
+        match self {
+            Value::HyperLogLog(hll) => Ok(hll),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_bloom_filter(&self) -> KVResult<&BloomFilter> {
+        match self {
+            Value::BloomFilter(filter) => Ok(filter),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_rate_limiter(&self) -> KVResult<&RateLimiter> {
+        match self {
+            Value::RateLimiter(limiter) => Ok(limiter),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_lock(&self) -> KVResult<&Lock> {
+        match self {
+            Value::Lock(lock) => Ok(lock),
+            _ => Err(KVError::WrongType),
+        }
+    }
+
+    pub fn as_job_queue(&self) -> KVResult<&JobQueue> {
+        match self {
+            Value::JobQueue(queue) => Ok(queue),
+            _ => Err(KVError::WrongType),
+        }
+    }
+}
+
+pub(super) fn list_memory_size<'a>(elements: impl Iterator<Item = &'a Vec<u8>>) -> usize {
+    elements
+        .map(|element| element.len() + LIST_ELEMENT_OVERHEAD)
+        .sum()
+}
+
+pub(super) fn hash_field_size(field: &str, value: &[u8]) -> usize {
+    field.len() + value.len() + HASH_FIELD_OVERHEAD
+}
+
+pub(super) fn set_member_size(member: &str) -> usize {
+    member.len() + SET_MEMBER_OVERHEAD
+}
+
+pub(super) fn sorted_set_member_size(member: &str) -> usize {
+    member.len() * 2 + SORTED_SET_MEMBER_OVERHEAD
+}
+
+/// A write to a value that is applied in place, and logged as is instead of the value
+/// it results in, so the cost of the write follows the size of the change rather than
+/// the size of the value. See `KVEngine::mutate_value`.
+///
+/// A mutation carries its resolved effect (generated IDs, timestamps, resulting numbers),
+/// so applying it again when the log is replayed gives the same value.
+pub(super) trait Mutation: Sized {
+    /// Kind of the values the mutation applies to, see `Value::kind`
+    const KIND: u32;
+
+    fn encode(&self, buffer: &mut Vec<u8>);
+
+    fn decode(bytes: &[u8]) -> Option<Self>;
+
+    /// Value a key that doesn't exist starts from; `None` if the mutation needs an existing one.
+    fn create(&self) -> Option<Value>;
+
+    /// Applies the mutation, keeping `size`, the `Value::memory_size` of the value, up to date.
+    /// Fails without changing anything if the value is of another kind.
+    fn apply(self, value: &mut Value, size: &mut usize) -> KVResult<()>;
+}
+
+/// What `KVEngine::update_value` does with the key.
+pub(super) enum Update {
+    /// Leave the key as it is
+    Keep,
+    /// Replace the value, keeping the key's expiration
+    Store(Value),
+    /// Delete the key, e.g. because its collection became empty
+    Remove,
+}
+
+impl KVEngine {
+    /// Reads the live value at `key` under the shard's read lock. `None` if the key doesn't exist.
+    pub(super) fn read_value<T>(
+        &self,
+        key: &str,
+        read: impl FnOnce(&Value) -> KVResult<T>,
+    ) -> KVResult<Option<T>> {
+        let shard = self.shard(key);
+        let now = now_unix_millis();
+
+        {
+            let kv = read_shard(shard)?;
+            match kv.entries.get(key) {
+                Some(entry) if !entry.is_expired(now) => {
+                    entry.touch(now);
+                    return read(&entry.value).map(Some);
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+
+        // The key has expired: reclaim it under the write lock.
+        write_shard(shard)?.get_live_mut(key, now);
+        Ok(None)
+    }
+
+    /// Replaces the value at `key` with one computed from the current value (`None` if the
+    /// key doesn't exist), all under the shard lock. `growth` is how many bytes the value
+    /// may grow by, reserved up front.
+    ///
+    /// The whole resulting value is logged; writes to collections go through `mutate_value`.
+    pub(super) fn update_value<T>(
+        &self,
+        key: &str,
+        growth: usize,
+        update: impl FnOnce(Option<&Value>) -> KVResult<(Update, T)>,
+    ) -> KVResult<T> {
+        let _reservation = self.reserve_growth(key, growth)?;
+
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+
+        let entry = kv.get_live_mut(key, now);
+        let expires_at = entry.as_ref().and_then(|entry| entry.expires_at);
+        let (update, result) = update(entry.map(|entry| &*entry.value))?;
+
+        let stored = match update {
+            Update::Keep => None,
+            Update::Store(value) => Some((value, expires_at)),
+            Update::Remove => {
+                if kv.entries.contains_key(key) {
+                    self.log(WalRecord::Delete { key: key.into() })?;
+                    kv.remove(key, KeyEventOp::Delete);
+                }
+                None
+            }
+        };
+
+        if let Some((value, expires_at)) = stored {
+            let version = self.next_version();
+            self.log(WalRecord::Set {
+                key: key.into(),
+                value: value.encode(),
+                expires_at,
+                version,
+                kind: value.kind(),
+            })?;
+            kv.insert(key.to_owned(), value, expires_at, version, now);
+        }
+
+        Ok(result)
+    }
+
+    /// Changes the value at `key` in place with a mutation planned from the current value
+    /// (`None` if the key doesn't exist), all under the shard lock, and logs the mutation.
+    /// Planning no mutation leaves the key untouched. `growth` is how many bytes the value
+    /// may grow by, reserved up front.
+    pub(super) fn mutate_value<M: Mutation, T>(
+        &self,
+        key: &str,
+        growth: usize,
+        plan: impl FnOnce(Option<&Value>) -> KVResult<(Option<M>, T)>,
+    ) -> KVResult<T> {
+        let _reservation = self.reserve_growth(key, growth)?;
+
+        let mut kv = write_shard(self.shard(key))?;
+        let now = now_unix_millis();
+
+        let current = kv.get_live_mut(key, now).map(|entry| &*entry.value);
+        let (mutation, result) = plan(current)?;
+        let Some(mutation) = mutation else {
+            return Ok(result);
+        };
+
+        let version = self.next_version();
+        let mut op = vec![];
+        mutation.encode(&mut op);
+        self.log(WalRecord::Mutate {
+            key: key.into(),
+            version,
+            kind: M::KIND,
+            op: op.into(),
+        })?;
+        kv.mutate(key, mutation, version, now)?;
+
+        Ok(result)
+    }
+
+    // Reserves room for a value growing by `growth` bytes, and for the key itself if it's new
+    pub(super) fn reserve_growth(
+        &self,
+        key: &str,
+        growth: usize,
+    ) -> KVResult<Option<MemoryReservation>> {
+        if growth == 0 {
+            return Ok(None);
+        }
+
+        let exists = read_shard(self.shard(key))?.entries.contains_key(key);
+        let base = if exists {
+            0
+        } else {
+            entry_size(key.len(), 0, false)
+        };
+        self.reserve_memory(base + growth).map(Some)
+    }
+}
+
+/// Applies a logged mutation of a value of `kind`, see `KeySpace::mutate`.
+/// `None` if the mutation doesn't decode or doesn't apply to the value at `key`.
+pub(super) fn replay_mutation(
+    kv: &mut KeySpace,
+    key: &str,
+    kind: u32,
+    op: &[u8],
+    version: u64,
+    now: u64,
+) -> Option<()> {
+    fn replay<M: Mutation>(
+        kv: &mut KeySpace,
+        key: &str,
+        op: &[u8],
+        version: u64,
+        now: u64,
+    ) -> Option<()> {
+        kv.mutate(key, M::decode(op)?, version, now).ok()
+    }
+
+    match kind {
+        KIND_LIST => replay::<ListOp>(kv, key, op, version, now),
+        KIND_HASH => replay::<HashOp>(kv, key, op, version, now),
+        KIND_SET => replay::<SetOp>(kv, key, op, version, now),
+        KIND_SORTED_SET => replay::<SortedSetOp>(kv, key, op, version, now),
+        KIND_STREAM => replay::<StreamOp>(kv, key, op, version, now),
+        KIND_JSON => replay::<JsonOp>(kv, key, op, version, now),
+        KIND_HYPERLOGLOG => replay::<HllOp>(kv, key, op, version, now),
+        KIND_BLOOM_FILTER => replay::<BloomOp>(kv, key, op, version, now),
+        KIND_JOB_QUEUE => replay::<JobOp>(kv, key, op, version, now),
+        _ => None,
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::protocol::{WireField, read_chunk, write_chunk};
+
+// Record frame: LENGTH (u32) | CRC32 of payload (u32) | PAYLOAD
+// Payload: OPERATION (u8) | fields... | NAMESPACE
+const FRAME_HEADER_SIZE: usize = 4 + 4;
+
+const OP_SET: u8 = 0x01;
+const OP_DELETE: u8 = 0x02;
+const OP_EXPIRE: u8 = 0x03;
+const OP_CLEAR: u8 = 0x04;
+const OP_MUTATE: u8 = 0x05;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("A log rewrite is already running")]
+    RewriteInProgress,
+}
+
+/// When appended records are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every record; no acknowledged write is ever lost
+    Always,
+    /// fsync once per second from a background task; up to a second of writes can be lost
+    EverySec,
+    /// leave flushing to the operating system
+    No,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!("unknown fsync policy: {}", value)),
+        }
+    }
+}
+
+/// A mutation as it is written to the log.
+///
+/// Records carry the effect of an operation (absolute deadlines, resulting values),
+/// never the command itself, so replaying a record twice is harmless. Mutations are only
+/// applied to keys whose version is below theirs for the same reason.
+#[derive(Debug, Clone)]
+pub(crate) enum WalRecord<'a> {
+    Set {
+        key: Cow<'a, str>,
+        value: Cow<'a, [u8]>,
+        expires_at: Option<u64>,
+        version: u64,
+        // See `Value::kind`
+        kind: u32,
+    },
+    Delete {
+        key: Cow<'a, str>,
+    },
+    Expire {
+        key: Cow<'a, str>,
+        expires_at: Option<u64>,
+    },
+    Clear,
+    /// An in-place change to a value, see `Mutation`
+    Mutate {
+        key: Cow<'a, str>,
+        // Version of the key once the mutation is applied
+        version: u64,
+        // See `Value::kind`
+        kind: u32,
+        op: Cow<'a, [u8]>,
+    },
+}
+
+impl WalRecord<'_> {
+    /// Frames the record as applied to `namespace`.
+    fn encode_frame(&self, namespace: &str, buffer: &mut Vec<u8>) {
+        let mut payload = vec![];
+
+        match self {
+            WalRecord::Set {
+                key,
+                value,
+                expires_at,
+                version,
+                kind,
+            } => {
+                payload.push(OP_SET);
+                write_chunk(&mut payload, key.as_bytes());
+                write_chunk(&mut payload, value);
+                expires_at.write_field(&mut payload);
+                version.write_field(&mut payload);
+                kind.write_field(&mut payload);
+            }
+            WalRecord::Delete { key } => {
+                payload.push(OP_DELETE);
+                write_chunk(&mut payload, key.as_bytes());
+            }
+            WalRecord::Expire { key, expires_at } => {
+                payload.push(OP_EXPIRE);
+                write_chunk(&mut payload, key.as_bytes());
+                expires_at.write_field(&mut payload);
+            }
+            WalRecord::Clear => payload.push(OP_CLEAR),
+            WalRecord::Mutate {
+                key,
+                version,
+                kind,
+                op,
+            } => {
+                payload.push(OP_MUTATE);
+                write_chunk(&mut payload, key.as_bytes());
+                version.write_field(&mut payload);
+                kind.write_field(&mut payload);
+                write_chunk(&mut payload, op);
+            }
+        }
+
+        write_chunk(&mut payload, namespace.as_bytes());
+
+        buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        buffer.extend_from_slice(&payload);
+    }
+
+    /// Decodes a payload into the namespace it applies to and the record.
+    fn decode_payload(payload: &[u8]) -> Option<(String, WalRecord<'static>)> {
+        let (operation, fields) = payload.split_first()?;
+
+        let (record, fields) = match *operation {
+            OP_SET => {
+                let (key, fields) = String::read_field(fields).ok()?;
+                let (value, fields) = read_chunk(fields).ok()?;
+                let (expires_at, fields) = Option::<u64>::read_field(fields).ok()?;
+                let (version, fields) = u64::read_field(fields).ok()?;
+                let (kind, fields) = u32::read_field(fields).ok()?;
+
+                let record = WalRecord::Set {
+                    key: key.into(),
+                    value: value.to_vec().into(),
+                    expires_at,
+                    version,
+                    kind,
+                };
+                (record, fields)
+            }
+            OP_DELETE => {
+                let (key, fields) = String::read_field(fields).ok()?;
+
+                (WalRecord::Delete { key: key.into() }, fields)
+            }
+            OP_EXPIRE => {
+                let (key, fields) = String::read_field(fields).ok()?;
+                let (expires_at, fields) = Option::<u64>::read_field(fields).ok()?;
+
+                let record = WalRecord::Expire {
+                    key: key.into(),
+                    expires_at,
+                };
+                (record, fields)
+            }
+            OP_CLEAR => (WalRecord::Clear, fields),
+            OP_MUTATE => {
+                let (key, fields) = String::read_field(fields).ok()?;
+                let (version, fields) = u64::read_field(fields).ok()?;
+                let (kind, fields) = u32::read_field(fields).ok()?;
+                let (op, fields) = read_chunk(fields).ok()?;
+
+                let record = WalRecord::Mutate {
+                    key: key.into(),
+                    version,
+                    kind,
+                    op: op.to_vec().into(),
+                };
+                (record, fields)
+            }
+            _ => return None,
+        };
+
+        let (namespace, fields) = String::read_field(fields).ok()?;
+        if !fields.is_empty() {
+            return None;
+        }
+
+        Some((namespace, record))
+    }
+}
+
+#[derive(Debug)]
+struct WalState {
+    writer: BufWriter<File>,
+    // Current file size in bytes
+    size: u64,
+    // File size right after the last rewrite (or open); growth is measured against it
+    base_size: u64,
+    // Records written since the last fsync
+    unsynced: bool,
+    // Records appended while a rewrite is running, copied into the new file when it finishes
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    rewrite_min_size: u64,
+    // Invalid trailing bytes cut off when the log was opened
+    dropped: u64,
+    state: Mutex<WalState>,
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log and returns every valid record in it, with its namespace.
+    ///
+    /// A torn or corrupted record at the end of the file, typically left by a crash mid-write,
+    /// is cut off with a warning together with everything after it.
+    pub fn open(
+        path: &Path,
+        fsync: FsyncPolicy,
+        rewrite_min_size: u64,
+    ) -> Result<(Self, Vec<(String, WalRecord<'static>)>), WalError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match decode_frame(&bytes[offset..]) {
+                Some((record, frame_size)) => {
+                    records.push(record);
+                    offset += frame_size;
+                }
+                None => break,
+            }
+        }
+
+        if offset < bytes.len() {
+            log::warn!(
+                "Write-ahead log {} has {} invalid trailing bytes at offset {}, truncating",
+                path.display(),
+                bytes.len() - offset,
+                offset
+            );
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        let wal = WriteAheadLog {
+            path: path.to_path_buf(),
+            fsync,
+            rewrite_min_size,
+            dropped: (bytes.len() - offset) as u64,
+            state: Mutex::new(WalState {
+                writer: BufWriter::new(file),
+                size: offset as u64,
+                base_size: offset as u64,
+                unsynced: false,
+                rewrite_buffer: None,
+            }),
+        };
+
+        Ok((wal, records))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WalState> {
+        self.state.lock().unwrap_or_else(|error| {
+            self.state.clear_poison();
+            error.into_inner()
+        })
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Bytes of a torn or corrupted tail cut off by `open`.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends a record applied to `namespace`. Callers hold the lock of every shard the
+    /// record touches, so records of the same key reach the log in the order they were applied.
+    pub fn append(&self, namespace: &str, record: &WalRecord) -> Result<(), WalError> {
+        let mut frame = vec![];
+        record.encode_frame(namespace, &mut frame);
+
+        let mut state = self.lock();
+
+        state.writer.write_all(&frame)?;
+        state.writer.flush()?;
+        state.size += frame.len() as u64;
+
+        if let Some(rewrite_buffer) = state.rewrite_buffer.as_mut() {
+            rewrite_buffer.extend_from_slice(&frame);
+        }
+
+        if self.fsync == FsyncPolicy::Always {
+            state.writer.get_ref().sync_data()?;
+        } else {
+            state.unsynced = true;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes appended records to disk if any were written since the last call.
+    pub fn sync(&self) -> Result<(), WalError> {
+        let mut state = self.lock();
+
+        if state.unsynced {
+            state.writer.get_ref().sync_data()?;
+            state.unsynced = false;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the log has at least doubled since the last rewrite and passed the minimum size.
+    pub fn needs_rewrite(&self) -> bool {
+        let state = self.lock();
+
+        state.rewrite_buffer.is_none()
+            && state.size >= self.rewrite_min_size
+            && state.size >= state.base_size.saturating_mul(2)
+    }
+
+    /// Starts capturing appended records for a rewrite.
+    /// Must be called before the live keyset is copied, so nothing written in between is lost.
+    pub fn begin_rewrite(&self) -> Result<(), WalError> {
+        let mut state = self.lock();
+
+        if state.rewrite_buffer.is_some() {
+            return Err(WalError::RewriteInProgress);
+        }
+
+        state.rewrite_buffer = Some(vec![]);
+
+        Ok(())
+    }
+
+    pub fn abort_rewrite(&self) {
+        self.lock().rewrite_buffer = None;
+    }
+
+    /// Replaces the log with `records` (the live keyset, with the namespace of each record)
+    /// followed by everything appended since `begin_rewrite`. Returns the size of the new log.
+    pub fn finish_rewrite<'a>(
+        &self,
+        records: impl Iterator<Item = (&'a str, WalRecord<'a>)>,
+    ) -> Result<u64, WalError> {
+        let result = self.write_rewrite(records);
+
+        if result.is_err() {
+            self.abort_rewrite();
+        }
+
+        result
+    }
+
+    fn write_rewrite<'a>(
+        &self,
+        records: impl Iterator<Item = (&'a str, WalRecord<'a>)>,
+    ) -> Result<u64, WalError> {
+        let temp_path = self.path.with_extension("rewrite");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let mut size = 0;
+
+        // The bulk of the new log is written without blocking appenders.
+        let mut frame = vec![];
+        for (namespace, record) in records {
+            frame.clear();
+            record.encode_frame(namespace, &mut frame);
+            writer.write_all(&frame)?;
+            size += frame.len() as u64;
+        }
+
+        let mut state = self.lock();
+
+        let rewrite_buffer = state.rewrite_buffer.take().unwrap_or_default();
+        writer.write_all(&rewrite_buffer)?;
+        size += rewrite_buffer.len() as u64;
+
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        state.writer = BufWriter::new(file);
+        state.size = size;
+        state.base_size = size;
+        state.unsynced = false;
+
+        Ok(size)
+    }
+}
+
+fn decode_frame(bytes: &[u8]) -> Option<((String, WalRecord<'static>), usize)> {
+    if bytes.len() < FRAME_HEADER_SIZE {
+        return None;
+    }
+
+    let length = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+    let payload = bytes.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let record = WalRecord::decode_payload(payload)?;
+
+    Some((record, FRAME_HEADER_SIZE + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::DEFAULT_NAMESPACE;
+
+    // Collects warnings, as the servers' logger would print them
+    struct CapturedLog;
+
+    static WARNINGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    impl log::Log for CapturedLog {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn warnings_about(path: &Path) -> Vec<String> {
+        let path = path.display().to_string();
+        WARNINGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|warning| warning.contains(&path))
+            .cloned()
+            .collect()
+    }
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rstore-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn set(key: &str, value: &[u8]) -> WalRecord<'static> {
+        WalRecord::Set {
+            key: Cow::Owned(key.to_owned()),
+            value: Cow::Owned(value.to_vec()),
+            expires_at: None,
+            version: 1,
+            kind: 0,
+        }
+    }
+
+    fn keys(records: &[(String, WalRecord)]) -> Vec<String> {
+        records
+            .iter()
+            .map(|(_, record)| match record {
+                WalRecord::Set { key, .. } | WalRecord::Delete { key } => key.to_string(),
+                other => panic!("unexpected record {:?}", other),
+            })
+            .collect()
+    }
+
+    // Writes three records and returns the size of the log after the first two
+    fn write_log(path: &Path) -> u64 {
+        let (wal, records) = WriteAheadLog::open(path, FsyncPolicy::Always, 0).unwrap();
+        assert!(records.is_empty());
+
+        wal.append(DEFAULT_NAMESPACE, &set("a", b"1")).unwrap();
+        wal.append("other", &set("b", b"2")).unwrap();
+        let size = std::fs::metadata(path).unwrap().len();
+        wal.append(DEFAULT_NAMESPACE, &set("c", b"3")).unwrap();
+        size
+    }
+
+    #[test]
+    fn replays_every_record_with_its_namespace() {
+        let path = temp_log("replay");
+        write_log(&path);
+
+        let (_, records) = WriteAheadLog::open(&path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(keys(&records), ["a", "b", "c"]);
+        assert_eq!(records[0].0, DEFAULT_NAMESPACE);
+        assert_eq!(records[1].0, "other");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn truncates_a_torn_tail() {
+        let _ = log::set_logger(&CapturedLog);
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let path = temp_log("torn");
+        let valid_size = write_log(&path);
+
+        let size = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 3)
+            .unwrap();
+
+        let (wal, records) = WriteAheadLog::open(&path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(keys(&records), ["a", "b"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_size);
+        assert_eq!(wal.dropped_bytes(), size - 3 - valid_size);
+        assert_eq!(
+            warnings_about(&path),
+            [format!(
+                "Write-ahead log {} has {} invalid trailing bytes at offset {}, truncating",
+                path.display(),
+                size - 3 - valid_size,
+                valid_size
+            )]
+        );
+
+        // Appends continue right after the last valid record
+        wal.append(DEFAULT_NAMESPACE, &WalRecord::Delete { key: "a".into() })
+            .unwrap();
+        drop(wal);
+        let (wal, records) = WriteAheadLog::open(&path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(keys(&records), ["a", "b", "a"]);
+        assert_eq!(wal.dropped_bytes(), 0);
+        assert_eq!(warnings_about(&path).len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn truncates_a_tail_failing_its_checksum() {
+        let path = temp_log("crc");
+        let valid_size = write_log(&path);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = WriteAheadLog::open(&path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(keys(&records), ["a", "b"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_size);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,31 +1,165 @@
-mod engine;
+use std::{collections::BTreeMap, convert::Infallible, time::Duration};
 
 use axum::{
-    Json, Router,
-    extract::{Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    Extension, Json, Router, ServiceExt,
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::{delete, get, post},
 };
-use engine::KVEngine;
+use tower::Layer;
+
+use rstore::engine::{
+    self, EngineConfig, Expiration, GeoShape, GeoSort, KVEngine, KeyFilter, KeyRange, ListEnd,
+    RateLimitAlgorithm, SetCondition, SetOptions, TxOperation, TxResult,
+};
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+const EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() {
-    let engine = KVEngine::new();
+    rstore::logging::init();
+
+    let engine = match KVEngine::open(EngineConfig::from_env()) {
+        Ok(engine) => engine,
+        Err(error) => {
+            // Starting empty would let the next snapshot overwrite the data on disk.
+            log::error!("Failed to restore persisted data: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    engine.start_expiration_sweeper(EXPIRATION_SWEEP_INTERVAL);
+    engine.start_snapshot_scheduler();
+    engine.start_wal_maintenance();
 
     let app = Router::new()
         .route("/", get(health_check))
         .route("/value", post(set_value))
         .route("/value", get(get_value))
         .route("/value", delete(delete_value))
+        .route("/expire", post(expire))
+        .route("/persist", post(persist))
+        .route("/ttl", get(get_ttl))
+        .route("/incr", post(increment))
+        .route("/keys", get(scan_keys))
+        .route("/batch/get", post(batch_get))
+        .route("/batch/set", post(batch_set))
+        .route("/batch/delete", post(batch_delete))
+        .route("/tx", post(transaction))
+        .route("/watch", get(watch_keys))
+        .route(
+            "/list/:key",
+            post(list_push).get(list_range).delete(list_pop),
+        )
+        .route("/list/:key/len", get(list_len))
+        .route("/list/:key/trim", post(list_trim))
+        .route("/hash/:key", get(hash_get_all).post(hash_set))
+        .route(
+            "/hash/:key/:field",
+            get(hash_get_field)
+                .put(hash_set_field)
+                .delete(hash_delete_field),
+        )
+        .route("/hash/:key/:field/incr", post(hash_increment_field))
+        .route("/set/:key", post(set_add).get(set_members))
+        .route("/set/:key/:member", get(set_is_member).delete(set_remove))
+        .route("/sets/inter", post(set_intersection))
+        .route("/sets/union", post(set_union))
+        .route("/sets/diff", post(set_difference))
+        .route("/zset/:key", post(sorted_set_add).get(sorted_set_range))
+        .route("/zset/:key/:member", delete(sorted_set_remove))
+        .route("/zset/:key/:member/rank", get(sorted_set_rank))
+        .route("/zset/:key/:member/incr", post(sorted_set_increment))
+        .route(
+            "/json/:key",
+            get(json_get).put(json_set).delete(json_delete),
+        )
+        .route("/json/:key/append", post(json_array_append))
+        .route("/json/:key/incr", post(json_increment))
+        .route("/geo/:key", post(geo_add))
+        .route("/geo/:key/dist", get(geo_dist))
+        .route("/geo/:key/search", get(geo_search))
+        .route("/ratelimit", post(rate_limit))
+        .route("/lock/:name", post(lock_acquire).delete(lock_release))
+        .route("/lock/:name/renew", post(lock_renew))
         .route("/clear", delete(clear_all))
-        .with_state(engine);
+        .route("/stats", get(stats))
+        .route("/namespaces/:name", delete(drop_namespace))
+        .route("/save", post(save))
+        .route("/bgsave", post(background_save));
+    // Runs before routing, so it can strip the namespace prefix off the path
+    let app = middleware::from_fn_with_state(engine, select_namespace).layer(app);
 
     let addr = "0.0.0.0:13535";
-    println!("Listening on {}", addr);
+    log::info!("Listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+        .await
+        .unwrap();
+}
+
+/// Serves `/ns/{name}/...` like `/...`, on the keys of namespace `name` only;
+/// paths without the prefix use the default namespace. Handlers get the engine
+/// bound to the namespace as an `Extension`.
+async fn select_namespace(
+    State(engine): State<KVEngine>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(rest) = request.uri().path().strip_prefix("/ns/") else {
+        request.extensions_mut().insert(engine);
+        return next.run(request).await;
+    };
+
+    let (name, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let selected = if may_create_namespace(request.method(), path) {
+        engine.create_namespace(name)
+    } else {
+        engine.namespace(name)
+    };
+    let engine = match selected {
+        Ok(engine) => engine,
+        Err(error @ engine::KVError::TooManyNamespaces) => {
+            return (StatusCode::INSUFFICIENT_STORAGE, error.to_string()).into_response();
+        }
+        Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+    };
+
+    let path = if path.is_empty() { "/" } else { path };
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_owned(),
+    };
+    let Ok(uri) = Uri::builder().path_and_query(path_and_query).build() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    *request.uri_mut() = uri;
+    request.extensions_mut().insert(engine);
+    next.run(request).await
+}
+
+/// Whether the request may write, so the namespace it's sent to is created up front and
+/// running out of namespaces is answered before the handler runs. Reads leave no namespace
+/// behind, and deletes only touch keys that exist, so neither creates one.
+fn may_create_namespace(method: &Method, path: &str) -> bool {
+    match *method {
+        Method::GET | Method::HEAD | Method::DELETE => false,
+        Method::POST => !matches!(
+            path,
+            "/batch/get" | "/sets/inter" | "/sets/union" | "/sets/diff"
+        ),
+        _ => true,
+    }
 }
 
 async fn health_check() -> impl IntoResponse {
@@ -39,19 +173,164 @@ async fn health_check() -> impl IntoResponse {
 struct SetValueRequest {
     key: String,
     value: String,
+    // seconds
+    ttl: Option<u64>,
+    #[serde(default)]
+    nx: bool,
+    #[serde(default)]
+    xx: bool,
+    #[serde(default)]
+    get: bool,
+}
+
+// For `application/octet-stream` bodies the key, ttl and options travel in the query string
+#[derive(serde::Deserialize)]
+struct SetValueQuery {
+    key: Option<String>,
+    // seconds
+    ttl: Option<u64>,
+    #[serde(default)]
+    nx: bool,
+    #[serde(default)]
+    xx: bool,
+    #[serde(default)]
+    get: bool,
+}
+
+struct SetValueOptions {
+    // only if the key doesn't exist
+    nx: bool,
+    // only if the key exists
+    xx: bool,
+    // respond with the previous value
+    get: bool,
+}
+
+// Versions travel as strong entity tags: "42"
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn parse_etag(header: &HeaderValue) -> Option<u64> {
+    header
+        .to_str()
+        .ok()?
+        .trim()
+        .strip_prefix('"')?
+        .strip_suffix('"')?
+        .parse()
+        .ok()
+}
+
+fn is_octet_stream(header: Option<&HeaderValue>) -> bool {
+    header
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(OCTET_STREAM))
+}
+
+/// Collects the write precondition from the options and the `If-Match` / `If-None-Match` headers.
+/// Only one may be given.
+fn set_condition(
+    options: &SetValueOptions,
+    headers: &HeaderMap,
+) -> Result<SetCondition, StatusCode> {
+    let mut conditions = vec![];
+
+    if options.nx {
+        conditions.push(SetCondition::IfAbsent);
+    }
+    if options.xx {
+        conditions.push(SetCondition::IfPresent);
+    }
+    if let Some(if_match) = headers.get(header::IF_MATCH) {
+        if if_match.as_bytes() == b"*" {
+            conditions.push(SetCondition::IfPresent);
+        } else {
+            // A tag that isn't one of ours can never match the current version
+            let version = parse_etag(if_match).ok_or(StatusCode::PRECONDITION_FAILED)?;
+            conditions.push(SetCondition::IfVersion(version));
+        }
+    }
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        if if_none_match.as_bytes() != b"*" {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        conditions.push(SetCondition::IfAbsent);
+    }
+
+    match conditions.as_slice() {
+        [] => Ok(SetCondition::Always),
+        [condition] => Ok(*condition),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
 }
 
 async fn set_value(
-    engine: State<KVEngine>,
-    Json(body): Json<SetValueRequest>,
+    engine: Extension<KVEngine>,
+    Query(query): Query<SetValueQuery>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> impl IntoResponse {
-    let result = engine.set_key_value(body.key, body.value);
+    let (key, value, ttl, options) = if is_octet_stream(headers.get(header::CONTENT_TYPE)) {
+        let Some(key) = query.key else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        let options = SetValueOptions {
+            nx: query.nx,
+            xx: query.xx,
+            get: query.get,
+        };
+        (key, body.to_vec(), query.ttl, options)
+    } else {
+        let Ok(body) = serde_json::from_slice::<SetValueRequest>(&body) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        let options = SetValueOptions {
+            nx: body.nx,
+            xx: body.xx,
+            get: body.get,
+        };
+        (body.key, body.value.into_bytes(), body.ttl, options)
+    };
 
-    if result.is_err() {
-        return StatusCode::INTERNAL_SERVER_ERROR;
-    }
+    let condition = match set_condition(&options, &headers) {
+        Ok(condition) => condition,
+        Err(status) => return status.into_response(),
+    };
+    let set_options = SetOptions {
+        condition,
+        expiration: ttl.map(|ttl| Expiration::After(Duration::from_secs(ttl))),
+        return_previous: options.get,
+    };
+
+    match engine.set_with_options(key, value, set_options) {
+        Ok(outcome) => {
+            let Some(version) = outcome.version else {
+                if !options.get {
+                    return StatusCode::PRECONDITION_FAILED.into_response();
+                }
+                // The write was skipped, but the caller still gets the value it asked for
+                let mut response = value_response(outcome.previous, &headers);
+                *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                return response;
+            };
 
-    StatusCode::NO_CONTENT
+            if options.get {
+                let mut response = value_response(outcome.previous, &headers);
+                if let Ok(etag) = HeaderValue::from_str(&etag(version)) {
+                    response.headers_mut().insert(header::ETAG, etag);
+                }
+                return response;
+            }
+
+            (StatusCode::NO_CONTENT, [(header::ETAG, etag(version))]).into_response()
+        }
+        Err(error) => match error {
+            engine::KVError::OutOfMemory => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+            engine::KVError::WrongType => (StatusCode::CONFLICT, error.to_string()).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -61,36 +340,258 @@ struct GetValueRequest {
 
 #[derive(serde::Serialize)]
 struct GetValueResponse {
-    value: String,
+    // null when there was no previous value (`get` option)
+    value: Option<String>,
+}
+
+/// A value as JSON, or as raw bytes when asked for
+/// or when the value can't be represented as a JSON string.
+fn value_response(value: Option<Vec<u8>>, headers: &HeaderMap) -> Response {
+    let value = match value {
+        Some(value) if is_octet_stream(headers.get(header::ACCEPT)) => Err(value),
+        Some(value) => String::from_utf8(value)
+            .map(Some)
+            .map_err(|error| error.into_bytes()),
+        None => Ok(None),
+    };
+
+    match value {
+        Ok(value) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&GetValueResponse { value }).unwrap_or_default(),
+            ))
+            .unwrap(),
+        Err(bytes) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, OCTET_STREAM)
+            .body(Body::from(bytes))
+            .unwrap(),
+    }
 }
 
 async fn get_value(
-    engine: State<KVEngine>,
+    engine: Extension<KVEngine>,
     Query(body): Query<GetValueRequest>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    if let Ok(value) = engine.get_key_value(&body.key) {
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(serde_json::to_string(&GetValueResponse { value }).unwrap_or_default())
-            .unwrap()
-    } else {
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body("Key not found".to_string())
-            .unwrap()
+    let (value, version) = match engine.get_key_value_with_version(&body.key) {
+        Ok(found) => found,
+        Err(error @ engine::KVError::WrongType) => {
+            return (StatusCode::CONFLICT, error.to_string()).into_response();
+        }
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Key not found"))
+                .unwrap();
+        }
+    };
+
+    let mut response = value_response(Some(value), &headers);
+    if let Ok(etag) = HeaderValue::from_str(&etag(version)) {
+        response.headers_mut().insert(header::ETAG, etag);
     }
+    response
 }
 
 #[derive(serde::Deserialize)]
 struct DeleteValueRequest {
     key: String,
+    // respond with the deleted value
+    #[serde(default)]
+    get: bool,
 }
 
 async fn delete_value(
-    engine: State<KVEngine>,
+    engine: Extension<KVEngine>,
     Query(body): Query<DeleteValueRequest>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let result = if body.get {
+        engine
+            .get_and_delete(&body.key)
+            .map(|(value, _)| value_response(Some(value), &headers))
+    } else {
+        engine
+            .delete_key_value(&body.key)
+            .map(|_| StatusCode::NO_CONTENT.into_response())
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(error) => match error {
+            engine::KVError::KeyNotFound => StatusCode::NOT_FOUND.into_response(),
+            engine::KVError::WrongType => (StatusCode::CONFLICT, error.to_string()).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    }
+}
+
+const SCAN_LIMIT_DEFAULT: usize = 100;
+
+#[derive(serde::Deserialize)]
+struct ScanKeysQuery {
+    prefix: Option<String>,
+    // inclusive
+    start: Option<String>,
+    // exclusive
+    end: Option<String>,
+    // `cursor` of the previous response
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct ScanKeysResponse {
+    keys: Vec<String>,
+    // null once every key was returned
+    cursor: Option<String>,
+}
+
+async fn scan_keys(
+    engine: Extension<KVEngine>,
+    Query(query): Query<ScanKeysQuery>,
+) -> impl IntoResponse {
+    let range = match (query.prefix, query.start, query.end) {
+        (Some(prefix), None, None) => KeyRange::Prefix(prefix),
+        (None, start, end) => KeyRange::Range { start, end },
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let result = engine.scan(
+        &range,
+        query.cursor.as_deref(),
+        query.limit.unwrap_or(SCAN_LIMIT_DEFAULT),
+        false,
+    );
+
+    match result {
+        Ok(page) => Json(ScanKeysResponse {
+            keys: page.entries.into_iter().map(|(key, _)| key).collect(),
+            cursor: page.next_cursor,
+        })
+        .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BatchKeysRequest {
+    keys: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct BatchGetResponse {
+    values: BTreeMap<String, String>,
+    missing: Vec<String>,
+    // keys whose value isn't valid UTF-8; fetch them one by one with `GET /value`
+    binary: Vec<String>,
+}
+
+async fn batch_get(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchKeysRequest>,
+) -> impl IntoResponse {
+    let Ok(values) = engine.mget(&body.keys) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let mut response = BatchGetResponse {
+        values: BTreeMap::new(),
+        missing: vec![],
+        binary: vec![],
+    };
+    for (key, value) in body.keys.into_iter().zip(values) {
+        match value.map(String::from_utf8) {
+            Some(Ok(value)) => {
+                response.values.insert(key, value);
+            }
+            Some(Err(_)) => response.binary.push(key),
+            None => response.missing.push(key),
+        }
+    }
+
+    Json(response).into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct BatchSetRequest {
+    values: BTreeMap<String, String>,
+}
+
+#[derive(serde::Serialize)]
+struct BatchSetResponse {
+    // new version of every key
+    versions: BTreeMap<String, u64>,
+}
+
+async fn batch_set(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchSetRequest>,
+) -> impl IntoResponse {
+    let keys: Vec<String> = body.values.keys().cloned().collect();
+    let entries = body
+        .values
+        .into_iter()
+        .map(|(key, value)| (key, value.into_bytes()))
+        .collect();
+
+    match engine.mset(entries) {
+        Ok(versions) => Json(BatchSetResponse {
+            versions: keys.into_iter().zip(versions).collect(),
+        })
+        .into_response(),
+        Err(engine::KVError::OutOfMemory) => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct BatchDeleteResponse {
+    deleted: Vec<String>,
+    missing: Vec<String>,
+}
+
+async fn batch_delete(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchKeysRequest>,
 ) -> impl IntoResponse {
-    let result = engine.delete_key_value(&body.key);
+    let Ok(deleted) = engine.mdel(&body.keys) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let (deleted, missing): (Vec<_>, Vec<_>) = body
+        .keys
+        .into_iter()
+        .zip(deleted)
+        .partition(|(_, deleted)| *deleted);
+
+    Json(BatchDeleteResponse {
+        deleted: deleted.into_iter().map(|(key, _)| key).collect(),
+        missing: missing.into_iter().map(|(key, _)| key).collect(),
+    })
+    .into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct ExpireRequest {
+    key: String,
+    // seconds
+    ttl: Option<u64>,
+    // unix timestamp in seconds
+    expire_at: Option<u64>,
+}
+
+async fn expire(engine: Extension<KVEngine>, Json(body): Json<ExpireRequest>) -> impl IntoResponse {
+    let expiration = match (body.ttl, body.expire_at) {
+        (Some(ttl), None) => Expiration::After(Duration::from_secs(ttl)),
+        (None, Some(expire_at)) => Expiration::AtUnixMillis(expire_at.saturating_mul(1000)),
+        _ => return StatusCode::BAD_REQUEST,
+    };
+
+    let result = engine.expire(&body.key, expiration);
 
     match result {
         Ok(_) => StatusCode::NO_CONTENT,
@@ -101,11 +602,1330 @@ async fn delete_value(
     }
 }
 
-async fn clear_all(state: State<KVEngine>) -> impl IntoResponse {
-    let result = state.clear_all();
+#[derive(serde::Deserialize)]
+struct PersistRequest {
+    key: String,
+}
+
+async fn persist(
+    engine: Extension<KVEngine>,
+    Json(body): Json<PersistRequest>,
+) -> impl IntoResponse {
+    let result = engine.persist(&body.key);
 
     match result {
         Ok(_) => StatusCode::NO_CONTENT,
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        Err(error) => match error {
+            engine::KVError::KeyNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GetTtlRequest {
+    key: String,
+}
+
+#[derive(serde::Serialize)]
+struct GetTtlResponse {
+    // seconds, null if the key never expires
+    ttl: Option<u64>,
+}
+
+async fn get_ttl(
+    engine: Extension<KVEngine>,
+    Query(body): Query<GetTtlRequest>,
+) -> impl IntoResponse {
+    match engine.ttl(&body.key) {
+        Ok(ttl) => {
+            let ttl = ttl.map(|ttl| ttl.as_secs());
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(serde_json::to_string(&GetTtlResponse { ttl }).unwrap_or_default())
+                .unwrap()
+        }
+        Err(engine::KVError::KeyNotFound) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Key not found".to_string())
+            .unwrap(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("".to_string())
+            .unwrap(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct IncrementRequest {
+    key: String,
+    // integer or float, 1 if omitted; a float switches to floating point arithmetic
+    by: Option<serde_json::Number>,
+}
+
+#[derive(serde::Serialize)]
+struct IncrementResponse {
+    value: serde_json::Number,
+}
+
+async fn increment(
+    engine: Extension<KVEngine>,
+    Json(body): Json<IncrementRequest>,
+) -> impl IntoResponse {
+    let by = body.by.unwrap_or_else(|| 1.into());
+
+    let result = if by.is_f64() {
+        let by = by.as_f64().unwrap_or_default();
+        engine.incr_by_float(&body.key, by).map(|value| {
+            // The engine never stores non-finite values, so this always succeeds
+            serde_json::Number::from_f64(value).unwrap_or_else(|| 0.into())
+        })
+    } else {
+        let Some(by) = by.as_i64() else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        engine.incr_by(&body.key, by).map(serde_json::Number::from)
+    };
+
+    match result {
+        Ok(value) => Json(IncrementResponse { value }).into_response(),
+        Err(error) => match error {
+            engine::KVError::NotAnInteger | engine::KVError::NotAFloat => {
+                (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response()
+            }
+            engine::KVError::OutOfMemory => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+            engine::KVError::WrongType => (StatusCode::CONFLICT, error.to_string()).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TransactionRequest {
+    // key -> version (ETag) it must still have, 0 for "must not exist"
+    #[serde(default)]
+    watch: BTreeMap<String, u64>,
+    ops: Vec<TransactionOperation>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TransactionOperation {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        // seconds
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    Incr {
+        key: String,
+        by: Option<i64>,
+    },
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum TransactionResult {
+    Value { value: Option<String> },
+    Stored { ok: bool },
+    Deleted { deleted: bool },
+    Integer { value: i64 },
+}
+
+#[derive(serde::Serialize)]
+struct TransactionResponse {
+    results: Vec<TransactionResult>,
+}
+
+async fn transaction(
+    engine: Extension<KVEngine>,
+    Json(body): Json<TransactionRequest>,
+) -> impl IntoResponse {
+    let watches: Vec<(String, u64)> = body.watch.into_iter().collect();
+    let operations = body
+        .ops
+        .into_iter()
+        .map(|operation| match operation {
+            TransactionOperation::Get { key } => TxOperation::Get { key },
+            TransactionOperation::Set { key, value, ttl } => TxOperation::Set {
+                key,
+                value: value.into_bytes(),
+                expiration: ttl.map(|ttl| Expiration::After(Duration::from_secs(ttl))),
+            },
+            TransactionOperation::Delete { key } => TxOperation::Delete { key },
+            TransactionOperation::Incr { key, by } => TxOperation::IncrBy {
+                key,
+                delta: by.unwrap_or(1),
+            },
+        })
+        .collect();
+
+    match engine.execute_transaction(&watches, operations) {
+        Ok(results) => Json(TransactionResponse {
+            results: results
+                .into_iter()
+                .map(|result| match result {
+                    TxResult::Value(value) => TransactionResult::Value {
+                        value: value.map(|value| String::from_utf8_lossy(&value).into_owned()),
+                    },
+                    TxResult::Stored => TransactionResult::Stored { ok: true },
+                    TxResult::Deleted(deleted) => TransactionResult::Deleted { deleted },
+                    TxResult::Integer(value) => TransactionResult::Integer { value },
+                })
+                .collect(),
+        })
+        .into_response(),
+        Err(error) => match error {
+            engine::KVError::VersionMismatch => StatusCode::PRECONDITION_FAILED.into_response(),
+            engine::KVError::NotAnInteger => {
+                (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response()
+            }
+            engine::KVError::OutOfMemory => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+            engine::KVError::WrongType => (StatusCode::CONFLICT, error.to_string()).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WatchKeysQuery {
+    // every key if neither is given
+    key: Option<String>,
+    prefix: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct KeyEventResponse {
+    key: String,
+    op: &'static str,
+    version: u64,
+}
+
+/// Server-Sent Events of the matching keys. The stream ends if the client falls
+/// too far behind to receive every event, so it knows to resynchronize.
+async fn watch_keys(
+    engine: Extension<KVEngine>,
+    Query(query): Query<WatchKeysQuery>,
+) -> impl IntoResponse {
+    let filters = query
+        .key
+        .map(KeyFilter::Key)
+        .into_iter()
+        .chain(query.prefix.map(KeyFilter::Prefix))
+        .collect();
+    let watcher = engine.watch_keys(filters);
+
+    let events = futures_util::stream::unfold(watcher, |mut watcher| async move {
+        let event = watcher.recv().await?;
+        let data = serde_json::to_string(&KeyEventResponse {
+            key: event.key,
+            op: event.op.as_str(),
+            version: event.version,
+        })
+        .unwrap_or_default();
+
+        let event = Event::default().event(event.op.as_str()).data(data);
+        Some((Ok::<_, Infallible>(event), watcher))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ListSide {
+    Left,
+    #[default]
+    Right,
+}
+
+impl From<ListSide> for ListEnd {
+    fn from(side: ListSide) -> Self {
+        match side {
+            ListSide::Left => ListEnd::Left,
+            ListSide::Right => ListEnd::Right,
+        }
+    }
+}
+
+/// Status for errors of commands on typed values (lists, ...).
+fn collection_error(error: engine::KVError) -> Response {
+    match error {
+        engine::KVError::WrongType => (StatusCode::CONFLICT, error.to_string()).into_response(),
+        engine::KVError::OutOfMemory => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ListPushQuery {
+    // the tail if omitted
+    side: Option<ListSide>,
+}
+
+#[derive(serde::Deserialize)]
+struct ListPushRequest {
+    values: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ListLengthResponse {
+    length: usize,
+}
+
+#[derive(serde::Serialize)]
+struct ListValuesResponse {
+    values: Vec<String>,
+}
+
+impl ListValuesResponse {
+    fn new(values: Vec<Vec<u8>>) -> Self {
+        ListValuesResponse {
+            values: values
+                .into_iter()
+                .map(|value| String::from_utf8_lossy(&value).into_owned())
+                .collect(),
+        }
+    }
+}
+
+async fn list_push(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<ListPushQuery>,
+    Json(body): Json<ListPushRequest>,
+) -> impl IntoResponse {
+    let side = query.side.unwrap_or_default();
+    let values = body.values.into_iter().map(String::into_bytes).collect();
+
+    match engine.list_push(&key, side.into(), values) {
+        Ok(length) => Json(ListLengthResponse { length }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ListPopQuery {
+    // the head if omitted, so that pushing to the tail makes a FIFO queue
+    side: Option<ListSide>,
+    count: Option<usize>,
+    // seconds to wait for a value if the list is empty, 0 to wait forever; a single
+    // value is popped. Doesn't wait if omitted
+    timeout: Option<f64>,
+}
+
+async fn list_pop(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<ListPopQuery>,
+) -> impl IntoResponse {
+    let end = query.side.unwrap_or(ListSide::Left).into();
+
+    let result = match query.timeout {
+        Some(timeout) => {
+            let timeout = match Duration::try_from_secs_f64(timeout) {
+                Ok(timeout) if timeout.is_zero() => None,
+                Ok(timeout) => Some(timeout),
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            };
+
+            engine
+                .list_blocking_pop(std::slice::from_ref(&key), end, timeout)
+                .await
+                .map(|entry| entry.map(|(_, value)| value).into_iter().collect())
+        }
+        None => engine.list_pop(&key, end, query.count.unwrap_or(1)),
+    };
+
+    match result {
+        Ok(values) => Json(ListValuesResponse::new(values)).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ListRangeQuery {
+    // inclusive indexes, negative ones counting from the end; the whole list if omitted
+    start: Option<i64>,
+    stop: Option<i64>,
+}
+
+async fn list_range(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<ListRangeQuery>,
+) -> impl IntoResponse {
+    let start = query.start.unwrap_or(0);
+    let stop = query.stop.unwrap_or(-1);
+
+    match engine.list_range(&key, start, stop) {
+        Ok(values) => Json(ListValuesResponse::new(values)).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn list_len(engine: Extension<KVEngine>, Path(key): Path<String>) -> impl IntoResponse {
+    match engine.list_len(&key) {
+        Ok(length) => Json(ListLengthResponse { length }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ListTrimRequest {
+    start: i64,
+    stop: i64,
+}
+
+async fn list_trim(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Json(body): Json<ListTrimRequest>,
+) -> impl IntoResponse {
+    match engine.list_trim(&key, body.start, body.stop) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HashSetRequest {
+    fields: BTreeMap<String, String>,
+}
+
+#[derive(serde::Serialize)]
+struct HashSetResponse {
+    // fields that didn't exist before
+    added: usize,
+}
+
+#[derive(serde::Serialize)]
+struct HashGetAllResponse {
+    fields: BTreeMap<String, String>,
+}
+
+async fn hash_get_all(engine: Extension<KVEngine>, Path(key): Path<String>) -> impl IntoResponse {
+    match engine.hash_get_all(&key) {
+        Ok(fields) => Json(HashGetAllResponse {
+            fields: fields
+                .into_iter()
+                .map(|(field, value)| (field, String::from_utf8_lossy(&value).into_owned()))
+                .collect(),
+        })
+        .into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn hash_set(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Json(body): Json<HashSetRequest>,
+) -> impl IntoResponse {
+    let fields = body
+        .fields
+        .into_iter()
+        .map(|(field, value)| (field, value.into_bytes()))
+        .collect();
+
+    match engine.hash_set(&key, fields) {
+        Ok(added) => Json(HashSetResponse { added }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn hash_get_field(
+    engine: Extension<KVEngine>,
+    Path((key, field)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    match engine.hash_get(&key, &field) {
+        Ok(Some(value)) => value_response(Some(value), &headers),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HashSetFieldRequest {
+    value: String,
+}
+
+async fn hash_set_field(
+    engine: Extension<KVEngine>,
+    Path((key, field)): Path<(String, String)>,
+    Json(body): Json<HashSetFieldRequest>,
+) -> impl IntoResponse {
+    match engine.hash_set(&key, vec![(field, body.value.into_bytes())]) {
+        Ok(added) => Json(HashSetResponse { added }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn hash_delete_field(
+    engine: Extension<KVEngine>,
+    Path((key, field)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match engine.hash_delete(&key, &[field]) {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HashIncrementRequest {
+    // 1 if omitted
+    by: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct HashIncrementResponse {
+    value: i64,
+}
+
+async fn hash_increment_field(
+    engine: Extension<KVEngine>,
+    Path((key, field)): Path<(String, String)>,
+    Json(body): Json<HashIncrementRequest>,
+) -> impl IntoResponse {
+    match engine.hash_incr_by(&key, &field, body.by.unwrap_or(1)) {
+        Ok(value) => Json(HashIncrementResponse { value }).into_response(),
+        Err(error @ engine::KVError::NotAnInteger) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response()
+        }
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SetAddRequest {
+    members: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct MembersAddedResponse {
+    // members that weren't in the set before
+    added: usize,
+}
+
+#[derive(serde::Serialize)]
+struct SetMembersResponse {
+    members: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct IsMemberResponse {
+    member: bool,
+}
+
+async fn set_add(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Json(body): Json<SetAddRequest>,
+) -> impl IntoResponse {
+    match engine.set_add(&key, body.members) {
+        Ok(added) => Json(MembersAddedResponse { added }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_members(engine: Extension<KVEngine>, Path(key): Path<String>) -> impl IntoResponse {
+    match engine.set_members(&key) {
+        Ok(members) => Json(SetMembersResponse { members }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_is_member(
+    engine: Extension<KVEngine>,
+    Path((key, member)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match engine.set_is_member(&key, &member) {
+        Ok(member) => Json(IsMemberResponse { member }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_remove(
+    engine: Extension<KVEngine>,
+    Path((key, member)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match engine.set_remove(&key, &[member]) {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_intersection(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchKeysRequest>,
+) -> impl IntoResponse {
+    match engine.set_intersection(&body.keys) {
+        Ok(members) => Json(SetMembersResponse { members }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_union(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchKeysRequest>,
+) -> impl IntoResponse {
+    match engine.set_union(&body.keys) {
+        Ok(members) => Json(SetMembersResponse { members }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn set_difference(
+    engine: Extension<KVEngine>,
+    Json(body): Json<BatchKeysRequest>,
+) -> impl IntoResponse {
+    match engine.set_difference(&body.keys) {
+        Ok(members) => Json(SetMembersResponse { members }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SortedSetAddRequest {
+    // member => score
+    members: BTreeMap<String, f64>,
+}
+
+#[derive(serde::Deserialize)]
+struct SortedSetRangeQuery {
+    // by score if `min` or `max` is given (inclusive, the other side open),
+    // by position otherwise (inclusive indexes, the whole set if omitted)
+    min: Option<f64>,
+    max: Option<f64>,
+    start: Option<i64>,
+    stop: Option<i64>,
+    // from the highest score; positions only
+    #[serde(default)]
+    reverse: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ScoredMember {
+    member: String,
+    score: f64,
+}
+
+#[derive(serde::Serialize)]
+struct SortedSetRangeResponse {
+    members: Vec<ScoredMember>,
+}
+
+async fn sorted_set_add(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Json(body): Json<SortedSetAddRequest>,
+) -> impl IntoResponse {
+    match engine.sorted_set_add(&key, body.members.into_iter().collect()) {
+        Ok(added) => Json(MembersAddedResponse { added }).into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn sorted_set_range(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<SortedSetRangeQuery>,
+) -> impl IntoResponse {
+    let result = if query.min.is_some() || query.max.is_some() {
+        engine.sorted_set_range_by_score(
+            &key,
+            query.min.unwrap_or(f64::NEG_INFINITY),
+            query.max.unwrap_or(f64::INFINITY),
+        )
+    } else {
+        engine.sorted_set_range(
+            &key,
+            query.start.unwrap_or(0),
+            query.stop.unwrap_or(-1),
+            query.reverse,
+        )
+    };
+
+    match result {
+        Ok(members) => Json(SortedSetRangeResponse {
+            members: members
+                .into_iter()
+                .map(|(member, score)| ScoredMember { member, score })
+                .collect(),
+        })
+        .into_response(),
+        Err(error @ engine::KVError::NotAFloat) => {
+            (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+        }
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SortedSetRankQuery {
+    // from the highest score, e.g. for leaderboards
+    #[serde(default)]
+    reverse: bool,
+}
+
+#[derive(serde::Serialize)]
+struct SortedSetRankResponse {
+    rank: usize,
+}
+
+async fn sorted_set_rank(
+    engine: Extension<KVEngine>,
+    Path((key, member)): Path<(String, String)>,
+    Query(query): Query<SortedSetRankQuery>,
+) -> impl IntoResponse {
+    match engine.sorted_set_rank(&key, &member, query.reverse) {
+        Ok(Some(rank)) => Json(SortedSetRankResponse { rank }).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SortedSetIncrementRequest {
+    by: f64,
+}
+
+#[derive(serde::Serialize)]
+struct SortedSetIncrementResponse {
+    score: f64,
+}
+
+async fn sorted_set_increment(
+    engine: Extension<KVEngine>,
+    Path((key, member)): Path<(String, String)>,
+    Json(body): Json<SortedSetIncrementRequest>,
+) -> impl IntoResponse {
+    match engine.sorted_set_incr_by(&key, &member, body.by) {
+        Ok(score) => Json(SortedSetIncrementResponse { score }).into_response(),
+        Err(error @ engine::KVError::NotAFloat) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response()
+        }
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn sorted_set_remove(
+    engine: Extension<KVEngine>,
+    Path((key, member)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match engine.sorted_set_remove(&key, &[member]) {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+async fn clear_all(state: Extension<KVEngine>) -> impl IntoResponse {
+    let result = state.clear_all();
+
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct StatsResponse {
+    namespace: String,
+    keys: u64,
+    // keys with a deadline
+    expiring_keys: u64,
+    // bytes accounted to the keys of the namespace
+    used_memory: u64,
+}
+
+async fn stats(engine: Extension<KVEngine>) -> impl IntoResponse {
+    match engine.stats() {
+        Ok(stats) => Json(StatsResponse {
+            namespace: engine.namespace_name().to_owned(),
+            keys: stats.keys,
+            expiring_keys: stats.expiring_keys,
+            used_memory: stats.used_memory,
+        })
+        .into_response(),
+        Err(error) => collection_error(error),
+    }
+}
+
+/// Removes an empty namespace so it no longer counts towards `RSTORE_MAX_NAMESPACES`.
+async fn drop_namespace(
+    engine: Extension<KVEngine>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match engine.drop_namespace(&name) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(error @ engine::KVError::NamespaceNotEmpty) => {
+            (StatusCode::CONFLICT, error.to_string()).into_response()
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn snapshot_status(result: Result<(), engine::KVError>) -> StatusCode {
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(error) => match error {
+            engine::KVError::SnapshotDisabled => StatusCode::NOT_IMPLEMENTED,
+            engine::KVError::SnapshotInProgress => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
+async fn save(state: Extension<KVEngine>) -> impl IntoResponse {
+    let engine = state.0.clone();
+
+    let result = tokio::task::spawn_blocking(move || engine.save_snapshot())
+        .await
+        .unwrap_or_else(|error| Err(engine::KVError::SnapshotFailed(error.to_string())));
+
+    snapshot_status(result.map(|_| ()))
+}
+
+async fn background_save(state: Extension<KVEngine>) -> impl IntoResponse {
+    snapshot_status(state.background_save())
+}
+
+fn json_error(error: engine::KVError) -> Response {
+    match error {
+        engine::KVError::InvalidJsonPath => {
+            (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+        }
+        engine::KVError::JsonPathNotFound => {
+            (StatusCode::NOT_FOUND, error.to_string()).into_response()
+        }
+        engine::KVError::NotAFloat => {
+            (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response()
+        }
+        _ => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct JsonPathQuery {
+    // the whole document if omitted
+    path: Option<String>,
+}
+
+impl JsonPathQuery {
+    fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("$")
+    }
+}
+
+async fn json_get(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<JsonPathQuery>,
+) -> impl IntoResponse {
+    match engine.json_get(&key, query.path()) {
+        Ok(Some(value)) => Json(value).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => json_error(error),
+    }
+}
+
+async fn json_set(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<JsonPathQuery>,
+    Json(value): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    match engine.json_set(&key, query.path(), value) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => json_error(error),
+    }
+}
+
+async fn json_delete(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<JsonPathQuery>,
+) -> impl IntoResponse {
+    match engine.json_del(&key, query.path()) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => json_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct JsonAppendRequest {
+    values: Vec<serde_json::Value>,
+}
+
+async fn json_array_append(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<JsonPathQuery>,
+    Json(body): Json<JsonAppendRequest>,
+) -> impl IntoResponse {
+    match engine.json_array_append(&key, query.path(), body.values) {
+        Ok(length) => Json(ListLengthResponse { length }).into_response(),
+        Err(error) => json_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct JsonIncrementRequest {
+    // 1 if omitted
+    by: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonIncrementResponse {
+    value: serde_json::Number,
+}
+
+async fn json_increment(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<JsonPathQuery>,
+    Json(body): Json<JsonIncrementRequest>,
+) -> impl IntoResponse {
+    match engine.json_incr_by(&key, query.path(), body.by.unwrap_or(1.0)) {
+        Ok(value) => Json(JsonIncrementResponse { value }).into_response(),
+        Err(error) => json_error(error),
+    }
+}
+
+fn geo_error(error: engine::KVError) -> Response {
+    match error {
+        engine::KVError::InvalidCoordinates => {
+            (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+        }
+        _ => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GeoMember {
+    member: String,
+    longitude: f64,
+    latitude: f64,
+}
+
+#[derive(serde::Deserialize)]
+struct GeoAddRequest {
+    members: Vec<GeoMember>,
+}
+
+#[derive(serde::Serialize)]
+struct GeoAddResponse {
+    added: usize,
+}
+
+async fn geo_add(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Json(body): Json<GeoAddRequest>,
+) -> impl IntoResponse {
+    let members = body
+        .members
+        .into_iter()
+        .map(|member| (member.member, member.longitude, member.latitude))
+        .collect();
+
+    match engine.geo_add(&key, members) {
+        Ok(added) => Json(GeoAddResponse { added }).into_response(),
+        Err(error) => geo_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GeoDistQuery {
+    from: String,
+    to: String,
+}
+
+#[derive(serde::Serialize)]
+struct GeoDistResponse {
+    // meters
+    distance: f64,
+}
+
+async fn geo_dist(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<GeoDistQuery>,
+) -> impl IntoResponse {
+    match engine.geo_dist(&key, &query.from, &query.to) {
+        Ok(Some(distance)) => Json(GeoDistResponse { distance }).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => geo_error(error),
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum GeoSortQuery {
+    Asc,
+    Desc,
+}
+
+impl From<GeoSortQuery> for GeoSort {
+    fn from(sort: GeoSortQuery) -> Self {
+        match sort {
+            GeoSortQuery::Asc => GeoSort::Ascending,
+            GeoSortQuery::Desc => GeoSort::Descending,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GeoSearchQuery {
+    longitude: f64,
+    latitude: f64,
+    // meters; either a radius or both a width and a height
+    radius: Option<f64>,
+    width: Option<f64>,
+    height: Option<f64>,
+    // unsorted if omitted
+    sort: Option<GeoSortQuery>,
+    count: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct GeoMatchResponse {
+    member: String,
+    distance: f64,
+    longitude: f64,
+    latitude: f64,
+}
+
+#[derive(serde::Serialize)]
+struct GeoSearchResponse {
+    matches: Vec<GeoMatchResponse>,
+}
+
+async fn geo_search(
+    engine: Extension<KVEngine>,
+    Path(key): Path<String>,
+    Query(query): Query<GeoSearchQuery>,
+) -> impl IntoResponse {
+    let shape = match (query.radius, query.width, query.height) {
+        (Some(radius), None, None) => GeoShape::Radius(radius),
+        (None, Some(width), Some(height)) => GeoShape::Box { width, height },
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Expected either a radius or a width and a height",
+            )
+                .into_response();
+        }
+    };
+
+    match engine.geo_search(
+        &key,
+        query.longitude,
+        query.latitude,
+        shape,
+        query.sort.map(GeoSort::from),
+        query.count,
+    ) {
+        Ok(matches) => Json(GeoSearchResponse {
+            matches: matches
+                .into_iter()
+                .map(|found| GeoMatchResponse {
+                    member: found.member,
+                    distance: found.distance,
+                    longitude: found.longitude,
+                    latitude: found.latitude,
+                })
+                .collect(),
+        })
+        .into_response(),
+        Err(error) => geo_error(error),
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum RateLimitAlgorithmBody {
+    FixedWindow,
+    SlidingLog,
+    TokenBucket,
+}
+
+impl From<RateLimitAlgorithmBody> for RateLimitAlgorithm {
+    fn from(algorithm: RateLimitAlgorithmBody) -> Self {
+        match algorithm {
+            RateLimitAlgorithmBody::FixedWindow => RateLimitAlgorithm::FixedWindow,
+            RateLimitAlgorithmBody::SlidingLog => RateLimitAlgorithm::SlidingLog,
+            RateLimitAlgorithmBody::TokenBucket => RateLimitAlgorithm::TokenBucket,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RateLimitRequest {
+    key: String,
+    limit: u64,
+    // seconds
+    window: f64,
+    algorithm: RateLimitAlgorithmBody,
+}
+
+#[derive(serde::Serialize)]
+struct RateLimitResponse {
+    allowed: bool,
+    remaining: u64,
+    // seconds until the whole limit is available again
+    reset_after: f64,
+}
+
+/// Responds 429 Too Many Requests when denied, so a gateway can pass the response on as is.
+/// The `RateLimit-*` headers follow the IETF draft, with the reset in whole seconds.
+async fn rate_limit(
+    engine: Extension<KVEngine>,
+    Json(body): Json<RateLimitRequest>,
+) -> impl IntoResponse {
+    let Ok(window) = Duration::try_from_secs_f64(body.window) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let status = match engine.rate_limit(&body.key, body.limit, window, body.algorithm.into()) {
+        Ok(status) => status,
+        Err(error @ engine::KVError::InvalidRateLimit) => {
+            return (StatusCode::BAD_REQUEST, error.to_string()).into_response();
+        }
+        Err(error) => return collection_error(error),
+    };
+
+    let reset = status.reset_after.as_millis().div_ceil(1000) as u64;
+    let headers = [
+        ("RateLimit-Limit", body.limit),
+        ("RateLimit-Remaining", status.remaining),
+        ("RateLimit-Reset", reset),
+    ];
+    let response = Json(RateLimitResponse {
+        allowed: status.allowed,
+        remaining: status.remaining,
+        reset_after: status.reset_after.as_secs_f64(),
+    });
+
+    if status.allowed {
+        return (StatusCode::OK, headers, response).into_response();
+    }
+
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, headers, response).into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(reset));
+    response
+}
+
+fn lock_error(error: engine::KVError) -> Response {
+    match error {
+        engine::KVError::InvalidLockLease => {
+            (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+        }
+        _ => collection_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LockAcquireRequest {
+    owner: String,
+    // seconds
+    lease: f64,
+    // seconds to wait for the lock if it is taken, 0 to wait forever. Doesn't wait if omitted
+    wait: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+struct LockAcquireResponse {
+    token: u64,
+}
+
+/// Responds 409 Conflict if the lock wasn't acquired.
+async fn lock_acquire(
+    engine: Extension<KVEngine>,
+    Path(name): Path<String>,
+    Json(body): Json<LockAcquireRequest>,
+) -> impl IntoResponse {
+    let Ok(lease) = Duration::try_from_secs_f64(body.lease) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let result = match body.wait {
+        Some(wait) => {
+            let wait = match Duration::try_from_secs_f64(wait) {
+                Ok(wait) if wait.is_zero() => None,
+                Ok(wait) => Some(wait),
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            };
+
+            engine
+                .lock_acquire_blocking(&name, &body.owner, lease, wait)
+                .await
+        }
+        None => engine.lock_acquire(&name, &body.owner, lease),
+    };
+
+    match result {
+        Ok(Some(token)) => Json(LockAcquireResponse { token }).into_response(),
+        Ok(None) => StatusCode::CONFLICT.into_response(),
+        Err(error) => lock_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LockRenewRequest {
+    owner: String,
+    // seconds
+    lease: f64,
+}
+
+/// Responds 409 Conflict if the owner's lease had already ended.
+async fn lock_renew(
+    engine: Extension<KVEngine>,
+    Path(name): Path<String>,
+    Json(body): Json<LockRenewRequest>,
+) -> impl IntoResponse {
+    let Ok(lease) = Duration::try_from_secs_f64(body.lease) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    match engine.lock_renew(&name, &body.owner, lease) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::CONFLICT.into_response(),
+        Err(error) => lock_error(error),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LockReleaseQuery {
+    owner: String,
+}
+
+/// Responds 409 Conflict if the owner didn't hold the lock.
+async fn lock_release(
+    engine: Extension<KVEngine>,
+    Path(name): Path<String>,
+    Query(query): Query<LockReleaseQuery>,
+) -> impl IntoResponse {
+    match engine.lock_release(&name, &query.owner) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::CONFLICT.into_response(),
+        Err(error) => lock_error(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn set(engine: &KVEngine, body: &str) -> (StatusCode, String) {
+        let query = SetValueQuery {
+            key: None,
+            ttl: None,
+            nx: false,
+            xx: false,
+            get: false,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let response = set_value(
+            Extension(engine.clone()),
+            Query(query),
+            headers,
+            Bytes::from(body.to_owned()),
+        )
+        .await
+        .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn conditional_sets_answer_with_the_previous_value_when_asked() {
+        let engine = KVEngine::new();
+        let cases = [
+            // key exists, body, status, response body
+            (
+                false,
+                r#"{"key": "k", "value": "new", "nx": true}"#,
+                204,
+                "",
+            ),
+            (true, r#"{"key": "k", "value": "new", "nx": true}"#, 412, ""),
+            (
+                false,
+                r#"{"key": "k", "value": "new", "xx": true}"#,
+                412,
+                "",
+            ),
+            (true, r#"{"key": "k", "value": "new", "xx": true}"#, 204, ""),
+            (
+                false,
+                r#"{"key": "k", "value": "new", "nx": true, "get": true}"#,
+                200,
+                r#"{"value":null}"#,
+            ),
+            (
+                true,
+                r#"{"key": "k", "value": "new", "nx": true, "get": true}"#,
+                412,
+                r#"{"value":"old"}"#,
+            ),
+            (
+                false,
+                r#"{"key": "k", "value": "new", "xx": true, "get": true}"#,
+                412,
+                r#"{"value":null}"#,
+            ),
+            (
+                true,
+                r#"{"key": "k", "value": "new", "xx": true, "get": true}"#,
+                200,
+                r#"{"value":"old"}"#,
+            ),
+        ];
+
+        for (exists, body, status, response) in cases {
+            engine.clear_all().unwrap();
+            if exists {
+                engine.set_key_value("k".into(), b"old".to_vec()).unwrap();
+            }
+
+            assert_eq!(
+                set(&engine, body).await,
+                (StatusCode::from_u16(status).unwrap(), response.to_owned()),
+                "{} with the key existing: {}",
+                body,
+                exists
+            );
+
+            let written = status != 412;
+            let expected = match (written, exists) {
+                (true, _) => Some(b"new".to_vec()),
+                (false, true) => Some(b"old".to_vec()),
+                (false, false) => None,
+            };
+            assert_eq!(engine.get_key_value("k").ok(), expected, "{}", body);
+        }
     }
 }
@@ -1,2 +1,4 @@
 pub mod client;
+pub mod engine;
+pub mod logging;
 pub mod protocol;
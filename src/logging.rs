@@ -0,0 +1,33 @@
+use log::LevelFilter;
+use log4rs::{
+    append::console::{ConsoleAppender, Target},
+    config::{Appender, Config, Root},
+    encode::pattern::PatternEncoder,
+};
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Sends the log to stderr, at the level in `RSTORE_LOG_LEVEL`
+/// (`error`, `warn`, `info`, `debug` or `trace`, default: `info`).
+pub fn init() {
+    let level = match std::env::var("RSTORE_LOG_LEVEL") {
+        Ok(level) => level.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring RSTORE_LOG_LEVEL: unknown level {}", level);
+            DEFAULT_LEVEL
+        }),
+        Err(_) => DEFAULT_LEVEL,
+    };
+
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new("{d} {l} {t} - {m}{n}")))
+        .build();
+    let config = Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .build(Root::builder().appender("stderr").build(level))
+        .expect("logging config is valid");
+
+    if let Err(error) = log4rs::init_config(config) {
+        eprintln!("Failed to initialize logging: {}", error);
+    }
+}
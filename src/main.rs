@@ -22,7 +22,7 @@ async fn main() -> ClientResult<()> {
     client
         .set(SetRequest {
             key: "key".to_string(),
-            value: "value".to_string(),
+            value: "value".into(),
         })
         .await?;
 
@@ -1,64 +1,1789 @@
-use chorba::{Decode, Encode};
+use chorba::{Decode, DecodeError, Encode};
 use tokio::{io::AsyncReadExt, net::TcpStream};
 
+/// Field codec for request/response structs that the chorba derives can't carry
+/// (numbers, options, lists). Every field is written as a length-prefixed chunk,
+/// so the layout stays compatible with the derived `String`/`Vec<u8>` fields.
+pub trait WireField: Sized {
+    fn write_field(&self, buffer: &mut Vec<u8>);
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError>;
+}
+
+pub(crate) fn write_chunk(buffer: &mut Vec<u8>, chunk: &[u8]) {
+    buffer.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(chunk);
+}
+
+pub(crate) fn read_chunk(buffer: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+    chorba::deserialize(buffer).ok_or(DecodeError::InvalidLength)
+}
+
+impl WireField for String {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        write_chunk(buffer, self.as_bytes());
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        Ok((String::from_utf8_lossy(chunk).to_string(), rest))
+    }
+}
+
+impl WireField for Vec<u8> {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        write_chunk(buffer, self);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        Ok((chunk.to_vec(), rest))
+    }
+}
+
+macro_rules! impl_wire_number {
+    ($($number:ty),*) => {
+        $(
+            impl WireField for $number {
+                fn write_field(&self, buffer: &mut Vec<u8>) {
+                    write_chunk(buffer, &self.to_be_bytes());
+                }
+
+                fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+                    let (chunk, rest) = read_chunk(buffer)?;
+                    let bytes = chunk.try_into().map_err(|_| DecodeError::InvalidLength)?;
+                    Ok((<$number>::from_be_bytes(bytes), rest))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_number!(u32, u64, i64, f64);
+
+impl WireField for bool {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        write_chunk(buffer, &[*self as u8]);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        match chunk {
+            [0] => Ok((false, rest)),
+            [1] => Ok((true, rest)),
+            _ => Err(DecodeError::Other("invalid bool".into())),
+        }
+    }
+}
+
+impl<T: WireField> WireField for Option<T> {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        let mut chunk = vec![];
+        if let Some(value) = self {
+            chunk.push(1);
+            value.write_field(&mut chunk);
+        }
+        write_chunk(buffer, &chunk);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        match chunk.split_first() {
+            None => Ok((None, rest)),
+            Some((1, value)) => Ok((Some(T::read_field(value)?.0), rest)),
+            Some(_) => Err(DecodeError::Other("invalid option".into())),
+        }
+    }
+}
+
+impl<T: WireField> WireField for Vec<T> {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        let mut chunk = vec![];
+        for item in self {
+            item.write_field(&mut chunk);
+        }
+        write_chunk(buffer, &chunk);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (mut chunk, rest) = read_chunk(buffer)?;
+        let mut items = vec![];
+        while !chunk.is_empty() {
+            let (item, next) = T::read_field(chunk)?;
+            items.push(item);
+            chunk = next;
+        }
+        Ok((items, rest))
+    }
+}
+
+/// Implements `chorba::Encoder`, `chorba::Decoder` and `WireField` for a struct
+/// whose fields all implement `WireField`. Fields are encoded in the listed order.
+macro_rules! wire_struct {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl chorba::Encoder for $name {
+            fn encode(&self) -> Vec<u8> {
+                let mut buffer = Vec::new();
+                $( $crate::protocol::WireField::write_field(&self.$field, &mut buffer); )*
+                buffer
+            }
+        }
+
+        impl chorba::Decoder<$name> for $name {
+            fn decode(buffer: &[u8]) -> Result<$name, chorba::DecodeError> {
+                $( let ($field, buffer) = $crate::protocol::WireField::read_field(buffer)?; )*
+                let _ = buffer;
+                Ok($name { $($field),* })
+            }
+        }
+
+        impl $crate::protocol::WireField for $name {
+            fn write_field(&self, buffer: &mut Vec<u8>) {
+                $crate::protocol::write_chunk(buffer, &chorba::Encoder::encode(self));
+            }
+
+            fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), chorba::DecodeError> {
+                let (chunk, rest) = $crate::protocol::read_chunk(buffer)?;
+                Ok((<$name as chorba::Decoder<$name>>::decode(chunk)?, rest))
+            }
+        }
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use wire_struct;
+
 // Redis - 512MB (Key, Value)
 // Memcached - 1MB (Key, Value)
 pub const KEY_BYTE_LIMIT: u32 = 1024 * 1024; // 1MB
 pub const VALUE_BYTE_LIMIT: u32 = 1024 * 1024 * 10; // 10MB
 pub const PACKET_BYTE_LIMIT: u32 = 1024 * 1024 * 20; // 20MB
 
-pub const PAYLOAD_CHUNK_SIZE: u32 = 1024; // 1KB
-pub const PAYLOAD_HEAD_SIZE: u32 = 5; // Tag 1 Byte + Length 4 Bytes
-pub const PAYLOAD_FIRST_MAX_VALUE_SIZE: u32 = PAYLOAD_CHUNK_SIZE - PAYLOAD_HEAD_SIZE; // 1KB - 5 Bytes
+pub const PAYLOAD_CHUNK_SIZE: u32 = 1024; // 1KB
+pub const PAYLOAD_HEAD_SIZE: u32 = 5; // Tag 1 Byte + Length 4 Bytes
+pub const PAYLOAD_FIRST_MAX_VALUE_SIZE: u32 = PAYLOAD_CHUNK_SIZE - PAYLOAD_HEAD_SIZE; // 1KB - 5 Bytes
+
+// Request Tag - Start Byte
+pub const PING: u8 = 0x01;
+pub const SET: u8 = 0x02;
+pub const GET: u8 = 0x03;
+pub const DELETE: u8 = 0x04;
+pub const CLEAR: u8 = 0x05;
+pub const SET_EX: u8 = 0x06;
+pub const EXPIRE: u8 = 0x07;
+pub const EXPIRE_AT: u8 = 0x08;
+pub const PERSIST: u8 = 0x09;
+pub const TTL: u8 = 0x0a;
+pub const SAVE: u8 = 0x0b;
+pub const BGSAVE: u8 = 0x0c;
+pub const INCR_BY: u8 = 0x0d;
+pub const DECR_BY: u8 = 0x0e;
+pub const INCR_BY_FLOAT: u8 = 0x0f;
+pub const COMPARE_AND_SET: u8 = 0x10;
+pub const SET_WITH_OPTIONS: u8 = 0x11;
+pub const GET_DELETE: u8 = 0x12;
+pub const MGET: u8 = 0x13;
+pub const MSET: u8 = 0x14;
+pub const MDEL: u8 = 0x15;
+pub const SCAN: u8 = 0x16;
+pub const WATCH: u8 = 0x17;
+pub const EXEC: u8 = 0x18;
+pub const SUBSCRIBE: u8 = 0x19;
+pub const PSUBSCRIBE: u8 = 0x1a;
+pub const UNSUBSCRIBE: u8 = 0x1b;
+pub const PUBLISH: u8 = 0x1c;
+pub const WATCH_KEYS: u8 = 0x1d;
+pub const LPUSH: u8 = 0x1e;
+pub const RPUSH: u8 = 0x1f;
+pub const LPOP: u8 = 0x20;
+pub const RPOP: u8 = 0x21;
+pub const LRANGE: u8 = 0x22;
+pub const LLEN: u8 = 0x23;
+pub const LTRIM: u8 = 0x24;
+pub const BLPOP: u8 = 0x25;
+pub const BRPOP: u8 = 0x26;
+pub const HSET: u8 = 0x27;
+pub const HGET: u8 = 0x28;
+pub const HMGET: u8 = 0x29;
+pub const HDEL: u8 = 0x2a;
+pub const HGETALL: u8 = 0x2b;
+pub const HINCRBY: u8 = 0x2c;
+pub const HEXISTS: u8 = 0x2d;
+pub const HLEN: u8 = 0x2e;
+pub const SADD: u8 = 0x2f;
+pub const SREM: u8 = 0x30;
+pub const SISMEMBER: u8 = 0x31;
+pub const SMEMBERS: u8 = 0x32;
+pub const SINTER: u8 = 0x33;
+pub const SUNION: u8 = 0x34;
+pub const SDIFF: u8 = 0x35;
+pub const ZADD: u8 = 0x36;
+pub const ZINCRBY: u8 = 0x37;
+pub const ZRANGE: u8 = 0x38;
+pub const ZRANGEBYSCORE: u8 = 0x39;
+pub const ZRANK: u8 = 0x3a;
+pub const ZREM: u8 = 0x3b;
+pub const XADD: u8 = 0x3c;
+pub const XRANGE: u8 = 0x3d;
+pub const XREAD: u8 = 0x3e;
+pub const XGROUP_CREATE: u8 = 0x3f;
+pub const XREADGROUP: u8 = 0x40;
+pub const XACK: u8 = 0x41;
+pub const XPENDING: u8 = 0x42;
+pub const XCLAIM: u8 = 0x43;
+pub const JSON_GET: u8 = 0x44;
+pub const JSON_SET: u8 = 0x45;
+pub const JSON_DEL: u8 = 0x46;
+pub const JSON_ARRAPPEND: u8 = 0x47;
+pub const JSON_INCRBY: u8 = 0x48;
+pub const PFADD: u8 = 0x49;
+pub const PFCOUNT: u8 = 0x4a;
+pub const PFMERGE: u8 = 0x4b;
+pub const BF_RESERVE: u8 = 0x4c;
+pub const BF_ADD: u8 = 0x4d;
+pub const BF_EXISTS: u8 = 0x4e;
+pub const GEOADD: u8 = 0x4f;
+pub const GEODIST: u8 = 0x50;
+pub const GEOSEARCH: u8 = 0x51;
+pub const RATE_LIMIT: u8 = 0x52;
+pub const LOCK_ACQUIRE: u8 = 0x53;
+pub const LOCK_RENEW: u8 = 0x54;
+pub const LOCK_RELEASE: u8 = 0x55;
+pub const JOB_ENQUEUE: u8 = 0x56;
+pub const JOB_DEQUEUE: u8 = 0x57;
+pub const JOB_ACK: u8 = 0x58;
+pub const JOB_NACK: u8 = 0x59;
+pub const JOB_DEAD_LETTERS: u8 = 0x5a;
+pub const SELECT: u8 = 0x5b;
+pub const STATS: u8 = 0x5c;
+pub const DROP_NAMESPACE: u8 = 0x5d;
+
+// Response Tag - Start Byte
+pub const PONG: u8 = 0xf1;
+pub const SET_OK: u8 = 0xf2;
+pub const GET_OK: u8 = 0xf3;
+pub const DELETE_OK: u8 = 0xf4;
+pub const CLEAR_OK: u8 = 0xf5;
+pub const EXPIRE_OK: u8 = 0xf6;
+pub const PERSIST_OK: u8 = 0xf7;
+pub const TTL_OK: u8 = 0xf8;
+pub const SAVE_OK: u8 = 0xf9;
+pub const BGSAVE_OK: u8 = 0xfa;
+pub const INCR_OK: u8 = 0xfb;
+pub const NOT_A_NUMBER: u8 = 0xfc;
+pub const OUT_OF_MEMORY: u8 = 0xfd;
+pub const PACKET_INVALID: u8 = 0xfe;
+pub const ERROR: u8 = 0xff;
+
+// Response Tag - Start Byte (0xf0 row is full, continued from 0xe1)
+pub const INCR_FLOAT_OK: u8 = 0xe1;
+pub const COMPARE_AND_SET_OK: u8 = 0xe2;
+pub const SET_WITH_OPTIONS_OK: u8 = 0xe3;
+pub const MGET_OK: u8 = 0xe4;
+pub const MSET_OK: u8 = 0xe5;
+pub const MDEL_OK: u8 = 0xe6;
+pub const SCAN_OK: u8 = 0xe7;
+pub const WATCH_OK: u8 = 0xe8;
+pub const EXEC_OK: u8 = 0xe9;
+pub const SUBSCRIBE_OK: u8 = 0xea;
+pub const PUBLISH_OK: u8 = 0xeb;
+// Pushed to subscribed connections, never a response to a request
+pub const MESSAGE: u8 = 0xec;
+pub const WATCH_KEYS_OK: u8 = 0xed;
+
+// Response Tag - Start Byte (0xe0 row is full, continued from 0xd1)
+// Pushed to watching connections, never a response to a request
+pub const KEY_EVENT: u8 = 0xd1;
+pub const LIST_LENGTH_OK: u8 = 0xd2;
+pub const LIST_VALUES_OK: u8 = 0xd3;
+pub const LTRIM_OK: u8 = 0xd4;
+pub const BLOCKING_POP_OK: u8 = 0xd5;
+pub const HASH_COUNT_OK: u8 = 0xd6;
+pub const HASH_VALUE_OK: u8 = 0xd7;
+pub const HASH_VALUES_OK: u8 = 0xd8;
+pub const HASH_ENTRIES_OK: u8 = 0xd9;
+pub const HASH_EXISTS_OK: u8 = 0xda;
+pub const MEMBER_COUNT_OK: u8 = 0xdb;
+pub const IS_MEMBER_OK: u8 = 0xdc;
+pub const MEMBERS_OK: u8 = 0xdd;
+pub const SCORED_MEMBERS_OK: u8 = 0xde;
+pub const RANK_OK: u8 = 0xdf;
+
+// Response Tag - Start Byte (0xd0 row is full, continued from 0xc1)
+pub const STREAM_ID_OK: u8 = 0xc1;
+pub const STREAM_ENTRIES_OK: u8 = 0xc2;
+pub const STREAM_READ_OK: u8 = 0xc3;
+pub const XGROUP_CREATE_OK: u8 = 0xc4;
+pub const XACK_OK: u8 = 0xc5;
+pub const PENDING_OK: u8 = 0xc6;
+pub const JSON_VALUE_OK: u8 = 0xc7;
+pub const JSON_SET_OK: u8 = 0xc8;
+pub const JSON_DEL_OK: u8 = 0xc9;
+
+// Response Tag - Start Byte (0xc0 row is full, continued from 0xb1)
+pub const PFADD_OK: u8 = 0xb1;
+pub const PFCOUNT_OK: u8 = 0xb2;
+pub const PFMERGE_OK: u8 = 0xb3;
+pub const BF_RESERVE_OK: u8 = 0xb4;
+pub const BF_ADD_OK: u8 = 0xb5;
+pub const GEODIST_OK: u8 = 0xb6;
+pub const GEOSEARCH_OK: u8 = 0xb7;
+pub const RATE_LIMIT_OK: u8 = 0xb8;
+pub const LOCK_ACQUIRE_OK: u8 = 0xb9;
+pub const LOCK_RENEW_OK: u8 = 0xba;
+
+// Response Tag - Start Byte (0xb0 row is full, continued from 0xa1)
+pub const LOCK_RELEASE_OK: u8 = 0xa1;
+pub const JOB_ENQUEUE_OK: u8 = 0xa2;
+pub const JOBS_OK: u8 = 0xa3;
+pub const JOB_ACK_OK: u8 = 0xa4;
+pub const JOB_NACK_OK: u8 = 0xa5;
+pub const SELECT_OK: u8 = 0xa6;
+pub const STATS_OK: u8 = 0xa7;
+pub const DROP_NAMESPACE_OK: u8 = 0xa8;
+
+// Error Tag - Start Byte (continued downwards from 0xef)
+pub const VERSION_MISMATCH: u8 = 0xef;
+pub const WRONG_TYPE: u8 = 0xee;
+
+// Error Tag - Start Byte (0xed is taken, continued downwards from 0xcf)
+pub const INVALID_STREAM_ID: u8 = 0xcf;
+pub const NO_SUCH_GROUP: u8 = 0xce;
+pub const GROUP_EXISTS: u8 = 0xcd;
+pub const INVALID_JSON: u8 = 0xcc;
+pub const INVALID_JSON_PATH: u8 = 0xcb;
+pub const JSON_PATH_NOT_FOUND: u8 = 0xca;
+
+// Error Tag - Start Byte (0xc0 row is full, continued downwards from 0xbf)
+pub const KEY_EXISTS: u8 = 0xbf;
+pub const INVALID_FILTER_OPTIONS: u8 = 0xbe;
+pub const INVALID_COORDINATES: u8 = 0xbd;
+pub const INVALID_RATE_LIMIT: u8 = 0xbc;
+pub const INVALID_LOCK_LEASE: u8 = 0xbb;
+
+// Error Tag - Start Byte (0xba is taken, continued downwards from 0xaf)
+pub const INVALID_NAMESPACE: u8 = 0xaf;
+pub const TOO_MANY_NAMESPACES: u8 = 0xae;
+pub const NAMESPACE_NOT_EMPTY: u8 = 0xad;
+
+pub const NO_VALUE_TAGS: [u8; 40] = [
+    PING,
+    CLEAR,
+    SAVE,
+    BGSAVE,
+    STATS,
+    PONG,
+    SET_OK,
+    DELETE_OK,
+    CLEAR_OK,
+    EXPIRE_OK,
+    PERSIST_OK,
+    SAVE_OK,
+    BGSAVE_OK,
+    WATCH_KEYS_OK,
+    LTRIM_OK,
+    XGROUP_CREATE_OK,
+    JSON_SET_OK,
+    PFMERGE_OK,
+    BF_RESERVE_OK,
+    SELECT_OK,
+    NOT_A_NUMBER,
+    VERSION_MISMATCH,
+    WRONG_TYPE,
+    INVALID_STREAM_ID,
+    NO_SUCH_GROUP,
+    GROUP_EXISTS,
+    INVALID_JSON,
+    INVALID_JSON_PATH,
+    JSON_PATH_NOT_FOUND,
+    KEY_EXISTS,
+    INVALID_FILTER_OPTIONS,
+    INVALID_COORDINATES,
+    INVALID_RATE_LIMIT,
+    INVALID_LOCK_LEASE,
+    INVALID_NAMESPACE,
+    TOO_MANY_NAMESPACES,
+    NAMESPACE_NOT_EMPTY,
+    OUT_OF_MEMORY,
+    PACKET_INVALID,
+    ERROR,
+];
+
+#[derive(Decode, Encode, Debug, Clone)]
+pub struct SetRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Decode, Encode, Debug, Clone)]
+pub struct GetRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct GetResponse {
+    pub value: Vec<u8>,
+    /// Current version of the key, for `COMPARE_AND_SET`
+    pub version: u64,
+}
+
+wire_struct!(GetResponse { value, version });
+
+#[derive(Decode, Encode, Debug, Clone)]
+pub struct DeleteRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SetExpireRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub ttl_millis: u64,
+}
+
+wire_struct!(SetExpireRequest {
+    key,
+    value,
+    ttl_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct ExpireRequest {
+    pub key: String,
+    pub ttl_millis: u64,
+}
+
+wire_struct!(ExpireRequest { key, ttl_millis });
+
+#[derive(Debug, Clone)]
+pub struct ExpireAtRequest {
+    pub key: String,
+    pub unix_millis: u64,
+}
+
+wire_struct!(ExpireAtRequest { key, unix_millis });
+
+#[derive(Decode, Encode, Debug, Clone)]
+pub struct PersistRequest {
+    pub key: String,
+}
+
+#[derive(Decode, Encode, Debug, Clone)]
+pub struct TtlRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TtlResponse {
+    /// Remaining lifetime of the key, `None` if the key never expires
+    pub ttl_millis: Option<u64>,
+}
+
+wire_struct!(TtlResponse { ttl_millis });
+
+/// Used by both `INCR_BY` and `DECR_BY`
+#[derive(Debug, Clone)]
+pub struct IncrByRequest {
+    pub key: String,
+    pub delta: i64,
+}
+
+wire_struct!(IncrByRequest { key, delta });
+
+#[derive(Debug, Clone)]
+pub struct IncrByResponse {
+    pub value: i64,
+}
+
+wire_struct!(IncrByResponse { value });
+
+#[derive(Debug, Clone)]
+pub struct IncrByFloatRequest {
+    pub key: String,
+    pub delta: f64,
+}
+
+wire_struct!(IncrByFloatRequest { key, delta });
+
+#[derive(Debug, Clone)]
+pub struct IncrByFloatResponse {
+    pub value: f64,
+}
+
+wire_struct!(IncrByFloatResponse { value });
+
+#[derive(Debug, Clone)]
+pub struct CompareAndSetRequest {
+    pub key: String,
+    /// Version returned by `GET`, or 0 if the key must not exist yet
+    pub expected_version: u64,
+    pub value: Vec<u8>,
+    /// Expiration of the new value, `None` to keep it forever
+    pub ttl_millis: Option<u64>,
+}
+
+wire_struct!(CompareAndSetRequest {
+    key,
+    expected_version,
+    value,
+    ttl_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct CompareAndSetResponse {
+    pub version: u64,
+}
+
+wire_struct!(CompareAndSetResponse { version });
+
+#[derive(Debug, Clone, Default)]
+pub struct SetWithOptionsRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Expiration of the new value, `None` to keep it forever
+    pub ttl_millis: Option<u64>,
+    /// Only write if the key doesn't exist (NX)
+    pub if_absent: bool,
+    /// Only write if the key exists (XX)
+    pub if_present: bool,
+    /// Return the previous value (GET)
+    pub get: bool,
+}
+
+wire_struct!(SetWithOptionsRequest {
+    key,
+    value,
+    ttl_millis,
+    if_absent,
+    if_present,
+    get
+});
+
+#[derive(Debug, Clone)]
+pub struct SetWithOptionsResponse {
+    /// New version of the key, `None` if the condition didn't hold and nothing was written
+    pub version: Option<u64>,
+    /// Previous value, if requested and the key existed
+    pub previous: Option<Vec<u8>>,
+}
+
+wire_struct!(SetWithOptionsResponse { version, previous });
+
+/// Used by both `MGET` and `MDEL`
+#[derive(Debug, Clone)]
+pub struct MultiKeyRequest {
+    pub keys: Vec<String>,
+}
+
+wire_struct!(MultiKeyRequest { keys });
+
+#[derive(Debug, Clone)]
+pub struct MGetResponse {
+    /// One per requested key, in order; `None` if the key doesn't exist
+    pub values: Vec<Option<Vec<u8>>>,
+}
+
+wire_struct!(MGetResponse { values });
+
+#[derive(Debug, Clone)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+wire_struct!(KeyValue { key, value });
+
+#[derive(Debug, Clone)]
+pub struct MSetRequest {
+    pub entries: Vec<KeyValue>,
+}
+
+wire_struct!(MSetRequest { entries });
+
+#[derive(Debug, Clone)]
+pub struct MSetResponse {
+    /// New version of each key, in order
+    pub versions: Vec<u64>,
+}
+
+wire_struct!(MSetResponse { versions });
+
+#[derive(Debug, Clone)]
+pub struct MDelResponse {
+    /// One per requested key, in order; whether the key existed
+    pub deleted: Vec<bool>,
+}
+
+wire_struct!(MDelResponse { deleted });
+
+/// Scans keys matching `prefix`, or between `start` (inclusive) and `end` (exclusive).
+/// `prefix` can't be combined with `start`/`end`.
+#[derive(Debug, Clone, Default)]
+pub struct ScanRequest {
+    pub prefix: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// `cursor` of the previous response, `None` for the first page
+    pub cursor: Option<String>,
+    pub limit: u32,
+    pub with_values: bool,
+}
+
+wire_struct!(ScanRequest {
+    prefix,
+    start,
+    end,
+    cursor,
+    limit,
+    with_values
+});
+
+#[derive(Debug, Clone)]
+pub struct ScanEntry {
+    pub key: String,
+    /// Only set if the scan asked for values
+    pub value: Option<Vec<u8>>,
+}
+
+wire_struct!(ScanEntry { key, value });
+
+#[derive(Debug, Clone)]
+pub struct ScanResponse {
+    pub entries: Vec<ScanEntry>,
+    /// Cursor for the next page, `None` once every key was returned
+    pub cursor: Option<String>,
+}
+
+wire_struct!(ScanResponse { entries, cursor });
+
+#[derive(Debug, Clone)]
+pub struct WatchResponse {
+    /// Current version of each requested key, in order; 0 if the key doesn't exist
+    pub versions: Vec<u64>,
+}
+
+wire_struct!(WatchResponse { versions });
+
+#[derive(Debug, Clone)]
+pub struct WatchedKey {
+    pub key: String,
+    /// Version returned by `WATCH`
+    pub version: u64,
+}
+
+wire_struct!(WatchedKey { key, version });
+
+// Discriminants of `TxCommand` and `TxReply` on the wire
+const TX_GET: u32 = 1;
+const TX_SET: u32 = 2;
+const TX_DELETE: u32 = 3;
+const TX_INCR_BY: u32 = 4;
+
+/// An operation queued in a transaction.
+#[derive(Debug, Clone)]
+pub enum TxCommand {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Vec<u8>,
+        ttl_millis: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+}
+
+impl WireField for TxCommand {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        let mut chunk = vec![];
+        match self {
+            TxCommand::Get { key } => {
+                TX_GET.write_field(&mut chunk);
+                key.write_field(&mut chunk);
+            }
+            TxCommand::Set {
+                key,
+                value,
+                ttl_millis,
+            } => {
+                TX_SET.write_field(&mut chunk);
+                key.write_field(&mut chunk);
+                value.write_field(&mut chunk);
+                ttl_millis.write_field(&mut chunk);
+            }
+            TxCommand::Delete { key } => {
+                TX_DELETE.write_field(&mut chunk);
+                key.write_field(&mut chunk);
+            }
+            TxCommand::IncrBy { key, delta } => {
+                TX_INCR_BY.write_field(&mut chunk);
+                key.write_field(&mut chunk);
+                delta.write_field(&mut chunk);
+            }
+        }
+        write_chunk(buffer, &chunk);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        let (kind, chunk) = u32::read_field(chunk)?;
+        let (key, chunk) = String::read_field(chunk)?;
+
+        let command = match kind {
+            TX_GET => TxCommand::Get { key },
+            TX_SET => {
+                let (value, chunk) = Vec::<u8>::read_field(chunk)?;
+                let (ttl_millis, _) = Option::<u64>::read_field(chunk)?;
+                TxCommand::Set {
+                    key,
+                    value,
+                    ttl_millis,
+                }
+            }
+            TX_DELETE => TxCommand::Delete { key },
+            TX_INCR_BY => {
+                let (delta, _) = i64::read_field(chunk)?;
+                TxCommand::IncrBy { key, delta }
+            }
+            _ => return Err(DecodeError::Other("invalid transaction command".into())),
+        };
+
+        Ok((command, rest))
+    }
+}
+
+/// Result of a `TxCommand`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxReply {
+    Value(Option<Vec<u8>>),
+    Stored,
+    Deleted(bool),
+    Integer(i64),
+}
+
+impl WireField for TxReply {
+    fn write_field(&self, buffer: &mut Vec<u8>) {
+        let mut chunk = vec![];
+        match self {
+            TxReply::Value(value) => {
+                TX_GET.write_field(&mut chunk);
+                value.write_field(&mut chunk);
+            }
+            TxReply::Stored => TX_SET.write_field(&mut chunk),
+            TxReply::Deleted(deleted) => {
+                TX_DELETE.write_field(&mut chunk);
+                deleted.write_field(&mut chunk);
+            }
+            TxReply::Integer(value) => {
+                TX_INCR_BY.write_field(&mut chunk);
+                value.write_field(&mut chunk);
+            }
+        }
+        write_chunk(buffer, &chunk);
+    }
+
+    fn read_field(buffer: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (chunk, rest) = read_chunk(buffer)?;
+        let (kind, chunk) = u32::read_field(chunk)?;
+
+        let reply = match kind {
+            TX_GET => TxReply::Value(Option::<Vec<u8>>::read_field(chunk)?.0),
+            TX_SET => TxReply::Stored,
+            TX_DELETE => TxReply::Deleted(bool::read_field(chunk)?.0),
+            TX_INCR_BY => TxReply::Integer(i64::read_field(chunk)?.0),
+            _ => return Err(DecodeError::Other("invalid transaction reply".into())),
+        };
+
+        Ok((reply, rest))
+    }
+}
+
+/// Runs `commands` atomically, unless one of `watches` changed since it was watched.
+#[derive(Debug, Clone, Default)]
+pub struct ExecRequest {
+    pub watches: Vec<WatchedKey>,
+    pub commands: Vec<TxCommand>,
+}
+
+wire_struct!(ExecRequest { watches, commands });
+
+#[derive(Debug, Clone)]
+pub struct ExecResponse {
+    /// One per command, in order
+    pub replies: Vec<TxReply>,
+}
+
+wire_struct!(ExecResponse { replies });
+
+/// Channel names for `SUBSCRIBE`, or glob patterns for `PSUBSCRIBE`.
+#[derive(Debug, Clone, Default)]
+pub struct SubscribeRequest {
+    pub channels: Vec<String>,
+}
+
+wire_struct!(SubscribeRequest { channels });
+
+/// Unsubscribes from everything if both lists are empty.
+#[derive(Debug, Clone, Default)]
+pub struct UnsubscribeRequest {
+    pub channels: Vec<String>,
+    pub patterns: Vec<String>,
+}
+
+wire_struct!(UnsubscribeRequest { channels, patterns });
+
+#[derive(Debug, Clone)]
+pub struct SubscribeResponse {
+    /// Subscriptions left on the connection; it leaves push mode at 0
+    pub count: u64,
+}
+
+wire_struct!(SubscribeResponse { count });
+
+#[derive(Debug, Clone)]
+pub struct PublishRequest {
+    pub channel: String,
+    pub message: Vec<u8>,
+}
+
+wire_struct!(PublishRequest { channel, message });
+
+#[derive(Debug, Clone)]
+pub struct PublishResponse {
+    /// Number of subscribers the message was delivered to
+    pub receivers: u64,
+}
+
+wire_struct!(PublishResponse { receivers });
+
+#[derive(Debug, Clone)]
+pub struct PushMessage {
+    pub channel: String,
+    /// The matching pattern, for messages received through `PSUBSCRIBE`
+    pub pattern: Option<String>,
+    pub message: Vec<u8>,
+}
+
+wire_struct!(PushMessage {
+    channel,
+    pattern,
+    message
+});
+
+/// Watches exact keys and key prefixes; every key if both are empty.
+#[derive(Debug, Clone, Default)]
+pub struct WatchKeysRequest {
+    pub keys: Vec<String>,
+    pub prefixes: Vec<String>,
+}
+
+wire_struct!(WatchKeysRequest { keys, prefixes });
+
+#[derive(Debug, Clone)]
+pub struct KeyEventMessage {
+    /// Empty for `clear`
+    pub key: String,
+    /// `set`, `delete`, `expire`, `evict` or `clear`
+    pub op: String,
+    /// New version for `set`, last version of the removed key otherwise
+    pub version: u64,
+}
+
+wire_struct!(KeyEventMessage { key, op, version });
+
+/// Pushes the values one after the other, so pushing `a`, `b` to the left leaves `b` first.
+#[derive(Debug, Clone)]
+pub struct ListPushRequest {
+    pub key: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+wire_struct!(ListPushRequest { key, values });
+
+#[derive(Debug, Clone)]
+pub struct ListLengthResponse {
+    pub length: u64,
+}
+
+wire_struct!(ListLengthResponse { length });
+
+#[derive(Debug, Clone)]
+pub struct ListPopRequest {
+    pub key: String,
+    /// Maximum number of values to pop
+    pub count: u64,
+}
+
+wire_struct!(ListPopRequest { key, count });
+
+/// Indexes are inclusive; negative ones count from the end, so `0`, `-1` is the whole list.
+#[derive(Debug, Clone)]
+pub struct ListRangeRequest {
+    pub key: String,
+    pub start: i64,
+    pub stop: i64,
+}
+
+wire_struct!(ListRangeRequest { key, start, stop });
+
+#[derive(Debug, Clone)]
+pub struct ListValuesResponse {
+    pub values: Vec<Vec<u8>>,
+}
+
+wire_struct!(ListValuesResponse { values });
+
+#[derive(Debug, Clone)]
+pub struct BlockingPopRequest {
+    /// Popped from the first non-empty list
+    pub keys: Vec<String>,
+    /// How long to wait for a push; 0 waits forever
+    pub timeout_millis: u64,
+}
+
+wire_struct!(BlockingPopRequest {
+    keys,
+    timeout_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct BlockingPopResponse {
+    /// The key popped from and the value; `None` if the timeout elapsed
+    pub entry: Option<KeyValue>,
+}
+
+wire_struct!(BlockingPopResponse { entry });
+
+#[derive(Debug, Clone)]
+pub struct HashField {
+    pub field: String,
+    pub value: Vec<u8>,
+}
+
+wire_struct!(HashField { field, value });
+
+#[derive(Debug, Clone)]
+pub struct HashSetRequest {
+    pub key: String,
+    pub fields: Vec<HashField>,
+}
+
+wire_struct!(HashSetRequest { key, fields });
+
+/// Reply to `HSET` (fields added), `HDEL` (fields removed) and `HLEN`.
+#[derive(Debug, Clone)]
+pub struct HashCountResponse {
+    pub count: u64,
+}
+
+wire_struct!(HashCountResponse { count });
+
+/// Used by `HGET` and `HEXISTS`.
+#[derive(Debug, Clone)]
+pub struct HashFieldRequest {
+    pub key: String,
+    pub field: String,
+}
+
+wire_struct!(HashFieldRequest { key, field });
+
+#[derive(Debug, Clone)]
+pub struct HashValueResponse {
+    /// `None` if the field or the key doesn't exist
+    pub value: Option<Vec<u8>>,
+}
+
+wire_struct!(HashValueResponse { value });
+
+/// Used by `HMGET` and `HDEL`.
+#[derive(Debug, Clone)]
+pub struct HashFieldsRequest {
+    pub key: String,
+    pub fields: Vec<String>,
+}
+
+wire_struct!(HashFieldsRequest { key, fields });
+
+#[derive(Debug, Clone)]
+pub struct HashValuesResponse {
+    /// One per requested field, in order; `None` if the field doesn't exist
+    pub values: Vec<Option<Vec<u8>>>,
+}
+
+wire_struct!(HashValuesResponse { values });
+
+#[derive(Debug, Clone)]
+pub struct HashEntriesResponse {
+    /// In no particular order
+    pub fields: Vec<HashField>,
+}
+
+wire_struct!(HashEntriesResponse { fields });
+
+#[derive(Debug, Clone)]
+pub struct HashIncrByRequest {
+    pub key: String,
+    pub field: String,
+    pub delta: i64,
+}
+
+wire_struct!(HashIncrByRequest { key, field, delta });
+
+#[derive(Debug, Clone)]
+pub struct HashExistsResponse {
+    pub exists: bool,
+}
+
+wire_struct!(HashExistsResponse { exists });
+
+/// Used by `SADD`, `SREM` and `ZREM`.
+#[derive(Debug, Clone)]
+pub struct MembersRequest {
+    pub key: String,
+    pub members: Vec<String>,
+}
+
+wire_struct!(MembersRequest { key, members });
+
+/// Reply to `SADD`/`ZADD` (members added) and `SREM`/`ZREM` (members removed).
+#[derive(Debug, Clone)]
+pub struct MemberCountResponse {
+    pub count: u64,
+}
+
+wire_struct!(MemberCountResponse { count });
+
+#[derive(Debug, Clone)]
+pub struct MemberRequest {
+    pub key: String,
+    pub member: String,
+}
+
+wire_struct!(MemberRequest { key, member });
+
+#[derive(Debug, Clone)]
+pub struct IsMemberResponse {
+    pub is_member: bool,
+}
+
+wire_struct!(IsMemberResponse { is_member });
+
+/// Reply to `SMEMBERS`, `SINTER`, `SUNION` and `SDIFF`.
+#[derive(Debug, Clone)]
+pub struct MembersResponse {
+    /// In no particular order
+    pub members: Vec<String>,
+}
+
+wire_struct!(MembersResponse { members });
+
+#[derive(Debug, Clone)]
+pub struct ScoredMember {
+    pub member: String,
+    pub score: f64,
+}
+
+wire_struct!(ScoredMember { member, score });
+
+#[derive(Debug, Clone)]
+pub struct SortedSetAddRequest {
+    pub key: String,
+    pub members: Vec<ScoredMember>,
+}
+
+wire_struct!(SortedSetAddRequest { key, members });
+
+#[derive(Debug, Clone)]
+pub struct SortedSetIncrByRequest {
+    pub key: String,
+    pub member: String,
+    pub delta: f64,
+}
+
+wire_struct!(SortedSetIncrByRequest { key, member, delta });
+
+/// Indexes are inclusive; negative ones count from the end, so `0`, `-1` is the whole set.
+#[derive(Debug, Clone)]
+pub struct SortedSetRangeRequest {
+    pub key: String,
+    pub start: i64,
+    pub stop: i64,
+    /// Orders from the highest score
+    pub reverse: bool,
+}
+
+wire_struct!(SortedSetRangeRequest {
+    key,
+    start,
+    stop,
+    reverse
+});
+
+/// Both bounds are inclusive; infinities leave a side open.
+#[derive(Debug, Clone)]
+pub struct SortedSetRangeByScoreRequest {
+    pub key: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+wire_struct!(SortedSetRangeByScoreRequest { key, min, max });
+
+#[derive(Debug, Clone)]
+pub struct ScoredMembersResponse {
+    pub members: Vec<ScoredMember>,
+}
+
+wire_struct!(ScoredMembersResponse { members });
+
+#[derive(Debug, Clone)]
+pub struct SortedSetRankRequest {
+    pub key: String,
+    pub member: String,
+    /// Ranks from the highest score
+    pub reverse: bool,
+}
+
+wire_struct!(SortedSetRankRequest {
+    key,
+    member,
+    reverse
+});
+
+#[derive(Debug, Clone)]
+pub struct RankResponse {
+    /// `None` if the member or the key doesn't exist
+    pub rank: Option<u64>,
+}
+
+wire_struct!(RankResponse { rank });
+
+#[derive(Debug, Clone)]
+pub struct StreamAddRequest {
+    pub key: String,
+    /// `*` to generate one, otherwise `millis-seq` above the last ID of the stream
+    pub id: String,
+    pub fields: Vec<HashField>,
+}
+
+wire_struct!(StreamAddRequest { key, id, fields });
+
+#[derive(Debug, Clone)]
+pub struct StreamAddResponse {
+    pub id: String,
+}
+
+wire_struct!(StreamAddResponse { id });
+
+#[derive(Debug, Clone)]
+pub struct StreamEntry {
+    pub id: String,
+    pub fields: Vec<HashField>,
+}
+
+wire_struct!(StreamEntry { id, fields });
+
+#[derive(Debug, Clone)]
+pub struct StreamRangeRequest {
+    pub key: String,
+    /// Inclusive; `-` for the first entry
+    pub start: String,
+    /// Inclusive; `+` for the last entry
+    pub end: String,
+    /// `None` returns every entry in the range
+    pub count: Option<u64>,
+}
+
+wire_struct!(StreamRangeRequest {
+    key,
+    start,
+    end,
+    count
+});
+
+#[derive(Debug, Clone)]
+pub struct StreamEntriesResponse {
+    pub entries: Vec<StreamEntry>,
+}
+
+wire_struct!(StreamEntriesResponse { entries });
 
-// Request Tag - Start Byte
-pub const PING: u8 = 0x01;
-pub const SET: u8 = 0x02;
-pub const GET: u8 = 0x03;
-pub const DELETE: u8 = 0x04;
-pub const CLEAR: u8 = 0x05;
+#[derive(Debug, Clone)]
+pub struct StreamReadRequest {
+    pub keys: Vec<String>,
+    /// One per key: entries above this ID are read; `$` for entries added from now on
+    pub ids: Vec<String>,
+    /// At most this many entries per stream
+    pub count: Option<u64>,
+    /// Wait this long for an entry if there is none, 0 waiting forever; `None` doesn't wait
+    pub block_millis: Option<u64>,
+}
 
-// Response Tag - Start Byte
-pub const PONG: u8 = 0xf1;
-pub const SET_OK: u8 = 0xf2;
-pub const GET_OK: u8 = 0xf3;
-pub const DELETE_OK: u8 = 0xf4;
-pub const CLEAR_OK: u8 = 0xf5;
-pub const PACKET_INVALID: u8 = 0xfe;
-pub const ERROR: u8 = 0xff;
+wire_struct!(StreamReadRequest {
+    keys,
+    ids,
+    count,
+    block_millis
+});
 
-pub const NO_VALUE_TAGS: [u8; 8] = [
-    PING,
-    CLEAR,
-    PONG,
-    SET_OK,
-    DELETE_OK,
-    CLEAR_OK,
-    PACKET_INVALID,
-    ERROR,
-];
+#[derive(Debug, Clone)]
+pub struct StreamEntries {
+    pub key: String,
+    pub entries: Vec<StreamEntry>,
+}
 
-#[derive(Decode, Encode, Debug, Clone)]
-pub struct SetRequest {
+wire_struct!(StreamEntries { key, entries });
+
+#[derive(Debug, Clone)]
+pub struct StreamReadResponse {
+    /// Only the streams that had entries to read, empty if the wait timed out
+    pub streams: Vec<StreamEntries>,
+}
+
+wire_struct!(StreamReadResponse { streams });
+
+#[derive(Debug, Clone)]
+pub struct StreamGroupCreateRequest {
     pub key: String,
-    pub value: String,
+    pub group: String,
+    /// The group delivers entries above this ID; `$` for entries added from now on
+    pub id: String,
 }
 
-#[derive(Decode, Encode, Debug, Clone)]
-pub struct GetRequest {
+wire_struct!(StreamGroupCreateRequest { key, group, id });
+
+#[derive(Debug, Clone)]
+pub struct StreamReadGroupRequest {
+    pub group: String,
+    pub consumer: String,
+    pub keys: Vec<String>,
+    /// One per key: `>` for entries not delivered yet, otherwise the consumer's pending
+    /// entries above this ID
+    pub ids: Vec<String>,
+    pub count: Option<u64>,
+    /// See `StreamReadRequest::block_millis`
+    pub block_millis: Option<u64>,
+}
+
+wire_struct!(StreamReadGroupRequest {
+    group,
+    consumer,
+    keys,
+    ids,
+    count,
+    block_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct StreamAckRequest {
     pub key: String,
+    pub group: String,
+    pub ids: Vec<String>,
 }
 
-#[derive(Decode, Encode, Debug, Clone)]
-pub struct GetResponse {
+wire_struct!(StreamAckRequest { key, group, ids });
+
+#[derive(Debug, Clone)]
+pub struct StreamAckResponse {
+    pub acked: u64,
+}
+
+wire_struct!(StreamAckResponse { acked });
+
+#[derive(Debug, Clone)]
+pub struct StreamPendingRequest {
+    pub key: String,
+    pub group: String,
+    pub count: Option<u64>,
+}
+
+wire_struct!(StreamPendingRequest { key, group, count });
+
+#[derive(Debug, Clone)]
+pub struct StreamPendingEntry {
+    pub id: String,
+    pub consumer: String,
+    pub idle_millis: u64,
+    pub deliveries: u64,
+}
+
+wire_struct!(StreamPendingEntry {
+    id,
+    consumer,
+    idle_millis,
+    deliveries
+});
+
+#[derive(Debug, Clone)]
+pub struct StreamPendingResponse {
+    pub entries: Vec<StreamPendingEntry>,
+}
+
+wire_struct!(StreamPendingResponse { entries });
+
+#[derive(Debug, Clone)]
+pub struct StreamClaimRequest {
+    pub key: String,
+    pub group: String,
+    pub consumer: String,
+    /// Only entries delivered at least this long ago are claimed
+    pub min_idle_millis: u64,
+    pub count: Option<u64>,
+}
+
+wire_struct!(StreamClaimRequest {
+    key,
+    group,
+    consumer,
+    min_idle_millis,
+    count
+});
+
+#[derive(Debug, Clone)]
+pub struct JsonPathRequest {
+    pub key: String,
+    /// Like `$.users[0].name`; `$` for the whole document
+    pub path: String,
+}
+
+wire_struct!(JsonPathRequest { key, path });
+
+#[derive(Debug, Clone)]
+pub struct JsonValueResponse {
+    /// JSON text; `None` if nothing is at the path or the key doesn't exist
+    pub value: Option<String>,
+}
+
+wire_struct!(JsonValueResponse { value });
+
+#[derive(Debug, Clone)]
+pub struct JsonSetRequest {
+    pub key: String,
+    pub path: String,
+    /// JSON text
     pub value: String,
 }
 
-#[derive(Decode, Encode, Debug, Clone)]
-pub struct DeleteRequest {
+wire_struct!(JsonSetRequest { key, path, value });
+
+#[derive(Debug, Clone)]
+pub struct JsonDeleteResponse {
+    pub deleted: bool,
+}
+
+wire_struct!(JsonDeleteResponse { deleted });
+
+#[derive(Debug, Clone)]
+pub struct JsonAppendRequest {
+    pub key: String,
+    /// Path of an array
+    pub path: String,
+    /// JSON texts
+    pub values: Vec<String>,
+}
+
+wire_struct!(JsonAppendRequest { key, path, values });
+
+#[derive(Debug, Clone)]
+pub struct JsonIncrByRequest {
+    pub key: String,
+    /// Path of a number
+    pub path: String,
+    pub delta: f64,
+}
+
+wire_struct!(JsonIncrByRequest { key, path, delta });
+
+#[derive(Debug, Clone)]
+pub struct PfAddResponse {
+    /// Whether the estimated count may have changed
+    pub changed: bool,
+}
+
+wire_struct!(PfAddResponse { changed });
+
+#[derive(Debug, Clone)]
+pub struct PfCountResponse {
+    /// Estimated number of distinct elements, with a standard error of 0.81%
+    pub count: u64,
+}
+
+wire_struct!(PfCountResponse { count });
+
+#[derive(Debug, Clone)]
+pub struct PfMergeRequest {
+    pub destination: String,
+    pub sources: Vec<String>,
+}
+
+wire_struct!(PfMergeRequest {
+    destination,
+    sources
+});
+
+#[derive(Debug, Clone)]
+pub struct BloomReserveRequest {
+    pub key: String,
+    /// Chance that an item that was never added is reported as added, between 0 and 1
+    pub error_rate: f64,
+    /// Items the filter is sized for; adding more grows it
+    pub capacity: u64,
+}
+
+wire_struct!(BloomReserveRequest {
+    key,
+    error_rate,
+    capacity
+});
+
+#[derive(Debug, Clone)]
+pub struct BloomAddResponse {
+    /// False if the item may have been added before
+    pub added: bool,
+}
+
+wire_struct!(BloomAddResponse { added });
+
+#[derive(Debug, Clone)]
+pub struct GeoMember {
+    pub member: String,
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+wire_struct!(GeoMember {
+    member,
+    longitude,
+    latitude
+});
+
+#[derive(Debug, Clone)]
+pub struct GeoAddRequest {
+    pub key: String,
+    pub members: Vec<GeoMember>,
+}
+
+wire_struct!(GeoAddRequest { key, members });
+
+#[derive(Debug, Clone)]
+pub struct GeoDistRequest {
+    pub key: String,
+    pub from: String,
+    pub to: String,
+}
+
+wire_struct!(GeoDistRequest { key, from, to });
+
+#[derive(Debug, Clone)]
+pub struct GeoDistResponse {
+    /// Meters; `None` if either member doesn't exist
+    pub distance: Option<f64>,
+}
+
+wire_struct!(GeoDistResponse { distance });
+
+#[derive(Debug, Clone, Default)]
+pub struct GeoSearchRequest {
+    pub key: String,
+    pub longitude: f64,
+    pub latitude: f64,
+    /// Searches within this many meters; otherwise `width` and `height` are required
+    pub radius: Option<f64>,
+    /// Width in meters of a box search
+    pub width: Option<f64>,
+    /// Height in meters of a box search
+    pub height: Option<f64>,
+    /// `Some(true)` sorts nearest first, `Some(false)` farthest first
+    pub ascending: Option<bool>,
+    pub count: Option<u64>,
+}
+
+wire_struct!(GeoSearchRequest {
+    key,
+    longitude,
+    latitude,
+    radius,
+    width,
+    height,
+    ascending,
+    count
+});
+
+#[derive(Debug, Clone)]
+pub struct GeoMatch {
+    pub member: String,
+    /// Meters from the center of the search
+    pub distance: f64,
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+wire_struct!(GeoMatch {
+    member,
+    distance,
+    longitude,
+    latitude
+});
+
+#[derive(Debug, Clone)]
+pub struct GeoSearchResponse {
+    pub matches: Vec<GeoMatch>,
+}
+
+wire_struct!(GeoSearchResponse { matches });
+
+#[derive(Debug, Clone)]
+pub struct RateLimitRequest {
+    pub key: String,
+    /// Requests allowed per window
+    pub limit: u64,
+    pub window_millis: u64,
+    /// `fixed-window`, `sliding-log` or `token-bucket`
+    pub algorithm: String,
+}
+
+wire_struct!(RateLimitRequest {
+    key,
+    limit,
+    window_millis,
+    algorithm
+});
+
+#[derive(Debug, Clone)]
+pub struct RateLimitResponse {
+    pub allowed: bool,
+    pub remaining: u64,
+    /// Until the whole limit is available again
+    pub reset_after_millis: u64,
+}
+
+wire_struct!(RateLimitResponse {
+    allowed,
+    remaining,
+    reset_after_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct LockAcquireRequest {
+    pub name: String,
+    /// Identifies the holder to renewals and releases; unique per acquirer
+    pub owner: String,
+    /// Released if not renewed within this
+    pub lease_millis: u64,
+    /// Wait this long for the lock if it is taken, 0 waiting forever; `None` doesn't wait
+    pub wait_millis: Option<u64>,
+}
+
+wire_struct!(LockAcquireRequest {
+    name,
+    owner,
+    lease_millis,
+    wait_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct LockAcquireResponse {
+    /// Fencing token, higher than every earlier one of the lock; `None` if not acquired
+    pub token: Option<u64>,
+}
+
+wire_struct!(LockAcquireResponse { token });
+
+#[derive(Debug, Clone)]
+pub struct LockRenewRequest {
+    pub name: String,
+    pub owner: String,
+    pub lease_millis: u64,
+}
+
+wire_struct!(LockRenewRequest {
+    name,
+    owner,
+    lease_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct LockRenewResponse {
+    /// False if the lease had already ended
+    pub renewed: bool,
+}
+
+wire_struct!(LockRenewResponse { renewed });
+
+#[derive(Debug, Clone)]
+pub struct LockReleaseRequest {
+    pub name: String,
+    pub owner: String,
+}
+
+wire_struct!(LockReleaseRequest { name, owner });
+
+#[derive(Debug, Clone)]
+pub struct LockReleaseResponse {
+    /// False if the owner didn't hold the lock
+    pub released: bool,
+}
+
+wire_struct!(LockReleaseResponse { released });
+
+#[derive(Debug, Clone)]
+pub struct JobEnqueueRequest {
+    pub key: String,
+    pub payload: Vec<u8>,
+    /// Handed out no earlier than this from now
+    pub delay_millis: u64,
+    /// Dead-lettered once a delivery fails after this many attempts; 0 never
+    pub max_attempts: u32,
+}
+
+wire_struct!(JobEnqueueRequest {
+    key,
+    payload,
+    delay_millis,
+    max_attempts
+});
+
+#[derive(Debug, Clone)]
+pub struct JobEnqueueResponse {
+    pub id: u64,
+}
+
+wire_struct!(JobEnqueueResponse { id });
+
+#[derive(Debug, Clone)]
+pub struct JobDequeueRequest {
+    pub key: String,
+    /// Handed out again unless acked or nacked within this
+    pub visibility_millis: u64,
+    pub count: u64,
+}
+
+wire_struct!(JobDequeueRequest {
+    key,
+    visibility_millis,
+    count
+});
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub payload: Vec<u8>,
+    /// Deliveries so far, including this one; the receipt to ack or nack this delivery with
+    pub attempts: u32,
+}
+
+wire_struct!(Job {
+    id,
+    payload,
+    attempts
+});
+
+#[derive(Debug, Clone)]
+pub struct JobsResponse {
+    pub jobs: Vec<Job>,
+}
+
+wire_struct!(JobsResponse { jobs });
+
+#[derive(Debug, Clone)]
+pub struct JobAckRequest {
+    pub key: String,
+    pub id: u64,
+    /// `attempts` of the delivery being acked
+    pub attempts: u32,
+}
+
+wire_struct!(JobAckRequest { key, id, attempts });
+
+#[derive(Debug, Clone)]
+pub struct JobAckResponse {
+    /// False if the job wasn't handed out in that delivery, e.g. already acked or handed
+    /// out again since
+    pub acked: bool,
+}
+
+wire_struct!(JobAckResponse { acked });
+
+#[derive(Debug, Clone)]
+pub struct JobNackRequest {
+    pub key: String,
+    pub id: u64,
+    /// `attempts` of the delivery being nacked
+    pub attempts: u32,
+    /// Handed out again no earlier than this from now
+    pub delay_millis: u64,
+}
+
+wire_struct!(JobNackRequest {
+    key,
+    id,
+    attempts,
+    delay_millis
+});
+
+#[derive(Debug, Clone)]
+pub struct JobNackResponse {
+    /// False if the job wasn't handed out in that delivery
+    pub nacked: bool,
+}
+
+wire_struct!(JobNackResponse { nacked });
+
+#[derive(Debug, Clone)]
+pub struct JobDeadLettersRequest {
     pub key: String,
+    pub count: u64,
+}
+
+wire_struct!(JobDeadLettersRequest { key, count });
+
+#[derive(Debug, Clone)]
+pub struct SelectRequest {
+    /// Namespace the connection operates on from now on
+    pub namespace: String,
+}
+
+wire_struct!(SelectRequest { namespace });
+
+#[derive(Debug, Clone)]
+pub struct DropNamespaceRequest {
+    /// Namespace to remove; it must hold no keys
+    pub namespace: String,
+}
+
+wire_struct!(DropNamespaceRequest { namespace });
+
+#[derive(Debug, Clone)]
+pub struct DropNamespaceResponse {
+    /// Whether the namespace existed
+    pub dropped: bool,
 }
 
+wire_struct!(DropNamespaceResponse { dropped });
+
+#[derive(Debug, Clone)]
+pub struct StatsResponse {
+    /// Namespace the figures are of, the one selected by the connection
+    pub namespace: String,
+    pub keys: u64,
+    /// Keys with a deadline
+    pub expiring_keys: u64,
+    /// Bytes accounted to the keys of the namespace
+    pub used_memory: u64,
+}
+
+wire_struct!(StatsResponse {
+    namespace,
+    keys,
+    expiring_keys,
+    used_memory
+});
+
 #[derive(Debug, Clone)]
 pub struct StartPacket<'a> {
     pub tag: u8,
@@ -74,6 +1799,8 @@ pub enum PacketError {
     ReadFailed(#[from] std::io::Error),
     #[error("No Data received")]
     NoDataReceived,
+    #[error("Packet of {0} bytes is over the limit of {PACKET_BYTE_LIMIT} bytes")]
+    TooLarge(u32),
 }
 
 #[allow(dead_code)]
@@ -125,6 +1852,32 @@ pub(crate) fn generate_packet(packet_type: u8, payload: &[u8]) -> Vec<u8> {
     packet
 }
 
+/// Reads exactly one packet. Unlike `read_all_from_stream` it never consumes
+/// bytes of the next packet, so it suits connections the server pushes to.
+///
+/// Both readers refuse packets over `PACKET_BYTE_LIMIT` before allocating for them;
+/// the rest of such a packet is left unread, so the connection can't be used further.
+#[allow(dead_code)]
+pub(crate) async fn read_packet(tcp_stream: &mut TcpStream) -> Result<(u8, Vec<u8>), PacketError> {
+    let mut tag = [0; 1];
+    if tcp_stream.read(&mut tag).await? == 0 {
+        return Err(PacketError::NoDataReceived);
+    }
+
+    if NO_VALUE_TAGS.contains(&tag[0]) {
+        return Ok((tag[0], vec![]));
+    }
+
+    let length = tcp_stream.read_u32().await?;
+    if length > PACKET_BYTE_LIMIT {
+        return Err(PacketError::TooLarge(length));
+    }
+    let mut bytes = vec![0; length as usize];
+    tcp_stream.read_exact(&mut bytes).await?;
+
+    Ok((tag[0], bytes))
+}
+
 #[allow(dead_code)]
 pub(crate) async fn read_all_from_stream(
     tcp_stream: &mut TcpStream,
@@ -142,6 +1895,9 @@ pub(crate) async fn read_all_from_stream(
 
         let length = start_packet.length;
         let tag = start_packet.tag;
+        if length > PACKET_BYTE_LIMIT {
+            return Err(PacketError::TooLarge(length));
+        }
 
         let mut all_bytes = start_packet.value.to_vec();
         all_bytes.reserve(start_packet.length as usize);
@@ -1,21 +1,88 @@
+use std::time::Duration;
+
 use chorba::{decode, encode};
-use engine::KVEngine;
 use protocol::{
-    CLEAR, CLEAR_OK, DELETE, DELETE_OK, DeleteRequest, ERROR, GET, GET_OK, GetRequest, GetResponse,
-    PACKET_INVALID, PING, PONG, PacketError, SET, SET_OK, SetRequest, generate_packet,
-    read_all_from_stream,
+    BF_ADD, BF_ADD_OK, BF_EXISTS, BF_RESERVE, BF_RESERVE_OK, BGSAVE, BGSAVE_OK, BLOCKING_POP_OK,
+    BLPOP, BRPOP, BlockingPopRequest, BlockingPopResponse, BloomAddResponse, BloomReserveRequest,
+    CLEAR, CLEAR_OK, COMPARE_AND_SET, COMPARE_AND_SET_OK, CompareAndSetRequest,
+    CompareAndSetResponse, DECR_BY, DELETE, DELETE_OK, DROP_NAMESPACE, DROP_NAMESPACE_OK,
+    DeleteRequest, DropNamespaceRequest, DropNamespaceResponse, ERROR, EXEC, EXEC_OK, EXPIRE,
+    EXPIRE_AT, EXPIRE_OK, ExecRequest, ExecResponse, ExpireAtRequest, ExpireRequest, GEOADD,
+    GEODIST, GEODIST_OK, GEOSEARCH, GEOSEARCH_OK, GET, GET_DELETE, GET_OK, GROUP_EXISTS,
+    GeoAddRequest, GeoDistRequest, GeoDistResponse, GeoMatch, GeoSearchRequest, GeoSearchResponse,
+    GetRequest, GetResponse, HASH_COUNT_OK, HASH_ENTRIES_OK, HASH_EXISTS_OK, HASH_VALUE_OK,
+    HASH_VALUES_OK, HDEL, HEXISTS, HGET, HGETALL, HINCRBY, HLEN, HMGET, HSET, HashCountResponse,
+    HashEntriesResponse, HashExistsResponse, HashField, HashFieldRequest, HashFieldsRequest,
+    HashIncrByRequest, HashSetRequest, HashValueResponse, HashValuesResponse, INCR_BY,
+    INCR_BY_FLOAT, INCR_FLOAT_OK, INCR_OK, INVALID_COORDINATES, INVALID_FILTER_OPTIONS,
+    INVALID_JSON, INVALID_JSON_PATH, INVALID_LOCK_LEASE, INVALID_NAMESPACE, INVALID_RATE_LIMIT,
+    INVALID_STREAM_ID, IS_MEMBER_OK, IncrByFloatRequest, IncrByFloatResponse, IncrByRequest,
+    IncrByResponse, IsMemberResponse, JOB_ACK, JOB_ACK_OK, JOB_DEAD_LETTERS, JOB_DEQUEUE,
+    JOB_ENQUEUE, JOB_ENQUEUE_OK, JOB_NACK, JOB_NACK_OK, JOBS_OK, JSON_ARRAPPEND, JSON_DEL,
+    JSON_DEL_OK, JSON_GET, JSON_INCRBY, JSON_PATH_NOT_FOUND, JSON_SET, JSON_SET_OK, JSON_VALUE_OK,
+    Job, JobAckRequest, JobAckResponse, JobDeadLettersRequest, JobDequeueRequest,
+    JobEnqueueRequest, JobEnqueueResponse, JobNackRequest, JobNackResponse, JobsResponse,
+    JsonAppendRequest, JsonDeleteResponse, JsonIncrByRequest, JsonPathRequest, JsonSetRequest,
+    JsonValueResponse, KEY_EVENT, KEY_EXISTS, KeyEventMessage, KeyValue, LIST_LENGTH_OK,
+    LIST_VALUES_OK, LLEN, LOCK_ACQUIRE, LOCK_ACQUIRE_OK, LOCK_RELEASE, LOCK_RELEASE_OK, LOCK_RENEW,
+    LOCK_RENEW_OK, LPOP, LPUSH, LRANGE, LTRIM, LTRIM_OK, ListLengthResponse, ListPopRequest,
+    ListPushRequest, ListRangeRequest, ListValuesResponse, LockAcquireRequest, LockAcquireResponse,
+    LockReleaseRequest, LockReleaseResponse, LockRenewRequest, LockRenewResponse, MDEL, MDEL_OK,
+    MDelResponse, MEMBER_COUNT_OK, MEMBERS_OK, MESSAGE, MGET, MGET_OK, MGetResponse, MSET, MSET_OK,
+    MSetRequest, MSetResponse, MemberCountResponse, MemberRequest, MembersRequest, MembersResponse,
+    MultiKeyRequest, NAMESPACE_NOT_EMPTY, NO_SUCH_GROUP, NOT_A_NUMBER, OUT_OF_MEMORY,
+    PACKET_INVALID, PENDING_OK, PERSIST, PERSIST_OK, PFADD, PFADD_OK, PFCOUNT, PFCOUNT_OK, PFMERGE,
+    PFMERGE_OK, PING, PONG, PSUBSCRIBE, PUBLISH, PUBLISH_OK, PacketError, PersistRequest,
+    PfAddResponse, PfCountResponse, PfMergeRequest, PublishRequest, PublishResponse, PushMessage,
+    RANK_OK, RATE_LIMIT, RATE_LIMIT_OK, RPOP, RPUSH, RankResponse, RateLimitRequest,
+    RateLimitResponse, SADD, SAVE, SAVE_OK, SCAN, SCAN_OK, SCORED_MEMBERS_OK, SDIFF, SELECT,
+    SELECT_OK, SET, SET_EX, SET_OK, SET_WITH_OPTIONS, SET_WITH_OPTIONS_OK, SINTER, SISMEMBER,
+    SMEMBERS, SREM, STATS, STATS_OK, STREAM_ENTRIES_OK, STREAM_ID_OK, STREAM_READ_OK, SUBSCRIBE,
+    SUBSCRIBE_OK, SUNION, ScanEntry, ScanRequest, ScanResponse, ScoredMember,
+    ScoredMembersResponse, SelectRequest, SetExpireRequest, SetRequest, SetWithOptionsRequest,
+    SetWithOptionsResponse, SortedSetAddRequest, SortedSetIncrByRequest,
+    SortedSetRangeByScoreRequest, SortedSetRangeRequest, SortedSetRankRequest, StatsResponse,
+    StreamAckRequest, StreamAckResponse, StreamAddRequest, StreamAddResponse, StreamClaimRequest,
+    StreamEntries, StreamEntriesResponse, StreamEntry, StreamGroupCreateRequest,
+    StreamPendingEntry, StreamPendingRequest, StreamPendingResponse, StreamRangeRequest,
+    StreamReadGroupRequest, StreamReadRequest, StreamReadResponse, SubscribeRequest,
+    SubscribeResponse, TOO_MANY_NAMESPACES, TTL, TTL_OK, TtlRequest, TtlResponse, TxCommand,
+    TxReply, UNSUBSCRIBE, UnsubscribeRequest, VERSION_MISMATCH, WATCH, WATCH_KEYS, WATCH_KEYS_OK,
+    WATCH_OK, WRONG_TYPE, WatchKeysRequest, WatchResponse, XACK, XACK_OK, XADD, XCLAIM,
+    XGROUP_CREATE, XGROUP_CREATE_OK, XPENDING, XRANGE, XREAD, XREADGROUP, ZADD, ZINCRBY, ZRANGE,
+    ZRANGEBYSCORE, ZRANK, ZREM, generate_packet, read_all_from_stream,
+};
+use rstore::engine::{
+    EngineConfig, Expiration, GeoShape, GeoSort, KVEngine, KVError, KeyFilter, KeyRange, ListEnd,
+    QueuedJob, RateLimitAlgorithm, SetCondition, SetOptions, StreamId, Subscriber, TxOperation,
+    TxResult,
 };
 use tokio::{io::AsyncWriteExt, net::TcpStream};
 
-mod engine;
 pub mod protocol;
 
+const EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
 #[tokio::main]
 async fn main() {
-    let engine = KVEngine::new();
+    rstore::logging::init();
+
+    // 0. 스냅샷, WAL 복원 (연결 수신 전)
+    let engine = match KVEngine::open(EngineConfig::from_env()) {
+        Ok(engine) => engine,
+        Err(error) => {
+            // Starting empty would let the next snapshot overwrite the data on disk.
+            log::error!("Failed to restore persisted data: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    engine.start_expiration_sweeper(EXPIRATION_SWEEP_INTERVAL);
+    engine.start_snapshot_scheduler();
+    engine.start_wal_maintenance();
 
     let address = "0.0.0.0:13535";
-    log::debug!("Listening on {}", address);
+    log::info!("Listening on {}", address);
 
     // 1. 서버 시작
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
@@ -45,6 +112,11 @@ async fn handle_stream(mut tcp_stream: TcpStream, mut engine: KVEngine) {
                 log::debug!("No data received");
                 return;
             }
+            Err(error @ PacketError::TooLarge(_)) => {
+                log::error!("Closing connection: {}", error);
+                let _ = tcp_stream.write_all(&[PACKET_INVALID]).await;
+                return;
+            }
             Err(error) => {
                 log::error!("Failed to fetch packet: {}", error);
                 let _ = tcp_stream.write_all(&[PACKET_INVALID]).await;
@@ -76,6 +148,457 @@ async fn handle_stream(mut tcp_stream: TcpStream, mut engine: KVEngine) {
 
                 process_delete(&mut tcp_stream, &mut engine, &bytes).await;
             }
+            SET_EX => {
+                log::debug!("Received SET_EX");
+
+                process_set_expire(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            EXPIRE => {
+                log::debug!("Received EXPIRE");
+
+                process_expire(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            EXPIRE_AT => {
+                log::debug!("Received EXPIRE_AT");
+
+                process_expire_at(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            PERSIST => {
+                log::debug!("Received PERSIST");
+
+                process_persist(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            TTL => {
+                log::debug!("Received TTL");
+
+                process_ttl(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            INCR_BY => {
+                log::debug!("Received INCR_BY");
+
+                process_incr_by(&mut tcp_stream, &mut engine, &bytes, false).await;
+            }
+            DECR_BY => {
+                log::debug!("Received DECR_BY");
+
+                process_incr_by(&mut tcp_stream, &mut engine, &bytes, true).await;
+            }
+            INCR_BY_FLOAT => {
+                log::debug!("Received INCR_BY_FLOAT");
+
+                process_incr_by_float(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            COMPARE_AND_SET => {
+                log::debug!("Received COMPARE_AND_SET");
+
+                process_compare_and_set(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SET_WITH_OPTIONS => {
+                log::debug!("Received SET_WITH_OPTIONS");
+
+                process_set_with_options(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            GET_DELETE => {
+                log::debug!("Received GET_DELETE");
+
+                process_get_delete(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            MGET => {
+                log::debug!("Received MGET");
+
+                process_mget(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            MSET => {
+                log::debug!("Received MSET");
+
+                process_mset(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            MDEL => {
+                log::debug!("Received MDEL");
+
+                process_mdel(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SCAN => {
+                log::debug!("Received SCAN");
+
+                process_scan(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            WATCH => {
+                log::debug!("Received WATCH");
+
+                process_watch(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            EXEC => {
+                log::debug!("Received EXEC");
+
+                process_exec(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SUBSCRIBE | PSUBSCRIBE => {
+                log::debug!("Received SUBSCRIBE");
+
+                // The connection only takes subscription commands until every subscription is gone
+                if !process_push_mode(&mut tcp_stream, &engine, tag, &bytes).await {
+                    return;
+                }
+            }
+            UNSUBSCRIBE => {
+                log::debug!("Received UNSUBSCRIBE");
+
+                // Nothing to unsubscribe from outside of push mode
+                let response_bytes = encode(&SubscribeResponse { count: 0 });
+                let response = generate_packet(SUBSCRIBE_OK, &response_bytes);
+                let _ = tcp_stream.write_all(&response).await;
+            }
+            WATCH_KEYS => {
+                log::debug!("Received WATCH_KEYS");
+
+                // Watching lasts for the rest of the connection
+                if !process_watch_keys(&mut tcp_stream, &engine, &bytes).await {
+                    return;
+                }
+            }
+            PUBLISH => {
+                log::debug!("Received PUBLISH");
+
+                process_publish(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LPUSH => {
+                log::debug!("Received LPUSH");
+
+                process_list_push(&mut tcp_stream, &mut engine, &bytes, ListEnd::Left).await;
+            }
+            RPUSH => {
+                log::debug!("Received RPUSH");
+
+                process_list_push(&mut tcp_stream, &mut engine, &bytes, ListEnd::Right).await;
+            }
+            LPOP => {
+                log::debug!("Received LPOP");
+
+                process_list_pop(&mut tcp_stream, &mut engine, &bytes, ListEnd::Left).await;
+            }
+            RPOP => {
+                log::debug!("Received RPOP");
+
+                process_list_pop(&mut tcp_stream, &mut engine, &bytes, ListEnd::Right).await;
+            }
+            LRANGE => {
+                log::debug!("Received LRANGE");
+
+                process_list_range(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LLEN => {
+                log::debug!("Received LLEN");
+
+                process_list_len(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LTRIM => {
+                log::debug!("Received LTRIM");
+
+                process_list_trim(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            BLPOP => {
+                log::debug!("Received BLPOP");
+
+                process_blocking_pop(&mut tcp_stream, &mut engine, &bytes, ListEnd::Left).await;
+            }
+            BRPOP => {
+                log::debug!("Received BRPOP");
+
+                process_blocking_pop(&mut tcp_stream, &mut engine, &bytes, ListEnd::Right).await;
+            }
+            HSET => {
+                log::debug!("Received HSET");
+
+                process_hash_set(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HGET => {
+                log::debug!("Received HGET");
+
+                process_hash_get(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HMGET => {
+                log::debug!("Received HMGET");
+
+                process_hash_mget(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HDEL => {
+                log::debug!("Received HDEL");
+
+                process_hash_delete(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HGETALL => {
+                log::debug!("Received HGETALL");
+
+                process_hash_get_all(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HINCRBY => {
+                log::debug!("Received HINCRBY");
+
+                process_hash_incr_by(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HEXISTS => {
+                log::debug!("Received HEXISTS");
+
+                process_hash_exists(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            HLEN => {
+                log::debug!("Received HLEN");
+
+                process_hash_len(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SADD => {
+                log::debug!("Received SADD");
+
+                process_set_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SREM => {
+                log::debug!("Received SREM");
+
+                process_set_remove(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SISMEMBER => {
+                log::debug!("Received SISMEMBER");
+
+                process_set_is_member(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SMEMBERS => {
+                log::debug!("Received SMEMBERS");
+
+                process_set_members(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SINTER | SUNION | SDIFF => {
+                log::debug!("Received SINTER/SUNION/SDIFF");
+
+                process_set_combine(&mut tcp_stream, &mut engine, &bytes, tag).await;
+            }
+            ZADD => {
+                log::debug!("Received ZADD");
+
+                process_sorted_set_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            ZINCRBY => {
+                log::debug!("Received ZINCRBY");
+
+                process_sorted_set_incr_by(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            ZRANGE => {
+                log::debug!("Received ZRANGE");
+
+                process_sorted_set_range(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            ZRANGEBYSCORE => {
+                log::debug!("Received ZRANGEBYSCORE");
+
+                process_sorted_set_range_by_score(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            ZRANK => {
+                log::debug!("Received ZRANK");
+
+                process_sorted_set_rank(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            ZREM => {
+                log::debug!("Received ZREM");
+
+                process_sorted_set_remove(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XADD => {
+                log::debug!("Received XADD");
+
+                process_stream_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XRANGE => {
+                log::debug!("Received XRANGE");
+
+                process_stream_range(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XREAD => {
+                log::debug!("Received XREAD");
+
+                process_stream_read(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XGROUP_CREATE => {
+                log::debug!("Received XGROUP_CREATE");
+
+                process_stream_group_create(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XREADGROUP => {
+                log::debug!("Received XREADGROUP");
+
+                process_stream_read_group(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XACK => {
+                log::debug!("Received XACK");
+
+                process_stream_ack(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XPENDING => {
+                log::debug!("Received XPENDING");
+
+                process_stream_pending(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            XCLAIM => {
+                log::debug!("Received XCLAIM");
+
+                process_stream_claim(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JSON_GET => {
+                log::debug!("Received JSON_GET");
+
+                process_json_get(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JSON_SET => {
+                log::debug!("Received JSON_SET");
+
+                process_json_set(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JSON_DEL => {
+                log::debug!("Received JSON_DEL");
+
+                process_json_del(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JSON_ARRAPPEND => {
+                log::debug!("Received JSON_ARRAPPEND");
+
+                process_json_array_append(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JSON_INCRBY => {
+                log::debug!("Received JSON_INCRBY");
+
+                process_json_incr_by(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            PFADD => {
+                log::debug!("Received PFADD");
+
+                process_hll_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            PFCOUNT => {
+                log::debug!("Received PFCOUNT");
+
+                process_hll_count(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            PFMERGE => {
+                log::debug!("Received PFMERGE");
+
+                process_hll_merge(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            BF_RESERVE => {
+                log::debug!("Received BF_RESERVE");
+
+                process_bloom_reserve(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            BF_ADD => {
+                log::debug!("Received BF_ADD");
+
+                process_bloom_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            BF_EXISTS => {
+                log::debug!("Received BF_EXISTS");
+
+                process_bloom_exists(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            GEOADD => {
+                log::debug!("Received GEOADD");
+
+                process_geo_add(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            GEODIST => {
+                log::debug!("Received GEODIST");
+
+                process_geo_dist(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            GEOSEARCH => {
+                log::debug!("Received GEOSEARCH");
+
+                process_geo_search(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            RATE_LIMIT => {
+                log::debug!("Received RATE_LIMIT");
+
+                process_rate_limit(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LOCK_ACQUIRE => {
+                log::debug!("Received LOCK_ACQUIRE");
+
+                process_lock_acquire(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LOCK_RENEW => {
+                log::debug!("Received LOCK_RENEW");
+
+                process_lock_renew(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            LOCK_RELEASE => {
+                log::debug!("Received LOCK_RELEASE");
+
+                process_lock_release(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JOB_ENQUEUE => {
+                log::debug!("Received JOB_ENQUEUE");
+
+                process_job_enqueue(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JOB_DEQUEUE => {
+                log::debug!("Received JOB_DEQUEUE");
+
+                process_job_dequeue(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JOB_ACK => {
+                log::debug!("Received JOB_ACK");
+
+                process_job_ack(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JOB_NACK => {
+                log::debug!("Received JOB_NACK");
+
+                process_job_nack(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            JOB_DEAD_LETTERS => {
+                log::debug!("Received JOB_DEAD_LETTERS");
+
+                process_job_dead_letters(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SELECT => {
+                log::debug!("Received SELECT");
+
+                process_select(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            STATS => {
+                log::debug!("Received STATS");
+
+                process_stats(&mut tcp_stream, &mut engine).await;
+            }
+            DROP_NAMESPACE => {
+                log::debug!("Received DROP_NAMESPACE");
+
+                process_drop_namespace(&mut tcp_stream, &mut engine, &bytes).await;
+            }
+            SAVE => {
+                log::debug!("Received SAVE");
+
+                let snapshot_engine = engine.clone();
+                let result = tokio::task::spawn_blocking(move || snapshot_engine.save_snapshot())
+                    .await
+                    .unwrap_or_else(|error| Err(KVError::SnapshotFailed(error.to_string())));
+
+                if let Err(error) = result {
+                    log::error!("Failed to save snapshot: {}", error);
+                    let _ = tcp_stream.write_all(&[ERROR]).await;
+                    continue;
+                }
+
+                let _ = tcp_stream.write_all(&[SAVE_OK]).await;
+            }
+            BGSAVE => {
+                log::debug!("Received BGSAVE");
+
+                if let Err(error) = engine.background_save() {
+                    log::error!("Failed to start background snapshot: {}", error);
+                    let _ = tcp_stream.write_all(&[ERROR]).await;
+                    continue;
+                }
+
+                let _ = tcp_stream.write_all(&[BGSAVE_OK]).await;
+            }
             CLEAR => {
                 log::debug!("Received CLEAR");
 
@@ -98,6 +621,30 @@ async fn handle_stream(mut tcp_stream: TcpStream, mut engine: KVEngine) {
     }
 }
 
+/// Response tag for an engine error; errors without a dedicated tag map to `ERROR`.
+fn error_tag(error: &KVError) -> u8 {
+    match error {
+        KVError::OutOfMemory => OUT_OF_MEMORY,
+        KVError::NotAnInteger | KVError::NotAFloat => NOT_A_NUMBER,
+        KVError::VersionMismatch => VERSION_MISMATCH,
+        KVError::WrongType => WRONG_TYPE,
+        KVError::InvalidStreamId => INVALID_STREAM_ID,
+        KVError::NoSuchGroup => NO_SUCH_GROUP,
+        KVError::GroupExists => GROUP_EXISTS,
+        KVError::InvalidJsonPath => INVALID_JSON_PATH,
+        KVError::JsonPathNotFound => JSON_PATH_NOT_FOUND,
+        KVError::KeyExists => KEY_EXISTS,
+        KVError::InvalidFilterOptions => INVALID_FILTER_OPTIONS,
+        KVError::InvalidCoordinates => INVALID_COORDINATES,
+        KVError::InvalidRateLimit => INVALID_RATE_LIMIT,
+        KVError::InvalidLockLease => INVALID_LOCK_LEASE,
+        KVError::InvalidNamespace => INVALID_NAMESPACE,
+        KVError::TooManyNamespaces => TOO_MANY_NAMESPACES,
+        KVError::NamespaceNotEmpty => NAMESPACE_NOT_EMPTY,
+        _ => ERROR,
+    }
+}
+
 pub async fn process_set(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
     let decode_result = decode::<SetRequest>(bytes);
 
@@ -114,7 +661,8 @@ pub async fn process_set(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &
     let value = set_request.value;
     if let Err(error) = engine.set_key_value(key, value) {
         log::error!("Failed to set key-value pair: {}", error);
-        let _ = stream.write_all(&[ERROR]).await;
+        let _ = stream.write_all(&[error_tag(&error)]).await;
+        return;
     }
 
     // Send a response back to the client
@@ -134,10 +682,10 @@ pub async fn process_get(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &
     };
 
     let key = get_request.key;
-    match engine.get_key_value(&key) {
-        Ok(value) => {
+    match engine.get_key_value_with_version(&key) {
+        Ok((value, version)) => {
             // Send the value back to the client
-            let get_response = GetResponse { value };
+            let get_response = GetResponse { value, version };
             let response_bytes = encode(&get_response);
 
             let response = generate_packet(GET_OK, &response_bytes);
@@ -171,3 +719,2694 @@ pub async fn process_delete(stream: &mut TcpStream, engine: &mut KVEngine, bytes
     // Send a response back to the client
     let _ = stream.write_all(&[DELETE_OK]).await;
 }
+
+pub async fn process_set_expire(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SetExpireRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode SetExpireRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let expiration = Expiration::After(Duration::from_millis(set_request.ttl_millis));
+    if let Err(error) =
+        engine.set_key_value_with_expiration(set_request.key, set_request.value, expiration)
+    {
+        log::error!("Failed to set key-value pair: {}", error);
+        let _ = stream.write_all(&[error_tag(&error)]).await;
+        return;
+    }
+
+    let _ = stream.write_all(&[SET_OK]).await;
+}
+
+pub async fn process_expire(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ExpireRequest>(bytes);
+
+    let expire_request = match decode_result {
+        Ok(expire_request) => expire_request,
+        Err(error) => {
+            log::error!("Failed to decode ExpireRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let expiration = Expiration::After(Duration::from_millis(expire_request.ttl_millis));
+    if let Err(error) = engine.expire(&expire_request.key, expiration) {
+        log::error!("Failed to set expiration: {}", error);
+        let _ = stream.write_all(&[ERROR]).await;
+        return;
+    }
+
+    let _ = stream.write_all(&[EXPIRE_OK]).await;
+}
+
+pub async fn process_expire_at(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ExpireAtRequest>(bytes);
+
+    let expire_request = match decode_result {
+        Ok(expire_request) => expire_request,
+        Err(error) => {
+            log::error!("Failed to decode ExpireAtRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let expiration = Expiration::AtUnixMillis(expire_request.unix_millis);
+    if let Err(error) = engine.expire(&expire_request.key, expiration) {
+        log::error!("Failed to set expiration: {}", error);
+        let _ = stream.write_all(&[ERROR]).await;
+        return;
+    }
+
+    let _ = stream.write_all(&[EXPIRE_OK]).await;
+}
+
+pub async fn process_persist(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<PersistRequest>(bytes);
+
+    let persist_request = match decode_result {
+        Ok(persist_request) => persist_request,
+        Err(error) => {
+            log::error!("Failed to decode PersistRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    if let Err(error) = engine.persist(&persist_request.key) {
+        log::error!("Failed to remove expiration: {}", error);
+        let _ = stream.write_all(&[ERROR]).await;
+        return;
+    }
+
+    let _ = stream.write_all(&[PERSIST_OK]).await;
+}
+
+pub async fn process_ttl(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<TtlRequest>(bytes);
+
+    let ttl_request = match decode_result {
+        Ok(ttl_request) => ttl_request,
+        Err(error) => {
+            log::error!("Failed to decode TtlRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.ttl(&ttl_request.key) {
+        Ok(ttl) => {
+            let ttl_response = TtlResponse {
+                ttl_millis: ttl.map(|ttl| ttl.as_millis() as u64),
+            };
+            let response_bytes = encode(&ttl_response);
+
+            let response = generate_packet(TTL_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get ttl: {}", error);
+            let _ = stream.write_all(&[ERROR]).await;
+        }
+    }
+}
+
+pub async fn process_incr_by(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+    decrement: bool,
+) {
+    let decode_result = decode::<IncrByRequest>(bytes);
+
+    let incr_request = match decode_result {
+        Ok(incr_request) => incr_request,
+        Err(error) => {
+            log::error!("Failed to decode IncrByRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = if decrement {
+        engine.decr_by(&incr_request.key, incr_request.delta)
+    } else {
+        engine.incr_by(&incr_request.key, incr_request.delta)
+    };
+
+    match result {
+        Ok(value) => {
+            let response_bytes = encode(&IncrByResponse { value });
+
+            let response = generate_packet(INCR_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to increment value: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_incr_by_float(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<IncrByFloatRequest>(bytes);
+
+    let incr_request = match decode_result {
+        Ok(incr_request) => incr_request,
+        Err(error) => {
+            log::error!("Failed to decode IncrByFloatRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.incr_by_float(&incr_request.key, incr_request.delta) {
+        Ok(value) => {
+            let response_bytes = encode(&IncrByFloatResponse { value });
+
+            let response = generate_packet(INCR_FLOAT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to increment value: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_compare_and_set(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<CompareAndSetRequest>(bytes);
+
+    let cas_request = match decode_result {
+        Ok(cas_request) => cas_request,
+        Err(error) => {
+            log::error!("Failed to decode CompareAndSetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = match cas_request.ttl_millis {
+        Some(ttl_millis) => engine.compare_and_set_with_expiration(
+            cas_request.key,
+            cas_request.expected_version,
+            cas_request.value,
+            Expiration::After(Duration::from_millis(ttl_millis)),
+        ),
+        None => engine.compare_and_set(
+            cas_request.key,
+            cas_request.expected_version,
+            cas_request.value,
+        ),
+    };
+
+    match result {
+        Ok(version) => {
+            let response_bytes = encode(&CompareAndSetResponse { version });
+
+            let response = generate_packet(COMPARE_AND_SET_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to compare and set: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_with_options(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SetWithOptionsRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode SetWithOptionsRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let condition = match (set_request.if_absent, set_request.if_present) {
+        (false, false) => SetCondition::Always,
+        (true, false) => SetCondition::IfAbsent,
+        (false, true) => SetCondition::IfPresent,
+        (true, true) => {
+            log::error!("SET_WITH_OPTIONS with both if_absent and if_present");
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+    let options = SetOptions {
+        condition,
+        expiration: set_request
+            .ttl_millis
+            .map(|ttl_millis| Expiration::After(Duration::from_millis(ttl_millis))),
+        return_previous: set_request.get,
+    };
+
+    match engine.set_with_options(set_request.key, set_request.value, options) {
+        Ok(outcome) => {
+            let set_response = SetWithOptionsResponse {
+                version: outcome.version,
+                previous: outcome.previous,
+            };
+            let response_bytes = encode(&set_response);
+
+            let response = generate_packet(SET_WITH_OPTIONS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to set key-value pair: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_get_delete(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GetRequest>(bytes);
+
+    let get_request = match decode_result {
+        Ok(get_request) => get_request,
+        Err(error) => {
+            log::error!("Failed to decode GetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.get_and_delete(&get_request.key) {
+        Ok((value, version)) => {
+            let response_bytes = encode(&GetResponse { value, version });
+
+            let response = generate_packet(GET_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get and delete key-value pair: {}", error);
+            let _ = stream.write_all(&[ERROR]).await;
+        }
+    }
+}
+
+pub async fn process_mget(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MultiKeyRequest>(bytes);
+
+    let mget_request = match decode_result {
+        Ok(mget_request) => mget_request,
+        Err(error) => {
+            log::error!("Failed to decode MultiKeyRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.mget(&mget_request.keys) {
+        Ok(values) => {
+            let response_bytes = encode(&MGetResponse { values });
+
+            let response = generate_packet(MGET_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get key-value pairs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_mset(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MSetRequest>(bytes);
+
+    let mset_request = match decode_result {
+        Ok(mset_request) => mset_request,
+        Err(error) => {
+            log::error!("Failed to decode MSetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let entries = mset_request
+        .entries
+        .into_iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+
+    match engine.mset(entries) {
+        Ok(versions) => {
+            let response_bytes = encode(&MSetResponse { versions });
+
+            let response = generate_packet(MSET_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to set key-value pairs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_mdel(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MultiKeyRequest>(bytes);
+
+    let mdel_request = match decode_result {
+        Ok(mdel_request) => mdel_request,
+        Err(error) => {
+            log::error!("Failed to decode MultiKeyRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.mdel(&mdel_request.keys) {
+        Ok(deleted) => {
+            let response_bytes = encode(&MDelResponse { deleted });
+
+            let response = generate_packet(MDEL_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to delete key-value pairs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_scan(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ScanRequest>(bytes);
+
+    let scan_request = match decode_result {
+        Ok(scan_request) => scan_request,
+        Err(error) => {
+            log::error!("Failed to decode ScanRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let range = match (scan_request.prefix, scan_request.start, scan_request.end) {
+        (Some(prefix), None, None) => KeyRange::Prefix(prefix),
+        (None, start, end) => KeyRange::Range { start, end },
+        _ => {
+            log::error!("SCAN with both a prefix and a range");
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = engine.scan(
+        &range,
+        scan_request.cursor.as_deref(),
+        scan_request.limit as usize,
+        scan_request.with_values,
+    );
+
+    match result {
+        Ok(page) => {
+            let scan_response = ScanResponse {
+                entries: page
+                    .entries
+                    .into_iter()
+                    .map(|(key, value)| ScanEntry { key, value })
+                    .collect(),
+                cursor: page.next_cursor,
+            };
+            let response_bytes = encode(&scan_response);
+
+            let response = generate_packet(SCAN_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to scan keys: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_watch(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MultiKeyRequest>(bytes);
+
+    let watch_request = match decode_result {
+        Ok(watch_request) => watch_request,
+        Err(error) => {
+            log::error!("Failed to decode MultiKeyRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.versions(&watch_request.keys) {
+        Ok(versions) => {
+            let response_bytes = encode(&WatchResponse { versions });
+
+            let response = generate_packet(WATCH_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to watch keys: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_exec(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ExecRequest>(bytes);
+
+    let exec_request = match decode_result {
+        Ok(exec_request) => exec_request,
+        Err(error) => {
+            log::error!("Failed to decode ExecRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let watches: Vec<(String, u64)> = exec_request
+        .watches
+        .into_iter()
+        .map(|watched| (watched.key, watched.version))
+        .collect();
+
+    let operations = exec_request
+        .commands
+        .into_iter()
+        .map(|command| match command {
+            TxCommand::Get { key } => TxOperation::Get { key },
+            TxCommand::Set {
+                key,
+                value,
+                ttl_millis,
+            } => TxOperation::Set {
+                key,
+                value,
+                expiration: ttl_millis
+                    .map(|ttl_millis| Expiration::After(Duration::from_millis(ttl_millis))),
+            },
+            TxCommand::Delete { key } => TxOperation::Delete { key },
+            TxCommand::IncrBy { key, delta } => TxOperation::IncrBy { key, delta },
+        })
+        .collect();
+
+    match engine.execute_transaction(&watches, operations) {
+        Ok(results) => {
+            let replies = results
+                .into_iter()
+                .map(|result| match result {
+                    TxResult::Value(value) => TxReply::Value(value),
+                    TxResult::Stored => TxReply::Stored,
+                    TxResult::Deleted(deleted) => TxReply::Deleted(deleted),
+                    TxResult::Integer(value) => TxReply::Integer(value),
+                })
+                .collect();
+            let response_bytes = encode(&ExecResponse { replies });
+
+            let response = generate_packet(EXEC_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to execute transaction: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_publish(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<PublishRequest>(bytes);
+
+    let publish_request = match decode_result {
+        Ok(publish_request) => publish_request,
+        Err(error) => {
+            log::error!("Failed to decode PublishRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let receivers = engine.publish(&publish_request.channel, &publish_request.message);
+
+    let response_bytes = encode(&PublishResponse {
+        receivers: receivers as u64,
+    });
+    let response = generate_packet(PUBLISH_OK, &response_bytes);
+    let _ = stream.write_all(&response).await;
+}
+
+/// Serves a subscribed connection: pushes published messages and handles
+/// subscription changes and PING. Returns whether the connection should be kept,
+/// i.e. the client unsubscribed from everything rather than going away or falling behind.
+async fn process_push_mode(
+    stream: &mut TcpStream,
+    engine: &KVEngine,
+    tag: u8,
+    bytes: &[u8],
+) -> bool {
+    let mut subscriber = engine.subscriber();
+    process_subscription(stream, &subscriber, tag, bytes).await;
+
+    while subscriber.subscription_count() > 0 {
+        tokio::select! {
+            message = subscriber.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+
+                let push_message = PushMessage {
+                    channel: message.channel,
+                    pattern: message.pattern,
+                    message: message.payload.to_vec(),
+                };
+                let packet = generate_packet(MESSAGE, &encode(&push_message));
+
+                tokio::select! {
+                    result = stream.write_all(&packet) => {
+                        if let Err(error) = result {
+                            log::error!("Failed to push message: {}", error);
+                            return false;
+                        }
+                    }
+                    _ = subscriber.disconnected() => break,
+                }
+            }
+            readable = stream.readable() => {
+                if readable.is_err() {
+                    return false;
+                }
+
+                let (tag, bytes) = match read_all_from_stream(stream).await {
+                    Ok(packet) => packet,
+                    Err(PacketError::NoDataReceived) => return false,
+                    Err(error @ PacketError::TooLarge(_)) => {
+                        log::error!("Closing connection: {}", error);
+                        let _ = stream.write_all(&[PACKET_INVALID]).await;
+                        return false;
+                    }
+                    Err(error) => {
+                        log::error!("Failed to fetch packet: {}", error);
+                        let _ = stream.write_all(&[PACKET_INVALID]).await;
+                        continue;
+                    }
+                };
+
+                match tag {
+                    SUBSCRIBE | PSUBSCRIBE | UNSUBSCRIBE => {
+                        process_subscription(stream, &subscriber, tag, &bytes).await;
+                    }
+                    PING => {
+                        let _ = stream.write_all(&[PONG]).await;
+                    }
+                    _ => {
+                        log::error!("Only subscription commands are allowed while subscribed");
+                        let _ = stream.write_all(&[ERROR]).await;
+                    }
+                }
+            }
+        }
+    }
+
+    if subscriber.is_disconnected() {
+        log::warn!("Disconnecting a subscriber that exceeded the output buffer limit");
+        return false;
+    }
+
+    true
+}
+
+async fn process_subscription(
+    stream: &mut TcpStream,
+    subscriber: &Subscriber,
+    tag: u8,
+    bytes: &[u8],
+) {
+    let count = if tag == UNSUBSCRIBE {
+        match decode::<UnsubscribeRequest>(bytes) {
+            Ok(request) => subscriber.unsubscribe(&request.channels, &request.patterns),
+            Err(error) => {
+                log::error!("Failed to decode UnsubscribeRequest: {}", error);
+                let _ = stream.write_all(&[PACKET_INVALID]).await;
+                return;
+            }
+        }
+    } else {
+        match decode::<SubscribeRequest>(bytes) {
+            Ok(request) if tag == PSUBSCRIBE => subscriber.psubscribe(&request.channels),
+            Ok(request) => subscriber.subscribe(&request.channels),
+            Err(error) => {
+                log::error!("Failed to decode SubscribeRequest: {}", error);
+                let _ = stream.write_all(&[PACKET_INVALID]).await;
+                return;
+            }
+        }
+    };
+
+    let response_bytes = encode(&SubscribeResponse {
+        count: count as u64,
+    });
+    let response = generate_packet(SUBSCRIBE_OK, &response_bytes);
+    let _ = stream.write_all(&response).await;
+}
+
+/// Streams the events of the watched keys until the client goes away or falls too
+/// far behind. Returns whether the connection should be kept, which is only the case
+/// if the request was invalid.
+async fn process_watch_keys(stream: &mut TcpStream, engine: &KVEngine, bytes: &[u8]) -> bool {
+    let decode_result = decode::<WatchKeysRequest>(bytes);
+
+    let watch_keys_request = match decode_result {
+        Ok(watch_keys_request) => watch_keys_request,
+        Err(error) => {
+            log::error!("Failed to decode WatchKeysRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return true;
+        }
+    };
+
+    let filters = watch_keys_request
+        .keys
+        .into_iter()
+        .map(KeyFilter::Key)
+        .chain(
+            watch_keys_request
+                .prefixes
+                .into_iter()
+                .map(KeyFilter::Prefix),
+        )
+        .collect();
+    let mut watcher = engine.watch_keys(filters);
+
+    if stream.write_all(&[WATCH_KEYS_OK]).await.is_err() {
+        return false;
+    }
+
+    loop {
+        tokio::select! {
+            event = watcher.recv() => {
+                let Some(event) = event else {
+                    log::warn!("Disconnecting a key watcher that fell behind");
+                    return false;
+                };
+
+                let event_message = KeyEventMessage {
+                    key: event.key,
+                    op: event.op.as_str().to_owned(),
+                    version: event.version,
+                };
+                let packet = generate_packet(KEY_EVENT, &encode(&event_message));
+
+                if let Err(error) = stream.write_all(&packet).await {
+                    log::error!("Failed to push key event: {}", error);
+                    return false;
+                }
+            }
+            readable = stream.readable() => {
+                if readable.is_err() {
+                    return false;
+                }
+
+                match read_all_from_stream(stream).await {
+                    Ok((PING, _)) => {
+                        let _ = stream.write_all(&[PONG]).await;
+                    }
+                    Ok(_) => {
+                        log::error!("Only PING is allowed while watching keys");
+                        let _ = stream.write_all(&[ERROR]).await;
+                    }
+                    Err(PacketError::NoDataReceived) => return false,
+                    Err(error @ PacketError::TooLarge(_)) => {
+                        log::error!("Closing connection: {}", error);
+                        let _ = stream.write_all(&[PACKET_INVALID]).await;
+                        return false;
+                    }
+                    Err(error) => {
+                        log::error!("Failed to fetch packet: {}", error);
+                        let _ = stream.write_all(&[PACKET_INVALID]).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub async fn process_list_push(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+    end: ListEnd,
+) {
+    let decode_result = decode::<ListPushRequest>(bytes);
+
+    let push_request = match decode_result {
+        Ok(push_request) => push_request,
+        Err(error) => {
+            log::error!("Failed to decode ListPushRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.list_push(&push_request.key, end, push_request.values) {
+        Ok(length) => {
+            let response_bytes = encode(&ListLengthResponse {
+                length: length as u64,
+            });
+
+            let response = generate_packet(LIST_LENGTH_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to push to list: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_list_pop(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+    end: ListEnd,
+) {
+    let decode_result = decode::<ListPopRequest>(bytes);
+
+    let pop_request = match decode_result {
+        Ok(pop_request) => pop_request,
+        Err(error) => {
+            log::error!("Failed to decode ListPopRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let count = usize::try_from(pop_request.count).unwrap_or(usize::MAX);
+
+    match engine.list_pop(&pop_request.key, end, count) {
+        Ok(values) => {
+            let response_bytes = encode(&ListValuesResponse { values });
+
+            let response = generate_packet(LIST_VALUES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to pop from list: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_list_range(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ListRangeRequest>(bytes);
+
+    let range_request = match decode_result {
+        Ok(range_request) => range_request,
+        Err(error) => {
+            log::error!("Failed to decode ListRangeRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.list_range(&range_request.key, range_request.start, range_request.stop) {
+        Ok(values) => {
+            let response_bytes = encode(&ListValuesResponse { values });
+
+            let response = generate_packet(LIST_VALUES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read list range: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_list_len(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GetRequest>(bytes);
+
+    let len_request = match decode_result {
+        Ok(len_request) => len_request,
+        Err(error) => {
+            log::error!("Failed to decode GetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.list_len(&len_request.key) {
+        Ok(length) => {
+            let response_bytes = encode(&ListLengthResponse {
+                length: length as u64,
+            });
+
+            let response = generate_packet(LIST_LENGTH_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read list length: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_list_trim(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<ListRangeRequest>(bytes);
+
+    let trim_request = match decode_result {
+        Ok(trim_request) => trim_request,
+        Err(error) => {
+            log::error!("Failed to decode ListRangeRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.list_trim(&trim_request.key, trim_request.start, trim_request.stop) {
+        Ok(()) => {
+            let _ = stream.write_all(&[LTRIM_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to trim list: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Parks the connection until a value can be popped or the timeout elapses.
+/// The wait is abandoned if the client goes away in the meantime.
+pub async fn process_blocking_pop(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+    end: ListEnd,
+) {
+    let decode_result = decode::<BlockingPopRequest>(bytes);
+
+    let pop_request = match decode_result {
+        Ok(pop_request) => pop_request,
+        Err(error) => {
+            log::error!("Failed to decode BlockingPopRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let timeout =
+        (pop_request.timeout_millis > 0).then(|| Duration::from_millis(pop_request.timeout_millis));
+
+    let result = tokio::select! {
+        result = engine.list_blocking_pop(&pop_request.keys, end, timeout) => result,
+        _ = peer_closed(stream) => {
+            log::debug!("Client went away during a blocking pop");
+            return;
+        }
+    };
+
+    match result {
+        Ok(entry) => {
+            let response_bytes = encode(&BlockingPopResponse {
+                entry: entry.map(|(key, value)| KeyValue { key, value }),
+            });
+
+            let response = generate_packet(BLOCKING_POP_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to pop from list: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Resolves once the client closed the connection. Pipelined requests can only be read
+/// after the current one, so once one is pending this never resolves.
+async fn peer_closed(stream: &TcpStream) {
+    let mut byte = [0; 1];
+    match stream.peek(&mut byte).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+pub async fn process_hash_set(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashSetRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode HashSetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_set(
+        &hash_request.key,
+        hash_request
+            .fields
+            .into_iter()
+            .map(|field| (field.field, field.value))
+            .collect(),
+    ) {
+        Ok(result) => {
+            let response_bytes = encode(&HashCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(HASH_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to set hash fields: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_get(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashFieldRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode HashFieldRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_get(&hash_request.key, &hash_request.field) {
+        Ok(result) => {
+            let response_bytes = encode(&HashValueResponse { value: result });
+
+            let response = generate_packet(HASH_VALUE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get hash field: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_mget(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashFieldsRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode HashFieldsRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_mget(&hash_request.key, &hash_request.fields) {
+        Ok(result) => {
+            let response_bytes = encode(&HashValuesResponse { values: result });
+
+            let response = generate_packet(HASH_VALUES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get hash fields: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_delete(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashFieldsRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode HashFieldsRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_delete(&hash_request.key, &hash_request.fields) {
+        Ok(result) => {
+            let response_bytes = encode(&HashCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(HASH_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to delete hash fields: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_get_all(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GetRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode GetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_get_all(&hash_request.key) {
+        Ok(result) => {
+            let response_bytes = encode(&HashEntriesResponse {
+                fields: result
+                    .into_iter()
+                    .map(|(field, value)| HashField { field, value })
+                    .collect(),
+            });
+
+            let response = generate_packet(HASH_ENTRIES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get hash: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_incr_by(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashIncrByRequest>(bytes);
+
+    let incr_request = match decode_result {
+        Ok(incr_request) => incr_request,
+        Err(error) => {
+            log::error!("Failed to decode HashIncrByRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_incr_by(&incr_request.key, &incr_request.field, incr_request.delta) {
+        Ok(result) => {
+            let response_bytes = encode(&IncrByResponse { value: result });
+
+            let response = generate_packet(INCR_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to increment hash field: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_exists(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<HashFieldRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode HashFieldRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_exists(&hash_request.key, &hash_request.field) {
+        Ok(result) => {
+            let response_bytes = encode(&HashExistsResponse { exists: result });
+
+            let response = generate_packet(HASH_EXISTS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to check hash field: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hash_len(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GetRequest>(bytes);
+
+    let hash_request = match decode_result {
+        Ok(hash_request) => hash_request,
+        Err(error) => {
+            log::error!("Failed to decode GetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hash_len(&hash_request.key) {
+        Ok(result) => {
+            let response_bytes = encode(&HashCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(HASH_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read hash length: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MembersRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode MembersRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.set_add(&set_request.key, set_request.members) {
+        Ok(result) => {
+            let response_bytes = encode(&MemberCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(MEMBER_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add set members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_remove(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MembersRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode MembersRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.set_remove(&set_request.key, &set_request.members) {
+        Ok(result) => {
+            let response_bytes = encode(&MemberCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(MEMBER_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to remove set members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_is_member(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MemberRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode MemberRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.set_is_member(&set_request.key, &set_request.member) {
+        Ok(result) => {
+            let response_bytes = encode(&IsMemberResponse { is_member: result });
+
+            let response = generate_packet(IS_MEMBER_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to check set member: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_members(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GetRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode GetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.set_members(&set_request.key) {
+        Ok(result) => {
+            let response_bytes = encode(&MembersResponse { members: result });
+
+            let response = generate_packet(MEMBERS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get set members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_set_combine(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+    tag: u8,
+) {
+    let decode_result = decode::<MultiKeyRequest>(bytes);
+
+    let combine_request = match decode_result {
+        Ok(combine_request) => combine_request,
+        Err(error) => {
+            log::error!("Failed to decode MultiKeyRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = match tag {
+        SINTER => engine.set_intersection(&combine_request.keys),
+        SUNION => engine.set_union(&combine_request.keys),
+        _ => engine.set_difference(&combine_request.keys),
+    };
+
+    match result {
+        Ok(members) => {
+            let response_bytes = encode(&MembersResponse { members });
+
+            let response = generate_packet(MEMBERS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to combine sets: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SortedSetAddRequest>(bytes);
+
+    let add_request = match decode_result {
+        Ok(add_request) => add_request,
+        Err(error) => {
+            log::error!("Failed to decode SortedSetAddRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_add(
+        &add_request.key,
+        add_request
+            .members
+            .into_iter()
+            .map(|member| (member.member, member.score))
+            .collect(),
+    ) {
+        Ok(result) => {
+            let response_bytes = encode(&MemberCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(MEMBER_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add sorted set members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_incr_by(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<SortedSetIncrByRequest>(bytes);
+
+    let incr_request = match decode_result {
+        Ok(incr_request) => incr_request,
+        Err(error) => {
+            log::error!("Failed to decode SortedSetIncrByRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_incr_by(&incr_request.key, &incr_request.member, incr_request.delta) {
+        Ok(result) => {
+            let response_bytes = encode(&IncrByFloatResponse { value: result });
+
+            let response = generate_packet(INCR_FLOAT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to increment sorted set score: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_range(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SortedSetRangeRequest>(bytes);
+
+    let range_request = match decode_result {
+        Ok(range_request) => range_request,
+        Err(error) => {
+            log::error!("Failed to decode SortedSetRangeRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_range(
+        &range_request.key,
+        range_request.start,
+        range_request.stop,
+        range_request.reverse,
+    ) {
+        Ok(result) => {
+            let response_bytes = encode(&ScoredMembersResponse {
+                members: result
+                    .into_iter()
+                    .map(|(member, score)| ScoredMember { member, score })
+                    .collect(),
+            });
+
+            let response = generate_packet(SCORED_MEMBERS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read sorted set range: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_range_by_score(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<SortedSetRangeByScoreRequest>(bytes);
+
+    let range_request = match decode_result {
+        Ok(range_request) => range_request,
+        Err(error) => {
+            log::error!("Failed to decode SortedSetRangeByScoreRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_range_by_score(&range_request.key, range_request.min, range_request.max)
+    {
+        Ok(result) => {
+            let response_bytes = encode(&ScoredMembersResponse {
+                members: result
+                    .into_iter()
+                    .map(|(member, score)| ScoredMember { member, score })
+                    .collect(),
+            });
+
+            let response = generate_packet(SCORED_MEMBERS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read sorted set range: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_rank(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SortedSetRankRequest>(bytes);
+
+    let rank_request = match decode_result {
+        Ok(rank_request) => rank_request,
+        Err(error) => {
+            log::error!("Failed to decode SortedSetRankRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_rank(
+        &rank_request.key,
+        &rank_request.member,
+        rank_request.reverse,
+    ) {
+        Ok(result) => {
+            let response_bytes = encode(&RankResponse {
+                rank: result.map(|rank| rank as u64),
+            });
+
+            let response = generate_packet(RANK_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read sorted set rank: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_sorted_set_remove(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<MembersRequest>(bytes);
+
+    let remove_request = match decode_result {
+        Ok(remove_request) => remove_request,
+        Err(error) => {
+            log::error!("Failed to decode MembersRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.sorted_set_remove(&remove_request.key, &remove_request.members) {
+        Ok(result) => {
+            let response_bytes = encode(&MemberCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(MEMBER_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to remove sorted set members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+// Parses an ID of a stream request; `special` (e.g. `$`) stands for `None`.
+fn parse_stream_id(id: &str, special: &str) -> Result<Option<StreamId>, KVError> {
+    if id == special {
+        return Ok(None);
+    }
+    id.parse().map(Some)
+}
+
+// Pairs each key with its ID, see `parse_stream_id`.
+fn parse_stream_ids(
+    keys: Vec<String>,
+    ids: &[String],
+    special: &str,
+) -> Result<Vec<(String, Option<StreamId>)>, KVError> {
+    if keys.len() != ids.len() {
+        return Err(KVError::InvalidStreamId);
+    }
+
+    keys.into_iter()
+        .zip(ids)
+        .map(|(key, id)| Ok((key, parse_stream_id(id, special)?)))
+        .collect()
+}
+
+fn stream_entry_message(entry: rstore::engine::StreamEntry) -> StreamEntry {
+    StreamEntry {
+        id: entry.id.to_string(),
+        fields: entry
+            .fields
+            .into_iter()
+            .map(|(field, value)| HashField { field, value })
+            .collect(),
+    }
+}
+
+fn stream_read_response(streams: Vec<(String, Vec<rstore::engine::StreamEntry>)>) -> Vec<u8> {
+    encode(&StreamReadResponse {
+        streams: streams
+            .into_iter()
+            .map(|(key, entries)| StreamEntries {
+                key,
+                entries: entries.into_iter().map(stream_entry_message).collect(),
+            })
+            .collect(),
+    })
+}
+
+// 0 waits forever
+fn block_timeout(block_millis: u64) -> Option<Duration> {
+    (block_millis > 0).then(|| Duration::from_millis(block_millis))
+}
+
+pub async fn process_stream_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamAddRequest>(bytes);
+
+    let add_request = match decode_result {
+        Ok(add_request) => add_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamAddRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let fields = add_request
+        .fields
+        .into_iter()
+        .map(|field| (field.field, field.value))
+        .collect();
+    let result = parse_stream_id(&add_request.id, "*")
+        .and_then(|id| engine.stream_add(&add_request.key, id, fields));
+
+    match result {
+        Ok(id) => {
+            let response_bytes = encode(&StreamAddResponse { id: id.to_string() });
+
+            let response = generate_packet(STREAM_ID_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add stream entry: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stream_range(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamRangeRequest>(bytes);
+
+    let range_request = match decode_result {
+        Ok(range_request) => range_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamRangeRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = parse_stream_id(&range_request.start, "-").and_then(|start| {
+        let end = parse_stream_id(&range_request.end, "+")?;
+        engine.stream_range(
+            &range_request.key,
+            start.unwrap_or(StreamId::MIN),
+            end.unwrap_or(StreamId::MAX),
+            range_request.count.map(|count| count as usize),
+        )
+    });
+
+    match result {
+        Ok(entries) => {
+            let response_bytes = encode(&StreamEntriesResponse {
+                entries: entries.into_iter().map(stream_entry_message).collect(),
+            });
+
+            let response = generate_packet(STREAM_ENTRIES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read stream range: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Reads from streams, parking the connection until an entry is added if asked to block.
+pub async fn process_stream_read(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamReadRequest>(bytes);
+
+    let read_request = match decode_result {
+        Ok(read_request) => read_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamReadRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let count = read_request.count.map(|count| count as usize);
+    let streams = match parse_stream_ids(read_request.keys, &read_request.ids, "$") {
+        Ok(streams) => streams,
+        Err(error) => {
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+            return;
+        }
+    };
+
+    let result = match read_request.block_millis {
+        Some(block_millis) => {
+            tokio::select! {
+                result = engine.stream_read_blocking(&streams, count, block_timeout(block_millis)) => result,
+                _ = peer_closed(stream) => {
+                    log::debug!("Client went away during a blocking stream read");
+                    return;
+                }
+            }
+        }
+        None => {
+            // Without waiting, `$` has nothing to read
+            let streams: Vec<(String, StreamId)> = streams
+                .into_iter()
+                .map(|(key, id)| (key, id.unwrap_or(StreamId::MAX)))
+                .collect();
+            engine.stream_read(&streams, count)
+        }
+    };
+
+    match result {
+        Ok(streams) => {
+            let response = generate_packet(STREAM_READ_OK, &stream_read_response(streams));
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read streams: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stream_group_create(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<StreamGroupCreateRequest>(bytes);
+
+    let create_request = match decode_result {
+        Ok(create_request) => create_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamGroupCreateRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = parse_stream_id(&create_request.id, "$").and_then(|start| {
+        engine.stream_group_create(&create_request.key, &create_request.group, start)
+    });
+
+    match result {
+        Ok(()) => {
+            let _ = stream.write_all(&[XGROUP_CREATE_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to create consumer group: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Reads from streams for a consumer of a group, parking the connection until an entry
+/// is added if asked to block.
+pub async fn process_stream_read_group(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<StreamReadGroupRequest>(bytes);
+
+    let read_request = match decode_result {
+        Ok(read_request) => read_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamReadGroupRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let count = read_request.count.map(|count| count as usize);
+    let streams = match parse_stream_ids(read_request.keys, &read_request.ids, ">") {
+        Ok(streams) => streams,
+        Err(error) => {
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+            return;
+        }
+    };
+    let group = &read_request.group;
+    let consumer = &read_request.consumer;
+
+    let result = match read_request.block_millis {
+        Some(block_millis) => {
+            let timeout = block_timeout(block_millis);
+            tokio::select! {
+                result = engine.stream_read_group_blocking(group, consumer, &streams, count, timeout) => result,
+                _ = peer_closed(stream) => {
+                    log::debug!("Client went away during a blocking group read");
+                    return;
+                }
+            }
+        }
+        None => engine.stream_read_group(group, consumer, &streams, count),
+    };
+
+    match result {
+        Ok(streams) => {
+            let response = generate_packet(STREAM_READ_OK, &stream_read_response(streams));
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to read streams for group: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stream_ack(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamAckRequest>(bytes);
+
+    let ack_request = match decode_result {
+        Ok(ack_request) => ack_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamAckRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = ack_request
+        .ids
+        .iter()
+        .map(|id| id.parse())
+        .collect::<Result<Vec<StreamId>, KVError>>()
+        .and_then(|ids| engine.stream_ack(&ack_request.key, &ack_request.group, &ids));
+
+    match result {
+        Ok(acked) => {
+            let response_bytes = encode(&StreamAckResponse {
+                acked: acked as u64,
+            });
+
+            let response = generate_packet(XACK_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to acknowledge stream entries: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stream_pending(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamPendingRequest>(bytes);
+
+    let pending_request = match decode_result {
+        Ok(pending_request) => pending_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamPendingRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.stream_pending(
+        &pending_request.key,
+        &pending_request.group,
+        pending_request.count.map(|count| count as usize),
+    ) {
+        Ok(entries) => {
+            let response_bytes = encode(&StreamPendingResponse {
+                entries: entries
+                    .into_iter()
+                    .map(|entry| StreamPendingEntry {
+                        id: entry.id.to_string(),
+                        consumer: entry.consumer,
+                        idle_millis: entry.idle.as_millis() as u64,
+                        deliveries: entry.deliveries,
+                    })
+                    .collect(),
+            });
+
+            let response = generate_packet(PENDING_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to list pending stream entries: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stream_claim(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<StreamClaimRequest>(bytes);
+
+    let claim_request = match decode_result {
+        Ok(claim_request) => claim_request,
+        Err(error) => {
+            log::error!("Failed to decode StreamClaimRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.stream_claim(
+        &claim_request.key,
+        &claim_request.group,
+        &claim_request.consumer,
+        Duration::from_millis(claim_request.min_idle_millis),
+        claim_request.count.map(|count| count as usize),
+    ) {
+        Ok(entries) => {
+            let response_bytes = encode(&StreamEntriesResponse {
+                entries: entries.into_iter().map(stream_entry_message).collect(),
+            });
+
+            let response = generate_packet(STREAM_ENTRIES_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to claim pending stream entries: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_json_get(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JsonPathRequest>(bytes);
+
+    let get_request = match decode_result {
+        Ok(get_request) => get_request,
+        Err(error) => {
+            log::error!("Failed to decode JsonPathRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.json_get(&get_request.key, &get_request.path) {
+        Ok(value) => {
+            let response_bytes = encode(&JsonValueResponse {
+                value: value.map(|value| value.to_string()),
+            });
+
+            let response = generate_packet(JSON_VALUE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to get JSON value: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_json_set(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JsonSetRequest>(bytes);
+
+    let set_request = match decode_result {
+        Ok(set_request) => set_request,
+        Err(error) => {
+            log::error!("Failed to decode JsonSetRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let value = match serde_json::from_str(&set_request.value) {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to parse JSON value: {}", error);
+            let _ = stream.write_all(&[INVALID_JSON]).await;
+            return;
+        }
+    };
+
+    match engine.json_set(&set_request.key, &set_request.path, value) {
+        Ok(()) => {
+            let _ = stream.write_all(&[JSON_SET_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to set JSON value: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_json_del(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JsonPathRequest>(bytes);
+
+    let delete_request = match decode_result {
+        Ok(delete_request) => delete_request,
+        Err(error) => {
+            log::error!("Failed to decode JsonPathRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.json_del(&delete_request.key, &delete_request.path) {
+        Ok(deleted) => {
+            let response_bytes = encode(&JsonDeleteResponse { deleted });
+
+            let response = generate_packet(JSON_DEL_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to delete JSON value: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_json_array_append(
+    stream: &mut TcpStream,
+    engine: &mut KVEngine,
+    bytes: &[u8],
+) {
+    let decode_result = decode::<JsonAppendRequest>(bytes);
+
+    let append_request = match decode_result {
+        Ok(append_request) => append_request,
+        Err(error) => {
+            log::error!("Failed to decode JsonAppendRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let values = match append_request
+        .values
+        .iter()
+        .map(|value| serde_json::from_str(value))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(values) => values,
+        Err(error) => {
+            log::error!("Failed to parse JSON value: {}", error);
+            let _ = stream.write_all(&[INVALID_JSON]).await;
+            return;
+        }
+    };
+
+    match engine.json_array_append(&append_request.key, &append_request.path, values) {
+        Ok(length) => {
+            let response_bytes = encode(&ListLengthResponse {
+                length: length as u64,
+            });
+
+            let response = generate_packet(LIST_LENGTH_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to append to JSON array: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_json_incr_by(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JsonIncrByRequest>(bytes);
+
+    let incr_request = match decode_result {
+        Ok(incr_request) => incr_request,
+        Err(error) => {
+            log::error!("Failed to decode JsonIncrByRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.json_incr_by(&incr_request.key, &incr_request.path, incr_request.delta) {
+        Ok(number) => {
+            let response_bytes = encode(&JsonValueResponse {
+                value: Some(number.to_string()),
+            });
+
+            let response = generate_packet(JSON_VALUE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to increment JSON number: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hll_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MembersRequest>(bytes);
+
+    let add_request = match decode_result {
+        Ok(add_request) => add_request,
+        Err(error) => {
+            log::error!("Failed to decode MembersRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hll_add(&add_request.key, &add_request.members) {
+        Ok(changed) => {
+            let response_bytes = encode(&PfAddResponse { changed });
+
+            let response = generate_packet(PFADD_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add to HyperLogLog: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hll_count(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MultiKeyRequest>(bytes);
+
+    let count_request = match decode_result {
+        Ok(count_request) => count_request,
+        Err(error) => {
+            log::error!("Failed to decode MultiKeyRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hll_count(&count_request.keys) {
+        Ok(count) => {
+            let response_bytes = encode(&PfCountResponse { count });
+
+            let response = generate_packet(PFCOUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to count HyperLogLog: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_hll_merge(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<PfMergeRequest>(bytes);
+
+    let merge_request = match decode_result {
+        Ok(merge_request) => merge_request,
+        Err(error) => {
+            log::error!("Failed to decode PfMergeRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.hll_merge(&merge_request.destination, &merge_request.sources) {
+        Ok(()) => {
+            let _ = stream.write_all(&[PFMERGE_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to merge HyperLogLogs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_bloom_reserve(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<BloomReserveRequest>(bytes);
+
+    let reserve_request = match decode_result {
+        Ok(reserve_request) => reserve_request,
+        Err(error) => {
+            log::error!("Failed to decode BloomReserveRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.bloom_reserve(
+        &reserve_request.key,
+        reserve_request.error_rate,
+        reserve_request.capacity,
+    ) {
+        Ok(()) => {
+            let _ = stream.write_all(&[BF_RESERVE_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to reserve Bloom filter: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_bloom_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MemberRequest>(bytes);
+
+    let add_request = match decode_result {
+        Ok(add_request) => add_request,
+        Err(error) => {
+            log::error!("Failed to decode MemberRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.bloom_add(&add_request.key, &add_request.member) {
+        Ok(added) => {
+            let response_bytes = encode(&BloomAddResponse { added });
+
+            let response = generate_packet(BF_ADD_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add to Bloom filter: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_bloom_exists(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<MemberRequest>(bytes);
+
+    let exists_request = match decode_result {
+        Ok(exists_request) => exists_request,
+        Err(error) => {
+            log::error!("Failed to decode MemberRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.bloom_exists(&exists_request.key, &exists_request.member) {
+        Ok(is_member) => {
+            let response_bytes = encode(&IsMemberResponse { is_member });
+
+            let response = generate_packet(IS_MEMBER_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to check Bloom filter: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_geo_add(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GeoAddRequest>(bytes);
+
+    let add_request = match decode_result {
+        Ok(add_request) => add_request,
+        Err(error) => {
+            log::error!("Failed to decode GeoAddRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.geo_add(
+        &add_request.key,
+        add_request
+            .members
+            .into_iter()
+            .map(|member| (member.member, member.longitude, member.latitude))
+            .collect(),
+    ) {
+        Ok(result) => {
+            let response_bytes = encode(&MemberCountResponse {
+                count: result as u64,
+            });
+
+            let response = generate_packet(MEMBER_COUNT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to add geo members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_geo_dist(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GeoDistRequest>(bytes);
+
+    let dist_request = match decode_result {
+        Ok(dist_request) => dist_request,
+        Err(error) => {
+            log::error!("Failed to decode GeoDistRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.geo_dist(&dist_request.key, &dist_request.from, &dist_request.to) {
+        Ok(distance) => {
+            let response_bytes = encode(&GeoDistResponse { distance });
+
+            let response = generate_packet(GEODIST_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to measure geo distance: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_geo_search(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<GeoSearchRequest>(bytes);
+
+    let search_request = match decode_result {
+        Ok(search_request) => search_request,
+        Err(error) => {
+            log::error!("Failed to decode GeoSearchRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let shape = match search_request {
+        GeoSearchRequest {
+            radius: Some(radius),
+            ..
+        } => GeoShape::Radius(radius),
+        GeoSearchRequest {
+            width: Some(width),
+            height: Some(height),
+            ..
+        } => GeoShape::Box { width, height },
+        _ => {
+            log::error!("GeoSearchRequest has neither a radius nor a box");
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+    let sort = search_request.ascending.map(|ascending| {
+        if ascending {
+            GeoSort::Ascending
+        } else {
+            GeoSort::Descending
+        }
+    });
+
+    match engine.geo_search(
+        &search_request.key,
+        search_request.longitude,
+        search_request.latitude,
+        shape,
+        sort,
+        search_request.count.map(|count| count as usize),
+    ) {
+        Ok(matches) => {
+            let response_bytes = encode(&GeoSearchResponse {
+                matches: matches
+                    .into_iter()
+                    .map(|found| GeoMatch {
+                        member: found.member,
+                        distance: found.distance,
+                        longitude: found.longitude,
+                        latitude: found.latitude,
+                    })
+                    .collect(),
+            });
+
+            let response = generate_packet(GEOSEARCH_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to search geo members: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_rate_limit(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<RateLimitRequest>(bytes);
+
+    let rate_limit_request = match decode_result {
+        Ok(rate_limit_request) => rate_limit_request,
+        Err(error) => {
+            log::error!("Failed to decode RateLimitRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let result = rate_limit_request
+        .algorithm
+        .parse::<RateLimitAlgorithm>()
+        .and_then(|algorithm| {
+            engine.rate_limit(
+                &rate_limit_request.key,
+                rate_limit_request.limit,
+                Duration::from_millis(rate_limit_request.window_millis),
+                algorithm,
+            )
+        });
+
+    match result {
+        Ok(status) => {
+            let response_bytes = encode(&RateLimitResponse {
+                allowed: status.allowed,
+                remaining: status.remaining,
+                reset_after_millis: status.reset_after.as_millis() as u64,
+            });
+
+            let response = generate_packet(RATE_LIMIT_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to rate limit: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_lock_acquire(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<LockAcquireRequest>(bytes);
+
+    let acquire_request = match decode_result {
+        Ok(acquire_request) => acquire_request,
+        Err(error) => {
+            log::error!("Failed to decode LockAcquireRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    let lease = Duration::from_millis(acquire_request.lease_millis);
+    let result = match acquire_request.wait_millis {
+        Some(wait_millis) => {
+            tokio::select! {
+                result = engine.lock_acquire_blocking(
+                    &acquire_request.name,
+                    &acquire_request.owner,
+                    lease,
+                    block_timeout(wait_millis),
+                ) => result,
+                _ = peer_closed(stream) => {
+                    log::debug!("Client went away while waiting for a lock");
+                    return;
+                }
+            }
+        }
+        None => engine.lock_acquire(&acquire_request.name, &acquire_request.owner, lease),
+    };
+
+    match result {
+        Ok(token) => {
+            let response_bytes = encode(&LockAcquireResponse { token });
+
+            let response = generate_packet(LOCK_ACQUIRE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to acquire lock: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_lock_renew(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<LockRenewRequest>(bytes);
+
+    let renew_request = match decode_result {
+        Ok(renew_request) => renew_request,
+        Err(error) => {
+            log::error!("Failed to decode LockRenewRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.lock_renew(
+        &renew_request.name,
+        &renew_request.owner,
+        Duration::from_millis(renew_request.lease_millis),
+    ) {
+        Ok(renewed) => {
+            let response_bytes = encode(&LockRenewResponse { renewed });
+
+            let response = generate_packet(LOCK_RENEW_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to renew lock: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_lock_release(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<LockReleaseRequest>(bytes);
+
+    let release_request = match decode_result {
+        Ok(release_request) => release_request,
+        Err(error) => {
+            log::error!("Failed to decode LockReleaseRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.lock_release(&release_request.name, &release_request.owner) {
+        Ok(released) => {
+            let response_bytes = encode(&LockReleaseResponse { released });
+
+            let response = generate_packet(LOCK_RELEASE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to release lock: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_job_enqueue(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JobEnqueueRequest>(bytes);
+
+    let enqueue_request = match decode_result {
+        Ok(enqueue_request) => enqueue_request,
+        Err(error) => {
+            log::error!("Failed to decode JobEnqueueRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.job_enqueue(
+        &enqueue_request.key,
+        enqueue_request.payload,
+        Duration::from_millis(enqueue_request.delay_millis),
+        enqueue_request.max_attempts,
+    ) {
+        Ok(id) => {
+            let response_bytes = encode(&JobEnqueueResponse { id });
+
+            let response = generate_packet(JOB_ENQUEUE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to enqueue job: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+fn jobs_response(jobs: Vec<QueuedJob>) -> Vec<u8> {
+    encode(&JobsResponse {
+        jobs: jobs
+            .into_iter()
+            .map(|job| Job {
+                id: job.id,
+                payload: job.payload,
+                attempts: job.attempts,
+            })
+            .collect(),
+    })
+}
+
+pub async fn process_job_dequeue(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JobDequeueRequest>(bytes);
+
+    let dequeue_request = match decode_result {
+        Ok(dequeue_request) => dequeue_request,
+        Err(error) => {
+            log::error!("Failed to decode JobDequeueRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.job_dequeue(
+        &dequeue_request.key,
+        Duration::from_millis(dequeue_request.visibility_millis),
+        dequeue_request.count as usize,
+    ) {
+        Ok(jobs) => {
+            let response = generate_packet(JOBS_OK, &jobs_response(jobs));
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to dequeue jobs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_job_ack(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JobAckRequest>(bytes);
+
+    let ack_request = match decode_result {
+        Ok(ack_request) => ack_request,
+        Err(error) => {
+            log::error!("Failed to decode JobAckRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.job_ack(&ack_request.key, ack_request.id, ack_request.attempts) {
+        Ok(acked) => {
+            let response_bytes = encode(&JobAckResponse { acked });
+
+            let response = generate_packet(JOB_ACK_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to ack job: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_job_nack(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JobNackRequest>(bytes);
+
+    let nack_request = match decode_result {
+        Ok(nack_request) => nack_request,
+        Err(error) => {
+            log::error!("Failed to decode JobNackRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.job_nack(
+        &nack_request.key,
+        nack_request.id,
+        nack_request.attempts,
+        Duration::from_millis(nack_request.delay_millis),
+    ) {
+        Ok(nacked) => {
+            let response_bytes = encode(&JobNackResponse { nacked });
+
+            let response = generate_packet(JOB_NACK_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to nack job: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_job_dead_letters(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<JobDeadLettersRequest>(bytes);
+
+    let dead_letters_request = match decode_result {
+        Ok(dead_letters_request) => dead_letters_request,
+        Err(error) => {
+            log::error!("Failed to decode JobDeadLettersRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.job_dead_letters(
+        &dead_letters_request.key,
+        dead_letters_request.count as usize,
+    ) {
+        Ok(jobs) => {
+            let response = generate_packet(JOBS_OK, &jobs_response(jobs));
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to pop dead-lettered jobs: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Binds the connection to another namespace; every later command operates on its keys.
+pub async fn process_select(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<SelectRequest>(bytes);
+
+    let select_request = match decode_result {
+        Ok(select_request) => select_request,
+        Err(error) => {
+            log::error!("Failed to decode SelectRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.namespace(&select_request.namespace) {
+        Ok(selected) => {
+            *engine = selected;
+            let _ = stream.write_all(&[SELECT_OK]).await;
+        }
+        Err(error) => {
+            log::error!("Failed to select namespace: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+/// Removes an empty namespace. The connection keeps its own selection, even if it's the
+/// namespace dropped.
+pub async fn process_drop_namespace(stream: &mut TcpStream, engine: &mut KVEngine, bytes: &[u8]) {
+    let decode_result = decode::<DropNamespaceRequest>(bytes);
+
+    let drop_request = match decode_result {
+        Ok(drop_request) => drop_request,
+        Err(error) => {
+            log::error!("Failed to decode DropNamespaceRequest: {}", error);
+            let _ = stream.write_all(&[PACKET_INVALID]).await;
+            return;
+        }
+    };
+
+    match engine.drop_namespace(&drop_request.namespace) {
+        Ok(dropped) => {
+            let response_bytes = encode(&DropNamespaceResponse { dropped });
+
+            let response = generate_packet(DROP_NAMESPACE_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to drop namespace: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}
+
+pub async fn process_stats(stream: &mut TcpStream, engine: &mut KVEngine) {
+    match engine.stats() {
+        Ok(stats) => {
+            let response_bytes = encode(&StatsResponse {
+                namespace: engine.namespace_name().to_owned(),
+                keys: stats.keys,
+                expiring_keys: stats.expiring_keys,
+                used_memory: stats.used_memory,
+            });
+
+            let response = generate_packet(STATS_OK, &response_bytes);
+            let _ = stream.write_all(&response).await;
+        }
+        Err(error) => {
+            log::error!("Failed to collect namespace stats: {}", error);
+            let _ = stream.write_all(&[error_tag(&error)]).await;
+        }
+    }
+}